@@ -0,0 +1,295 @@
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+// 解像度の増減幅（1.0 = 通常サイズ）
+const SCALE_STEP: f32 = 0.125;
+// 何回続けて良好な報告が来たら品質を上げるか
+const RECOVERY_REPORTS: u32 = 3;
+// ビットレートの丸め単位（細かな変更でエンコーダーを作り直さないため）
+const BITRATE_STEP_KBPS: u32 = 50;
+// 帯域推定に追従する最小の変化率（ビットレート変更のたびにエンコーダーを作り直すため）
+const ESTIMATE_HYSTERESIS: f32 = 0.15;
+
+/// 適応制御の範囲（デスクトップ側で設定）
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct StreamBounds {
+    pub min_bitrate_kbps: u32,
+    pub max_bitrate_kbps: u32,
+    pub min_fps: u32,
+    pub max_fps: u32,
+    pub min_scale: f32,
+    pub max_scale: f32,
+}
+
+impl Default for StreamBounds {
+    fn default() -> Self {
+        Self {
+            min_bitrate_kbps: 300,
+            max_bitrate_kbps: 8_000,
+            min_fps: 5,
+            max_fps: 30,
+            min_scale: 0.5,
+            max_scale: 1.0,
+        }
+    }
+}
+
+static BOUNDS: Lazy<RwLock<StreamBounds>> = Lazy::new(|| RwLock::new(StreamBounds::default()));
+
+/// 現在の適応制御の範囲
+pub fn bounds() -> StreamBounds {
+    *BOUNDS.read()
+}
+
+/// 適応制御の範囲を設定（次の統計報告から反映）
+pub fn set_bounds(bounds: StreamBounds) -> Result<(), String> {
+    if bounds.min_bitrate_kbps == 0 || bounds.min_bitrate_kbps > bounds.max_bitrate_kbps {
+        return Err("Invalid bitrate range".to_string());
+    }
+    if bounds.min_fps == 0 || bounds.min_fps > bounds.max_fps || bounds.max_fps > 60 {
+        return Err("Invalid frame rate range".to_string());
+    }
+    if bounds.min_scale <= 0.0 || bounds.min_scale > bounds.max_scale || bounds.max_scale > 1.0 {
+        return Err("Invalid scale range".to_string());
+    }
+    *BOUNDS.write() = bounds;
+    Ok(())
+}
+
+/// クライアントからの受信統計（前回の報告以降の値）
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StreamStats {
+    /// 受信ビットレート
+    pub received_kbps: f32,
+    /// 受信フレームレート
+    pub received_fps: f32,
+    /// 1フレームあたりの平均デコード時間
+    pub decode_ms: f32,
+    /// 欠落・破棄したフレーム数
+    #[serde(default)]
+    pub dropped_frames: u32,
+    /// 対象の経路（"websocket" または "webrtc"）
+    #[serde(default = "default_transport")]
+    pub transport: String,
+}
+
+fn default_transport() -> String {
+    "websocket".to_string()
+}
+
+/// 現在の送信目標（クライアントに通知する）
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StreamTargets {
+    pub bitrate_kbps: u32,
+    pub fps: u32,
+    /// 通常の送信サイズに対する倍率
+    pub scale: f32,
+}
+
+impl StreamTargets {
+    /// 送信サイズに倍率を掛ける（YUV420のため偶数に揃える）
+    pub fn scale_size(&self, width: u32, height: u32) -> (u32, u32) {
+        let w = ((width as f32 * self.scale) as u32 / 2) * 2;
+        let h = ((height as f32 * self.scale) as u32 / 2) * 2;
+        (w.max(2), h.max(2))
+    }
+
+    /// 1フレームあたりの目標バイト数
+    pub fn frame_budget_bytes(&self) -> usize {
+        (self.bitrate_kbps as usize * 1000 / 8) / self.fps.max(1) as usize
+    }
+}
+
+/// 受信統計からビットレート・フレームレート・解像度を調整する
+pub struct RateController {
+    targets: StreamTargets,
+    healthy_reports: u32,
+}
+
+impl Default for RateController {
+    fn default() -> Self {
+        let bounds = bounds();
+        Self {
+            targets: StreamTargets {
+                // 回線品質が分からないので中間から開始
+                bitrate_kbps: (2_500).clamp(bounds.min_bitrate_kbps, bounds.max_bitrate_kbps),
+                fps: bounds.max_fps,
+                scale: bounds.max_scale,
+            },
+            healthy_reports: 0,
+        }
+    }
+}
+
+impl RateController {
+    pub fn targets(&self) -> StreamTargets {
+        self.targets
+    }
+
+    /// 統計を反映して新しい目標を返す
+    pub fn update(&mut self, stats: &StreamStats) -> StreamTargets {
+        let bounds = bounds();
+        let t = &mut self.targets;
+        let frame_interval_ms = 1000.0 / t.fps.max(1) as f32;
+
+        if stats.dropped_frames > 0 {
+            // 回線が詰まっている: ビットレート -> 解像度 -> フレームレートの順に下げる
+            self.healthy_reports = 0;
+            if t.bitrate_kbps > bounds.min_bitrate_kbps {
+                t.bitrate_kbps = t.bitrate_kbps * 7 / 10;
+            } else if t.scale > bounds.min_scale {
+                t.scale -= SCALE_STEP;
+            } else {
+                t.fps = t.fps * 3 / 4;
+            }
+        } else if stats.decode_ms > frame_interval_ms * 0.8 {
+            // クライアントのデコードが追いつかない: フレームレート -> 解像度の順に下げる
+            self.healthy_reports = 0;
+            if t.fps > bounds.min_fps {
+                t.fps = t.fps * 3 / 4;
+            } else {
+                t.scale -= SCALE_STEP;
+            }
+        } else {
+            self.healthy_reports += 1;
+            if self.healthy_reports >= RECOVERY_REPORTS {
+                self.healthy_reports = 0;
+                // 解像度 -> フレームレート -> ビットレートの順に戻す
+                // ビットレートは実際に目標近くまで使っている時だけ上げる（静止画面では上げない）
+                if t.scale < bounds.max_scale {
+                    t.scale += SCALE_STEP;
+                } else if t.fps < bounds.max_fps {
+                    t.fps += 5;
+                } else if stats.received_kbps >= t.bitrate_kbps as f32 * 0.5 {
+                    // 丸めで元に戻らないよう最低1単位は上げる
+                    t.bitrate_kbps = (t.bitrate_kbps.saturating_mul(115) / 100)
+                        .max(t.bitrate_kbps.saturating_add(BITRATE_STEP_KBPS));
+                }
+            }
+        }
+
+        t.bitrate_kbps = (t.bitrate_kbps / BITRATE_STEP_KBPS * BITRATE_STEP_KBPS)
+            .clamp(bounds.min_bitrate_kbps, bounds.max_bitrate_kbps);
+        t.fps = t.fps.clamp(bounds.min_fps, bounds.max_fps);
+        t.scale = t.scale.clamp(bounds.min_scale, bounds.max_scale);
+
+        println!(
+            "[Adaptive] {} stats: {:.0} kbps, {:.1} fps, decode {:.1} ms, dropped {} -> targets {:?}",
+            stats.transport, stats.received_kbps, stats.received_fps, stats.decode_ms, stats.dropped_frames, t
+        );
+        *t
+    }
+
+    /// 受信側の帯域推定（RTCP REMB）にビットレートを合わせる
+    pub fn apply_estimate(&mut self, estimated_kbps: u32) -> StreamTargets {
+        let bounds = bounds();
+        // 推定値ぎりぎりだと詰まりやすいので1割の余裕を持たせる
+        // （推定値は相手が送ってくる値なので、u64で計算して先に上限で抑える）
+        let headroom = (estimated_kbps as u64 * 9 / 10).min(bounds.max_bitrate_kbps as u64) as u32;
+        let bitrate = (headroom / BITRATE_STEP_KBPS * BITRATE_STEP_KBPS)
+            .clamp(bounds.min_bitrate_kbps, bounds.max_bitrate_kbps);
+        let current = self.targets.bitrate_kbps as f32;
+        if (bitrate as f32 - current).abs() > current * ESTIMATE_HYSTERESIS {
+            println!("[Adaptive] Bandwidth estimate {} kbps -> bitrate {} kbps", estimated_kbps, bitrate);
+            self.targets.bitrate_kbps = bitrate;
+        }
+        self.targets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 既定の範囲: 300-8000 kbps, 5-30 fps, 倍率 0.5-1.0（開始は 2500 kbps, 30 fps, 1.0）
+    fn stats(received_kbps: f32, decode_ms: f32, dropped_frames: u32) -> StreamStats {
+        StreamStats {
+            received_kbps,
+            received_fps: 30.0,
+            decode_ms,
+            dropped_frames,
+            transport: "webrtc".to_string(),
+        }
+    }
+
+    fn healthy(rate: &mut RateController, received_kbps: f32) -> StreamTargets {
+        for _ in 1..RECOVERY_REPORTS {
+            rate.update(&stats(received_kbps, 5.0, 0));
+        }
+        rate.update(&stats(received_kbps, 5.0, 0))
+    }
+
+    #[test]
+    fn test_step_down() {
+        // 欠落: ビットレート -> 解像度 -> フレームレートの順に下げる
+        let mut rate = RateController::default();
+        assert_eq!(rate.update(&stats(2500.0, 5.0, 3)).bitrate_kbps, 1750);
+        while rate.targets().bitrate_kbps > 300 {
+            rate.update(&stats(300.0, 5.0, 3));
+        }
+        assert_eq!(rate.update(&stats(300.0, 5.0, 3)).scale, 1.0 - SCALE_STEP);
+        assert_eq!(rate.targets().fps, 30);
+
+        // デコードが追いつかない: フレームレートから下げる
+        let mut rate = RateController::default();
+        let t = rate.update(&stats(2500.0, 40.0, 0));
+        assert_eq!((t.bitrate_kbps, t.fps, t.scale), (2500, 22, 1.0));
+    }
+
+    #[test]
+    fn test_step_up() {
+        let mut rate = RateController::default();
+        rate.update(&stats(2500.0, 5.0, 3));
+        rate.update(&stats(2500.0, 40.0, 0));
+        let t = rate.targets();
+        assert_eq!((t.bitrate_kbps, t.fps), (1750, 22));
+
+        // 良好な報告が続くまでは上げない
+        assert_eq!(rate.update(&stats(1750.0, 5.0, 0)), t);
+        // フレームレート -> ビットレートの順に戻す
+        assert_eq!(healthy(&mut rate, 1750.0).fps, 27);
+        assert_eq!(healthy(&mut rate, 1750.0).fps, 30);
+        // 帯域を使っていなければビットレートは上げない（静止画面）
+        assert_eq!(healthy(&mut rate, 100.0).bitrate_kbps, 1750);
+        assert_eq!(healthy(&mut rate, 1750.0).bitrate_kbps, 2000);
+    }
+
+    #[test]
+    fn test_clamped_to_bounds() {
+        let bounds = StreamBounds::default();
+        let mut rate = RateController::default();
+        for _ in 0..50 {
+            rate.update(&stats(0.0, 500.0, 10));
+        }
+        let t = rate.targets();
+        assert_eq!(t.bitrate_kbps, bounds.min_bitrate_kbps);
+        assert_eq!(t.fps, bounds.min_fps);
+        assert_eq!(t.scale, bounds.min_scale);
+
+        for _ in 0..200 {
+            rate.update(&stats(100_000.0, 1.0, 0));
+        }
+        let t = rate.targets();
+        assert_eq!(t.bitrate_kbps, bounds.max_bitrate_kbps);
+        assert_eq!(t.fps, bounds.max_fps);
+        assert_eq!(t.scale, bounds.max_scale);
+    }
+
+    #[test]
+    fn test_apply_estimate() {
+        let bounds = StreamBounds::default();
+        let mut rate = RateController::default();
+
+        // 1割の余裕を持たせて丸める
+        assert_eq!(rate.apply_estimate(1_000).bitrate_kbps, 900);
+        // 小さな変化には追従しない
+        assert_eq!(rate.apply_estimate(1_100).bitrate_kbps, 900);
+        assert_eq!(rate.apply_estimate(2_000).bitrate_kbps, 1_800);
+
+        // 範囲外の推定値（相手が送ってくる値）は範囲に収める
+        assert_eq!(rate.apply_estimate(0).bitrate_kbps, bounds.min_bitrate_kbps);
+        assert_eq!(rate.apply_estimate(u32::MAX).bitrate_kbps, bounds.max_bitrate_kbps);
+        assert_eq!(rate.apply_estimate(600_000_000).bitrate_kbps, bounds.max_bitrate_kbps);
+    }
+}
@@ -0,0 +1,424 @@
+use audiopus::coder::Encoder as OpusEncoder;
+use audiopus::{Application, Bitrate, Channels, SampleRate};
+use bytes::Bytes;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use webrtc::media::Sample;
+use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+
+/// 送信する音声の形式（Opus: 48kHz ステレオ、20msフレーム）
+pub const SAMPLE_RATE: u32 = 48_000;
+pub const CHANNELS: usize = 2;
+pub const FRAME_DURATION: Duration = Duration::from_millis(20);
+// 1フレームのサンプル数（全チャンネル分）
+const FRAME_LEN: usize = SAMPLE_RATE as usize / 50 * CHANNELS;
+const BITRATE: i32 = 96_000;
+// システム音声の受信バッファがこれを超えたら古いものから捨てる（遅延を溜めない、200ms）
+const MAX_BUFFERED: usize = FRAME_LEN * 10;
+
+/// 音声の入力元
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum AudioSource {
+    /// システムの出力音声（LinuxはPulseAudio/PipeWireのモニターソース、Windowsはループバック）
+    #[default]
+    System,
+    /// サイン波（動作確認用）
+    Sine { frequency: f32 },
+    /// WAVファイルを繰り返し再生（動作確認用）
+    File { path: String },
+}
+
+// 新しいセッションで使う入力元（全セッション共通）
+static SOURCE: Lazy<RwLock<AudioSource>> = Lazy::new(|| RwLock::new(AudioSource::default()));
+
+pub fn source() -> AudioSource {
+    SOURCE.read().clone()
+}
+
+/// 入力元を変更（次に開始するセッションから有効）
+pub fn set_source(source: AudioSource) -> Result<(), String> {
+    match &source {
+        AudioSource::Sine { frequency } if !(*frequency > 0.0 && *frequency < SAMPLE_RATE as f32 / 2.0) => {
+            return Err(format!("Invalid sine frequency: {}", frequency));
+        }
+        AudioSource::File { path } if !std::path::Path::new(path).is_file() => {
+            return Err(format!("Audio file not found: {}", path));
+        }
+        _ => {}
+    }
+    println!("[Audio] Source set to {:?}", source);
+    *SOURCE.write() = source;
+    Ok(())
+}
+
+// 20ms分のPCM（48kHz ステレオ、インターリーブ）を埋める
+trait PcmSource {
+    fn fill(&mut self, frame: &mut [i16]);
+}
+
+fn open(source: &AudioSource) -> Result<Box<dyn PcmSource>, String> {
+    match source {
+        AudioSource::System => Ok(Box::new(system::open()?)),
+        AudioSource::Sine { frequency } => Ok(Box::new(SineSource::new(*frequency))),
+        AudioSource::File { path } => Ok(Box::new(FileSource::open(path)?)),
+    }
+}
+
+/// 音声の送信（セッションごと）: 入力元をOpusにエンコードしてWebRTCの音声トラックへ書き込む
+/// 映像トラックと同じく実時間でサンプルを書くので、受信側はRTCP送信レポートで映像と同期できる
+pub struct AudioStream {
+    running: Arc<AtomicBool>,
+}
+
+impl AudioStream {
+    /// 送信を開始（muted の間は無音を送り、タイムスタンプは進め続ける）
+    pub fn start(track: Arc<TrackLocalStaticSample>, muted: Arc<AtomicBool>) -> Self {
+        let running = Arc::new(AtomicBool::new(true));
+        let flag = running.clone();
+        let source = source();
+        let rt = tokio::runtime::Handle::current();
+        std::thread::spawn(move || {
+            if let Err(e) = run(&source, &track, &flag, &muted, &rt) {
+                eprintln!("[Audio] {}", e);
+            }
+            println!("[Audio] Stream ended");
+        });
+        Self { running }
+    }
+
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Drop for AudioStream {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn run(
+    source: &AudioSource,
+    track: &TrackLocalStaticSample,
+    running: &AtomicBool,
+    muted: &AtomicBool,
+    rt: &tokio::runtime::Handle,
+) -> Result<(), String> {
+    // キャプチャデバイスはスレッドをまたげない場合があるので、このスレッドで開く
+    let mut input = open(source)?;
+    let mut encoder = OpusEncoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Audio)
+        .map_err(|e| format!("Failed to create Opus encoder: {}", e))?;
+    encoder
+        .set_bitrate(Bitrate::BitsPerSecond(BITRATE))
+        .map_err(|e| format!("Failed to set Opus bitrate: {}", e))?;
+    println!("[Audio] Streaming {:?} as Opus ({} kbps)", source, BITRATE / 1000);
+
+    let mut pcm = vec![0i16; FRAME_LEN];
+    let mut packet = vec![0u8; 4000];
+    let start = Instant::now();
+    let mut frames: u32 = 0;
+    while running.load(Ordering::SeqCst) {
+        input.fill(&mut pcm);
+        if muted.load(Ordering::SeqCst) {
+            pcm.fill(0);
+        }
+        let len = encoder
+            .encode(&pcm, &mut packet)
+            .map_err(|e| format!("Opus encode error: {}", e))?;
+        let sample = Sample {
+            data: Bytes::copy_from_slice(&packet[..len]),
+            duration: FRAME_DURATION,
+            ..Default::default()
+        };
+        if let Err(e) = rt.block_on(track.write_sample(&sample)) {
+            eprintln!("[Audio] Track write error: {}", e);
+        }
+
+        // 入力元によらず20msごとに1フレーム（実時間に合わせる）
+        frames += 1;
+        let next = start + FRAME_DURATION * frames;
+        let now = Instant::now();
+        if next > now {
+            std::thread::sleep(next - now);
+        }
+    }
+    Ok(())
+}
+
+// サイン波（振幅は最大の1/4）
+struct SineSource {
+    phase: f32,
+    step: f32,
+}
+
+impl SineSource {
+    fn new(frequency: f32) -> Self {
+        Self { phase: 0.0, step: frequency * std::f32::consts::TAU / SAMPLE_RATE as f32 }
+    }
+}
+
+impl PcmSource for SineSource {
+    fn fill(&mut self, frame: &mut [i16]) {
+        for sample in frame.chunks_exact_mut(CHANNELS) {
+            sample.fill((self.phase.sin() * i16::MAX as f32 / 4.0) as i16);
+            self.phase = (self.phase + self.step) % std::f32::consts::TAU;
+        }
+    }
+}
+
+// WAVファイル（読み込み時に48kHz ステレオへ変換し、末尾まで来たら先頭に戻る）
+struct FileSource {
+    samples: Vec<i16>,
+    pos: usize,
+}
+
+impl FileSource {
+    fn open(path: &str) -> Result<Self, String> {
+        let mut reader = hound::WavReader::open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+        let spec = reader.spec();
+        let input: Vec<f32> = match spec.sample_format {
+            hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>(),
+            hound::SampleFormat::Int => {
+                let full_scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+                reader.samples::<i32>().map(|s| s.map(|s| s as f32 / full_scale)).collect::<Result<_, _>>()
+            }
+        }
+        .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+
+        let mut samples = Vec::new();
+        Resampler::new(spec.sample_rate, spec.channels).push(&input, &mut samples);
+        if samples.len() < FRAME_LEN {
+            return Err(format!("Audio file is too short: {}", path));
+        }
+        println!("[Audio] Loaded {} ({} Hz, {} ch, {:.1}s)", path, spec.sample_rate, spec.channels,
+            samples.len() as f32 / (SAMPLE_RATE as usize * CHANNELS) as f32);
+        Ok(Self { samples, pos: 0 })
+    }
+}
+
+impl PcmSource for FileSource {
+    fn fill(&mut self, frame: &mut [i16]) {
+        for sample in frame.iter_mut() {
+            *sample = self.samples[self.pos];
+            self.pos = (self.pos + 1) % self.samples.len();
+        }
+    }
+}
+
+// キャプチャスレッド・コールバックから届くPCMをためて20msずつ取り出す（足りなければ無音で埋める）
+struct LiveSource<K> {
+    rx: Receiver<Vec<i16>>,
+    buffer: VecDeque<i16>,
+    // キャプチャを止めずに保持しておくもの（プロセス・ストリーム）
+    _keep: K,
+}
+
+impl<K> PcmSource for LiveSource<K> {
+    fn fill(&mut self, frame: &mut [i16]) {
+        for chunk in self.rx.try_iter() {
+            self.buffer.extend(chunk);
+        }
+        if self.buffer.len() > MAX_BUFFERED {
+            let excess = (self.buffer.len() - MAX_BUFFERED) / CHANNELS * CHANNELS;
+            self.buffer.drain(..excess);
+        }
+        for sample in frame.iter_mut() {
+            *sample = self.buffer.pop_front().unwrap_or(0);
+        }
+    }
+}
+
+// 任意のサンプルレート・チャンネル数（-1.0〜1.0）を48kHz ステレオのi16に変換する（線形補間）
+struct Resampler {
+    channels: usize,
+    // 出力1サンプルあたりに進む入力サンプル数
+    step: f64,
+    // 直前の入力（0）と現在の入力（1）の間での次の出力位置
+    pos: f64,
+    prev: [f32; 2],
+}
+
+impl Resampler {
+    fn new(rate: u32, channels: u16) -> Self {
+        Self {
+            channels: channels.max(1) as usize,
+            step: rate as f64 / SAMPLE_RATE as f64,
+            pos: 0.0,
+            prev: [0.0; 2],
+        }
+    }
+
+    fn push(&mut self, input: &[f32], out: &mut Vec<i16>) {
+        for frame in input.chunks_exact(self.channels) {
+            // モノラルは両チャンネルに、3ch以上は先頭2chを使う
+            let current = [frame[0], frame[frame.len().min(2) - 1]];
+            while self.pos < 1.0 {
+                let t = self.pos as f32;
+                for (prev, cur) in self.prev.iter().zip(current) {
+                    let value = prev + (cur - prev) * t;
+                    out.push((value.clamp(-1.0, 1.0) * i16::MAX as f32) as i16);
+                }
+                self.pos += self.step;
+            }
+            self.pos -= 1.0;
+            self.prev = current;
+        }
+    }
+}
+
+// システムの出力音声: PulseAudio/PipeWire（pipewire-pulse）の既定出力のモニターソースを parec で録音
+#[cfg(target_os = "linux")]
+mod system {
+    use super::{LiveSource, CHANNELS, SAMPLE_RATE};
+    use std::io::Read;
+    use std::process::{Child, Command, Stdio};
+
+    // 停止時にparecを終了させる
+    pub struct Parec(Child);
+
+    impl Drop for Parec {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    pub fn open() -> Result<LiveSource<Parec>, String> {
+        let mut child = Command::new("parec")
+            .args([
+                "--device=@DEFAULT_MONITOR@",
+                "--format=s16le",
+                &format!("--rate={}", SAMPLE_RATE),
+                &format!("--channels={}", CHANNELS),
+                "--latency-msec=20",
+                "--raw",
+            ])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("Failed to start parec (PulseAudio/PipeWire required): {}", e))?;
+        let mut stdout = child.stdout.take().ok_or("parec has no stdout")?;
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            // 10ms単位で読む
+            let mut buf = vec![0u8; SAMPLE_RATE as usize / 100 * CHANNELS * 2];
+            while stdout.read_exact(&mut buf).is_ok() {
+                let samples = buf.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+                if tx.send(samples).is_err() {
+                    break;
+                }
+            }
+        });
+        println!("[Audio] Capturing default monitor source via parec");
+        Ok(LiveSource { rx, buffer: Default::default(), _keep: Parec(child) })
+    }
+}
+
+// システムの出力音声: Windowsは既定の出力デバイスのループバック（WASAPI）
+// macOSはOSにループバックがないので、BlackHole等の仮想デバイスを入力として使う
+#[cfg(not(target_os = "linux"))]
+mod system {
+    use super::{LiveSource, Resampler};
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+    use cpal::{SampleFormat, Stream};
+
+    pub fn open() -> Result<LiveSource<Stream>, String> {
+        let host = cpal::default_host();
+        #[cfg(target_os = "windows")]
+        let (device, config) = {
+            // WASAPIでは出力デバイスに入力ストリームを作るとループバック録音になる
+            let device = host.default_output_device().ok_or("No output device")?;
+            let config = device.default_output_config().map_err(|e| format!("Failed to get output config: {}", e))?;
+            (device, config)
+        };
+        #[cfg(not(target_os = "windows"))]
+        let (device, config) = {
+            let device = host
+                .input_devices()
+                .map_err(|e| format!("Failed to list input devices: {}", e))?
+                .find(|d| d.name().is_ok_and(|n| n.contains("BlackHole") || n.contains("Loopback")))
+                .ok_or("No loopback device (install BlackHole and route the output to it)")?;
+            let config = device.default_input_config().map_err(|e| format!("Failed to get input config: {}", e))?;
+            (device, config)
+        };
+
+        let name = device.name().unwrap_or_default();
+        let mut resampler = Resampler::new(config.sample_rate().0, config.channels());
+        let (tx, rx) = std::sync::mpsc::channel();
+        let on_error = |e: cpal::StreamError| eprintln!("[Audio] Capture error: {}", e);
+        let stream = match config.sample_format() {
+            SampleFormat::F32 => device.build_input_stream(
+                &config.config(),
+                move |data: &[f32], _: &_| {
+                    let mut out = Vec::new();
+                    resampler.push(data, &mut out);
+                    let _ = tx.send(out);
+                },
+                on_error,
+                None,
+            ),
+            SampleFormat::I16 => device.build_input_stream(
+                &config.config(),
+                move |data: &[i16], _: &_| {
+                    let input: Vec<f32> = data.iter().map(|&s| s as f32 / 32768.0).collect();
+                    let mut out = Vec::new();
+                    resampler.push(&input, &mut out);
+                    let _ = tx.send(out);
+                },
+                on_error,
+                None,
+            ),
+            format => return Err(format!("Unsupported sample format: {:?}", format)),
+        }
+        .map_err(|e| format!("Failed to open {}: {}", name, e))?;
+        stream.play().map_err(|e| format!("Failed to start {}: {}", name, e))?;
+        println!("[Audio] Capturing {} ({} Hz, {} ch)", name, config.sample_rate().0, config.channels());
+        Ok(LiveSource { rx, buffer: Default::default(), _keep: stream })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_test_sources() {
+        // サイン波: 1kHzは48サンプル周期、左右同じ値
+        let mut sine = SineSource::new(1000.0);
+        let mut frame = vec![0i16; FRAME_LEN];
+        sine.fill(&mut frame);
+        assert_eq!(frame[0], 0);
+        assert_eq!(frame[24], frame[25]);
+        assert!((frame[24] as i32 - i16::MAX as i32 / 4).abs() <= 1);
+        assert!(frame[48 * 2].abs() <= 1);
+
+        // 44.1kHz モノラルのWAVを1秒分 -> 48kHz ステレオ
+        let path = std::env::temp_dir().join(format!("pocket-remote-audio-{}.wav", std::process::id()));
+        let spec = hound::WavSpec { channels: 1, sample_rate: 44_100, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for _ in 0..44_100 {
+            writer.write_sample(i16::MAX / 2).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let mut file = FileSource::open(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert!((file.samples.len() as i32 - 48_000 * 2).abs() <= 4);
+        file.fill(&mut frame);
+        assert_eq!(frame[100], frame[101]);
+        assert!((frame[100] as i32 - i16::MAX as i32 / 2).abs() <= 2);
+        // 末尾まで来たら先頭に戻る
+        for _ in 0..50 {
+            file.fill(&mut frame);
+        }
+        assert_eq!(file.pos, FRAME_LEN * 51 % file.samples.len());
+    }
+}
@@ -0,0 +1,154 @@
+use rav1e::prelude::*;
+
+use crate::capture::I420Buffer;
+use crate::h264_encoder::gop_length;
+
+// rav1eの最小フレームサイズ
+const MIN_SIZE: usize = 16;
+// 速度優先のプリセット（0〜10、10が最速）
+const SPEED_PRESET: u8 = 10;
+
+/// AV1エンコーダー（rav1e使用、ロイヤリティフリー）
+pub struct Av1Encoder {
+    context: Context<u8>,
+    width: usize,
+    height: usize,
+    bitrate_bps: u32,
+    max_fps: f32,
+    // エンコーダー内に残っているフレーム数（先読み分）
+    pending: usize,
+    force_keyframe: bool,
+}
+
+fn new_context(width: usize, height: usize, bitrate_bps: u32, max_fps: f32) -> Result<Context<u8>, String> {
+    let mut speed_settings = SpeedSettings::from_preset(SPEED_PRESET);
+    // 先読みを最小にして遅延を減らす
+    speed_settings.rdo_lookahead_frames = 1;
+
+    // 定期キーフレームの間隔はH.264と共通（0なら定期送信なし）
+    let gop = match gop_length() {
+        0 => i32::MAX as u64 / 3,
+        frames => frames,
+    };
+    let config = EncoderConfig {
+        width,
+        height,
+        time_base: Rational::new(1, max_fps.round().max(1.0) as u64),
+        chroma_sampling: ChromaSampling::Cs420,
+        low_latency: true,
+        bitrate: bitrate_bps.min(i32::MAX as u32) as i32,
+        min_key_frame_interval: 0,
+        max_key_frame_interval: gop,
+        speed_settings,
+        ..Default::default()
+    };
+    Config::new()
+        .with_encoder_config(config)
+        .new_context()
+        .map_err(|e| format!("Failed to create AV1 encoder: {}", e))
+}
+
+impl Av1Encoder {
+    /// 新しいAV1エンコーダーを作成（ビットレート・フレームレート指定）
+    pub fn with_rate(width: usize, height: usize, bitrate_bps: u32, max_fps: f32) -> Result<Self, String> {
+        if width < MIN_SIZE || height < MIN_SIZE {
+            return Err(format!("AV1 frame too small: {}x{}", width, height));
+        }
+        let context = new_context(width, height, bitrate_bps, max_fps)?;
+        println!("[AV1] Encoder created: {}x{}, {} kbps, {} fps", width, height, bitrate_bps / 1000, max_fps);
+        Ok(Self {
+            context,
+            width,
+            height,
+            bitrate_bps,
+            max_fps,
+            pending: 0,
+            force_keyframe: true,
+        })
+    }
+
+    /// ビットレートとフレームレートを変更（変わった場合のみ作り直し、次はキーフレーム）
+    pub fn set_rate(&mut self, bitrate_bps: u32, max_fps: f32) -> Result<(), String> {
+        if bitrate_bps == self.bitrate_bps && max_fps == self.max_fps {
+            return Ok(());
+        }
+        println!("[AV1] Rate changed: {} kbps/{} fps -> {} kbps/{} fps",
+            self.bitrate_bps / 1000, self.max_fps, bitrate_bps / 1000, max_fps);
+        *self = Self::with_rate(self.width, self.height, bitrate_bps, max_fps)?;
+        Ok(())
+    }
+
+    /// キーフレームを強制的に生成
+    pub fn force_keyframe(&mut self) {
+        self.force_keyframe = true;
+    }
+
+    /// I420フレームをAV1にエンコード
+    /// 返り値: テンポラルユニット（OBU列）ごとのデータ
+    pub fn encode_i420(&mut self, yuv: &I420Buffer) -> Result<Vec<Vec<u8>>, String> {
+        // サイズが変わったらエンコーダーを再作成（最初のフレームはキーフレーム）
+        if yuv.width != self.width || yuv.height != self.height {
+            println!("[AV1] Resolution changed: {}x{} -> {}x{}", self.width, self.height, yuv.width, yuv.height);
+            *self = Self::with_rate(yuv.width, yuv.height, self.bitrate_bps, self.max_fps)?;
+        }
+
+        let mut frame = self.context.new_frame();
+        frame.planes[0].copy_from_raw_u8(&yuv.y, yuv.width, 1);
+        frame.planes[1].copy_from_raw_u8(&yuv.u, yuv.width / 2, 1);
+        frame.planes[2].copy_from_raw_u8(&yuv.v, yuv.width / 2, 1);
+
+        let params = FrameParameters {
+            frame_type_override: if self.force_keyframe { FrameTypeOverride::Key } else { FrameTypeOverride::No },
+            ..Default::default()
+        };
+        self.force_keyframe = false;
+        self.send(frame.clone(), params)?;
+
+        // 先読みのためにエンコーダー内にフレームが残るので、静止画面で止まらないよう
+        // このフレームが出てくるまで同じ内容を送り足す（変化がないので小さなフレームになる）
+        let needed = self.pending;
+        let mut output = Vec::with_capacity(needed);
+        while output.len() < needed {
+            match self.context.receive_packet() {
+                Ok(packet) => {
+                    self.pending -= 1;
+                    output.push(packet.data);
+                }
+                Err(EncoderStatus::Encoded) => {}
+                Err(EncoderStatus::NeedMoreData) => self.send(frame.clone(), FrameParameters::default())?,
+                Err(e) => return Err(format!("AV1 encode error: {:?}", e)),
+            }
+        }
+        Ok(output)
+    }
+
+    fn send(&mut self, frame: Frame<u8>, params: FrameParameters) -> Result<(), String> {
+        self.context
+            .send_frame((frame, params))
+            .map_err(|e| format!("AV1 send error: {:?}", e))?;
+        self.pending += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_frame() {
+        let mut encoder = Av1Encoder::with_rate(64, 64, 500_000, 30.0).unwrap();
+        let mut yuv = I420Buffer::default();
+        yuv.resize(64, 64);
+        yuv.y.fill(128); // グレー画面
+        yuv.u.fill(128);
+        yuv.v.fill(128);
+
+        // 先読みがあっても1回の呼び出しでこのフレームまでの出力が得られる
+        for _ in 0..3 {
+            let packets = encoder.encode_i420(&yuv).unwrap();
+            assert!(!packets.is_empty());
+            assert!(packets.iter().all(|p| !p.is_empty()));
+        }
+    }
+}
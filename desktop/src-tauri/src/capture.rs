@@ -0,0 +1,511 @@
+use image::{imageops, RgbaImage};
+use once_cell::sync::Lazy;
+use parking_lot::{Condvar, Mutex};
+use rayon::prelude::*;
+use scrap::{Capturer, Display};
+use std::collections::HashMap;
+use std::io::ErrorKind::WouldBlock;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::adaptive::{self, StreamTargets};
+use crate::client_display::{self, ClientDisplay};
+use crate::damage::DamageTracker;
+use crate::metrics::METRICS;
+use crate::monitors::{MonitorCapturer, MonitorSelection, Rect};
+use crate::CaptureRegion;
+
+/// キャプチャしたフレーム（BGRA、ネイティブ解像度）
+#[derive(Clone)]
+pub struct CapturedFrame {
+    pub data: Vec<u8>,
+    pub width: usize,
+    pub height: usize,
+    /// 1行あたりのバイト数（パディング込み）
+    pub stride: usize,
+    /// キャプチャ範囲（仮想デスクトップ上の論理座標）
+    pub bounds: Rect,
+    /// 論理ピクセル -> ネイティブピクセルの倍率
+    pub scale_factor: f32,
+    /// キャプチャ番号（キャプチャごとに増える）
+    pub seq: u64,
+    /// 画面内容の番号（前回から変化があった時だけ増える）
+    pub content_id: u64,
+}
+
+/// 画面キャプチャの実装（xcap / scrap）
+pub trait CaptureSource {
+    fn name(&self) -> &'static str;
+    /// 1フレーム取得（まだ準備できていなければNone）
+    fn capture(&mut self) -> Result<Option<CapturedFrame>, String>;
+}
+
+/// xcapによるキャプチャ（複数モニターの結合にも対応）
+struct XcapSource {
+    capturer: MonitorCapturer,
+}
+
+impl CaptureSource for XcapSource {
+    fn name(&self) -> &'static str {
+        "xcap"
+    }
+
+    fn capture(&mut self) -> Result<Option<CapturedFrame>, String> {
+        let img = self.capturer.capture()?;
+        let (width, height) = (img.width() as usize, img.height() as usize);
+        // RGBA -> BGRA
+        let mut data = img.into_raw();
+        data.par_chunks_mut(4).for_each(|px| px.swap(0, 2));
+        Ok(Some(CapturedFrame {
+            data,
+            width,
+            height,
+            stride: width * 4,
+            bounds: self.capturer.bounds(),
+            scale_factor: self.capturer.scale_factor(),
+            seq: 0,
+            content_id: 0,
+        }))
+    }
+}
+
+/// scrapによるキャプチャ（単一モニター、BGRAをそのまま取得できる）
+struct ScrapSource {
+    capturer: Capturer,
+    bounds: Rect,
+}
+
+impl CaptureSource for ScrapSource {
+    fn name(&self) -> &'static str {
+        "scrap"
+    }
+
+    fn capture(&mut self) -> Result<Option<CapturedFrame>, String> {
+        let (width, height) = (self.capturer.width(), self.capturer.height());
+        let frame = match self.capturer.frame() {
+            Ok(frame) => frame,
+            Err(e) if e.kind() == WouldBlock => return Ok(None),
+            Err(e) => return Err(e.to_string()),
+        };
+        // プラットフォームによって行末にパディングがある（macOSは128バイト境界）
+        let stride = frame.len() / height.max(1);
+        Ok(Some(CapturedFrame {
+            data: frame.to_vec(),
+            width,
+            height,
+            stride,
+            bounds: self.bounds,
+            scale_factor: width as f32 / self.bounds.width.max(1) as f32,
+            seq: 0,
+            content_id: 0,
+        }))
+    }
+}
+
+// 選択に対応するscrapのディスプレイを探す（xcapと同じ並び順・同じサイズのもの）
+fn scrap_display(selection: &MonitorSelection) -> Option<Display> {
+    match selection {
+        MonitorSelection::Primary => Display::primary().ok(),
+        MonitorSelection::Monitor { id } => {
+            let monitors = crate::monitors::list_monitors().ok()?;
+            let index = monitors.iter().position(|m| m.id == *id)?;
+            let info = &monitors[index];
+            let display = Display::all().ok()?.into_iter().nth(index)?;
+            let native_w = (info.width as f32 * info.scale_factor) as usize;
+            let native_h = (info.height as f32 * info.scale_factor) as usize;
+            (display.width() == native_w && display.height() == native_h).then_some(display)
+        }
+        MonitorSelection::All | MonitorSelection::Window { .. } => None,
+    }
+}
+
+/// 選択に対応するキャプチャを開く（単一モニターはscrap、扱えない選択はxcap）
+pub fn open_source(selection: &MonitorSelection) -> Result<Box<dyn CaptureSource>, String> {
+    let capturer = MonitorCapturer::new(selection)?;
+    if let Some(display) = scrap_display(selection) {
+        match Capturer::new(display) {
+            Ok(c) => return Ok(Box::new(ScrapSource { capturer: c, bounds: capturer.bounds() })),
+            Err(e) => eprintln!("[Capture] scrap unavailable for {:?}, falling back to xcap: {}", selection, e),
+        }
+    }
+    Ok(Box::new(XcapSource { capturer }))
+}
+
+/// モニター選択ごとのキャプチャサービス（WebSocket・WebRTCの両方が購読する）
+struct Service {
+    latest: Mutex<Option<Arc<CapturedFrame>>>,
+    updated: Condvar,
+}
+
+static SERVICES: Lazy<Mutex<HashMap<MonitorSelection, Arc<Service>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// キャプチャサービスの購読（dropで購読解除、購読者がいなくなるとキャプチャも止まる）
+pub struct CaptureHandle {
+    service: Arc<Service>,
+}
+
+impl CaptureHandle {
+    /// `after` より新しいフレームを待つ（タイムアウトでNone）
+    pub fn next_frame(&self, after: u64, timeout: Duration) -> Option<Arc<CapturedFrame>> {
+        let deadline = Instant::now() + timeout;
+        let mut latest = self.service.latest.lock();
+        loop {
+            if let Some(frame) = latest.as_ref().filter(|f| f.seq > after) {
+                return Some(frame.clone());
+            }
+            if self.service.updated.wait_until(&mut latest, deadline).timed_out() {
+                return None;
+            }
+        }
+    }
+}
+
+/// 選択の現在の範囲（ウィンドウは移動するので最新フレームの範囲、なければ `fallback`）
+pub fn current_bounds(selection: &MonitorSelection, fallback: Rect) -> Rect {
+    if !matches!(selection, MonitorSelection::Window { .. }) {
+        return fallback;
+    }
+    let service = SERVICES.lock().get(selection).cloned();
+    service
+        .and_then(|s| s.latest.lock().as_ref().map(|f| f.bounds))
+        .unwrap_or(fallback)
+}
+
+/// 選択したモニターのキャプチャを購読（サービスがなければ起動）
+pub fn subscribe(selection: &MonitorSelection) -> CaptureHandle {
+    let mut services = SERVICES.lock();
+    if let Some(service) = services.get(selection) {
+        return CaptureHandle { service: service.clone() };
+    }
+
+    let service = Arc::new(Service {
+        latest: Mutex::new(None),
+        updated: Condvar::new(),
+    });
+    services.insert(selection.clone(), service.clone());
+    println!("[Capture] Starting capture service for {:?}", selection);
+
+    let selection = selection.clone();
+    let thread_service = service.clone();
+    std::thread::spawn(move || run(selection, thread_service));
+    CaptureHandle { service }
+}
+
+fn run(selection: MonitorSelection, service: Arc<Service>) {
+    let mut source: Option<Box<dyn CaptureSource>> = None;
+    let mut damage = DamageTracker::default();
+    let mut seq: u64 = 0;
+    let mut content_id: u64 = 0;
+
+    loop {
+        {
+            // 登録とこのスレッド以外に参照がなければ終了（購読はロック中に行うので競合しない）
+            let mut services = SERVICES.lock();
+            if Arc::strong_count(&service) <= 2 {
+                services.remove(&selection);
+                println!("[Capture] Capture service for {:?} stopped (no subscribers)", selection);
+                return;
+            }
+        }
+
+        // 購読者ごとに間引くので、キャプチャは設定上の最大フレームレートで行う
+        let frame_interval = Duration::from_millis(1000 / adaptive::bounds().max_fps.max(1) as u64);
+        let start = Instant::now();
+
+        let src = match source.as_mut() {
+            Some(src) => src,
+            None => match open_source(&selection) {
+                Ok(src) => {
+                    println!("[Capture] Using {} for {:?}", src.name(), selection);
+                    damage.invalidate();
+                    source.insert(src)
+                }
+                Err(e) => {
+                    eprintln!("[Capture] {}", e);
+                    std::thread::sleep(Duration::from_secs(1));
+                    continue;
+                }
+            },
+        };
+
+        match src.capture() {
+            Ok(Some(mut frame)) => {
+                METRICS.frames_captured.inc();
+                METRICS.capture_seconds.observe_duration(start.elapsed());
+                if damage.is_damaged(&frame.data, frame.stride) {
+                    content_id += 1;
+                }
+                seq += 1;
+                frame.seq = seq;
+                frame.content_id = content_id;
+                *service.latest.lock() = Some(Arc::new(frame));
+                service.updated.notify_all();
+            }
+            Ok(None) => {
+                // フレーム準備中
+                std::thread::sleep(Duration::from_millis(5));
+                continue;
+            }
+            Err(e) => {
+                // ディスプレイ構成の変更などに備えて開き直す
+                METRICS.capture_errors.inc();
+                eprintln!("[Capture] Capture error: {}", e);
+                source = None;
+                std::thread::sleep(Duration::from_secs(1));
+                continue;
+            }
+        }
+
+        if let Some(rest) = frame_interval.checked_sub(start.elapsed()) {
+            std::thread::sleep(rest);
+        }
+    }
+}
+
+/// 送信範囲（ネイティブ座標）と送信サイズ
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameLayout {
+    pub crop_x: usize,
+    pub crop_y: usize,
+    pub crop_width: usize,
+    pub crop_height: usize,
+    /// 送信範囲の論理ピクセル数（画質の目安）
+    pub logical_pixels: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// 論理座標の範囲 [x, y, 幅, 高さ] を、原点 `bounds`・倍率 `scale` の画像の切り抜き範囲 (x, y, 幅, 高さ) にする
+/// 両端をそれぞれ丸めるので、1.25倍などの小数倍率でも隣り合う範囲に隙間や重なりができない
+pub fn native_rect(bounds: Rect, scale: f32, (width, height): (usize, usize), [x, y, w, h]: [i32; 4]) -> (usize, usize, usize, usize) {
+    let edge = |v: i32, origin: i32, limit: usize| (((v - origin).max(0) as f32 * scale).round() as usize).min(limit);
+    let left = edge(x, bounds.x, width);
+    let top = edge(y, bounds.y, height);
+    let right = edge(x + w.max(0), bounds.x, width).max(left);
+    let bottom = edge(y + h.max(0), bounds.y, height).max(top);
+    (left, top, right - left, bottom - top)
+}
+
+/// キャプチャ領域と送信目標から、切り抜き範囲と送信サイズを決める（両経路共通）
+/// displayはその接続のクライアントが申告した表示環境（Noneは旧クライアントの規則）
+pub fn layout(
+    frame: &CapturedFrame,
+    region: Option<&CaptureRegion>,
+    targets: &StreamTargets,
+    display: Option<&ClientDisplay>,
+) -> FrameLayout {
+    let scale = frame.scale_factor;
+    let full = (0, 0, frame.width, frame.height, frame.width as f32 / scale, frame.height as f32 / scale);
+
+    // 領域指定の座標は仮想デスクトップ上の論理座標なので、キャプチャ範囲の原点を引いてスケールする
+    let (crop_x, crop_y, crop_width, crop_height, logical_w, logical_h) = match region {
+        Some(r) => {
+            let (x, y, w, h) = native_rect(frame.bounds, scale, (frame.width, frame.height), [r.x, r.y, r.width, r.height]);
+            if w > 0 && h > 0 {
+                (x, y, w, h, r.width as f32, r.height as f32)
+            } else {
+                full
+            }
+        }
+        None => full,
+    };
+
+    // クライアントが申告した表示環境から送信サイズを決める
+    let logical_pixels = (logical_w * logical_h) as u32;
+    let (w, h) = client_display::output_size(display, (logical_w, logical_h), (crop_width, crop_height));
+    // 回線状況に応じた縮小（YUV420のため偶数に揃える）
+    let (width, height) = targets.scale_size(w as u32, h as u32);
+
+    FrameLayout { crop_x, crop_y, crop_width, crop_height, logical_pixels, width, height }
+}
+
+/// 切り抜き・BGRA -> RGBA変換・リサイズ（JPEG用）
+pub fn crop_scale_rgba(frame: &CapturedFrame, layout: &FrameLayout) -> RgbaImage {
+    if layout.crop_width == 0 || layout.crop_height == 0 {
+        return RgbaImage::new(layout.width, layout.height);
+    }
+    let row_bytes = layout.crop_width * 4;
+    let mut rgba = vec![0u8; row_bytes * layout.crop_height];
+
+    // 行単位で並列処理
+    rgba.par_chunks_mut(row_bytes).enumerate().for_each(|(row, dst)| {
+        let start = (layout.crop_y + row) * frame.stride + layout.crop_x * 4;
+        let src = &frame.data[start..start + row_bytes];
+        for (d, s) in dst.chunks_exact_mut(4).zip(src.chunks_exact(4)) {
+            d[0] = s[2]; // R
+            d[1] = s[1]; // G
+            d[2] = s[0]; // B
+            d[3] = s[3]; // A
+        }
+    });
+
+    let img = RgbaImage::from_raw(layout.crop_width as u32, layout.crop_height as u32, rgba)
+        .expect("crop buffer size matches layout");
+    if img.width() == layout.width && img.height() == layout.height {
+        img
+    } else {
+        imageops::resize(&img, layout.width, layout.height, imageops::FilterType::Triangle)
+    }
+}
+
+/// エンコーダー入力用のI420バッファ（フレーム間で使い回して確保を減らす）
+#[derive(Default)]
+pub struct I420Buffer {
+    pub width: usize,
+    pub height: usize,
+    pub y: Vec<u8>,
+    pub u: Vec<u8>,
+    pub v: Vec<u8>,
+}
+
+impl I420Buffer {
+    /// サイズを変更（幅・高さは偶数、同じサイズなら確保し直さない）
+    pub fn resize(&mut self, width: usize, height: usize) {
+        self.width = width;
+        self.height = height;
+        self.y.resize(width * height, 0);
+        self.u.resize((width / 2) * (height / 2), 0);
+        self.v.resize((width / 2) * (height / 2), 0);
+    }
+}
+
+// 出力座標 -> 入力座標の対応表（双線形補間用、重みは8bit固定小数点）
+fn sample_map(out_len: usize, src_len: usize, offset: usize) -> Vec<(usize, usize, u32)> {
+    let ratio = src_len as f32 / out_len as f32;
+    (0..out_len)
+        .map(|i| {
+            let pos = ((i as f32 + 0.5) * ratio - 0.5).max(0.0);
+            let i0 = (pos as usize).min(src_len - 1);
+            let i1 = (i0 + 1).min(src_len - 1);
+            let frac = ((pos - i0 as f32) * 256.0) as u32;
+            (offset + i0, offset + i1, frac.min(256))
+        })
+        .collect()
+}
+
+// BGRAの4点を補間して(R, G, B)を返す
+#[inline]
+fn bilinear(data: &[u8], row0: usize, row1: usize, fy: u32, (x0, x1, fx): (usize, usize, u32)) -> (i32, i32, i32) {
+    let lerp = |a: u8, b: u8, f: u32| (a as u32 * (256 - f) + b as u32 * f) >> 8;
+    let channel = |c: usize| {
+        let top = lerp(data[row0 + x0 * 4 + c], data[row0 + x1 * 4 + c], fx);
+        let bottom = lerp(data[row1 + x0 * 4 + c], data[row1 + x1 * 4 + c], fx);
+        ((top * (256 - fy) + bottom * fy) >> 8) as i32
+    };
+    (channel(2), channel(1), channel(0))
+}
+
+/// 切り抜き・リサイズ・BGRA -> I420変換を1パスで行う（BT.601、H.264エンコーダー用）
+pub fn crop_scale_i420(frame: &CapturedFrame, layout: &FrameLayout, out: &mut I420Buffer) {
+    let (width, height) = (layout.width as usize, layout.height as usize);
+    out.resize(width, height);
+    if layout.crop_width == 0 || layout.crop_height == 0 {
+        out.y.fill(16);
+        out.u.fill(128);
+        out.v.fill(128);
+        return;
+    }
+
+    let xs = sample_map(width, layout.crop_width, layout.crop_x);
+    let ys = sample_map(height, layout.crop_height, layout.crop_y);
+    let half_width = width / 2;
+
+    // 出力2行（UV1行）単位で並列処理
+    out.y
+        .par_chunks_mut(width * 2)
+        .zip(out.u.par_chunks_mut(half_width))
+        .zip(out.v.par_chunks_mut(half_width))
+        .enumerate()
+        .for_each(|(pair, ((y_rows, u_row), v_row))| {
+            let (top, bottom) = y_rows.split_at_mut(width);
+            for cx in 0..half_width {
+                let (mut r_sum, mut g_sum, mut b_sum) = (0, 0, 0);
+                for dy in 0..2 {
+                    let (row0, row1, fy) = ys[pair * 2 + dy];
+                    let y_row = if dy == 0 { &mut *top } else { &mut *bottom };
+                    for dx in 0..2 {
+                        let x = cx * 2 + dx;
+                        let (r, g, b) = bilinear(&frame.data, row0 * frame.stride, row1 * frame.stride, fy, xs[x]);
+                        y_row[x] = (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8;
+                        r_sum += r;
+                        g_sum += g;
+                        b_sum += b;
+                    }
+                }
+                // 色差は2x2の平均から求める
+                let (r, g, b) = (r_sum / 4, g_sum / 4, b_sum / 4);
+                u_row[cx] = (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8;
+                v_row[cx] = (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8;
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crop_scale_i420_solid_color() {
+        // 白と赤で左右に塗り分けた 8x4 のフレームから右半分を切り抜いて 2x2 に縮小
+        let (width, height) = (8, 4);
+        let mut data = vec![0u8; width * height * 4];
+        for (i, px) in data.chunks_exact_mut(4).enumerate() {
+            let color = if i % width < 4 { [255, 255, 255, 255] } else { [0, 0, 255, 255] };
+            px.copy_from_slice(&color);
+        }
+        let frame = CapturedFrame {
+            data,
+            width,
+            height,
+            stride: width * 4,
+            bounds: Rect { x: 0, y: 0, width: width as u32, height: height as u32 },
+            scale_factor: 1.0,
+            seq: 1,
+            content_id: 1,
+        };
+        let layout = FrameLayout {
+            crop_x: 4,
+            crop_y: 0,
+            crop_width: 4,
+            crop_height: 4,
+            logical_pixels: 16,
+            width: 2,
+            height: 2,
+        };
+
+        let mut yuv = I420Buffer::default();
+        crop_scale_i420(&frame, &layout, &mut yuv);
+
+        // BT.601（リミテッドレンジ）の赤
+        assert_eq!(yuv.y, vec![82; 4]);
+        assert_eq!(yuv.u, vec![90]);
+        assert_eq!(yuv.v, vec![240]);
+    }
+
+    #[test]
+    fn test_native_rect_fractional_scales() {
+        for scale in [1.0f32, 1.25, 1.5, 1.75, 2.0] {
+            // 論理1001x601（原点が負の位置）のモニター
+            let bounds = Rect { x: -1001, y: 0, width: 1001, height: 601 };
+            let native = ((1001.0 * scale).round() as usize, (601.0 * scale).round() as usize);
+
+            // 全体はキャプチャ画像全体
+            assert_eq!(native_rect(bounds, scale, native, [-1001, 0, 1001, 601]), (0, 0, native.0, native.1), "scale {}", scale);
+
+            // 隣り合う領域は隙間も重なりもなく並ぶ
+            let left = native_rect(bounds, scale, native, [-1001, 0, 333, 601]);
+            let right = native_rect(bounds, scale, native, [-668, 0, 668, 601]);
+            assert_eq!(left.0 + left.2, right.0, "scale {}", scale);
+            assert_eq!(right.0 + right.2, native.0, "scale {}", scale);
+
+            // 論理座標はネイティブ座標に倍率どおり対応する
+            let (x, y, w, h) = native_rect(bounds, scale, native, [-901, 100, 400, 200]);
+            assert_eq!((x, y), ((100.0 * scale).round() as usize, (100.0 * scale).round() as usize), "scale {}", scale);
+            assert_eq!((w, h), ((400.0 * scale).round() as usize, (200.0 * scale).round() as usize), "scale {}", scale);
+
+            // はみ出す部分は切り捨てる
+            let (_, _, w, h) = native_rect(bounds, scale, native, [-100, 500, 400, 400]);
+            assert_eq!((w, h), (native.0 - (901.0 * scale).round() as usize, native.1 - (500.0 * scale).round() as usize));
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+
+use crate::capture::FrameLayout;
+
+// 表示環境を申告しない旧クライアント向け: 論理ピクセル数がこれを超える場合は1/2サイズで送信
+// （旧モバイルアプリが同じ規則で座標を換算しているため残す）
+const LEGACY_HALF_SIZE_PIXELS: f32 = 600_000.0;
+
+/// クライアントの表示環境（送信解像度の決定に使う。接続ごとに持つ）
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClientDisplay {
+    /// 映像の表示領域（論理ピクセル）
+    pub width: u32,
+    pub height: u32,
+    /// デバイスピクセル比
+    pub pixel_ratio: f32,
+    /// 希望倍率（1.0 = 表示領域の物理ピクセルに合わせる、0.5 = その半分）
+    #[serde(default = "default_preferred_scale")]
+    pub preferred_scale: f32,
+}
+
+fn default_preferred_scale() -> f32 {
+    1.0
+}
+
+/// 実際の送信解像度（クライアントへの通知用）
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct OutputResolution {
+    /// 送信サイズ（ピクセル）
+    pub width: u32,
+    pub height: u32,
+    /// 送信範囲の論理サイズ
+    pub logical_width: u32,
+    pub logical_height: u32,
+}
+
+impl OutputResolution {
+    pub fn new(layout: &FrameLayout, scale_factor: f32) -> Self {
+        Self {
+            width: layout.width,
+            height: layout.height,
+            logical_width: (layout.crop_width as f32 / scale_factor).round() as u32,
+            logical_height: (layout.crop_height as f32 / scale_factor).round() as u32,
+        }
+    }
+}
+
+impl ClientDisplay {
+    /// クライアントから申告された値を検証
+    pub fn validate(&self) -> Result<(), String> {
+        if self.width == 0 || self.height == 0 {
+            return Err(format!("Invalid display size: {}x{}", self.width, self.height));
+        }
+        let valid = self.pixel_ratio > 0.0
+            && self.pixel_ratio <= 8.0
+            && self.preferred_scale > 0.0
+            && self.preferred_scale <= 4.0;
+        if !valid {
+            return Err(format!("Invalid pixel ratio / scale: {} / {}", self.pixel_ratio, self.preferred_scale));
+        }
+        Ok(())
+    }
+}
+
+/// 送信範囲（論理サイズ・ネイティブサイズ）から回線調整前の送信サイズを決める
+/// 表示環境が申告されていれば、表示領域の物理ピクセルに収まる大きさ（ネイティブ解像度が上限）
+pub fn output_size(display: Option<&ClientDisplay>, logical: (f32, f32), native: (usize, usize)) -> (f32, f32) {
+    let (logical_w, logical_h) = logical;
+    let Some(d) = display else {
+        return if logical_w * logical_h > LEGACY_HALF_SIZE_PIXELS {
+            (logical_w / 2.0, logical_h / 2.0)
+        } else {
+            (logical_w, logical_h)
+        };
+    };
+    if logical_w <= 0.0 || logical_h <= 0.0 {
+        return (logical_w, logical_h);
+    }
+    let scale = d.pixel_ratio * d.preferred_scale;
+    let (box_w, box_h) = (d.width as f32 * scale, d.height as f32 * scale);
+    let fit = (box_w / logical_w).min(box_h / logical_h);
+    let native_fit = (native.0 as f32 / logical_w).min(native.1 as f32 / logical_h);
+    let fit = fit.min(native_fit);
+    (logical_w * fit, logical_h * fit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(display: Option<&ClientDisplay>, logical: (f32, f32), native: (usize, usize)) -> (u32, u32) {
+        let (w, h) = output_size(display, logical, native);
+        (w.round() as u32, h.round() as u32)
+    }
+
+    #[test]
+    fn test_output_size() {
+        // 申告なし: 旧クライアントの規則
+        assert_eq!(size(None, (1920.0, 1080.0), (3840, 2160)), (960, 540));
+        assert_eq!(size(None, (800.0, 600.0), (1600, 1200)), (800, 600));
+
+        // 横向きの表示領域 800x400 @3x に収める（アスペクト比は維持）
+        let phone = ClientDisplay { width: 800, height: 400, pixel_ratio: 3.0, preferred_scale: 1.0 };
+        assert_eq!(size(Some(&phone), (1920.0, 1080.0), (3840, 2160)), (2133, 1200));
+        // 希望倍率 0.5
+        let half = ClientDisplay { preferred_scale: 0.5, ..phone };
+        assert_eq!(size(Some(&half), (1920.0, 1080.0), (3840, 2160)), (1067, 600));
+        // ネイティブ解像度は超えない
+        assert_eq!(size(Some(&phone), (1920.0, 1080.0), (1920, 1080)), (1920, 1080));
+    }
+
+    #[test]
+    fn test_validate() {
+        let phone = ClientDisplay { width: 800, height: 400, pixel_ratio: 3.0, preferred_scale: 1.0 };
+        assert!(phone.validate().is_ok());
+        assert!(ClientDisplay { width: 0, ..phone }.validate().is_err());
+        assert!(ClientDisplay { pixel_ratio: 0.0, ..phone }.validate().is_err());
+        assert!(ClientDisplay { preferred_scale: 5.0, ..phone }.validate().is_err());
+    }
+}
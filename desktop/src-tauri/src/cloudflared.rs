@@ -0,0 +1,351 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tauri::{AppHandle, Emitter};
+
+/// 動作確認済みのcloudflaredバージョン（通常のインストールではこのバージョンを入れる）
+pub const PINNED_VERSION: &str = "2024.12.2";
+
+const RELEASES_API: &str = "https://api.github.com/repos/cloudflare/cloudflared/releases";
+const DOWNLOAD_BASE: &str = "https://github.com/cloudflare/cloudflared/releases/download";
+
+/// インストール進捗（cloudflared_install_progress イベントのペイロード）
+#[derive(Clone, Debug, Serialize)]
+pub struct InstallProgress {
+    /// "download" / "verify" / "extract" / "done"
+    pub stage: &'static str,
+    /// ダウンロード進捗（0-100、サイズ不明の場合はNone）
+    pub percent: Option<u8>,
+    pub message: String,
+}
+
+/// アップデート確認結果
+#[derive(Clone, Debug, Serialize)]
+pub struct UpdateInfo {
+    pub installed_version: Option<String>,
+    pub pinned_version: String,
+    /// GitHub上の最新版（参考表示のみ。ハッシュを固定していないので自動では入れない）
+    pub latest_version: String,
+    /// インストール済みのバージョンがpinned_versionより古い
+    pub update_available: bool,
+    /// システム（Homebrew等）のcloudflaredを使用中の場合は自動更新しない
+    pub is_system: bool,
+}
+
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+}
+
+/// 実行ファイル名
+pub fn binary_name() -> &'static str {
+    if cfg!(windows) {
+        "cloudflared.exe"
+    } else {
+        "cloudflared"
+    }
+}
+
+/// アプリのデータディレクトリにインストールしたcloudflaredのパス
+pub fn local_path() -> PathBuf {
+    crate::app_data_dir().join(binary_name())
+}
+
+/// PATH上にcloudflaredがあるか
+pub fn system_installed() -> bool {
+    let finder = if cfg!(windows) { "where" } else { "which" };
+    std::process::Command::new(finder)
+        .arg("cloudflared")
+        .output()
+        .map(|o| o.status.success())
+        .unwrap_or(false)
+}
+
+/// 使用するcloudflaredのパス（システム優先、なければローカル）
+pub fn path() -> Option<PathBuf> {
+    if system_installed() {
+        return Some(PathBuf::from("cloudflared"));
+    }
+
+    let local_path = local_path();
+    if local_path.exists() {
+        return Some(local_path);
+    }
+
+    None
+}
+
+/// この OS/アーキテクチャ向けのリリースアセット名
+fn asset_name() -> Result<&'static str, String> {
+    pinned_asset().map(|(name, _)| name)
+}
+
+/// PINNED_VERSION の各アセットのSHA-256（リリースと同じ場所から取得した値は信用しない）
+/// 値はcloudflared 2024.12.2 のリリースノート "SHA256 Checksums" から転記する。
+/// 未記入（空文字）のアセットはインストールを拒否する（test_pinned_checksum も失敗する）。
+const SHA256_DARWIN_AMD64: &str = "";
+const SHA256_DARWIN_ARM64: &str = "";
+const SHA256_LINUX_AMD64: &str = "";
+const SHA256_LINUX_ARM64: &str = "";
+const SHA256_LINUX_ARM: &str = "";
+const SHA256_LINUX_386: &str = "";
+const SHA256_WINDOWS_AMD64: &str = "";
+const SHA256_WINDOWS_386: &str = "";
+
+/// この OS/アーキテクチャ向けのアセット名と、PINNED_VERSION での期待SHA-256
+fn pinned_asset() -> Result<(&'static str, &'static str), String> {
+    let asset = match (std::env::consts::OS, std::env::consts::ARCH) {
+        ("macos", "x86_64") => ("cloudflared-darwin-amd64.tgz", SHA256_DARWIN_AMD64),
+        ("macos", "aarch64") => ("cloudflared-darwin-arm64.tgz", SHA256_DARWIN_ARM64),
+        ("linux", "x86_64") => ("cloudflared-linux-amd64", SHA256_LINUX_AMD64),
+        ("linux", "aarch64") => ("cloudflared-linux-arm64", SHA256_LINUX_ARM64),
+        ("linux", "arm") => ("cloudflared-linux-arm", SHA256_LINUX_ARM),
+        ("linux", "x86") => ("cloudflared-linux-386", SHA256_LINUX_386),
+        ("windows", "x86_64") => ("cloudflared-windows-amd64.exe", SHA256_WINDOWS_AMD64),
+        ("windows", "x86") => ("cloudflared-windows-386.exe", SHA256_WINDOWS_386),
+        (os, arch) => return Err(format!("cloudflared is not available for {}/{}", os, arch)),
+    };
+    Ok(asset)
+}
+
+/// 指定バージョンのアセットの期待SHA-256（コードに固定したハッシュがない場合はNone）
+fn pinned_checksum(version: &str, asset: &str) -> Option<&'static str> {
+    let (pinned, hash) = pinned_asset().ok()?;
+    let version = version.trim_start_matches('v');
+    (version == PINNED_VERSION && pinned == asset && is_sha256(hash)).then_some(hash)
+}
+
+fn is_sha256(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+/// `cloudflared --version` からバージョンを取得（"cloudflared version 2024.12.2 (built ...)"）
+pub async fn installed_version(path: &Path) -> Option<String> {
+    let output = tokio::process::Command::new(path)
+        .arg("--version")
+        .output()
+        .await
+        .ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout
+        .split_whitespace()
+        .skip_while(|w| *w != "version")
+        .nth(1)
+        .map(|v| v.to_string())
+}
+
+/// "2024.12.2" 形式のバージョン比較（aがbより新しければtrue）
+fn is_newer(a: &str, b: &str) -> bool {
+    let parse = |v: &str| -> Vec<u32> {
+        v.trim_start_matches('v')
+            .split('.')
+            .map(|p| p.parse().unwrap_or(0))
+            .collect()
+    };
+    parse(a) > parse(b)
+}
+
+fn http_client() -> Result<reqwest::Client, String> {
+    // GitHub APIはUser-Agentが必須
+    reqwest::Client::builder()
+        .user_agent(concat!("PocketRemote/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .map_err(|e| format!("Failed to create HTTP client: {}", e))
+}
+
+async fn fetch_latest_release(client: &reqwest::Client) -> Result<Release, String> {
+    let url = format!("{}/latest", RELEASES_API);
+    let response = client
+        .get(&url)
+        .send()
+        .await
+        .map_err(|e| format!("Failed to fetch release info: {}", e))?;
+    if !response.status().is_success() {
+        return Err(format!("Failed to fetch release info: HTTP {}", response.status()));
+    }
+    let text = response
+        .text()
+        .await
+        .map_err(|e| format!("Failed to read release info: {}", e))?;
+    serde_json::from_str(&text).map_err(|e| format!("Invalid release info: {}", e))
+}
+
+/// 最新バージョンを確認（更新できるのはハッシュを固定したPINNED_VERSIONまで）
+pub async fn check_update() -> Result<UpdateInfo, String> {
+    let client = http_client()?;
+    let latest = fetch_latest_release(&client).await?;
+    let is_system = system_installed();
+    let installed_version = match path() {
+        Some(p) => installed_version(&p).await,
+        None => None,
+    };
+
+    let update_available = match installed_version {
+        Some(ref v) => is_newer(PINNED_VERSION, v),
+        None => false,
+    };
+
+    Ok(UpdateInfo {
+        installed_version,
+        pinned_version: PINNED_VERSION.to_string(),
+        latest_version: latest.tag_name,
+        update_available,
+        is_system,
+    })
+}
+
+fn emit_progress(app_handle: &AppHandle, stage: &'static str, percent: Option<u8>, message: &str) {
+    app_handle
+        .emit(
+            "cloudflared_install_progress",
+            &InstallProgress {
+                stage,
+                percent,
+                message: message.to_string(),
+            },
+        )
+        .ok();
+}
+
+/// 指定バージョンをダウンロードし、コードに固定したSHA-256と照合してインストール
+pub async fn install(app_handle: &AppHandle, version: &str) -> Result<String, String> {
+    let asset = asset_name()?;
+    let version = version.trim_start_matches('v');
+
+    // 固定したチェックサムがないバイナリはダウンロードもしない
+    let expected = pinned_checksum(version, asset).ok_or_else(|| {
+        format!(
+            "No pinned SHA-256 for {} {} (only {} can be installed)",
+            asset, version, PINNED_VERSION
+        )
+    })?;
+    let client = http_client()?;
+    let download_url = format!("{}/{}/{}", DOWNLOAD_BASE, version, asset);
+
+    println!("Downloading cloudflared {} from: {}", version, download_url);
+    emit_progress(app_handle, "download", Some(0), "ダウンロード中...");
+
+    let mut response = client
+        .get(&download_url)
+        .send()
+        .await
+        .map_err(|e| format!("Download failed: {}", e))?;
+    if !response.status().is_success() {
+        return Err(format!("Download failed: HTTP {}", response.status()));
+    }
+
+    // 進捗は1%単位で通知
+    let total = response.content_length();
+    let mut bytes = Vec::with_capacity(total.unwrap_or(0) as usize);
+    let mut last_percent = None;
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| format!("Failed to read response: {}", e))?
+    {
+        bytes.extend_from_slice(&chunk);
+        let percent = total
+            .filter(|t| *t > 0)
+            .map(|t| ((bytes.len() as u64 * 100) / t).min(100) as u8);
+        if percent != last_percent {
+            last_percent = percent;
+            emit_progress(app_handle, "download", percent, "ダウンロード中...");
+        }
+    }
+
+    emit_progress(app_handle, "verify", None, "検証中...");
+    let actual = format!("{:x}", Sha256::digest(&bytes));
+    if actual != expected {
+        return Err(format!(
+            "Checksum mismatch for {}: expected {}, got {}",
+            asset, expected, actual
+        ));
+    }
+
+    emit_progress(app_handle, "extract", None, "展開中...");
+    let data_dir = crate::app_data_dir();
+    std::fs::create_dir_all(&data_dir).map_err(|e| format!("Failed to create directory: {}", e))?;
+
+    // 一時ファイルに書き出してから置き換える（途中で失敗しても既存のバイナリを壊さない）
+    let target = local_path();
+    let tmp_path = data_dir.join(format!("{}.download", binary_name()));
+    if asset.ends_with(".tgz") {
+        extract_binary(&bytes, &tmp_path)?;
+    } else {
+        std::fs::write(&tmp_path, &bytes).map_err(|e| format!("Failed to write file: {}", e))?;
+    }
+
+    // 実行権限を付与
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(0o755))
+            .map_err(|e| format!("Failed to set permissions: {}", e))?;
+    }
+
+    std::fs::rename(&tmp_path, &target).map_err(|e| {
+        let _ = std::fs::remove_file(&tmp_path);
+        format!("Failed to install cloudflared: {}", e)
+    })?;
+
+    emit_progress(app_handle, "done", Some(100), "インストール完了");
+    println!("cloudflared {} installed to: {:?}", version, target);
+
+    Ok(version.to_string())
+}
+
+// tgzからcloudflaredバイナリを取り出す
+fn extract_binary(archive_bytes: &[u8], dest: &Path) -> Result<(), String> {
+    let tar_gz = flate2::read::GzDecoder::new(archive_bytes);
+    let mut archive = tar::Archive::new(tar_gz);
+
+    for entry in archive.entries().map_err(|e| format!("Failed to read archive: {}", e))? {
+        let mut entry = entry.map_err(|e| format!("Failed to read entry: {}", e))?;
+        let path = entry.path().map_err(|e| format!("Failed to get path: {}", e))?;
+
+        if path.file_name().map(|n| n == "cloudflared").unwrap_or(false) {
+            let mut file = std::fs::File::create(dest)
+                .map_err(|e| format!("Failed to create file: {}", e))?;
+            std::io::copy(&mut entry, &mut file)
+                .map_err(|e| format!("Failed to write file: {}", e))?;
+            return Ok(());
+        }
+    }
+
+    Err("cloudflared binary not found in archive".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pinned_checksum() {
+        let asset = asset_name().unwrap();
+        let (_, hash) = pinned_asset().unwrap();
+
+        // 固定ハッシュは64桁の小文字16進数（未記入ではインストールできない）
+        assert!(is_sha256(hash), "SHA-256 for {} {} is not pinned: {:?}", asset, PINNED_VERSION, hash);
+        assert_eq!(pinned_checksum(PINNED_VERSION, asset), Some(hash));
+
+        // 他のバージョン・アセットにはハッシュがない
+        assert!(pinned_checksum("2025.1.0", asset).is_none());
+        assert!(pinned_checksum(PINNED_VERSION, "cloudflared-unknown").is_none());
+    }
+
+    #[test]
+    fn test_is_sha256() {
+        assert!(is_sha256("0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef"));
+        assert!(!is_sha256("0123456789ABCDEF0123456789ABCDEF0123456789ABCDEF0123456789ABCDEF"));
+        assert!(!is_sha256("not-a-hash"));
+        assert!(!is_sha256(""));
+    }
+
+    #[test]
+    fn test_is_newer() {
+        assert!(is_newer("2024.12.2", "2024.11.1"));
+        assert!(is_newer("2025.1.0", "2024.12.2"));
+        assert!(!is_newer("2024.12.2", "2024.12.2"));
+        assert!(!is_newer("2024.9.1", "2024.10.0"));
+    }
+}
@@ -0,0 +1,485 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::codecs::webp::WebPEncoder;
+use image::{ExtendedColorType, ImageEncoder, RgbaImage};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+use crate::adaptive::StreamTargets;
+use crate::av1_encoder::Av1Encoder;
+use crate::capture::{self, CapturedFrame, FrameLayout, I420Buffer};
+use crate::damage::hash_band;
+use crate::h264_encoder::H264Encoder;
+
+// Data Channelの1パケットの上限（flutter_webrtcは16KB制限の可能性）
+const MAX_PACKET_SIZE: usize = 15 * 1024;
+// 可逆タイルの大きさ（変化したタイルだけ送る）
+const TILE_SIZE: u32 = 128;
+// これより小さいタイルは分割しない（32x32 RGBなら非圧縮でも1パケットに収まる）
+const MIN_TILE_SIZE: u32 = 32;
+
+// パケット種別（先頭1バイト）
+const PACKET_JPEG: u8 = 0x00;
+const PACKET_H264: u8 = 0x01;
+const PACKET_H264_FRAGMENT: u8 = 0x02;
+pub const PACKET_TILE: u8 = 0x03;
+const PACKET_AV1: u8 = 0x04;
+const PACKET_AV1_FRAGMENT: u8 = 0x05;
+
+/// 画面送信のコーデック
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Jpeg,
+    H264,
+    /// 可逆PNGタイル
+    Png,
+    /// 可逆WebPタイル
+    Webp,
+    Av1,
+}
+
+impl Codec {
+    /// 対応コーデック（クライアントに通知する、優先順）
+    pub const ALL: [Codec; 5] = [Codec::H264, Codec::Av1, Codec::Jpeg, Codec::Webp, Codec::Png];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Jpeg => "jpeg",
+            Codec::H264 => "h264",
+            Codec::Png => "png",
+            Codec::Webp => "webp",
+            Codec::Av1 => "av1",
+        }
+    }
+
+    /// 名前からコーデックを取得（大文字小文字・"h.264"表記も受け付ける）
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "jpeg" | "jpg" => Some(Codec::Jpeg),
+            "h264" | "h.264" => Some(Codec::H264),
+            "png" => Some(Codec::Png),
+            "webp" => Some(Codec::Webp),
+            "av1" => Some(Codec::Av1),
+            _ => None,
+        }
+    }
+
+    /// クライアントの希望順リストから最初に対応しているものを選ぶ
+    pub fn negotiate(preferred: &[String]) -> Option<Self> {
+        preferred.iter().find_map(|name| Self::parse(name))
+    }
+}
+
+/// フレームエンコーダー（コーデックごとに実装する）
+pub trait Encoder: Send {
+    fn codec(&self) -> Codec;
+
+    /// 切り抜き・縮小してエンコードし、Data Channel用のパケットにして返す（空なら送るものなし）
+    fn encode(&mut self, frame: &CapturedFrame, layout: &FrameLayout, targets: &StreamTargets) -> Result<Vec<Vec<u8>>, String>;
+
+    /// 次のフレームをキーフレーム（画面全体を送り直す）にする
+    fn force_keyframe(&mut self);
+}
+
+/// コーデックに対応するエンコーダーを作成
+pub fn create_encoder(codec: Codec) -> Box<dyn Encoder> {
+    match codec {
+        Codec::Jpeg => Box::new(JpegFrameEncoder::default()),
+        Codec::H264 => Box::new(H264FrameEncoder::default()),
+        Codec::Png | Codec::Webp => Box::new(TileEncoder::new(codec)),
+        Codec::Av1 => Box::new(Av1FrameEncoder::default()),
+    }
+}
+
+/// 1パケットに収まればそのまま、収まらなければ分割する
+/// 単一: [種別] + データ / 分割: [分割種別, index, total, frame_id(2bytes)] + データ
+fn packetize(data: &[u8], single: u8, fragment: u8, frame_id: u16) -> Vec<Vec<u8>> {
+    if data.is_empty() {
+        return vec![];
+    }
+    if data.len() <= MAX_PACKET_SIZE {
+        let mut packet = Vec::with_capacity(data.len() + 1);
+        packet.push(single);
+        packet.extend_from_slice(data);
+        return vec![packet];
+    }
+
+    let total_fragments = data.len().div_ceil(MAX_PACKET_SIZE) as u8;
+    data.chunks(MAX_PACKET_SIZE)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut packet = Vec::with_capacity(chunk.len() + 5);
+            packet.push(fragment);
+            packet.push(i as u8);
+            packet.push(total_fragments);
+            packet.extend_from_slice(&frame_id.to_be_bytes());
+            packet.extend_from_slice(chunk);
+            packet
+        })
+        .collect()
+}
+
+/// JPEG（毎フレーム独立、サイズ上限に収まるまで画質を下げる）
+#[derive(Default)]
+struct JpegFrameEncoder {
+    frame_count: u64,
+}
+
+impl Encoder for JpegFrameEncoder {
+    fn codec(&self) -> Codec {
+        Codec::Jpeg
+    }
+
+    fn encode(&mut self, frame: &CapturedFrame, layout: &FrameLayout, targets: &StreamTargets) -> Result<Vec<Vec<u8>>, String> {
+        let should_log = self.frame_count < 5;
+        self.frame_count += 1;
+        let encode_start = Instant::now();
+
+        // 小さいウィンドウほど高画質から始める
+        let start_quality = if layout.logical_pixels <= 300000 {
+            75u8
+        } else if layout.logical_pixels <= 600000 {
+            65u8
+        } else {
+            60u8
+        };
+
+        let final_img = capture::crop_scale_rgba(frame, layout);
+        let scale_time = encode_start.elapsed();
+
+        let jpeg_start = Instant::now();
+        // 動的品質調整: 目標サイズ（最大63KB、WebRTC上限64KB）以下になるまで品質を下げる
+        let max_size = targets.frame_budget_bytes().clamp(8 * 1024, 63 * 1024);
+        let mut quality = start_quality;
+
+        loop {
+            let mut jpeg_data = vec![PACKET_JPEG];
+            final_img
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg_data, quality))
+                .map_err(|e| format!("JPEG encode error: {}", e))?;
+
+            if jpeg_data.len() <= max_size {
+                if should_log {
+                    println!("[WebRTC] Timing: crop+scale={:?}, jpeg={:?}, total={:?}",
+                        scale_time, jpeg_start.elapsed(), encode_start.elapsed());
+                    if quality < start_quality {
+                        println!("[WebRTC] Quality adjusted: {}% → {}%, size: {} KB", start_quality, quality, jpeg_data.len() / 1024);
+                    }
+                }
+                return Ok(vec![jpeg_data]);
+            }
+            // 品質が最低でもサイズオーバーの場合はフレームをスキップ
+            if quality <= 10 {
+                eprintln!("[WebRTC] Frame too large even at {}% quality ({} KB), skipping", quality, jpeg_data.len() / 1024);
+                return Ok(vec![]);
+            }
+            quality = quality.saturating_sub(5); // 5%刻みで細かく調整
+        }
+    }
+
+    fn force_keyframe(&mut self) {
+        // 毎フレームが独立しているので何もしない
+    }
+}
+
+/// H.264（OpenH264）
+#[derive(Default)]
+pub struct H264FrameEncoder {
+    encoder: Option<H264Encoder>,
+    yuv: I420Buffer,
+    frame_id: u16,
+}
+
+impl H264FrameEncoder {
+    /// 切り抜き・縮小してH.264にエンコード
+    /// 返り値: Annex-BのH.264ビットストリーム（空ならスキップされたフレーム）
+    pub fn encode_bitstream(&mut self, frame: &CapturedFrame, layout: &FrameLayout, targets: &StreamTargets) -> Result<Vec<u8>, String> {
+        // 切り抜き・リサイズ・I420変換を1パスで行う
+        capture::crop_scale_i420(frame, layout, &mut self.yuv);
+
+        let encoder = match self.encoder.as_mut() {
+            Some(encoder) => {
+                encoder.set_rate(targets.bitrate_kbps * 1000, targets.fps as f32)?;
+                encoder
+            }
+            None => {
+                let encoder = H264Encoder::with_rate(layout.width, layout.height, targets.bitrate_kbps * 1000, targets.fps as f32)?;
+                println!("[H264] Encoder initialized: {}x{}", layout.width, layout.height);
+                self.encoder.insert(encoder)
+            }
+        };
+        encoder.encode_i420(&self.yuv)
+    }
+}
+
+impl Encoder for H264FrameEncoder {
+    fn codec(&self) -> Codec {
+        Codec::H264
+    }
+
+    fn encode(&mut self, frame: &CapturedFrame, layout: &FrameLayout, targets: &StreamTargets) -> Result<Vec<Vec<u8>>, String> {
+        let h264_data = self.encode_bitstream(frame, layout, targets)?;
+        self.frame_id = self.frame_id.wrapping_add(1);
+        Ok(packetize(&h264_data, PACKET_H264, PACKET_H264_FRAGMENT, self.frame_id))
+    }
+
+    fn force_keyframe(&mut self) {
+        if let Some(encoder) = self.encoder.as_mut() {
+            let _ = encoder.force_keyframe();
+        }
+    }
+}
+
+/// AV1（rav1e）
+#[derive(Default)]
+struct Av1FrameEncoder {
+    encoder: Option<Av1Encoder>,
+    yuv: I420Buffer,
+    frame_id: u16,
+}
+
+impl Encoder for Av1FrameEncoder {
+    fn codec(&self) -> Codec {
+        Codec::Av1
+    }
+
+    fn encode(&mut self, frame: &CapturedFrame, layout: &FrameLayout, targets: &StreamTargets) -> Result<Vec<Vec<u8>>, String> {
+        capture::crop_scale_i420(frame, layout, &mut self.yuv);
+
+        let encoder = match self.encoder.as_mut() {
+            Some(encoder) => {
+                encoder.set_rate(targets.bitrate_kbps * 1000, targets.fps as f32)?;
+                encoder
+            }
+            None => self.encoder.insert(Av1Encoder::with_rate(
+                self.yuv.width,
+                self.yuv.height,
+                targets.bitrate_kbps * 1000,
+                targets.fps as f32,
+            )?),
+        };
+
+        // テンポラルユニットごとに1フレームとして送る
+        let mut packets = Vec::new();
+        for unit in encoder.encode_i420(&self.yuv)? {
+            self.frame_id = self.frame_id.wrapping_add(1);
+            packets.extend(packetize(&unit, PACKET_AV1, PACKET_AV1_FRAGMENT, self.frame_id));
+        }
+        Ok(packets)
+    }
+
+    fn force_keyframe(&mut self) {
+        if let Some(encoder) = self.encoder.as_mut() {
+            encoder.force_keyframe();
+        }
+    }
+}
+
+/// タイルの圧縮形式
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TileFormat {
+    /// 画質指定のJPEG（非可逆）
+    Jpeg(u8),
+    Png,
+    Webp,
+}
+
+/// タイルの矩形 (x, y, 幅, 高さ)
+pub type TileRect = (u32, u32, u32, u32);
+
+/// 画像をタイルに分割した矩形（行優先）
+pub fn tile_rects(width: u32, height: u32) -> Vec<TileRect> {
+    (0..height.div_ceil(TILE_SIZE))
+        .flat_map(|row| (0..width.div_ceil(TILE_SIZE)).map(move |col| (col * TILE_SIZE, row * TILE_SIZE)))
+        .map(|(x, y)| (x, y, TILE_SIZE.min(width - x), TILE_SIZE.min(height - y)))
+        .collect()
+}
+
+/// 画像の矩形をRGBで圧縮してヘッダーの後ろに付ける（1パケットに収まらなければ4分割する）
+/// header: 矩形を受け取ってパケットの先頭部分を返す
+pub fn encode_tile<H>(img: &RgbaImage, rect: TileRect, format: TileFormat, header: &H, out: &mut Vec<Vec<u8>>) -> Result<(), String>
+where
+    H: Fn(TileRect) -> Vec<u8>,
+{
+    let (x, y, w, h) = rect;
+    let mut rgb = Vec::with_capacity((w * h * 3) as usize);
+    for row in y..y + h {
+        let start = ((row * img.width() + x) * 4) as usize;
+        for px in img.as_raw()[start..start + (w * 4) as usize].chunks_exact(4) {
+            rgb.extend_from_slice(&px[..3]);
+        }
+    }
+
+    let mut packet = header(rect);
+    let result = match format {
+        TileFormat::Jpeg(quality) => JpegEncoder::new_with_quality(&mut packet, quality).write_image(&rgb, w, h, ExtendedColorType::Rgb8),
+        TileFormat::Png => PngEncoder::new_with_quality(&mut packet, CompressionType::Fast, FilterType::Adaptive)
+            .write_image(&rgb, w, h, ExtendedColorType::Rgb8),
+        TileFormat::Webp => WebPEncoder::new_lossless(&mut packet).encode(&rgb, w, h, ExtendedColorType::Rgb8),
+    };
+    result.map_err(|e| format!("Tile encode error: {}", e))?;
+
+    if packet.len() <= MAX_PACKET_SIZE || (w <= MIN_TILE_SIZE && h <= MIN_TILE_SIZE) {
+        out.push(packet);
+        return Ok(());
+    }
+    let (half_w, half_h) = (w.div_ceil(2), h.div_ceil(2));
+    for (sx, sw) in [(x, half_w), (x + half_w, w - half_w)] {
+        for (sy, sh) in [(y, half_h), (y + half_h, h - half_h)] {
+            if sw > 0 && sh > 0 {
+                encode_tile(img, (sx, sy, sw, sh), format, header, out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 可逆タイル（PNG / WebP）: 変化したタイルだけを劣化なしで送る
+/// パケット: [0x03, 形式(0=PNG, 1=WebP), キーフレーム, frame_id(2), 全体幅(2), 全体高さ(2), x(2), y(2), 幅(2), 高さ(2)] + 画像
+struct TileEncoder {
+    codec: Codec,
+    // 前回送ったタイルのハッシュ（行優先）
+    hashes: Vec<u64>,
+    width: u32,
+    height: u32,
+    force_keyframe: bool,
+    frame_id: u16,
+}
+
+impl TileEncoder {
+    fn new(codec: Codec) -> Self {
+        Self {
+            codec,
+            hashes: Vec::new(),
+            width: 0,
+            height: 0,
+            force_keyframe: true,
+            frame_id: 0,
+        }
+    }
+}
+
+impl Encoder for TileEncoder {
+    fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    fn encode(&mut self, frame: &CapturedFrame, layout: &FrameLayout, _targets: &StreamTargets) -> Result<Vec<Vec<u8>>, String> {
+        // 可逆なのでビットレート目標は使わない（解像度の縮小のみ反映）
+        let img = capture::crop_scale_rgba(frame, layout);
+        let (width, height) = img.dimensions();
+        let tiles = tile_rects(width, height);
+
+        // サイズが変わったら全タイルを送り直す
+        let keyframe = self.force_keyframe || width != self.width || height != self.height;
+        self.force_keyframe = false;
+        if keyframe {
+            self.width = width;
+            self.height = height;
+            self.hashes = vec![0; tiles.len()];
+        }
+        self.frame_id = self.frame_id.wrapping_add(1);
+
+        // タイルごとのハッシュで変化を検出
+        let hashes: Vec<u64> = tiles
+            .par_iter()
+            .map(|&(x, y, w, h)| {
+                (y..y + h).fold(0u64, |acc, row| {
+                    let start = ((row * width + x) * 4) as usize;
+                    acc.rotate_left(7) ^ hash_band(&img.as_raw()[start..start + (w * 4) as usize])
+                })
+            })
+            .collect();
+
+        let (format, format_id) = if self.codec == Codec::Webp { (TileFormat::Webp, 1) } else { (TileFormat::Png, 0) };
+        let frame_id = self.frame_id;
+        let header = |(x, y, w, h): TileRect| {
+            let mut packet = vec![PACKET_TILE, format_id, keyframe as u8];
+            packet.extend_from_slice(&frame_id.to_be_bytes());
+            for value in [width, height, x, y, w, h] {
+                packet.extend_from_slice(&(value as u16).to_be_bytes());
+            }
+            packet
+        };
+
+        let packets: Vec<Vec<Vec<u8>>> = tiles
+            .par_iter()
+            .zip(hashes.par_iter().zip(self.hashes.par_iter()))
+            .filter(|(_, (new, old))| keyframe || new != old)
+            .map(|(&rect, _)| {
+                let mut out = Vec::new();
+                encode_tile(&img, rect, format, &header, &mut out).map(|_| out)
+            })
+            .collect::<Result<_, _>>()?;
+        self.hashes = hashes;
+
+        Ok(packets.into_iter().flatten().collect())
+    }
+
+    fn force_keyframe(&mut self) {
+        self.force_keyframe = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packetize_fragments_large_frames() {
+        let data = vec![7u8; MAX_PACKET_SIZE * 2 + 10];
+        let packets = packetize(&data, PACKET_AV1, PACKET_AV1_FRAGMENT, 42);
+        assert_eq!(packets.len(), 3);
+        assert!(packets.iter().all(|p| p[0] == PACKET_AV1_FRAGMENT && p[2] == 3 && p[3..5] == 42u16.to_be_bytes()));
+        assert_eq!(packets.iter().map(|p| p.len() - 5).sum::<usize>(), data.len());
+
+        let small = packetize(&data[..10], PACKET_AV1, PACKET_AV1_FRAGMENT, 42);
+        assert_eq!(small, vec![[&[PACKET_AV1][..], &data[..10]].concat()]);
+    }
+
+    #[test]
+    fn test_tiles_resend_only_changed() {
+        let (width, height) = (256usize, 128usize);
+        let mut frame = CapturedFrame {
+            data: vec![255; width * height * 4],
+            width,
+            height,
+            stride: width * 4,
+            bounds: crate::monitors::Rect { x: 0, y: 0, width: width as u32, height: height as u32 },
+            scale_factor: 1.0,
+            seq: 1,
+            content_id: 1,
+        };
+        let layout = FrameLayout {
+            crop_x: 0,
+            crop_y: 0,
+            crop_width: width,
+            crop_height: height,
+            logical_pixels: (width * height) as u32,
+            width: width as u32,
+            height: height as u32,
+        };
+        let targets = StreamTargets { bitrate_kbps: 2500, fps: 30, scale: 1.0 };
+        let mut encoder = create_encoder(Codec::Png);
+
+        // 最初は全タイル、変化がなければ何も送らない
+        assert_eq!(encoder.encode(&frame, &layout, &targets).unwrap().len(), 2);
+        assert!(encoder.encode(&frame, &layout, &targets).unwrap().is_empty());
+
+        // 右のタイルだけ変える
+        frame.data[200 * 4] = 0;
+        let packets = encoder.encode(&frame, &layout, &targets).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0][0], PACKET_TILE);
+        assert_eq!(packets[0][9..11], 128u16.to_be_bytes()); // x
+    }
+
+    #[test]
+    fn test_negotiate_picks_first_supported() {
+        let preferred = ["hevc".to_string(), "AV1".to_string(), "h264".to_string()];
+        assert_eq!(Codec::negotiate(&preferred), Some(Codec::Av1));
+        assert_eq!(Codec::negotiate(&["vp9".to_string()]), None);
+    }
+}
@@ -0,0 +1,453 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use image::codecs::png::PngEncoder;
+use image::{ExtendedColorType, ImageEncoder};
+use once_cell::sync::Lazy;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::capture::CapturedFrame;
+
+// カーソルの監視間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// この時間読まれなければ監視スレッドを止める
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// カーソル画像（形が変わった時だけ取得し直す）
+pub struct CursorShape {
+    /// 形の識別子（同じ形なら同じ値、0は非表示）
+    pub id: u64,
+    pub width: u32,
+    pub height: u32,
+    /// ホットスポット（画像のピクセル座標）
+    pub hot_x: u32,
+    pub hot_y: u32,
+    /// 論理ピクセルあたりの画像ピクセル数（Retinaなら2）
+    pub scale: f32,
+    /// RGBA（乗算済みでないアルファ）
+    pub rgba: Vec<u8>,
+    /// クライアントに送るPNG（取得時に一度だけエンコード）
+    png: Vec<u8>,
+}
+
+/// 現在のカーソル（位置は仮想デスクトップ上の論理座標）
+#[derive(Clone)]
+pub struct CursorState {
+    pub x: i32,
+    pub y: i32,
+    pub shape: Option<Arc<CursorShape>>,
+}
+
+/// クライアントに送るカーソル画像
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CursorImage {
+    pub id: u64,
+    pub width: u32,
+    pub height: u32,
+    pub hot_x: u32,
+    pub hot_y: u32,
+    pub scale: f32,
+    /// PNG（Base64、非表示なら空）
+    pub image: String,
+}
+
+impl CursorShape {
+    fn new(id: u64, width: u32, height: u32, (hot_x, hot_y): (u32, u32), scale: f32, rgba: Vec<u8>) -> Self {
+        let mut png = Vec::new();
+        if width > 0 && height > 0 {
+            if let Err(e) = PngEncoder::new(&mut png).write_image(&rgba, width, height, ExtendedColorType::Rgba8) {
+                eprintln!("[Cursor] PNG encode error: {}", e);
+            }
+        }
+        Self { id, width, height, hot_x, hot_y, scale, rgba, png }
+    }
+
+    /// 非表示のカーソル
+    #[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+    fn hidden() -> Self {
+        Self::new(0, 0, 0, (0, 0), 1.0, Vec::new())
+    }
+
+    pub fn image(&self) -> CursorImage {
+        CursorImage {
+            id: self.id,
+            width: self.width,
+            height: self.height,
+            hot_x: self.hot_x,
+            hot_y: self.hot_y,
+            scale: self.scale,
+            image: STANDARD.encode(&self.png),
+        }
+    }
+}
+
+static STATE: Lazy<RwLock<Option<CursorState>>> = Lazy::new(|| RwLock::new(None));
+static TRACKER_RUNNING: AtomicBool = AtomicBool::new(false);
+static LAST_READ: Lazy<Mutex<Instant>> = Lazy::new(|| Mutex::new(Instant::now()));
+
+/// 現在のカーソル（取得できない環境ではNone、初回呼び出しで監視を開始）
+pub fn current() -> Option<CursorState> {
+    *LAST_READ.lock() = Instant::now();
+    if !TRACKER_RUNNING.swap(true, Ordering::SeqCst) {
+        std::thread::spawn(track);
+    }
+    STATE.read().clone()
+}
+
+// 監視スレッド: 位置を更新し、形が変わった時だけ画像を取得する
+fn track() {
+    let Some(mut platform) = Platform::new() else {
+        // 取得できない環境では再試行しない（TRACKER_RUNNINGは立てたまま）
+        eprintln!("[Cursor] Cursor tracking is not available on this platform");
+        return;
+    };
+    println!("[Cursor] Tracking started");
+
+    loop {
+        if LAST_READ.lock().elapsed() > IDLE_TIMEOUT {
+            *STATE.write() = None;
+            TRACKER_RUNNING.store(false, Ordering::SeqCst);
+            println!("[Cursor] Tracking stopped (idle)");
+            return;
+        }
+
+        let known = STATE.read().as_ref().and_then(|s| s.shape.as_ref().map(|shape| shape.id));
+        if let Some((position, shape)) = platform.poll(known) {
+            // OSの座標（WindowsとX11は物理ピクセル） -> 論理座標
+            let (x, y) = crate::monitors::from_os(position.0, position.1);
+            let mut state = STATE.write();
+            let shape = match shape {
+                Some(shape) => Some(Arc::new(shape)),
+                None => state.as_ref().and_then(|s| s.shape.clone()),
+            };
+            *state = Some(CursorState { x, y, shape });
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+impl CursorState {
+    /// 焼き込み結果が変わるかの判定用（位置と形）
+    pub fn key(&self) -> (i32, i32, u64) {
+        (self.x, self.y, self.shape.as_ref().map_or(0, |s| s.id))
+    }
+}
+
+/// 接続ごとの焼き込み（クライアントがカーソルを描画できない場合用）
+/// 共有キャプチャのフレームは他の接続も使うので、カーソルがあれば合成したコピーを返す
+pub fn burned_in(frame: Arc<CapturedFrame>, cursor: Option<&CursorState>) -> Arc<CapturedFrame> {
+    let Some(cursor) = cursor else { return frame };
+    let mut frame = (*frame).clone();
+    burn_in(&mut frame, cursor);
+    Arc::new(frame)
+}
+
+/// カーソルをフレームに合成（フレームの倍率に合わせて最近傍で拡縮）
+fn burn_in(frame: &mut CapturedFrame, cursor: &CursorState) {
+    let Some(shape) = cursor.shape.as_ref().filter(|s| s.width > 0 && s.height > 0) else {
+        return;
+    };
+    let ratio = frame.scale_factor / shape.scale;
+    let dst_w = ((shape.width as f32 * ratio).round() as i64).max(1);
+    let dst_h = ((shape.height as f32 * ratio).round() as i64).max(1);
+    let left = ((cursor.x - frame.bounds.x) as f32 * frame.scale_factor - shape.hot_x as f32 * ratio).round() as i64;
+    let top = ((cursor.y - frame.bounds.y) as f32 * frame.scale_factor - shape.hot_y as f32 * ratio).round() as i64;
+
+    for dy in 0..dst_h {
+        let y = top + dy;
+        if y < 0 || y >= frame.height as i64 {
+            continue;
+        }
+        let src_y = ((dy as f32 / ratio) as u32).min(shape.height - 1);
+        for dx in 0..dst_w {
+            let x = left + dx;
+            if x < 0 || x >= frame.width as i64 {
+                continue;
+            }
+            let src_x = ((dx as f32 / ratio) as u32).min(shape.width - 1);
+            let s = ((src_y * shape.width + src_x) * 4) as usize;
+            let [r, g, b, a] = [shape.rgba[s], shape.rgba[s + 1], shape.rgba[s + 2], shape.rgba[s + 3]];
+            if a == 0 {
+                continue;
+            }
+            let d = y as usize * frame.stride + x as usize * 4;
+            // BGRA
+            for (dst, src) in frame.data[d..d + 3].iter_mut().zip([b, g, r]) {
+                *dst = ((src as u32 * a as u32 + *dst as u32 * (255 - a as u32)) / 255) as u8;
+            }
+        }
+    }
+}
+
+/// X11: XFixesで位置・形・画像をまとめて取得（Waylandでは使えない）
+#[cfg(target_os = "linux")]
+struct Platform {
+    conn: x11rb::rust_connection::RustConnection,
+}
+
+#[cfg(target_os = "linux")]
+impl Platform {
+    fn new() -> Option<Self> {
+        use x11rb::protocol::xfixes::ConnectionExt as _;
+        let (conn, _) = x11rb::connect(None).ok()?;
+        // 拡張を使う前にバージョンの取り決めが必要
+        conn.xfixes_query_version(4, 0).ok()?.reply().ok()?;
+        Some(Self { conn })
+    }
+
+    fn poll(&mut self, known: Option<u64>) -> Option<((i32, i32), Option<CursorShape>)> {
+        use x11rb::protocol::xfixes::ConnectionExt as _;
+        let reply = self.conn.xfixes_get_cursor_image().ok()?.reply().ok()?;
+        let position = (reply.x as i32, reply.y as i32);
+        // シリアルは0にならないので非表示のidと衝突しない
+        let id = reply.cursor_serial as u64;
+        if known == Some(id) {
+            return Some((position, None));
+        }
+        // 乗算済みARGB -> RGBA
+        let rgba = reply
+            .cursor_image
+            .iter()
+            .flat_map(|&argb| {
+                let [b, g, r, a] = argb.to_le_bytes();
+                let unpremultiply = |c: u8| if a == 0 { 0 } else { (c as u32 * 255 / a as u32).min(255) as u8 };
+                [unpremultiply(r), unpremultiply(g), unpremultiply(b), a]
+            })
+            .collect();
+        let shape = CursorShape::new(
+            id,
+            reply.width as u32,
+            reply.height as u32,
+            (reply.xhot as u32, reply.yhot as u32),
+            // 画像は物理ピクセル（1.25倍などの小数倍率もそのまま）
+            crate::monitors::os_scale_at(position.0, position.1),
+            rgba,
+        );
+        Some((position, Some(shape)))
+    }
+}
+
+/// macOS: 位置はCGEvent、形はNSCursor（画像の内容で変化を判定）
+#[cfg(target_os = "macos")]
+struct Platform;
+
+#[cfg(target_os = "macos")]
+impl Platform {
+    fn new() -> Option<Self> {
+        Some(Self)
+    }
+
+    fn poll(&mut self, known: Option<u64>) -> Option<((i32, i32), Option<CursorShape>)> {
+        use image::ImageFormat;
+        use objc2_app_kit::NSCursor;
+
+        let position = crate::input_control::get_mouse_position()?;
+        let shape = unsafe {
+            NSCursor::currentSystemCursor().and_then(|cursor| {
+                let image = cursor.image();
+                let hot = cursor.hotSpot();
+                let tiff = image.TIFFRepresentation()?;
+                let bytes = tiff.bytes();
+                let id = crate::damage::hash_band(bytes) ^ (((hot.x as u64) << 32) | hot.y as u64);
+                if known == Some(id) {
+                    return None;
+                }
+                let decoded = image::load_from_memory_with_format(bytes, ImageFormat::Tiff).ok()?.to_rgba8();
+                let scale = decoded.width() as f32 / image.size().width.max(1.0) as f32;
+                Some(CursorShape::new(
+                    id,
+                    decoded.width(),
+                    decoded.height(),
+                    ((hot.x as f32 * scale) as u32, (hot.y as f32 * scale) as u32),
+                    scale,
+                    decoded.into_raw(),
+                ))
+            })
+        };
+        Some((position, shape))
+    }
+}
+
+/// Windows: GetCursorInfoでハンドルが変わった時だけビットマップを読む
+#[cfg(target_os = "windows")]
+struct Platform;
+
+#[cfg(target_os = "windows")]
+impl Platform {
+    fn new() -> Option<Self> {
+        Some(Self)
+    }
+
+    fn poll(&mut self, known: Option<u64>) -> Option<((i32, i32), Option<CursorShape>)> {
+        use windows::Win32::UI::WindowsAndMessaging::{GetCursorInfo, CURSORINFO, CURSOR_SHOWING};
+
+        let mut info = CURSORINFO { cbSize: std::mem::size_of::<CURSORINFO>() as u32, ..Default::default() };
+        unsafe { GetCursorInfo(&mut info) }.ok()?;
+        let position = (info.ptScreenPos.x, info.ptScreenPos.y);
+        let id = if info.flags.0 & CURSOR_SHOWING.0 == 0 || info.hCursor.is_invalid() {
+            0
+        } else {
+            info.hCursor.0 as usize as u64
+        };
+        if known == Some(id) {
+            return Some((position, None));
+        }
+        // 画像は物理ピクセル（1.25倍などの小数倍率もそのまま）
+        let scale = crate::monitors::os_scale_at(position.0, position.1);
+        let shape = if id == 0 { Some(CursorShape::hidden()) } else { unsafe { windows_cursor_image(info.hCursor, id, scale) } };
+        Some((position, shape))
+    }
+}
+
+// カーソルのビットマップをRGBAにする（モノクロカーソルはANDマスク・XORマスクから作る）
+#[cfg(target_os = "windows")]
+unsafe fn windows_cursor_image(cursor: windows::Win32::UI::WindowsAndMessaging::HCURSOR, id: u64, scale: f32) -> Option<CursorShape> {
+    use windows::Win32::Graphics::Gdi::{DeleteObject, HGDIOBJ};
+    use windows::Win32::UI::WindowsAndMessaging::{GetIconInfo, HICON, ICONINFO};
+
+    let mut icon = ICONINFO::default();
+    GetIconInfo(HICON(cursor.0), &mut icon).ok()?;
+    let mask = windows_bitmap_bgra(icon.hbmMask);
+    let color = if icon.hbmColor.is_invalid() { None } else { windows_bitmap_bgra(icon.hbmColor) };
+    let _ = DeleteObject(HGDIOBJ::from(icon.hbmMask));
+    if !icon.hbmColor.is_invalid() {
+        let _ = DeleteObject(HGDIOBJ::from(icon.hbmColor));
+    }
+    let (mask_w, mask_h, mask) = mask?;
+
+    let (width, height, rgba) = match color {
+        Some((w, h, bgra)) => {
+            // アルファがないカーソルはANDマスクを透明度に使う
+            let has_alpha = bgra.chunks_exact(4).any(|p| p[3] != 0);
+            let rgba = bgra
+                .chunks_exact(4)
+                .zip(mask.chunks_exact(4))
+                .flat_map(|(p, m)| [p[2], p[1], p[0], if has_alpha { p[3] } else if m[0] == 0 { 255 } else { 0 }])
+                .collect();
+            (w, h, rgba)
+        }
+        None => {
+            // 上半分がANDマスク、下半分がXORマスク（反転表示は黒で近似）
+            let h = mask_h / 2;
+            let half = (mask_w * h * 4) as usize;
+            let rgba = mask[..half]
+                .chunks_exact(4)
+                .zip(mask[half..].chunks_exact(4))
+                .flat_map(|(and, xor)| match (and[0] != 0, xor[0] != 0) {
+                    (false, false) => [0, 0, 0, 255],
+                    (false, true) => [255, 255, 255, 255],
+                    (true, false) => [0, 0, 0, 0],
+                    (true, true) => [0, 0, 0, 255],
+                })
+                .collect();
+            (mask_w, h, rgba)
+        }
+    };
+    Some(CursorShape::new(id, width, height, (icon.xHotspot, icon.yHotspot), scale, rgba))
+}
+
+#[cfg(target_os = "windows")]
+unsafe fn windows_bitmap_bgra(bitmap: windows::Win32::Graphics::Gdi::HBITMAP) -> Option<(u32, u32, Vec<u8>)> {
+    use windows::Win32::Foundation::HWND;
+    use windows::Win32::Graphics::Gdi::{
+        GetDC, GetDIBits, GetObjectW, ReleaseDC, BITMAP, BITMAPINFO, BITMAPINFOHEADER, BI_RGB, DIB_RGB_COLORS, HGDIOBJ,
+    };
+
+    let mut bm = BITMAP::default();
+    let size = std::mem::size_of::<BITMAP>() as i32;
+    if GetObjectW(HGDIOBJ::from(bitmap), size, Some(&mut bm as *mut BITMAP as *mut _)) == 0 {
+        return None;
+    }
+    let (width, height) = (bm.bmWidth.max(0) as u32, bm.bmHeight.max(0) as u32);
+    let mut info = BITMAPINFO {
+        bmiHeader: BITMAPINFOHEADER {
+            biSize: std::mem::size_of::<BITMAPINFOHEADER>() as u32,
+            biWidth: width as i32,
+            // 負の高さで上から下の並び
+            biHeight: -(height as i32),
+            biPlanes: 1,
+            biBitCount: 32,
+            biCompression: BI_RGB.0,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut data = vec![0u8; (width * height * 4) as usize];
+    let dc = GetDC(HWND::default());
+    let lines = GetDIBits(dc, bitmap, 0, height, Some(data.as_mut_ptr() as *mut _), &mut info, DIB_RGB_COLORS);
+    ReleaseDC(HWND::default(), dc);
+    (lines > 0).then_some((width, height, data))
+}
+
+/// その他のプラットフォーム: カーソル情報なし
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+struct Platform;
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+impl Platform {
+    fn new() -> Option<Self> {
+        None
+    }
+
+    fn poll(&mut self, _known: Option<u64>) -> Option<((i32, i32), Option<CursorShape>)> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitors::Rect;
+
+    #[test]
+    fn test_burn_in_at_hotspot() {
+        // 論理10x10、倍率2のフレーム（黒）
+        let mut frame = CapturedFrame {
+            data: vec![0; 20 * 20 * 4],
+            width: 20,
+            height: 20,
+            stride: 20 * 4,
+            bounds: Rect { x: 100, y: 0, width: 10, height: 10 },
+            scale_factor: 2.0,
+            seq: 1,
+            content_id: 1,
+        };
+        // 2x2の白いカーソル、ホットスポットは右下のピクセル
+        let shape = CursorShape::new(1, 2, 2, (1, 1), 1.0, vec![255; 16]);
+        let cursor = CursorState { x: 105, y: 5, shape: Some(Arc::new(shape)) };
+        burn_in(&mut frame, &cursor);
+
+        // 倍率2で4x4に拡大され、ネイティブ(10, 10)がホットスポット
+        let white = |x: usize, y: usize| frame.data[y * frame.stride + x * 4] == 255;
+        assert!(white(8, 8) && white(11, 11));
+        assert!(!white(7, 8) && !white(12, 11));
+        assert_eq!(cursor.shape.unwrap().image().id, 1);
+    }
+
+    #[test]
+    fn test_burned_in_leaves_shared_frame() {
+        let shared = Arc::new(CapturedFrame {
+            data: vec![0; 4 * 4 * 4],
+            width: 4,
+            height: 4,
+            stride: 4 * 4,
+            bounds: Rect { x: 0, y: 0, width: 4, height: 4 },
+            scale_factor: 1.0,
+            seq: 1,
+            content_id: 1,
+        });
+        let shape = CursorShape::new(1, 1, 1, (0, 0), 1.0, vec![255; 4]);
+        let cursor = CursorState { x: 1, y: 1, shape: Some(Arc::new(shape)) };
+
+        // 焼き込むのはコピーだけ（他の接続が使う共有フレームは変えない）
+        let burned = burned_in(shared.clone(), Some(&cursor));
+        assert_eq!(burned.data[4 * 4 + 4], 255);
+        assert!(shared.data.iter().all(|&b| b == 0));
+
+        // 焼き込まない接続は共有フレームをそのまま使う
+        assert!(Arc::ptr_eq(&burned_in(shared.clone(), None), &shared));
+        assert_eq!(cursor.key(), (1, 1, 1));
+    }
+}
@@ -0,0 +1,210 @@
+use rayon::prelude::*;
+use std::time::{Duration, Instant};
+
+use crate::CaptureRegion;
+
+// 変化検出の単位（行数）。この行数ごとの帯でハッシュを取り、前回キャプチャと比較する
+const BAND_ROWS: usize = 16;
+
+/// 画面が静止していても送るキーフレームの間隔（途中参加・パケットロスからの復帰用）
+pub const KEYFRAME_INTERVAL: Duration = Duration::from_secs(2);
+
+/// キャプチャしたフレームの扱い
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameAction {
+    /// 前回から変化なし（エンコードを省略）
+    Skip,
+    /// 変化あり（通常どおりエンコード）
+    Encode,
+    /// 変化はないが定期キーフレームを送る
+    Keyframe,
+}
+
+/// 送信経路ごとのエンコード判定（画面内容・キャプチャ領域・焼き込むカーソルの変化と定期キーフレーム）
+pub struct FrameGate {
+    content_id: Option<u64>,
+    region: Option<CaptureRegion>,
+    cursor: Option<(i32, i32, u64)>,
+    last_encoded: Instant,
+}
+
+impl Default for FrameGate {
+    fn default() -> Self {
+        Self {
+            content_id: None,
+            region: None,
+            cursor: None,
+            last_encoded: Instant::now(),
+        }
+    }
+}
+
+impl FrameGate {
+    /// `content_id` はキャプチャサービスが変化時に増やす番号
+    /// `cursor` はこの経路で焼き込むカーソルの位置と形（焼き込まない場合はNone）
+    pub fn check(&mut self, content_id: u64, region: &Option<CaptureRegion>, cursor: Option<(i32, i32, u64)>) -> FrameAction {
+        if self.content_id != Some(content_id) || self.region != *region || self.cursor != cursor {
+            self.content_id = Some(content_id);
+            self.region = region.clone();
+            self.cursor = cursor;
+            self.last_encoded = Instant::now();
+            FrameAction::Encode
+        } else if self.last_encoded.elapsed() >= KEYFRAME_INTERVAL {
+            self.last_encoded = Instant::now();
+            FrameAction::Keyframe
+        } else {
+            FrameAction::Skip
+        }
+    }
+}
+
+/// 前回キャプチャとの差分検出（帯ごとのハッシュ比較）
+#[derive(Default)]
+pub struct DamageTracker {
+    hashes: Vec<u64>,
+    stride: usize,
+}
+
+impl DamageTracker {
+    /// 次のフレームを必ず「変化あり」として扱う（キャプチャを開き直した時など）
+    pub fn invalidate(&mut self) {
+        self.hashes.clear();
+    }
+
+    /// 前回のフレームから変化があるか
+    /// `stride` は1行あたりのバイト数（パディング込み）
+    pub fn is_damaged(&mut self, data: &[u8], stride: usize) -> bool {
+        self.damaged_bands(data, stride) > 0
+    }
+
+    // 前回から変化した帯の数（サイズが変わった場合は全帯）
+    fn damaged_bands(&mut self, data: &[u8], stride: usize) -> usize {
+        let band_bytes = (stride * BAND_ROWS).max(1);
+        let hashes: Vec<u64> = data.par_chunks(band_bytes).map(hash_band).collect();
+
+        let damaged = if stride != self.stride || hashes.len() != self.hashes.len() {
+            hashes.len()
+        } else {
+            hashes.iter().zip(&self.hashes).filter(|(a, b)| a != b).count()
+        };
+
+        self.hashes = hashes;
+        self.stride = stride;
+        damaged
+    }
+}
+
+// 帯のハッシュ（暗号強度は不要なので8バイト単位の軽量な混合）
+pub fn hash_band(band: &[u8]) -> u64 {
+    const K: u64 = 0x9E37_79B9_7F4A_7C15;
+    let mut h = band.len() as u64;
+    let mut words = band.chunks_exact(8);
+    for word in &mut words {
+        let w = u64::from_le_bytes(word.try_into().unwrap());
+        h = (h.rotate_left(5) ^ w).wrapping_mul(K);
+    }
+    for &b in words.remainder() {
+        h = (h.rotate_left(5) ^ b as u64).wrapping_mul(K);
+    }
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: usize = 64;
+    const STRIDE: usize = WIDTH * 4;
+
+    fn frame(rows: usize) -> Vec<u8> {
+        (0..STRIDE * rows).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn region(x: i32) -> Option<CaptureRegion> {
+        Some(CaptureRegion {
+            x,
+            y: 0,
+            width: 100,
+            height: 100,
+            viewport_x: 0,
+            viewport_y: 0,
+            viewport_width: 100,
+            viewport_height: 100,
+            quality_mode: "low".to_string(),
+            roi: false,
+        })
+    }
+
+    #[test]
+    fn test_identical_frame_is_not_damaged() {
+        let mut tracker = DamageTracker::default();
+        let data = frame(BAND_ROWS * 4);
+        assert!(tracker.is_damaged(&data, STRIDE));
+        assert_eq!(tracker.damaged_bands(&data, STRIDE), 0);
+        assert!(!tracker.is_damaged(&data, STRIDE));
+    }
+
+    #[test]
+    fn test_changed_band_is_reported() {
+        let mut tracker = DamageTracker::default();
+        let mut data = frame(BAND_ROWS * 4);
+        tracker.is_damaged(&data, STRIDE);
+        let before = tracker.hashes.clone();
+
+        // 3番目の帯の1ピクセルだけ変える
+        data[(BAND_ROWS * 2 + 5) * STRIDE + 8] ^= 0xff;
+        assert_eq!(tracker.damaged_bands(&data, STRIDE), 1);
+        let changed: Vec<usize> = (0..before.len()).filter(|&i| before[i] != tracker.hashes[i]).collect();
+        assert_eq!(changed, vec![2]);
+    }
+
+    #[test]
+    fn test_size_or_stride_change_damages_all_bands() {
+        let mut tracker = DamageTracker::default();
+        let data = frame(BAND_ROWS * 4);
+        tracker.is_damaged(&data, STRIDE);
+
+        // 同じデータでもパディングが変われば全帯を変化ありとする
+        assert_eq!(tracker.damaged_bands(&data, STRIDE / 2), 8);
+        tracker.damaged_bands(&data, STRIDE);
+        assert_eq!(tracker.damaged_bands(&frame(BAND_ROWS * 6), STRIDE), 6);
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let mut tracker = DamageTracker::default();
+        let data = frame(BAND_ROWS * 4);
+        tracker.is_damaged(&data, STRIDE);
+        tracker.invalidate();
+        assert_eq!(tracker.damaged_bands(&data, STRIDE), 4);
+        assert!(!tracker.is_damaged(&data, STRIDE));
+    }
+
+    #[test]
+    fn test_frame_gate() {
+        let mut gate = FrameGate::default();
+        assert_eq!(gate.check(1, &None, None), FrameAction::Encode);
+        assert_eq!(gate.check(1, &None, None), FrameAction::Skip);
+        assert_eq!(gate.check(2, &None, None), FrameAction::Encode);
+
+        // キャプチャ領域・焼き込むカーソルの変化もエンコードする
+        assert_eq!(gate.check(2, &region(0), None), FrameAction::Encode);
+        assert_eq!(gate.check(2, &region(0), None), FrameAction::Skip);
+        assert_eq!(gate.check(2, &region(10), None), FrameAction::Encode);
+        assert_eq!(gate.check(2, &region(10), Some((5, 5, 1))), FrameAction::Encode);
+        assert_eq!(gate.check(2, &region(10), Some((5, 5, 1))), FrameAction::Skip);
+
+        // 静止したままKEYFRAME_INTERVALが過ぎたら定期キーフレーム
+        gate.last_encoded = Instant::now() - KEYFRAME_INTERVAL;
+        assert_eq!(gate.check(2, &region(10), Some((5, 5, 1))), FrameAction::Keyframe);
+        assert_eq!(gate.check(2, &region(10), Some((5, 5, 1))), FrameAction::Skip);
+    }
+
+    #[test]
+    fn test_hash_band() {
+        assert_eq!(hash_band(b"0123456789"), hash_band(b"0123456789"));
+        assert_ne!(hash_band(b"0123456789"), hash_band(b"0123456788"));
+        // 長さも混ぜるので、0で埋めた帯どうしでも長さが違えば区別される
+        assert_ne!(hash_band(&[0; 8]), hash_band(&[0; 16]));
+    }
+}
@@ -0,0 +1,200 @@
+use bytes::Bytes;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use tokio::sync::Notify;
+
+use crate::client_display::OutputResolution;
+use crate::metrics::METRICS;
+use crate::roi::RoiInfo;
+
+// 購読者ごとのキュー長（これを超えたら遅れているとみなす）
+const QUEUE_CAPACITY: usize = 4;
+
+/// エンコード済みフレーム（データはバスの中では全購読者で共有）
+/// WebSocketへの送信時はtokio-tungstenite 0.24のMessage::BinaryがVec<u8>しか受け取らないため、
+/// 受信者ごとに1回コピーが残る
+#[derive(Clone)]
+pub struct Frame {
+    pub data: Bytes,
+    pub keyframe: bool,
+    /// ビューポート優先エンコード時の座標情報（フレームの前に送る）
+    pub roi: Option<Arc<RoiInfo>>,
+    /// 送信解像度（変わった時にクライアントへ通知する）
+    pub output: OutputResolution,
+}
+
+struct Queue {
+    frames: VecDeque<Frame>,
+    // 遅れてフレームを捨てた後（または購読直後）、次のキーフレームまで待っている
+    resyncing: bool,
+    // 最初のキーフレームを受け取ったか（購読直後の待ちは取りこぼしに数えない）
+    started: bool,
+}
+
+struct Subscriber {
+    peer: String,
+    queue: Mutex<Queue>,
+    notify: Notify,
+    dropped: AtomicU64,
+}
+
+/// 購読者ごとの送信状況
+#[derive(Clone, Debug, Serialize)]
+pub struct SubscriberStats {
+    pub peer: String,
+    pub queued: usize,
+    pub dropped_frames: u64,
+}
+
+/// 1つのエンコーダーの出力を複数のクライアントに配る
+/// 遅れたクライアントはキーフレームまで読み飛ばし、エンコーダーにIDRを要求する
+pub struct FrameBus {
+    subscribers: Mutex<Vec<Weak<Subscriber>>>,
+    force_keyframe: Arc<AtomicBool>,
+}
+
+impl FrameBus {
+    pub fn new(force_keyframe: Arc<AtomicBool>) -> Self {
+        Self {
+            subscribers: Mutex::new(Vec::new()),
+            force_keyframe,
+        }
+    }
+
+    /// 購読を開始（最初のフレームはキーフレームから）
+    pub fn subscribe(&self, peer: &str) -> FrameSubscriber {
+        let subscriber = Arc::new(Subscriber {
+            peer: peer.to_string(),
+            queue: Mutex::new(Queue { frames: VecDeque::new(), resyncing: true, started: false }),
+            notify: Notify::new(),
+            dropped: AtomicU64::new(0),
+        });
+        self.subscribers.lock().push(Arc::downgrade(&subscriber));
+        self.force_keyframe.store(true, Ordering::SeqCst);
+        FrameSubscriber { subscriber }
+    }
+
+    /// 生きている購読者の数
+    pub fn subscriber_count(&self) -> usize {
+        let mut subscribers = self.subscribers.lock();
+        subscribers.retain(|s| s.strong_count() > 0);
+        subscribers.len()
+    }
+
+    /// 購読者ごとの送信状況
+    pub fn stats(&self) -> Vec<SubscriberStats> {
+        self.subscribers
+            .lock()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|s| SubscriberStats {
+                peer: s.peer.clone(),
+                queued: s.queue.lock().frames.len(),
+                dropped_frames: s.dropped.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// フレームを全購読者のキューに積む
+    pub fn publish(&self, frame: Frame) {
+        let subscribers: Vec<Arc<Subscriber>> = {
+            let mut subscribers = self.subscribers.lock();
+            subscribers.retain(|s| s.strong_count() > 0);
+            subscribers.iter().filter_map(Weak::upgrade).collect()
+        };
+
+        let mut need_keyframe = false;
+        for subscriber in subscribers {
+            let mut queue = subscriber.queue.lock();
+            let mut dropped = 0u64;
+
+            if queue.frames.len() >= QUEUE_CAPACITY {
+                // 送信が追いつかない: 溜まったフレームを捨ててキーフレームから再開する
+                dropped += queue.frames.len() as u64;
+                queue.frames.clear();
+                queue.resyncing = true;
+            }
+            if frame.keyframe {
+                queue.resyncing = false;
+                queue.started = true;
+            }
+
+            if queue.resyncing {
+                // 参照フレームが欠けているのでキーフレームまで送らない
+                if queue.started {
+                    dropped += 1;
+                }
+                need_keyframe = true;
+            } else {
+                queue.frames.push_back(frame.clone());
+            }
+            drop(queue);
+
+            if dropped > 0 {
+                subscriber.dropped.fetch_add(dropped, Ordering::Relaxed);
+                METRICS.broadcast_lagged_frames.add(dropped);
+            }
+            subscriber.notify.notify_one();
+        }
+
+        if need_keyframe {
+            self.force_keyframe.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// 購読者側の受信口（dropで購読解除）
+pub struct FrameSubscriber {
+    subscriber: Arc<Subscriber>,
+}
+
+impl FrameSubscriber {
+    /// 次のフレームを待つ
+    pub async fn recv(&mut self) -> Frame {
+        loop {
+            if let Some(frame) = self.subscriber.queue.lock().frames.pop_front() {
+                return frame;
+            }
+            self.subscriber.notify.notified().await;
+        }
+    }
+
+    /// このクライアントで捨てたフレーム数
+    pub fn dropped_frames(&self) -> u64 {
+        self.subscriber.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(keyframe: bool) -> Frame {
+        Frame { data: Bytes::from_static(b"frame"), keyframe, roi: None, output: OutputResolution::default() }
+    }
+
+    #[tokio::test]
+    async fn test_lagging_subscriber_resyncs_on_keyframe() {
+        let force_keyframe = Arc::new(AtomicBool::new(false));
+        let bus = FrameBus::new(force_keyframe.clone());
+        let mut rx = bus.subscribe("test");
+
+        bus.publish(frame(true));
+        for _ in 0..QUEUE_CAPACITY {
+            bus.publish(frame(false));
+        }
+        force_keyframe.store(false, Ordering::SeqCst);
+
+        // キューが溢れたらPフレームは捨てられ、キーフレームが要求される
+        bus.publish(frame(false));
+        assert!(force_keyframe.load(Ordering::SeqCst));
+        assert_eq!(rx.dropped_frames(), QUEUE_CAPACITY as u64 + 2);
+
+        // 次に届くのはキーフレーム
+        bus.publish(frame(true));
+        assert!(rx.recv().await.keyframe);
+    }
+}
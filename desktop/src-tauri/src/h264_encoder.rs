@@ -1,116 +1,152 @@
 use openh264::encoder::{Encoder, EncoderConfig};
-use openh264::formats::{YUVBuffer, BgraSliceU8};
+use openh264::formats::YUVSlices;
+use openh264_sys2::{SBitrateInfo, ENCODER_OPTION_BITRATE, SPATIAL_LAYER_ALL};
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Mutex;
 
+use crate::capture::I420Buffer;
+
+// 定期キーフレームの間隔（フレーム数、0なら定期送信なし）
+// パケットロス時はクライアントが request_keyframe で要求するので長めにする（30fpsで約10秒）
+static GOP_LENGTH: AtomicU64 = AtomicU64::new(300);
+
+/// 定期キーフレームの間隔（フレーム数）
+pub fn gop_length() -> u64 {
+    GOP_LENGTH.load(Ordering::Relaxed)
+}
+
+/// 定期キーフレームの間隔を設定（0で定期送信なし、次のフレームから反映）
+pub fn set_gop_length(frames: u64) {
+    GOP_LENGTH.store(frames, Ordering::Relaxed);
+    println!("[H264] GOP length set to {} frames", frames);
+}
+
 /// H.264エンコーダー（OpenH264使用）
 pub struct H264Encoder {
     encoder: Mutex<Option<Encoder>>,
     width: usize,
     height: usize,
     frame_count: u64,
-    keyframe_interval: u64, // キーフレーム間隔（フレーム数）
+    bitrate_bps: u32,
+    max_fps: f32,
+}
+
+// エンコーダー設定（フレームスキップは無効: 送るかどうかはキャプチャ側で決める）
+fn encoder_config(bitrate_bps: u32, max_fps: f32) -> EncoderConfig {
+    EncoderConfig::new()
+        .max_frame_rate(max_fps)
+        .set_bitrate_bps(bitrate_bps)
+        .enable_skip_frame(false)
 }
 
 impl H264Encoder {
-    /// 新しいH.264エンコーダーを作成
-    pub fn new(width: u32, height: u32) -> Result<Self, String> {
+    /// 新しいH.264エンコーダーを作成（ビットレート・フレームレート指定）
+    pub fn with_rate(width: u32, height: u32, bitrate_bps: u32, max_fps: f32) -> Result<Self, String> {
         // 幅と高さは2の倍数に調整（YUV420の要件）
         let aligned_width = ((width as usize + 1) & !1).max(2);
         let aligned_height = ((height as usize + 1) & !1).max(2);
 
-        let config = EncoderConfig::new()
-            .max_frame_rate(30.0)
-            .set_bitrate_bps(5_000_000) // 5 Mbps（最高画質）
-            .enable_skip_frame(false); // フレームスキップを無効化
-
-        let encoder = Encoder::with_api_config(openh264::OpenH264API::from_source(), config)
+        let encoder = Encoder::with_api_config(openh264::OpenH264API::from_source(), encoder_config(bitrate_bps, max_fps))
             .map_err(|e| format!("Failed to create H.264 encoder: {:?}", e))?;
 
-        println!("[H264] Encoder created: {}x{} (aligned: {}x{})",
-            width, height, aligned_width, aligned_height);
+        println!("[H264] Encoder created: {}x{} (aligned: {}x{}), {} kbps, {} fps",
+            width, height, aligned_width, aligned_height, bitrate_bps / 1000, max_fps);
 
         Ok(Self {
             encoder: Mutex::new(Some(encoder)),
             width: aligned_width,
             height: aligned_height,
             frame_count: 0,
-            keyframe_interval: 15, // 0.5秒ごとにキーフレーム（デバッグ用）
+            bitrate_bps,
+            max_fps,
         })
     }
 
-    /// BGRAフレームをH.264にエンコード
+    /// ビットレートとフレームレートを変更
+    /// ビットレートだけなら実行中のエンコーダーに反映（GOPはそのまま）、フレームレートが変わった場合のみ作り直して次はキーフレーム
+    pub fn set_rate(&mut self, bitrate_bps: u32, max_fps: f32) -> Result<(), String> {
+        if bitrate_bps == self.bitrate_bps && max_fps == self.max_fps {
+            return Ok(());
+        }
+        println!("[H264] Rate changed: {} kbps/{} fps -> {} kbps/{} fps",
+            self.bitrate_bps / 1000, self.max_fps, bitrate_bps / 1000, max_fps);
+
+        if max_fps == self.max_fps {
+            let mut encoder_lock = self.encoder.lock().unwrap();
+            let encoder = encoder_lock.as_mut().ok_or("Encoder not initialized")?;
+            let mut info = SBitrateInfo {
+                iLayer: SPATIAL_LAYER_ALL,
+                iBitrate: bitrate_bps.min(i32::MAX as u32) as i32,
+            };
+            // SAFETY: ENCODER_OPTION_BITRATE はSBitrateInfoへのポインタを受け取り、呼び出し中のみ参照される
+            let result = unsafe {
+                encoder.raw_api().set_option(ENCODER_OPTION_BITRATE, &mut info as *mut SBitrateInfo as *mut c_void)
+            };
+            if result != 0 {
+                return Err(format!("Failed to set bitrate: {}", result));
+            }
+            self.bitrate_bps = bitrate_bps;
+            return Ok(());
+        }
+
+        let new_encoder = Encoder::with_api_config(openh264::OpenH264API::from_source(), encoder_config(bitrate_bps, max_fps))
+            .map_err(|e| format!("Failed to recreate encoder: {:?}", e))?;
+        *self.encoder.lock().unwrap() = Some(new_encoder);
+        self.bitrate_bps = bitrate_bps;
+        self.max_fps = max_fps;
+        self.frame_count = 0;
+        Ok(())
+    }
+
+    /// I420フレームをH.264にエンコード
     /// 返り値: NAL units (H.264 bitstream)
-    pub fn encode_bgra(&mut self, bgra_data: &[u8], width: u32, height: u32) -> Result<Vec<u8>, String> {
-        // サイズが変わったらエンコーダーを再作成
-        let aligned_width = ((width as usize + 1) & !1).max(2);
-        let aligned_height = ((height as usize + 1) & !1).max(2);
+    pub fn encode_i420(&mut self, yuv: &I420Buffer) -> Result<Vec<u8>, String> {
+        // YUV420のため幅と高さは2の倍数
+        if yuv.width == 0 || yuv.height == 0 || (yuv.width | yuv.height) & 1 != 0 {
+            return Err(format!("Invalid I420 frame size: {}x{}", yuv.width, yuv.height));
+        }
 
-        if aligned_width != self.width || aligned_height != self.height {
+        // サイズが変わったらエンコーダーを再作成
+        if yuv.width != self.width || yuv.height != self.height {
             println!("[H264] Resolution changed: {}x{} -> {}x{}",
-                self.width, self.height, aligned_width, aligned_height);
-
-            let config = EncoderConfig::new()
-                .max_frame_rate(30.0)
-                .set_bitrate_bps(5_000_000) // 5 Mbps（最高画質）
-                .enable_skip_frame(false); // フレームスキップを無効化
+                self.width, self.height, yuv.width, yuv.height);
 
-            let new_encoder = Encoder::with_api_config(openh264::OpenH264API::from_source(), config)
+            let new_encoder = Encoder::with_api_config(
+                openh264::OpenH264API::from_source(),
+                encoder_config(self.bitrate_bps, self.max_fps),
+            )
                 .map_err(|e| format!("Failed to recreate encoder: {:?}", e))?;
 
             let mut encoder_lock = self.encoder.lock().unwrap();
             *encoder_lock = Some(new_encoder);
             drop(encoder_lock); // ロックを解放
-            self.width = aligned_width;
-            self.height = aligned_height;
+            self.width = yuv.width;
+            self.height = yuv.height;
             self.frame_count = 0; // リセットして最初のフレームでキーフレームを強制
         }
 
-        // BGRAデータをBgraSliceU8でラップしてYUVに変換
-        // 実際の幅と高さが2の倍数でない場合はパディングが必要
-        let actual_width = width as usize;
-        let actual_height = height as usize;
-
-        // 入力データが期待サイズと一致するか確認
-        let expected_size = actual_width * actual_height * 4;
-        if bgra_data.len() != expected_size {
-            return Err(format!(
-                "BGRA data size mismatch: expected {} bytes ({}x{}x4), got {} bytes",
-                expected_size, actual_width, actual_height, bgra_data.len()
-            ));
-        }
-
-        // 2の倍数にアラインされたBGRAバッファを作成
-        let yuv_buffer = if actual_width == aligned_width && actual_height == aligned_height {
-            // サイズが既にアラインされている場合は直接使用
-            let bgra_slice = BgraSliceU8::new(bgra_data, (aligned_width, aligned_height));
-            YUVBuffer::from_rgb_source(bgra_slice)
-        } else {
-            // パディングが必要な場合
-            let mut padded_bgra = vec![0u8; aligned_width * aligned_height * 4];
-            for y in 0..actual_height {
-                let src_offset = y * actual_width * 4;
-                let dst_offset = y * aligned_width * 4;
-                let row_bytes = actual_width * 4;
-                padded_bgra[dst_offset..dst_offset + row_bytes]
-                    .copy_from_slice(&bgra_data[src_offset..src_offset + row_bytes]);
-            }
-            let bgra_slice = BgraSliceU8::new(&padded_bgra, (aligned_width, aligned_height));
-            YUVBuffer::from_rgb_source(bgra_slice)
-        };
+        // 変換済みのバッファをそのまま渡す（コピーなし）
+        let yuv_slices = YUVSlices::new(
+            (&yuv.y, &yuv.u, &yuv.v),
+            (yuv.width, yuv.height),
+            (yuv.width, yuv.width / 2, yuv.width / 2),
+        );
 
         // エンコード
         let mut encoder_lock = self.encoder.lock().unwrap();
         let encoder = encoder_lock.as_mut().ok_or("Encoder not initialized")?;
 
         // 最初のフレームまたはキーフレーム間隔でIDRフレームを強制
+        let gop = gop_length();
         let is_keyframe = self.frame_count == 0 ||
-                          self.frame_count % self.keyframe_interval == 0;
+                          (gop > 0 && self.frame_count % gop == 0);
         if is_keyframe {
             encoder.force_intra_frame();
             println!("[H264] Forcing keyframe at frame {}", self.frame_count);
         }
 
-        let bitstream = encoder.encode(&yuv_buffer)
+        let bitstream = encoder.encode(&yuv_slices)
             .map_err(|e| format!("Encode error: {:?}", e))?;
 
         // NALユニットをVecに変換
@@ -137,34 +173,39 @@ impl H264Encoder {
     }
 }
 
+/// IDRフレーム（NALタイプ5）を含むか判定
+pub fn contains_idr(data: &[u8]) -> bool {
+    parse_nal_types(data).contains(&5)
+}
+
 /// NALユニットのタイプを解析（デバッグ用）
 fn parse_nal_types(data: &[u8]) -> Vec<u8> {
-    let mut types = Vec::new();
-    let mut i = 0;
-
-    while i < data.len().saturating_sub(4) {
-        // スタートコードを探す (0x00 0x00 0x00 0x01 or 0x00 0x00 0x01)
-        if data[i] == 0x00 && data[i + 1] == 0x00 {
-            let nal_start = if data[i + 2] == 0x00 && data[i + 3] == 0x01 {
-                i + 4
-            } else if data[i + 2] == 0x01 {
-                i + 3
-            } else {
-                i += 1;
-                continue;
-            };
+    nal_units(data).iter().map(|nal| nal[0] & 0x1F).collect()
+}
 
-            if nal_start < data.len() {
-                let nal_type = data[nal_start] & 0x1F;
-                types.push(nal_type);
-            }
-            i = nal_start;
-        } else {
-            i += 1;
-        }
-    }
+/// Annex B（スタートコード区切り）のデータをNALユニットに分割（スタートコードは含まない）
+pub fn nal_units(data: &[u8]) -> Vec<&[u8]> {
+    // スタートコード (0x00 0x00 0x01) の直後の位置
+    let starts: Vec<usize> = data
+        .windows(3)
+        .enumerate()
+        .filter(|(_, w)| *w == [0, 0, 1])
+        .map(|(i, _)| i + 3)
+        .collect();
 
-    types
+    starts
+        .iter()
+        .enumerate()
+        .filter_map(|(n, &start)| {
+            let end = starts.get(n + 1).map_or(data.len(), |&next| next - 3);
+            // 4バイトのスタートコードの先頭0x00や末尾の0埋めを除く
+            let mut nal = &data[start..end.max(start)];
+            while let [rest @ .., 0] = nal {
+                nal = rest;
+            }
+            (!nal.is_empty()).then_some(nal)
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -173,16 +214,53 @@ mod tests {
 
     #[test]
     fn test_encoder_creation() {
-        let encoder = H264Encoder::new(1920, 1080);
+        let encoder = H264Encoder::with_rate(1920, 1080, 5_000_000, 30.0);
         assert!(encoder.is_ok());
     }
 
     #[test]
     fn test_encode_frame() {
-        let mut encoder = H264Encoder::new(640, 480).unwrap();
-        let bgra_data = vec![128u8; 640 * 480 * 4]; // グレー画面
-        let result = encoder.encode_bgra(&bgra_data, 640, 480);
+        let mut encoder = H264Encoder::with_rate(640, 480, 5_000_000, 30.0).unwrap();
+        let mut yuv = I420Buffer::default();
+        yuv.resize(640, 480);
+        yuv.y.fill(128); // グレー画面
+        yuv.u.fill(128);
+        yuv.v.fill(128);
+        let result = encoder.encode_i420(&yuv);
         assert!(result.is_ok());
         assert!(!result.unwrap().is_empty());
     }
+
+    #[test]
+    fn test_bitrate_change_keeps_gop() {
+        let mut encoder = H264Encoder::with_rate(640, 480, 5_000_000, 30.0).unwrap();
+        let mut yuv = I420Buffer::default();
+        yuv.resize(640, 480);
+        yuv.u.fill(128);
+        yuv.v.fill(128);
+        for i in 0..3u8 {
+            yuv.y.fill(64 + i * 16);
+            let output = encoder.encode_i420(&yuv).unwrap();
+            assert_eq!(contains_idr(&output), i == 0);
+        }
+
+        // ビットレートだけの変更ではキーフレームを送らない
+        encoder.set_rate(1_000_000, 30.0).unwrap();
+        assert_eq!(encoder.bitrate_bps, 1_000_000);
+        yuv.y.fill(160);
+        assert!(!contains_idr(&encoder.encode_i420(&yuv).unwrap()));
+
+        // フレームレートが変わったら作り直してキーフレーム
+        encoder.set_rate(1_000_000, 15.0).unwrap();
+        yuv.y.fill(176);
+        assert!(contains_idr(&encoder.encode_i420(&yuv).unwrap()));
+    }
+
+    #[test]
+    fn test_nal_units() {
+        let data = [0, 0, 0, 1, 0x67, 0x42, 0, 0, 1, 0x68, 0xce, 0, 0, 0, 1, 0x65, 0x88, 0x80];
+        let nals = nal_units(&data);
+        assert_eq!(nals, vec![&[0x67, 0x42][..], &[0x68, 0xce][..], &[0x65, 0x88, 0x80][..]]);
+        assert!(contains_idr(&data));
+    }
 }
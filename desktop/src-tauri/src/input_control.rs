@@ -30,6 +30,36 @@ pub enum InputEvent {
     KeyType { text: String },
 }
 
+impl InputEvent {
+    /// 座標を平行移動（選択モニター内の座標 -> 仮想デスクトップ座標）
+    pub fn offset(self, dx: i32, dy: i32) -> Self {
+        self.map_points(|x, y| (x + dx, y + dy))
+    }
+
+    /// 座標を変換（仮想デスクトップの論理座標 -> OSの座標など）
+    pub fn map_points(self, f: impl Fn(i32, i32) -> (i32, i32)) -> Self {
+        match self {
+            InputEvent::MouseMove { x, y } => {
+                let (x, y) = f(x, y);
+                InputEvent::MouseMove { x, y }
+            }
+            InputEvent::MouseClick { x, y, button } => {
+                let (x, y) = f(x, y);
+                InputEvent::MouseClick { x, y, button }
+            }
+            InputEvent::MouseDown { x, y, button } => {
+                let (x, y) = f(x, y);
+                InputEvent::MouseDown { x, y, button }
+            }
+            InputEvent::MouseUp { x, y, button } => {
+                let (x, y) = f(x, y);
+                InputEvent::MouseUp { x, y, button }
+            }
+            other => other,
+        }
+    }
+}
+
 pub struct InputController {
     tx: mpsc::Sender<InputEvent>,
 }
@@ -398,8 +428,3 @@ pub fn get_mouse_position() -> Option<(i32, i32)> {
     let point = event.location();
     Some((point.x as i32, point.y as i32))
 }
-
-#[cfg(not(target_os = "macos"))]
-pub fn get_mouse_position() -> Option<(i32, i32)> {
-    None
-}
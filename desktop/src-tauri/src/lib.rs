@@ -5,6 +5,27 @@ mod accessibility;
 mod webrtc_screen;
 mod pty_session;
 mod h264_encoder;
+mod metrics;
+mod status_server;
+mod cloudflared;
+mod tunnel;
+mod relay_client;
+mod secure_channel;
+mod monitors;
+mod damage;
+mod adaptive;
+mod frame_bus;
+mod capture;
+mod av1_encoder;
+mod codec;
+mod refine;
+mod roi;
+mod cursor;
+mod mp4_writer;
+mod recording;
+mod screenshot;
+mod client_display;
+mod audio;
 
 use base64::{engine::general_purpose::STANDARD, Engine};
 use futures_util::{SinkExt, StreamExt};
@@ -17,13 +38,24 @@ use std::net::SocketAddr;
 use std::sync::Arc;
 use tauri::{AppHandle, Emitter};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{broadcast, mpsc, Mutex};
-use tokio_tungstenite::{accept_async, tungstenite::Message};
-
-use screen_capture::{ScreenCapturer, request_ws_keyframe};
-use input_control::{InputController, InputEvent, get_mouse_position};
+use tokio::sync::{mpsc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::{accept_async, tungstenite::Message, WebSocketStream};
+
+use screen_capture::ScreenCapturer;
+use monitors::{MonitorCapturer, MonitorInfo, MonitorSelection, Rect, WindowInfo};
+use adaptive::{StreamBounds, StreamStats, StreamTargets};
+use input_control::{InputController, InputEvent};
 use system_control::{SystemController, RunningApp, FileEntry, BrowserTab, TerminalTab, AppWindowInfo, WindowListItem, MessagesChat};
-use webrtc_screen::{WebRTCScreenShare, EncodingMode, set_encoding_mode, get_encoding_mode};
+use webrtc_screen::{StreamEncoding, WebRTCScreenShare};
+use codec::Codec;
+use roi::RoiInfo;
+use cursor::CursorImage;
+use recording::{RecordingInfo, RecordingStatus};
+use screenshot::{Screenshot, ScreenshotTarget};
+use client_display::{ClientDisplay, OutputResolution};
+use metrics::METRICS;
+use tunnel::{TunnelCallbacks, TunnelConfig, TunnelProvider, TunnelStopped, TunnelSupervisor};
 
 // 接続情報
 #[derive(Clone, Serialize)]
@@ -90,20 +122,103 @@ enum WsMessage {
         viewport_width: i32,
         viewport_height: i32,
         quality_mode: String,  // "low" or "high"
+        #[serde(default)]
+        roi: bool,
     },
+    // ビューポート優先時、各フレームの直前に送る座標情報と周辺画像
+    #[serde(rename = "roi_frame")]
+    RoiFrame(RoiInfo),
     // スクロール（ブラウザ等用）
     #[serde(rename = "scroll")]
     Scroll { direction: String, amount: i32 },
     // エンコーディングモード切り替え
     #[serde(rename = "set_encoding_mode")]
-    SetEncodingMode { mode: String }, // "jpeg" or "h264"
+    SetEncodingMode { mode: String }, // "jpeg", "h264", "png", "webp" or "av1"
     #[serde(rename = "encoding_mode")]
     EncodingModeResponse { mode: String },
+    // 対応コーデック一覧とネゴシエーション（codecs: クライアントが使える順）
+    #[serde(rename = "list_codecs")]
+    ListCodecs,
+    #[serde(rename = "codecs")]
+    Codecs { codecs: Vec<Codec>, current: Codec },
+    #[serde(rename = "negotiate_codec")]
+    NegotiateCodec { codecs: Vec<String> },
     // マウス位置
     #[serde(rename = "mouse_position")]
     MousePosition { x: i32, y: i32 },
+    // カーソル画像（形が変わった時に送信）と、フレームへの焼き込み設定
+    #[serde(rename = "cursor_shape")]
+    CursorShape(CursorImage),
+    #[serde(rename = "set_cursor_burn_in")]
+    SetCursorBurnIn { enabled: bool },
     #[serde(rename = "input")]
     Input(InputEvent),
+    // マルチモニター
+    #[serde(rename = "list_monitors")]
+    ListMonitors,
+    #[serde(rename = "monitor_list")]
+    MonitorList { monitors: Vec<MonitorInfo> },
+    // ウィンドウ単位のキャプチャ（select_monitor に {"mode": "window", "id": ...} を指定）
+    #[serde(rename = "list_capture_windows")]
+    ListCaptureWindows,
+    #[serde(rename = "capture_window_list")]
+    CaptureWindowList { windows: Vec<WindowInfo> },
+    #[serde(rename = "select_monitor")]
+    SelectMonitor { monitor: MonitorSelection },
+    #[serde(rename = "monitor_selected")]
+    MonitorSelected { monitor: MonitorSelection, bounds: Rect, scale_factor: f32, screen_info: ScreenInfo },
+    // 適応ビットレート（クライアントの受信統計 -> 現在の送信目標）
+    #[serde(rename = "stream_stats")]
+    StreamStats(StreamStats),
+    #[serde(rename = "stream_targets")]
+    StreamTargets(StreamTargets),
+    // デコーダーのエラー回復用キーフレーム要求（transport: "websocket" または "webrtc"）
+    #[serde(rename = "request_keyframe")]
+    RequestKeyframe { #[serde(default)] transport: Option<String> },
+    // クライアントの表示環境（送信解像度の決定に使う）と、実際の送信解像度（変わった時に送信）
+    #[serde(rename = "set_client_display")]
+    SetClientDisplay(ClientDisplay),
+    #[serde(rename = "output_resolution")]
+    OutputResolution(OutputResolution),
+    // 画面録画（monitor省略時は選択中のモニター、上限省略時は既定値）
+    #[serde(rename = "start_recording")]
+    StartRecording {
+        #[serde(default)]
+        monitor: Option<MonitorSelection>,
+        #[serde(default)]
+        max_duration_secs: Option<u64>,
+        #[serde(default)]
+        max_bytes: Option<u64>,
+    },
+    #[serde(rename = "stop_recording")]
+    StopRecording,
+    #[serde(rename = "get_recording_status")]
+    GetRecordingStatus,
+    #[serde(rename = "recording_status")]
+    RecordingStatus {
+        #[serde(flatten)]
+        status: RecordingStatus,
+        error: Option<String>,
+    },
+    #[serde(rename = "list_recordings")]
+    ListRecordings,
+    #[serde(rename = "recordings")]
+    Recordings { recordings: Vec<RecordingInfo> },
+    // 録画ファイルのダウンロード（Base64のチャンクで順に送る）
+    #[serde(rename = "download_recording")]
+    DownloadRecording { name: String },
+    #[serde(rename = "recording_chunk")]
+    RecordingChunk { name: String, offset: u64, total: u64, data: String, done: bool },
+    // ネイティブ解像度のスクリーンショット（save: 保存先フォルダにも書き出す）
+    #[serde(rename = "take_screenshot")]
+    TakeScreenshot {
+        #[serde(flatten)]
+        target: ScreenshotTarget,
+        #[serde(default)]
+        save: bool,
+    },
+    #[serde(rename = "screenshot")]
+    Screenshot { screenshot: Option<Screenshot>, error: Option<String> },
     // システム制御
     #[serde(rename = "get_running_apps")]
     GetRunningApps,
@@ -186,8 +301,15 @@ enum WsMessage {
     WebRTCAnswer { sdp: String },
     #[serde(rename = "webrtc_ice_candidate")]
     WebRTCIceCandidate { candidate: String },
+    // video_track: trueならRTPのH.264ビデオトラックで送信（省略時はData Channel）
+    // audio: trueならシステム音声をOpusの音声トラックで送信
     #[serde(rename = "start_webrtc")]
-    StartWebRTC,
+    StartWebRTC { #[serde(default)] video_track: bool, #[serde(default)] audio: bool },
+    // 音声のミュート（この接続だけ、応答はaudio_muted）
+    #[serde(rename = "set_audio_muted")]
+    SetAudioMuted { muted: bool },
+    #[serde(rename = "audio_muted")]
+    AudioMuted { muted: bool },
     #[serde(rename = "stop_webrtc")]
     StopWebRTC,
     // PTY（永続ターミナルセッション）
@@ -213,6 +335,7 @@ enum WsMessage {
 pub struct TunnelInfo {
     pub url: String,
     pub qr_code: String,
+    pub provider: String,
 }
 
 // 接続リクエスト（承認待ち）
@@ -231,22 +354,23 @@ pub struct AppState {
     auth_token: String,
     screen_width: RwLock<u32>,
     screen_height: RwLock<u32>,
-    frame_tx: broadcast::Sender<Vec<u8>>,
     input_controller: InputController,
     // キャプチャ領域（None = 全画面）- Arc<RwLock>でスレッド間共有
     capture_region: Arc<RwLock<Option<CaptureRegion>>>,
-    // WSキャプチャ停止フラグ
-    ws_capture_running: Arc<std::sync::atomic::AtomicBool>,
     // トンネル状態
     tunnel_info: RwLock<Option<TunnelInfo>>,
-    tunnel_process: RwLock<Option<u32>>, // プロセスID
+    tunnel: RwLock<Option<TunnelSupervisor>>,
+    // LAN内の平文（E2E暗号化なし）のセッションも拒否するか
+    require_encryption: std::sync::atomic::AtomicBool,
+    // リレー接続タスク
+    relay_task: RwLock<Option<tokio::task::JoinHandle<()>>>,
     // 接続承認用チャンネル
     pending_connections: RwLock<std::collections::HashMap<String, tokio::sync::oneshot::Sender<bool>>>,
     // ポーリング用: 保留中の接続リクエスト
     pending_requests: RwLock<Vec<ConnectionRequest>>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct CaptureRegion {
     pub x: i32,
     pub y: i32,
@@ -259,12 +383,12 @@ pub struct CaptureRegion {
     pub viewport_height: i32,
     // 画質モード: "low"（スクロール中）, "high"（停止時）
     pub quality_mode: String,
+    // ビューポート優先: ビューポートだけを高解像度で、領域全体は低解像度の周辺画像で送る
+    pub roi: bool,
 }
 
 impl AppState {
     pub fn new() -> Self {
-        let (frame_tx, _) = broadcast::channel(2);
-
         Self {
             connection_info: RwLock::new(None),
             connected_device: RwLock::new(None),
@@ -285,12 +409,12 @@ impl AppState {
             auth_token: uuid::Uuid::new_v4().to_string(),
             screen_width: RwLock::new(0),
             screen_height: RwLock::new(0),
-            frame_tx,
             input_controller: InputController::new(),
             capture_region: Arc::new(RwLock::new(None)),
-            ws_capture_running: Arc::new(std::sync::atomic::AtomicBool::new(true)),
             tunnel_info: RwLock::new(None),
-            tunnel_process: RwLock::new(None),
+            tunnel: RwLock::new(None),
+            relay_task: RwLock::new(None),
+            require_encryption: std::sync::atomic::AtomicBool::new(false),
             pending_connections: RwLock::new(std::collections::HashMap::new()),
             pending_requests: RwLock::new(Vec::new()),
         }
@@ -310,6 +434,39 @@ fn generate_qr_code(data: &str) -> Result<String, String> {
     Ok(STANDARD.encode(buffer.into_inner()))
 }
 
+// 録画ファイルをBase64のチャンクに分けて送信（最後のチャンクは done: true）
+async fn send_recording<W>(write: &Mutex<W>, name: &str) -> Result<(), String>
+where
+    W: futures_util::Sink<Message> + Unpin,
+    W::Error: std::fmt::Display,
+{
+    use tokio::io::AsyncReadExt;
+    const CHUNK_SIZE: usize = 256 * 1024;
+
+    let path = recording::path_of(name)?;
+    let mut file = tokio::fs::File::open(&path).await.map_err(|e| format!("Failed to open {}: {}", name, e))?;
+    let total = file.metadata().await.map_err(|e| format!("Failed to stat {}: {}", name, e))?.len();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut offset = 0u64;
+    loop {
+        let n = file.read(&mut buf).await.map_err(|e| format!("Failed to read {}: {}", name, e))?;
+        let done = n == 0 || offset + n as u64 >= total;
+        let chunk = WsMessage::RecordingChunk {
+            name: name.to_string(),
+            offset,
+            total,
+            data: STANDARD.encode(&buf[..n]),
+            done,
+        };
+        let json = serde_json::to_string(&chunk).map_err(|e| e.to_string())?;
+        write.lock().await.send(Message::Text(json)).await.map_err(|e| e.to_string())?;
+        offset += n as u64;
+        if done {
+            return Ok(());
+        }
+    }
+}
+
 // WebSocket接続処理
 async fn handle_connection(
     stream: TcpStream,
@@ -326,16 +483,55 @@ async fn handle_connection(
     };
 
     println!("New connection from: {}", addr);
-    let (write, mut read) = ws_stream.split();
+    serve_connection(ws_stream, addr.ip().to_string(), state, app_handle).await;
+}
+
+// WebSocketセッション本体（直接接続・リレー経由の共通処理）
+async fn serve_connection<S>(
+    ws_stream: WebSocketStream<S>,
+    peer: String,
+    state: Arc<AppState>,
+    app_handle: AppHandle,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // 最初のメッセージがNoiseハンドシェイクなら以降の送受信を暗号化
+    let (write, read) = ws_stream.split();
+    let (write, mut read, encrypted) = match secure_channel::accept(write, read).await {
+        Ok(channel) => channel,
+        Err(e) => {
+            eprintln!("[E2E] Handshake with {} failed: {}", peer, e);
+            return;
+        }
+    };
+    println!("Session with {}: encrypted={}", peer, encrypted);
+
+    METRICS.ws_connections.inc();
+    if encrypted {
+        METRICS.encrypted_sessions.inc();
+    }
     let write = Arc::new(Mutex::new(write));
     let mut authenticated = false;
     let mut screen_sharing = false;
-    let mut frame_rx: Option<broadcast::Receiver<Vec<u8>>> = None;
+    let mut frame_rx: Option<screen_capture::ScreenStream> = None;
+    let mut last_output: Option<OutputResolution> = None;
+    // この接続のクライアントが申告した表示環境（申告がなければ旧クライアントの規則）
+    let mut client_display: Option<ClientDisplay> = None;
+    // この接続の映像にカーソルを焼き込むか
+    let mut cursor_burn_in = false;
     let mut mouse_interval = tokio::time::interval(std::time::Duration::from_millis(50));
     let mut last_mouse_pos: (i32, i32) = (-1, -1); // 最後に送信したマウス位置
-
-    // WebRTC状態
+    let mut last_cursor_id: Option<u64> = None; // 最後に送信したカーソルの形
+    // 表示中のモニター（入力座標は選択範囲の原点を基準に変換）
+    let mut monitor_selection = MonitorSelection::default();
+    let mut monitor_bounds = MonitorCapturer::new(&monitor_selection)
+        .map(|m| m.bounds())
+        .unwrap_or_default();
+
+    // WebRTC状態（エンコード設定はこの接続だけのもの）
     let mut webrtc_session: Option<Arc<WebRTCScreenShare>> = None;
+    let encoding = Arc::new(StreamEncoding::default());
+    let audio_muted = Arc::new(std::sync::atomic::AtomicBool::new(false));
     let (ice_tx, mut ice_rx) = mpsc::channel::<String>(100);
 
     // PTY（永続ターミナル）セッション
@@ -345,19 +541,39 @@ async fn handle_connection(
     loop {
         tokio::select! {
             // フレーム送信
+            // 遅れた場合はフレームバス側でキーフレームまで読み飛ばす
             frame = async {
                 if let Some(ref mut rx) = frame_rx {
-                    rx.recv().await.ok()
+                    rx.recv().await
                 } else {
-                    std::future::pending::<Option<Vec<u8>>>().await
+                    std::future::pending::<frame_bus::Frame>().await
                 }
             }, if screen_sharing => {
-                if let Some(frame_data) = frame {
-                    // バイナリフレームとして送信
-                    if write.lock().await.send(Message::Binary(frame_data.into())).await.is_err() {
-                        break;
+                // 送信解像度が変わったら先に知らせる
+                if last_output != Some(frame.output) {
+                    last_output = Some(frame.output);
+                    if let Ok(json) = serde_json::to_string(&WsMessage::OutputResolution(frame.output)) {
+                        if write.lock().await.send(Message::Text(json)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+                // ビューポート優先ならフレームの範囲を先に知らせる
+                if let Some(ref roi) = frame.roi {
+                    if let Ok(json) = serde_json::to_string(&WsMessage::RoiFrame(roi.as_ref().clone())) {
+                        if write.lock().await.send(Message::Text(json)).await.is_err() {
+                            break;
+                        }
                     }
                 }
+                // バイナリフレームとして送信
+                // Message::BinaryはVec<u8>を要求するので、ここで受信者ごとに1回コピーする
+                let frame_len = frame.data.len() as u64;
+                if write.lock().await.send(Message::Binary(frame.data.to_vec())).await.is_err() {
+                    break;
+                }
+                METRICS.ws_frames_sent.inc();
+                METRICS.ws_bytes_sent.add(frame_len);
             }
 
             // WebRTC ICE候補送信
@@ -386,8 +602,22 @@ async fn handle_connection(
             }
 
             // マウス位置を定期送信（変化時のみ）
-            _ = mouse_interval.tick(), if screen_sharing && authenticated => {
-                if let Some((x, y)) = get_mouse_position() {
+            _ = mouse_interval.tick(), if (screen_sharing || webrtc_session.is_some()) && authenticated => {
+                if let Some(cursor) = cursor::current() {
+                    // 形が変わった時だけ画像を送る
+                    if let Some(shape) = cursor.shape.as_ref().filter(|s| last_cursor_id != Some(s.id)) {
+                        last_cursor_id = Some(shape.id);
+                        if let Ok(json) = serde_json::to_string(&WsMessage::CursorShape(shape.image())) {
+                            write.lock().await.send(Message::Text(json)).await.ok();
+                        }
+                    }
+                    let (x, y) = (cursor.x, cursor.y);
+                    // 仮想デスクトップ座標 -> 選択モニター（ウィンドウ）内の座標
+                    let (x, y) = if state.capture_region.read().is_none() {
+                        capture::current_bounds(&monitor_selection, monitor_bounds).to_local(x, y)
+                    } else {
+                        (x, y)
+                    };
                     if (x, y) != last_mouse_pos {
                         last_mouse_pos = (x, y);
                         let response = WsMessage::MousePosition { x, y };
@@ -418,7 +648,19 @@ async fn handle_connection(
                                 let token_valid = token == state.auth_token;
                                 println!("Auth request: device={}, is_external={}, token_valid={}", device_name, is_external, token_valid);
 
-                                if !token_valid {
+                                // トンネル・リレー経由は常に暗号化必須（LAN内は設定で必須にできる）
+                                let plaintext_rejected = !encrypted
+                                    && secure_channel::plaintext_rejected(
+                                        &peer,
+                                        state.tunnel.read().is_some(),
+                                        is_external,
+                                        state.require_encryption.load(std::sync::atomic::Ordering::SeqCst),
+                                    );
+                                if plaintext_rejected {
+                                    println!("Rejecting unencrypted session from {}", peer);
+                                }
+
+                                if !token_valid || plaintext_rejected {
                                     // トークンが無効な場合は即座に拒否
                                     let response = WsMessage::AuthResponse { success: false, screen_info: None };
                                     let json = serde_json::to_string(&response).unwrap();
@@ -426,6 +668,9 @@ async fn handle_connection(
                                 } else if is_external {
                                     // 外部接続（トンネル経由）の場合は承認不要
                                     println!("External connection - auto approving");
+                                    if !authenticated {
+                                        METRICS.authenticated_sessions.inc();
+                                    }
                                     authenticated = true;
                                     *state.connected_device.write() = Some(device_name.clone());
                                     app_handle.emit("device_connected", &device_name).ok();
@@ -457,7 +702,7 @@ async fn handle_connection(
                                     let connection_request = ConnectionRequest {
                                         request_id: request_id.clone(),
                                         device_name: device_name.clone(),
-                                        ip_address: addr.ip().to_string(),
+                                        ip_address: peer.clone(),
                                     };
                                     state.pending_requests.write().push(connection_request.clone());
                                     println!("Added to pending_requests: {:?}", connection_request);
@@ -478,6 +723,9 @@ async fn handle_connection(
                                     state.pending_requests.write().retain(|r| r.request_id != request_id);
 
                                     if approved {
+                                        if !authenticated {
+                                            METRICS.authenticated_sessions.inc();
+                                        }
                                         authenticated = true;
                                         *state.connected_device.write() = Some(device_name.clone());
                                         app_handle.emit("device_connected", &device_name).ok();
@@ -552,15 +800,28 @@ async fn handle_connection(
                             Ok(WsMessage::StartScreenShare) if authenticated => {
                                 println!("Starting screen share...");
                                 // 新しいクライアント用にキーフレームを強制リクエスト
-                                request_ws_keyframe();
-                                frame_rx = Some(state.frame_tx.subscribe());
+                                frame_rx = Some(screen_capture::ScreenStream::start(
+                                    &monitor_selection,
+                                    &peer,
+                                    state.capture_region.clone(),
+                                    client_display,
+                                    cursor_burn_in,
+                                ));
+                                if !screen_sharing {
+                                    METRICS.screen_share_sessions.inc();
+                                }
                                 screen_sharing = true;
                                 println!("Screen sharing started");
                             }
                             Ok(WsMessage::StopScreenShare) if authenticated => {
                                 println!("Stopping screen share...");
+                                if screen_sharing {
+                                    METRICS.screen_share_sessions.dec();
+                                }
                                 screen_sharing = false;
-                                frame_rx = None;
+                                if let Some(rx) = frame_rx.take() {
+                                    println!("Screen sharing stopped ({} frames dropped)", rx.dropped_frames());
+                                }
                             }
                             Ok(WsMessage::SetCaptureRegion { x, y, width, height }) if authenticated => {
                                 println!("SetCaptureRegion: {}x{} at ({}, {})", width, height, x, y);
@@ -572,9 +833,10 @@ async fn handle_connection(
                                     viewport_width: width,
                                     viewport_height: height,
                                     quality_mode: "high".to_string(),
+                                    roi: false,
                                 });
                             }
-                            Ok(WsMessage::SetViewport { viewport_x, viewport_y, viewport_width, viewport_height, quality_mode }) if authenticated => {
+                            Ok(WsMessage::SetViewport { viewport_x, viewport_y, viewport_width, viewport_height, quality_mode, roi }) if authenticated => {
                                 // 既存のCaptureRegionのビューポートを更新
                                 let mut region = state.capture_region.write();
                                 if let Some(ref mut r) = *region {
@@ -583,11 +845,100 @@ async fn handle_connection(
                                     r.viewport_width = viewport_width;
                                     r.viewport_height = viewport_height;
                                     r.quality_mode = quality_mode.clone();
+                                    r.roi = roi;
                                     if quality_mode == "high" {
                                         println!("SetViewport: {}x{} at ({}, {}) [HIGH QUALITY]", viewport_width, viewport_height, viewport_x, viewport_y);
                                     }
                                 }
                             }
+                            Ok(WsMessage::SetCursorBurnIn { enabled }) if authenticated => {
+                                // この接続の映像だけに合成する（他の接続・録画には影響しない）
+                                cursor_burn_in = enabled;
+                                encoding.set_cursor_burn_in(enabled);
+                                if let Some(ref mut rx) = frame_rx {
+                                    rx.set_cursor_burn_in(enabled);
+                                }
+                            }
+                            Ok(WsMessage::SetClientDisplay(display)) if authenticated => {
+                                match display.validate() {
+                                    Ok(()) => {
+                                        println!("SetClientDisplay: {:?}", display);
+                                        // この接続の送信解像度だけを変える
+                                        client_display = Some(display);
+                                        encoding.set_display(client_display);
+                                        if let Some(ref mut rx) = frame_rx {
+                                            rx.set_display(client_display);
+                                        }
+                                        // 次のフレームで送信解像度を通知し直す
+                                        last_output = None;
+                                    }
+                                    Err(e) => eprintln!("[Display] {}", e),
+                                }
+                            }
+                            Ok(WsMessage::StartRecording { monitor, max_duration_secs, max_bytes }) if authenticated => {
+                                let selection = monitor.unwrap_or_else(|| monitor_selection.clone());
+                                let response = match recording::start(&selection, max_duration_secs, max_bytes) {
+                                    Ok(status) => WsMessage::RecordingStatus { status, error: None },
+                                    Err(e) => WsMessage::RecordingStatus { status: recording::status(), error: Some(e) },
+                                };
+                                if let Ok(json) = serde_json::to_string(&response) {
+                                    write.lock().await.send(Message::Text(json)).await.ok();
+                                }
+                            }
+                            Ok(WsMessage::StopRecording) if authenticated => {
+                                // ファイルの書き出しを待つためブロッキングで実行
+                                let write_clone = write.clone();
+                                tokio::spawn(async move {
+                                    let result = tokio::task::spawn_blocking(recording::stop)
+                                        .await
+                                        .map_err(|e| e.to_string())
+                                        .and_then(|r| r);
+                                    let response = match result {
+                                        Ok(status) => WsMessage::RecordingStatus { status, error: None },
+                                        Err(e) => WsMessage::RecordingStatus { status: recording::status(), error: Some(e) },
+                                    };
+                                    if let Ok(json) = serde_json::to_string(&response) {
+                                        write_clone.lock().await.send(Message::Text(json)).await.ok();
+                                    }
+                                });
+                            }
+                            Ok(WsMessage::GetRecordingStatus) if authenticated => {
+                                let response = WsMessage::RecordingStatus { status: recording::status(), error: None };
+                                if let Ok(json) = serde_json::to_string(&response) {
+                                    write.lock().await.send(Message::Text(json)).await.ok();
+                                }
+                            }
+                            Ok(WsMessage::ListRecordings) if authenticated => {
+                                let recordings = tokio::task::spawn_blocking(recording::list).await.unwrap_or_default();
+                                if let Ok(json) = serde_json::to_string(&WsMessage::Recordings { recordings }) {
+                                    write.lock().await.send(Message::Text(json)).await.ok();
+                                }
+                            }
+                            Ok(WsMessage::TakeScreenshot { target, save }) if authenticated => {
+                                let region = state.capture_region.read().clone();
+                                let write_clone = write.clone();
+                                tokio::spawn(async move {
+                                    let result = tokio::task::spawn_blocking(move || screenshot::take(&target, region.as_ref(), save))
+                                        .await
+                                        .map_err(|e| e.to_string())
+                                        .and_then(|r| r);
+                                    let response = match result {
+                                        Ok(screenshot) => WsMessage::Screenshot { screenshot: Some(screenshot), error: None },
+                                        Err(e) => WsMessage::Screenshot { screenshot: None, error: Some(e) },
+                                    };
+                                    if let Ok(json) = serde_json::to_string(&response) {
+                                        write_clone.lock().await.send(Message::Text(json)).await.ok();
+                                    }
+                                });
+                            }
+                            Ok(WsMessage::DownloadRecording { name }) if authenticated => {
+                                let write_clone = write.clone();
+                                tokio::spawn(async move {
+                                    if let Err(e) = send_recording(&write_clone, &name).await {
+                                        eprintln!("[Recording] Download {} failed: {}", name, e);
+                                    }
+                                });
+                            }
                             Ok(WsMessage::ResetCaptureRegion) if authenticated => {
                                 println!("ResetCaptureRegion");
                                 *state.capture_region.write() = None;
@@ -598,25 +949,130 @@ async fn handle_connection(
                             }
                             Ok(WsMessage::SetEncodingMode { mode }) if authenticated => {
                                 println!("[SetEncodingMode] Requested: {}", mode);
-                                let encoding_mode = match mode.to_lowercase().as_str() {
-                                    "h264" | "h.264" => EncodingMode::H264,
-                                    _ => EncodingMode::Jpeg,
-                                };
-                                set_encoding_mode(encoding_mode);
+                                // 不明な名前はJPEG（従来どおり）
+                                encoding.set_mode(Codec::parse(&mode).unwrap_or(Codec::Jpeg));
                                 // 現在のモードを返す
-                                let current_mode = match get_encoding_mode() {
-                                    EncodingMode::H264 => "h264",
-                                    EncodingMode::Jpeg => "jpeg",
-                                };
-                                let response = WsMessage::EncodingModeResponse { mode: current_mode.to_string() };
+                                let response = WsMessage::EncodingModeResponse { mode: encoding.mode().name().to_string() };
                                 if let Ok(json) = serde_json::to_string(&response) {
                                     write.lock().await.send(Message::Text(json.into())).await.ok();
                                 }
                             }
+                            Ok(WsMessage::ListCodecs) if authenticated => {
+                                let response = WsMessage::Codecs { codecs: Codec::ALL.to_vec(), current: encoding.mode() };
+                                if let Ok(json) = serde_json::to_string(&response) {
+                                    write.lock().await.send(Message::Text(json)).await.ok();
+                                }
+                            }
+                            Ok(WsMessage::NegotiateCodec { codecs }) if authenticated => {
+                                // クライアントの希望順で最初に対応しているもの（なければ現在のまま）
+                                match Codec::negotiate(&codecs) {
+                                    Some(codec) => encoding.set_mode(codec),
+                                    None => println!("[Codec] No supported codec in {:?}, keeping {}", codecs, encoding.mode().name()),
+                                }
+                                let response = WsMessage::EncodingModeResponse { mode: encoding.mode().name().to_string() };
+                                if let Ok(json) = serde_json::to_string(&response) {
+                                    write.lock().await.send(Message::Text(json)).await.ok();
+                                }
+                            }
                             Ok(WsMessage::Input(event)) if authenticated => {
                                 // スクロールはユーザーがタッチした位置で実行
                                 // （マウスは既にその位置に移動済み）
-                                state.input_controller.send_event(event);
+                                // 領域指定中はクライアントが仮想デスクトップ座標を送るので変換しない
+                                let event = if state.capture_region.read().is_none() {
+                                    let bounds = capture::current_bounds(&monitor_selection, monitor_bounds);
+                                    event.offset(bounds.x, bounds.y)
+                                } else {
+                                    event
+                                };
+                                // OSの座標へ（WindowsとX11は物理ピクセルなので、1.25倍などの倍率を掛ける）
+                                state.input_controller.send_event(event.map_points(monitors::to_os));
+                            }
+                            Ok(WsMessage::ListMonitors) if authenticated => {
+                                let monitors = tokio::task::spawn_blocking(monitors::list_monitors)
+                                    .await
+                                    .map_err(|e| e.to_string())
+                                    .and_then(|r| r);
+                                match monitors {
+                                    Ok(monitors) => {
+                                        let response = WsMessage::MonitorList { monitors };
+                                        if let Ok(json) = serde_json::to_string(&response) {
+                                            write.lock().await.send(Message::Text(json)).await.ok();
+                                        }
+                                    }
+                                    Err(e) => eprintln!("[Monitors] {}", e),
+                                }
+                            }
+                            Ok(WsMessage::ListCaptureWindows) if authenticated => {
+                                let windows = tokio::task::spawn_blocking(monitors::list_windows)
+                                    .await
+                                    .map_err(|e| e.to_string())
+                                    .and_then(|r| r);
+                                match windows {
+                                    Ok(windows) => {
+                                        let response = WsMessage::CaptureWindowList { windows };
+                                        if let Ok(json) = serde_json::to_string(&response) {
+                                            write.lock().await.send(Message::Text(json)).await.ok();
+                                        }
+                                    }
+                                    Err(e) => eprintln!("[Monitors] {}", e),
+                                }
+                            }
+                            Ok(WsMessage::SelectMonitor { monitor }) if authenticated => {
+                                let selection = monitor.clone();
+                                let capturer = tokio::task::spawn_blocking(move || MonitorCapturer::new(&selection))
+                                    .await
+                                    .map_err(|e| e.to_string())
+                                    .and_then(|r| r);
+                                match capturer {
+                                    Ok(capturer) => {
+                                        println!("SelectMonitor: {:?} {:?}", monitor, capturer.bounds());
+                                        monitor_selection = monitor.clone();
+                                        monitor_bounds = capturer.bounds();
+                                        // 共有中なら新しいモニターのパイプラインに切り替え
+                                        if screen_sharing {
+                                            frame_rx = Some(screen_capture::ScreenStream::start(
+                                                &monitor_selection,
+                                                &peer,
+                                                state.capture_region.clone(),
+                                                client_display,
+                                                cursor_burn_in,
+                                            ));
+                                        }
+                                        let response = WsMessage::MonitorSelected {
+                                            monitor,
+                                            bounds: monitor_bounds,
+                                            scale_factor: capturer.scale_factor(),
+                                            screen_info: ScreenInfo {
+                                                width: monitor_bounds.width,
+                                                height: monitor_bounds.height,
+                                            },
+                                        };
+                                        if let Ok(json) = serde_json::to_string(&response) {
+                                            write.lock().await.send(Message::Text(json)).await.ok();
+                                        }
+                                    }
+                                    Err(e) => eprintln!("[Monitors] SelectMonitor failed: {}", e),
+                                }
+                            }
+                            Ok(WsMessage::RequestKeyframe { transport }) if authenticated => {
+                                if transport.as_deref() == Some("webrtc") {
+                                    encoding.request_keyframe();
+                                } else if let Some(ref rx) = frame_rx {
+                                    rx.request_keyframe();
+                                }
+                            }
+                            Ok(WsMessage::StreamStats(stats)) if authenticated => {
+                                let targets = if stats.transport == "webrtc" {
+                                    Some(encoding.report_stats(&stats))
+                                } else {
+                                    frame_rx.as_mut().map(|rx| rx.report_stats(&stats))
+                                };
+                                if let Some(targets) = targets {
+                                    let response = WsMessage::StreamTargets(targets);
+                                    if let Ok(json) = serde_json::to_string(&response) {
+                                        write.lock().await.send(Message::Text(json)).await.ok();
+                                    }
+                                }
                             }
                             Ok(WsMessage::GetRunningApps) if authenticated => {
                                 println!("GetRunningApps requested");
@@ -707,6 +1163,7 @@ async fn handle_connection(
                                 println!("[ActivateTab] Start: {} tab {}", app_name, tab_index);
                                 let start = std::time::Instant::now();
                                 let name = app_name.clone();
+                                let selection = monitor_selection.clone();
                                 let write_clone = write.clone();
                                 tokio::spawn(async move {
                                     let result = tokio::task::spawn_blocking(move || {
@@ -728,7 +1185,7 @@ async fn handle_connection(
 
                                     // タブ切り替え後にキーフレームを強制送信して即座に画面を更新
                                     if result {
-                                        request_ws_keyframe();
+                                        screen_capture::request_keyframe(&selection);
                                     }
 
                                     let response = WsMessage::ActivateTabResult { success: result };
@@ -865,6 +1322,7 @@ async fn handle_connection(
                             }
                             Ok(WsMessage::FocusAppWindow { app_name, window_index }) if authenticated => {
                                 println!("FocusAppWindow: {} - window {}", app_name, window_index);
+                                let selection = monitor_selection.clone();
                                 tokio::spawn(async move {
                                     let success = tokio::task::spawn_blocking(move || {
                                         SystemController::focus_app_window(&app_name, window_index)
@@ -872,7 +1330,7 @@ async fn handle_connection(
                                     println!("FocusAppWindow result: {}", success);
                                     // ウィンドウ切り替え後にキーフレームを強制送信
                                     if success {
-                                        request_ws_keyframe();
+                                        screen_capture::request_keyframe(&selection);
                                     }
                                 });
                             }
@@ -931,6 +1389,7 @@ async fn handle_connection(
                                                 viewport_width: window_info.width,
                                                 viewport_height: window_info.height,
                                                 quality_mode: "high".to_string(),
+                                                roi: false,
                                             });
                                         } else {
                                             println!("Window too small ({}x{}), using full screen capture",
@@ -963,13 +1422,8 @@ async fn handle_connection(
                                 });
                             }
                             // WebRTC開始
-                            Ok(WsMessage::StartWebRTC) if authenticated => {
-                                println!("[WebRTC] Starting WebRTC session...");
-                                // WSキャプチャを停止
-                                state.ws_capture_running.store(false, std::sync::atomic::Ordering::SeqCst);
-                                // キャプチャが完全に停止するまで待機
-                                tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
-
+                            Ok(WsMessage::StartWebRTC { video_track, audio }) if authenticated => {
+                                println!("[WebRTC] Starting WebRTC session (video track: {}, audio: {})...", video_track, audio);
                                 // 新規接続時はキャプチャ領域をリセット（全画面キャプチャから開始）
                                 *state.capture_region.write() = None;
                                 println!("[WebRTC] Capture region reset to full screen");
@@ -977,7 +1431,7 @@ async fn handle_connection(
                                 let ice_tx_clone = ice_tx.clone();
                                 let write_clone = write.clone();
 
-                                match WebRTCScreenShare::new(ice_tx_clone, state.capture_region.clone()).await {
+                                match WebRTCScreenShare::new(ice_tx_clone, state.capture_region.clone(), monitor_selection.clone(), Arc::clone(&encoding), video_track, audio.then(|| Arc::clone(&audio_muted))).await {
                                     Ok(session) => {
                                         let session = Arc::new(session);
                                         webrtc_session = Some(Arc::clone(&session));
@@ -1025,6 +1479,13 @@ async fn handle_connection(
                                     }
                                 }
                             }
+                            Ok(WsMessage::SetAudioMuted { muted }) if authenticated => {
+                                audio_muted.store(muted, std::sync::atomic::Ordering::SeqCst);
+                                println!("[Audio] Muted: {}", muted);
+                                if let Ok(json) = serde_json::to_string(&WsMessage::AudioMuted { muted }) {
+                                    write.lock().await.send(Message::Text(json)).await.ok();
+                                }
+                            }
                             // WebRTC停止
                             Ok(WsMessage::StopWebRTC) if authenticated => {
                                 println!("[WebRTC] Stopping WebRTC session...");
@@ -1033,8 +1494,6 @@ async fn handle_connection(
                                         eprintln!("[WebRTC] Failed to close session: {}", e);
                                     }
                                 }
-                                // WSキャプチャを再開
-                                state.ws_capture_running.store(true, std::sync::atomic::Ordering::SeqCst);
                             }
                             // PTY（永続ターミナル）セッション開始
                             Ok(WsMessage::PtyStart) if authenticated => {
@@ -1104,7 +1563,17 @@ async fn handle_connection(
         }
     }
 
-    println!("Connection closed: {}", addr);
+    println!("Connection closed: {}", peer);
+    METRICS.ws_connections.dec();
+    if encrypted {
+        METRICS.encrypted_sessions.dec();
+    }
+    if authenticated {
+        METRICS.authenticated_sessions.dec();
+    }
+    if screen_sharing {
+        METRICS.screen_share_sessions.dec();
+    }
     *state.connected_device.write() = None;
     app_handle.emit("device_disconnected", ()).ok();
 }
@@ -1128,7 +1597,7 @@ fn start_screen_capture(state: &Arc<AppState>) -> Result<(), String> {
         }
     }
 
-    let capturer = ScreenCapturer::new()?;
+    let capturer = ScreenCapturer::new(&MonitorSelection::Primary)?;
     let (width, height) = capturer.get_dimensions();
     let (logical_width, logical_height) = capturer.get_logical_dimensions();
 
@@ -1136,12 +1605,10 @@ fn start_screen_capture(state: &Arc<AppState>) -> Result<(), String> {
     *state.screen_width.write() = logical_width as u32;
     *state.screen_height.write() = logical_height as u32;
 
+    // キャプチャスレッドは画面共有の開始時にモニター選択ごとに起動する
     println!("[scrap] Screen capture initialized: {}x{} (logical: {}x{})",
              width, height, logical_width, logical_height);
 
-    // キャプチャスレッドを開始（領域指定対応）
-    ScreenCapturer::start_capture(width, height, state.frame_tx.clone(), state.capture_region.clone(), state.ws_capture_running.clone());
-
     Ok(())
 }
 
@@ -1157,8 +1624,8 @@ async fn start_server(state: Arc<AppState>, app_handle: AppHandle) -> Result<(),
         .await
         .map_err(|e| e.to_string())?;
 
-    // 接続情報を生成
-    let connection_data = format!("{}:{}:{}", ip, port, state.auth_token);
+    // 接続情報を生成（末尾はE2E暗号化用の公開鍵）
+    let connection_data = secure_channel::with_public_key(&format!("{}:{}:{}", ip, port, state.auth_token));
     let qr_base64 = generate_qr_code(&connection_data)?;
 
     let info = ConnectionInfo {
@@ -1173,6 +1640,13 @@ async fn start_server(state: Arc<AppState>, app_handle: AppHandle) -> Result<(),
     println!("Auth token: {}", state.auth_token);
     println!("Connection string: {}", connection_data);
 
+    // 環境変数でリレーが指定されていればリレークライアントモードも起動
+    if let Ok(relay_url) = std::env::var("POCKET_REMOTE_RELAY_URL") {
+        if let Err(e) = start_relay_client(&state, &app_handle, &relay_url) {
+            eprintln!("[Relay] {}", e);
+        }
+    }
+
     loop {
         let (stream, addr) = listener.accept().await.map_err(|e| e.to_string())?;
         let state_clone = state.clone();
@@ -1240,39 +1714,17 @@ fn respond_to_connection(state: tauri::State<Arc<AppState>>, request_id: String,
     }
 }
 
-// cloudflaredのローカルパスを取得
-fn get_cloudflared_local_path() -> std::path::PathBuf {
-    let data_dir = dirs::data_local_dir()
+// アプリのデータディレクトリ
+fn app_data_dir() -> std::path::PathBuf {
+    dirs::data_local_dir()
         .unwrap_or_else(|| std::path::PathBuf::from("."))
-        .join("PocketRemote");
-    data_dir.join("cloudflared")
-}
-
-// cloudflaredのパスを取得（システムまたはローカル）
-fn get_cloudflared_path() -> Option<std::path::PathBuf> {
-    // まずシステムにインストールされているか確認
-    if std::process::Command::new("which")
-        .arg("cloudflared")
-        .output()
-        .map(|o| o.status.success())
-        .unwrap_or(false)
-    {
-        return Some(std::path::PathBuf::from("cloudflared"));
-    }
-
-    // ローカルにインストールされているか確認
-    let local_path = get_cloudflared_local_path();
-    if local_path.exists() {
-        return Some(local_path);
-    }
-
-    None
+        .join("PocketRemote")
 }
 
 // Tauriコマンド: cloudflaredがインストールされているかチェック
 #[tauri::command]
 fn check_cloudflared() -> bool {
-    get_cloudflared_path().is_some()
+    cloudflared::path().is_some()
 }
 
 // cloudflaredのインストール状態を詳細に返す
@@ -1282,229 +1734,357 @@ pub struct CloudflaredStatus {
     is_system: bool,
     is_local: bool,
     path: Option<String>,
+    version: Option<String>,
+    pinned_version: String,
 }
 
 #[tauri::command]
-fn get_cloudflared_status() -> CloudflaredStatus {
-    let system_installed = std::process::Command::new("which")
-        .arg("cloudflared")
-        .output()
-        .map(|o| o.status.success())
-        .unwrap_or(false);
-
-    let local_path = get_cloudflared_local_path();
+async fn get_cloudflared_status() -> CloudflaredStatus {
+    let system_installed = cloudflared::system_installed();
+
+    let local_path = cloudflared::local_path();
     let local_installed = local_path.exists();
 
+    let path = if system_installed {
+        Some(std::path::PathBuf::from("cloudflared"))
+    } else if local_installed {
+        Some(local_path)
+    } else {
+        None
+    };
+    let version = match path {
+        Some(ref p) => cloudflared::installed_version(p).await,
+        None => None,
+    };
+
     CloudflaredStatus {
         installed: system_installed || local_installed,
         is_system: system_installed,
         is_local: local_installed,
-        path: if system_installed {
-            Some("cloudflared".to_string())
-        } else if local_installed {
-            Some(local_path.to_string_lossy().to_string())
-        } else {
-            None
-        },
+        path: path.map(|p| p.to_string_lossy().to_string()),
+        version,
+        pinned_version: cloudflared::PINNED_VERSION.to_string(),
     }
 }
 
-// Tauriコマンド: cloudflaredをダウンロード・インストール
+// Tauriコマンド: cloudflaredをダウンロード・インストール（versionを省略すると固定バージョン）
 #[tauri::command]
-async fn install_cloudflared(app_handle: tauri::AppHandle) -> Result<(), String> {
+async fn install_cloudflared(app_handle: tauri::AppHandle, version: Option<String>) -> Result<String, String> {
+    let version = version.unwrap_or_else(|| cloudflared::PINNED_VERSION.to_string());
+    cloudflared::install(&app_handle, &version).await
+}
 
-    // アーキテクチャを判定
-    let arch = if cfg!(target_arch = "aarch64") {
-        "arm64"
-    } else {
-        "amd64"
-    };
+// Tauriコマンド: cloudflaredのアップデートを確認
+#[tauri::command]
+async fn check_cloudflared_update() -> Result<cloudflared::UpdateInfo, String> {
+    cloudflared::check_update().await
+}
+
+// Tauriコマンド: ローカルのcloudflaredをハッシュを固定したバージョンに更新
+#[tauri::command]
+async fn update_cloudflared(app_handle: tauri::AppHandle) -> Result<String, String> {
+    if cloudflared::system_installed() {
+        return Err("cloudflared is managed by the system package manager".to_string());
+    }
+    // 検証できない最新版は入れない。起動中のトンネルは次回の起動（または自動再起動）から新しいバイナリを使う
+    cloudflared::install(&app_handle, cloudflared::PINNED_VERSION).await
+}
 
-    let download_url = format!(
-        "https://github.com/cloudflare/cloudflared/releases/latest/download/cloudflared-darwin-{}.tgz",
-        arch
-    );
+// Tauriコマンド: トンネルを開始（configを省略するとcloudflaredクイックトンネル）
+#[tauri::command]
+async fn start_tunnel(
+    state: tauri::State<'_, Arc<AppState>>,
+    app_handle: tauri::AppHandle,
+    config: Option<TunnelConfig>,
+) -> Result<(), String> {
+    // 既にトンネルが起動中なら何もしない
+    if state.tunnel.read().is_some() {
+        return Err("Tunnel is already running".to_string());
+    }
+    if state.relay_task.read().is_some() {
+        return Err("Relay is already running".to_string());
+    }
 
-    println!("Downloading cloudflared from: {}", download_url);
-    app_handle.emit("cloudflared_install_progress", "ダウンロード中...").ok();
+    let config = config.unwrap_or_default();
+    let provider: Arc<dyn TunnelProvider> = Arc::from(config.provider()?);
+    let port = 9876;
 
-    // ダウンロード
-    let response = reqwest::blocking::get(&download_url)
-        .map_err(|e| format!("Download failed: {}", e))?;
+    // 監視スレッドはAppStateを弱参照で持つ（AppState自身がスーパーバイザーを保持するため）
+    let weak_state = Arc::downgrade(state.inner());
+    let url_handle = app_handle.clone();
+    let weak_state_stopped = weak_state.clone();
+    let stopped_handle = app_handle.clone();
+    let callbacks = TunnelCallbacks {
+        on_url: Box::new(move |provider, url, previous| {
+            if let Some(state) = weak_state.upgrade() {
+                publish_tunnel_url(&state, &url_handle, provider, url, previous);
+            }
+        }),
+        on_stopped: Box::new(move |stopped| {
+            if let Some(state) = weak_state_stopped.upgrade() {
+                *state.tunnel_info.write() = None;
+            }
+            stopped_handle.emit("tunnel_stopped", &stopped).ok();
+        }),
+    };
 
-    if !response.status().is_success() {
-        return Err(format!("Download failed: HTTP {}", response.status()));
-    }
+    // トンネルプロセスをバックグラウンドで起動（異常終了時は自動で再起動）
+    let supervisor = TunnelSupervisor::start(provider, port, tunnel::default_pid_file(), callbacks)?;
+    *state.tunnel.write() = Some(supervisor);
 
-    let bytes = response.bytes()
-        .map_err(|e| format!("Failed to read response: {}", e))?;
+    Ok(())
+}
 
-    app_handle.emit("cloudflared_install_progress", "展開中...").ok();
+/// URL変更イベントのペイロード（新しいQRコードを含む）
+#[derive(Clone, Serialize)]
+struct TunnelUrlChanged {
+    previous_url: String,
+    #[serde(flatten)]
+    tunnel: TunnelInfo,
+}
 
-    // 保存先ディレクトリを作成
-    let data_dir = dirs::data_local_dir()
-        .unwrap_or_else(|| std::path::PathBuf::from("."))
-        .join("PocketRemote");
-    std::fs::create_dir_all(&data_dir)
-        .map_err(|e| format!("Failed to create directory: {}", e))?;
-
-    // tgzを展開
-    let tar_gz = flate2::read::GzDecoder::new(&bytes[..]);
-    let mut archive = tar::Archive::new(tar_gz);
-
-    let cloudflared_path = data_dir.join("cloudflared");
-
-    for entry in archive.entries().map_err(|e| format!("Failed to read archive: {}", e))? {
-        let mut entry = entry.map_err(|e| format!("Failed to read entry: {}", e))?;
-        let path = entry.path().map_err(|e| format!("Failed to get path: {}", e))?;
-
-        if path.file_name().map(|n| n == "cloudflared").unwrap_or(false) {
-            let mut file = std::fs::File::create(&cloudflared_path)
-                .map_err(|e| format!("Failed to create file: {}", e))?;
-
-            std::io::copy(&mut entry, &mut file)
-                .map_err(|e| format!("Failed to write file: {}", e))?;
-
-            // 実行権限を付与
-            #[cfg(unix)]
-            {
-                use std::os::unix::fs::PermissionsExt;
-                let mut perms = std::fs::metadata(&cloudflared_path)
-                    .map_err(|e| format!("Failed to get metadata: {}", e))?
-                    .permissions();
-                perms.set_mode(0o755);
-                std::fs::set_permissions(&cloudflared_path, perms)
-                    .map_err(|e| format!("Failed to set permissions: {}", e))?;
+// トンネルURLの確定（全プロバイダー共通: 状態更新・QRコード生成・イベント送信）
+fn publish_tunnel_url(
+    state: &AppState,
+    app_handle: &AppHandle,
+    provider: &str,
+    url: String,
+    previous_url: Option<String>,
+) {
+    // 同じURLが複数回出力されることがあるので重複は無視
+    if state.tunnel_info.read().as_ref().map(|t| t.url == url).unwrap_or(false) {
+        return;
+    }
+    println!("Tunnel URL found: {}", url);
+
+    // WebSocket URLを生成（https -> wss）
+    let connection_string = secure_channel::with_public_key(&format!(
+        "{}:{}",
+        tunnel::websocket_url(&url),
+        state.auth_token
+    ));
+
+    // QRコードを生成
+    match generate_qr_code(&connection_string) {
+        Ok(qr_code) => {
+            println!("QR code generated successfully");
+            let tunnel_info = TunnelInfo {
+                url,
+                qr_code,
+                provider: provider.to_string(),
+            };
+            *state.tunnel_info.write() = Some(tunnel_info.clone());
+
+            // 再起動でURLが変わった場合は接続し直してもらう
+            if let Some(previous_url) = previous_url.filter(|p| *p != tunnel_info.url) {
+                println!("Tunnel URL changed: {} -> {}", previous_url, tunnel_info.url);
+                app_handle
+                    .emit("tunnel_url_changed", &TunnelUrlChanged { previous_url, tunnel: tunnel_info })
+                    .ok();
+                return;
             }
 
-            break;
+            // フロントエンドにイベントを送信
+            match app_handle.emit("tunnel_started", &tunnel_info) {
+                Ok(_) => println!("tunnel_started event emitted successfully"),
+                Err(e) => println!("Failed to emit tunnel_started: {}", e),
+            }
         }
+        Err(e) => println!("Failed to generate QR code: {}", e),
     }
+}
 
-    if !cloudflared_path.exists() {
-        return Err("cloudflared binary not found in archive".to_string());
+// Tauriコマンド: トンネルを停止
+#[tauri::command]
+async fn stop_tunnel(state: tauri::State<'_, Arc<AppState>>, app_handle: tauri::AppHandle) -> Result<(), String> {
+    // ロックを保持したまま停止を待たないよう先に取り出す
+    let supervisor = state.tunnel.write().take();
+    if let Some(mut supervisor) = supervisor {
+        // プロセスの終了を最大数秒待つのでブロッキングで実行
+        let supervisor = tokio::task::spawn_blocking(move || {
+            supervisor.stop();
+            supervisor
+        })
+        .await
+        .map_err(|e| e.to_string())?;
+        *state.tunnel_info.write() = None;
+
+        app_handle
+            .emit(
+                "tunnel_stopped",
+                &TunnelStopped {
+                    provider: supervisor.provider_name().to_string(),
+                    reason: "stopped by user".to_string(),
+                    restarting: false,
+                    retry_in_secs: None,
+                },
+            )
+            .ok();
+        println!("Tunnel stopped");
     }
+    Ok(())
+}
 
-    app_handle.emit("cloudflared_install_progress", "インストール完了").ok();
-    println!("cloudflared installed to: {:?}", cloudflared_path);
+// リレークライアントを起動（トンネルとは排他）
+fn start_relay_client(state: &Arc<AppState>, app_handle: &AppHandle, relay_url: &str) -> Result<(), String> {
+    if state.tunnel.read().is_some() {
+        return Err("Tunnel is already running".to_string());
+    }
+    let mut relay_task = state.relay_task.write();
+    if relay_task.is_some() {
+        return Err("Relay is already running".to_string());
+    }
 
+    let base = relay_client::normalize_base_url(relay_url);
+    if !base.starts_with("ws://") && !base.starts_with("wss://") {
+        return Err(format!("Invalid relay URL: {}", relay_url));
+    }
+    // デスクトップIDは起動ごとにランダム生成（スマホはQRコードから知る）
+    let desktop_id = uuid::Uuid::new_v4().to_string();
+    println!("[Relay] Connecting to {} as {}", base, desktop_id);
+
+    *relay_task = Some(tokio::spawn(relay_client::run(
+        base,
+        desktop_id,
+        state.clone(),
+        app_handle.clone(),
+    )));
     Ok(())
 }
 
-// Tauriコマンド: トンネルを開始
+// Tauriコマンド: リレー経由の接続を開始
 #[tauri::command]
-async fn start_tunnel(state: tauri::State<'_, Arc<AppState>>, app_handle: tauri::AppHandle) -> Result<(), String> {
-    // 既にトンネルが起動中なら何もしない
-    if state.tunnel_process.read().is_some() {
-        return Err("Tunnel is already running".to_string());
+async fn start_relay(
+    state: tauri::State<'_, Arc<AppState>>,
+    app_handle: tauri::AppHandle,
+    url: String,
+) -> Result<(), String> {
+    start_relay_client(state.inner(), &app_handle, &url)
+}
+
+// Tauriコマンド: リレー経由の接続を停止（接続中のセッションはそのまま）
+#[tauri::command]
+fn stop_relay(state: tauri::State<Arc<AppState>>, app_handle: tauri::AppHandle) -> Result<(), String> {
+    let task = state.relay_task.write().take();
+    if let Some(task) = task {
+        task.abort();
+        *state.tunnel_info.write() = None;
+        app_handle
+            .emit(
+                "tunnel_stopped",
+                &TunnelStopped {
+                    provider: "relay".to_string(),
+                    reason: "stopped by user".to_string(),
+                    restarting: false,
+                    retry_in_secs: None,
+                },
+            )
+            .ok();
+        println!("Relay stopped");
     }
+    Ok(())
+}
 
-    // cloudflaredのパスを取得
-    let cloudflared_path = get_cloudflared_path()
-        .ok_or("cloudflared is not installed")?;
+// Tauriコマンド: LAN内でもE2E暗号化されていないセッションを拒否するか設定
+#[tauri::command]
+fn set_require_encryption(state: tauri::State<Arc<AppState>>, enabled: bool) {
+    state
+        .require_encryption
+        .store(enabled, std::sync::atomic::Ordering::SeqCst);
+    println!("[E2E] Require encryption: {}", enabled);
+}
 
-    let port = 9876;
-    let auth_token = state.auth_token.clone();
-
-    // cloudflaredをバックグラウンドで起動
-    let mut child = std::process::Command::new(&cloudflared_path)
-        .args(["tunnel", "--url", &format!("http://localhost:{}", port)])
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn()
-        .map_err(|e| format!("Failed to start cloudflared: {}", e))?;
-
-    let pid = child.id();
-    *state.tunnel_process.write() = Some(pid);
-
-    // stderrからURLをパース（cloudflaredはstderrに出力する）
-    let stderr = child.stderr.take().ok_or("Failed to get stderr")?;
-    let state_clone = state.inner().clone();
-    let app_handle_clone = app_handle.clone();
-
-    std::thread::spawn(move || {
-        use std::io::{BufRead, BufReader};
-        let reader = BufReader::new(stderr);
-
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                println!("cloudflared: {}", line);
-                // URLを探す（例: https://xxxx-xxxx.trycloudflare.com）
-                if line.contains(".trycloudflare.com") || line.contains("https://") {
-                    if let Some(url) = extract_tunnel_url(&line) {
-                        println!("Tunnel URL found: {}", url);
-
-                        // WebSocket URLを生成（https -> wss）
-                        let ws_url = url.replace("https://", "wss://");
-                        let connection_string = format!("{}:{}", ws_url, auth_token);
-
-                        // QRコードを生成
-                        match generate_qr_code(&connection_string) {
-                            Ok(qr_code) => {
-                                println!("QR code generated successfully");
-                                let tunnel_info = TunnelInfo {
-                                    url: url.clone(),
-                                    qr_code,
-                                };
-                                *state_clone.tunnel_info.write() = Some(tunnel_info.clone());
+// Tauriコマンド: 定期キーフレームの間隔（フレーム数）を取得
+#[tauri::command]
+fn get_gop_length() -> u64 {
+    h264_encoder::gop_length()
+}
 
-                                // フロントエンドにイベントを送信
-                                match app_handle_clone.emit("tunnel_started", &tunnel_info) {
-                                    Ok(_) => println!("tunnel_started event emitted successfully"),
-                                    Err(e) => println!("Failed to emit tunnel_started: {}", e),
-                                }
-                            }
-                            Err(e) => println!("Failed to generate QR code: {}", e),
-                        }
-                    }
-                }
-            }
-        }
-    });
+// Tauriコマンド: 定期キーフレームの間隔（フレーム数）を設定（0で定期送信なし）
+#[tauri::command]
+fn set_gop_length(frames: u64) {
+    h264_encoder::set_gop_length(frames);
+}
+
+// Tauriコマンド: 適応ビットレートの範囲を取得
+#[tauri::command]
+fn get_stream_bounds() -> StreamBounds {
+    adaptive::bounds()
+}
 
+// Tauriコマンド: 適応ビットレートの範囲を設定
+#[tauri::command]
+fn set_stream_bounds(bounds: StreamBounds) -> Result<(), String> {
+    adaptive::set_bounds(bounds)?;
+    println!("[Adaptive] Bounds set: {:?}", bounds);
     Ok(())
 }
 
-// URLを抽出するヘルパー関数
-fn extract_tunnel_url(line: &str) -> Option<String> {
-    // "https://xxxx.trycloudflare.com" のようなURLを探す
-    let patterns = [".trycloudflare.com", ".cloudflare.dev"];
-
-    for pattern in patterns {
-        if let Some(end_pos) = line.find(pattern) {
-            // https://の開始位置を探す
-            if let Some(start_pos) = line[..end_pos].rfind("https://") {
-                let url_end = end_pos + pattern.len();
-                return Some(line[start_pos..url_end].to_string());
-            }
-        }
-    }
-    None
+// Tauriコマンド: 画面録画を開始（monitor省略時はプライマリモニター）
+#[tauri::command]
+fn start_recording(
+    monitor: Option<MonitorSelection>,
+    max_duration_secs: Option<u64>,
+    max_bytes: Option<u64>,
+) -> Result<RecordingStatus, String> {
+    recording::start(&monitor.unwrap_or_default(), max_duration_secs, max_bytes)
 }
 
-// Tauriコマンド: トンネルを停止
+// Tauriコマンド: 画面録画を停止（ファイルの書き出しを待つ）
 #[tauri::command]
-fn stop_tunnel(state: tauri::State<Arc<AppState>>) -> Result<(), String> {
-    if let Some(pid) = state.tunnel_process.write().take() {
-        // プロセスを終了
-        #[cfg(unix)]
-        {
-            let _ = std::process::Command::new("kill")
-                .args(["-9", &pid.to_string()])
-                .spawn();
-        }
-        #[cfg(not(unix))]
-        {
-            std::process::Command::new("taskkill")
-                .args(["/F", "/PID", &pid.to_string()])
-                .spawn()
-                .ok();
-        }
+async fn stop_recording() -> Result<RecordingStatus, String> {
+    tokio::task::spawn_blocking(recording::stop).await.map_err(|e| e.to_string())?
+}
 
-        *state.tunnel_info.write() = None;
-        println!("Tunnel stopped");
-    }
-    Ok(())
+// Tauriコマンド: 録画の状態を取得
+#[tauri::command]
+fn get_recording_status() -> RecordingStatus {
+    recording::status()
+}
+
+// Tauriコマンド: 保存済みの録画一覧
+#[tauri::command]
+fn list_recordings() -> Vec<RecordingInfo> {
+    recording::list()
+}
+
+// Tauriコマンド: ネイティブ解像度のスクリーンショット（target省略時は全モニター）
+#[tauri::command]
+async fn take_screenshot(
+    state: tauri::State<'_, Arc<AppState>>,
+    target: Option<ScreenshotTarget>,
+    save: Option<bool>,
+) -> Result<Screenshot, String> {
+    let region = state.capture_region.read().clone();
+    let target = target.unwrap_or_default();
+    tokio::task::spawn_blocking(move || screenshot::take(&target, region.as_ref(), save.unwrap_or(false)))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+// Tauriコマンド: スクリーンショットの保存先を取得
+#[tauri::command]
+fn get_screenshot_dir() -> String {
+    screenshot::save_dir().to_string_lossy().to_string()
+}
+
+// Tauriコマンド: スクリーンショットの保存先を設定（空文字で既定に戻す）
+#[tauri::command]
+fn set_screenshot_dir(dir: String) -> Result<(), String> {
+    let dir = (!dir.is_empty()).then(|| std::path::PathBuf::from(dir));
+    screenshot::set_save_dir(dir)
+}
+
+// Tauriコマンド: 音声の入力元を取得
+#[tauri::command]
+fn get_audio_source() -> audio::AudioSource {
+    audio::source()
+}
+
+// Tauriコマンド: 音声の入力元を設定（動作確認用のサイン波・WAVファイル、次のWebRTCセッションから有効）
+#[tauri::command]
+fn set_audio_source(source: audio::AudioSource) -> Result<(), String> {
+    audio::set_source(source)
 }
 
 // Tauriコマンド: トンネル情報を取得
@@ -1513,30 +2093,14 @@ fn get_tunnel_info(state: tauri::State<Arc<AppState>>) -> Option<TunnelInfo> {
     state.tunnel_info.read().clone()
 }
 
-// Helper function to kill all cloudflared processes
-fn kill_all_cloudflared() {
-    #[cfg(unix)]
-    {
-        let _ = std::process::Command::new("pkill")
-            .args(["-f", "cloudflared"])
-            .spawn();
-    }
-    #[cfg(not(unix))]
-    {
-        let _ = std::process::Command::new("taskkill")
-            .args(["/F", "/IM", "cloudflared.exe"])
-            .spawn();
-    }
-}
-
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     let state = Arc::new(AppState::new());
     let state_clone = state.clone();
     let state_for_exit = state.clone();
 
-    // Kill any existing cloudflared processes on startup
-    kill_all_cloudflared();
+    // 前回の実行で残ったトンネルプロセスを片付ける（自分が起動したものだけ）
+    tunnel::cleanup_orphaned_tunnel(&tunnel::default_pid_file());
 
     tauri::Builder::default()
         .plugin(tauri_plugin_shell::init())
@@ -1554,14 +2118,40 @@ pub fn run() {
             check_cloudflared,
             get_cloudflared_status,
             install_cloudflared,
+            check_cloudflared_update,
+            update_cloudflared,
             start_tunnel,
             stop_tunnel,
             get_tunnel_info,
+            start_relay,
+            stop_relay,
+            set_require_encryption,
+            get_gop_length,
+            set_gop_length,
+            get_stream_bounds,
+            set_stream_bounds,
+            start_recording,
+            stop_recording,
+            get_recording_status,
+            list_recordings,
+            take_screenshot,
+            get_screenshot_dir,
+            set_screenshot_dir,
+            get_audio_source,
+            set_audio_source,
         ])
         .setup(move |app| {
             let app_handle = app.handle().clone();
             let state = state_clone.clone();
 
+            // ステータスサーバー（メトリクス/ヘルスチェック）を起動
+            let status_state = state.clone();
+            tauri::async_runtime::spawn(async move {
+                if let Err(e) = status_server::start_status_server(status_state).await {
+                    eprintln!("Status server error: {}", e);
+                }
+            });
+
             // WebSocketサーバーをバックグラウンドで起動
             tauri::async_runtime::spawn(async move {
                 if let Err(e) = start_server(state, app_handle).await {
@@ -1573,23 +2163,11 @@ pub fn run() {
         })
         .on_window_event(move |_window, event| {
             if let tauri::WindowEvent::Destroyed = event {
-                // Kill cloudflared when app window is destroyed
-                if let Some(pid) = state_for_exit.tunnel_process.write().take() {
-                    #[cfg(unix)]
-                    {
-                        let _ = std::process::Command::new("kill")
-                            .args(["-9", &pid.to_string()])
-                            .spawn();
-                    }
-                    #[cfg(not(unix))]
-                    {
-                        let _ = std::process::Command::new("taskkill")
-                            .args(["/F", "/PID", &pid.to_string()])
-                            .spawn();
-                    }
+                // ウィンドウ破棄時にトンネルを停止
+                let supervisor = state_for_exit.tunnel.write().take();
+                if let Some(mut supervisor) = supervisor {
+                    supervisor.stop();
                 }
-                // Also kill any orphaned cloudflared processes
-                kill_all_cloudflared();
             }
         })
         .run(tauri::generate_context!())
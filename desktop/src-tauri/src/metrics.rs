@@ -0,0 +1,311 @@
+use once_cell::sync::Lazy;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// プロセス全体のメトリクス（Prometheus形式で公開）
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+/// 単調増加カウンター
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// 増減するゲージ
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    const fn new() -> Self {
+        Self(AtomicI64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, v: i64) {
+        self.0.store(v, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// 固定バケットのヒストグラム（合計値はマイクロ単位で保持）
+pub struct Histogram {
+    bounds: &'static [f64],
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        if let Some(i) = self.bounds.iter().position(|b| value <= *b) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add((value * 1_000_000.0) as u64, Ordering::Relaxed);
+    }
+
+    pub fn observe_duration(&self, d: Duration) {
+        self.observe(d.as_secs_f64());
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// 平均値（観測なしの場合は0）
+    pub fn mean(&self) -> f64 {
+        let count = self.count();
+        if count == 0 {
+            return 0.0;
+        }
+        self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0 / count as f64
+    }
+}
+
+/// WebRTCピア接続状態（ラベル付きゲージ用）
+pub const WEBRTC_STATES: [&str; 6] = ["new", "connecting", "connected", "disconnected", "failed", "closed"];
+
+// ヒストグラムのバケット境界
+const SECONDS_BUCKETS: &[f64] = &[0.001, 0.0025, 0.005, 0.01, 0.02, 0.033, 0.05, 0.1, 0.25, 1.0];
+const BYTES_BUCKETS: &[f64] = &[1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0];
+
+pub struct Metrics {
+    started_at: Instant,
+    // キャプチャ/エンコード（WebSocket経路）
+    pub frames_captured: Counter,
+    pub frames_encoded: Counter,
+    pub keyframes_encoded: Counter,
+    pub frames_skipped: Counter,
+    pub capture_errors: Counter,
+    pub encode_errors: Counter,
+    pub capture_seconds: Histogram,
+    pub encode_seconds: Histogram,
+    pub encoded_frame_bytes: Histogram,
+    // WebSocket送信
+    pub ws_frames_sent: Counter,
+    pub ws_bytes_sent: Counter,
+    pub broadcast_lagged_frames: Counter,
+    // WebRTC
+    pub webrtc_frames_sent: Counter,
+    pub webrtc_frames_skipped: Counter,
+    pub webrtc_bytes_sent: Counter,
+    pub webrtc_encode_seconds: Histogram,
+    webrtc_states: [Gauge; WEBRTC_STATES.len()],
+    // セッション
+    pub ws_connections: Gauge,
+    pub authenticated_sessions: Gauge,
+    pub encrypted_sessions: Gauge,
+    pub screen_share_sessions: Gauge,
+    pub pty_sessions: Gauge,
+    // 直近1秒のスループット（サンプラーが更新）
+    pub ws_bytes_per_second: Gauge,
+    pub ws_frames_per_second: Gauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            frames_captured: Counter::new(),
+            frames_encoded: Counter::new(),
+            keyframes_encoded: Counter::new(),
+            frames_skipped: Counter::new(),
+            capture_errors: Counter::new(),
+            encode_errors: Counter::new(),
+            capture_seconds: Histogram::new(SECONDS_BUCKETS),
+            encode_seconds: Histogram::new(SECONDS_BUCKETS),
+            encoded_frame_bytes: Histogram::new(BYTES_BUCKETS),
+            ws_frames_sent: Counter::new(),
+            ws_bytes_sent: Counter::new(),
+            broadcast_lagged_frames: Counter::new(),
+            webrtc_frames_sent: Counter::new(),
+            webrtc_frames_skipped: Counter::new(),
+            webrtc_bytes_sent: Counter::new(),
+            webrtc_encode_seconds: Histogram::new(SECONDS_BUCKETS),
+            webrtc_states: std::array::from_fn(|_| Gauge::new()),
+            ws_connections: Gauge::new(),
+            authenticated_sessions: Gauge::new(),
+            encrypted_sessions: Gauge::new(),
+            screen_share_sessions: Gauge::new(),
+            pty_sessions: Gauge::new(),
+            ws_bytes_per_second: Gauge::new(),
+            ws_frames_per_second: Gauge::new(),
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    /// WebRTCピア接続の状態遷移を記録（前の状態を減算、新しい状態を加算、toがNoneなら接続の破棄）
+    pub fn webrtc_state_transition(&self, from: Option<&str>, to: Option<&str>) {
+        if let Some(i) = from.and_then(|s| WEBRTC_STATES.iter().position(|x| *x == s)) {
+            self.webrtc_states[i].dec();
+        }
+        if let Some(i) = to.and_then(|s| WEBRTC_STATES.iter().position(|x| *x == s)) {
+            self.webrtc_states[i].inc();
+        }
+    }
+
+    /// 状態ごとのWebRTCピア接続数
+    pub fn webrtc_state_counts(&self) -> Vec<(&'static str, i64)> {
+        WEBRTC_STATES.iter().zip(self.webrtc_states.iter()).map(|(s, g)| (*s, g.get())).collect()
+    }
+
+    /// Prometheusテキスト形式（version 0.0.4）で出力
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        gauge(&mut out, "uptime_seconds", "Seconds since the desktop app started", self.uptime().as_secs() as i64);
+
+        counter(&mut out, "frames_captured_total", "Screen frames captured", &self.frames_captured);
+        counter(&mut out, "frames_encoded_total", "Screen frames encoded to H.264", &self.frames_encoded);
+        counter(&mut out, "keyframes_encoded_total", "H.264 keyframes (IDR) produced", &self.keyframes_encoded);
+        counter(&mut out, "frames_skipped_total", "Frames not encoded because the screen was unchanged", &self.frames_skipped);
+        counter(&mut out, "capture_errors_total", "Screen capture failures", &self.capture_errors);
+        counter(&mut out, "encode_errors_total", "Frame encode failures", &self.encode_errors);
+        histogram(&mut out, "capture_seconds", "Time spent capturing one frame", &self.capture_seconds);
+        histogram(&mut out, "encode_seconds", "Time spent scaling and encoding one frame", &self.encode_seconds);
+        histogram(&mut out, "encoded_frame_bytes", "Size of encoded frames", &self.encoded_frame_bytes);
+
+        counter(&mut out, "ws_frames_sent_total", "Binary frames written to WebSocket clients", &self.ws_frames_sent);
+        counter(&mut out, "ws_bytes_sent_total", "Binary frame bytes written to WebSocket clients", &self.ws_bytes_sent);
+        counter(&mut out, "broadcast_lagged_frames_total", "Frames dropped for lagging clients until the next keyframe", &self.broadcast_lagged_frames);
+        gauge(&mut out, "ws_bytes_per_second", "WebSocket frame bytes sent during the last second", self.ws_bytes_per_second.get());
+        gauge(&mut out, "ws_frames_per_second", "WebSocket frames sent during the last second", self.ws_frames_per_second.get());
+
+        counter(&mut out, "webrtc_frames_sent_total", "Frames sent over WebRTC data channels", &self.webrtc_frames_sent);
+        counter(&mut out, "webrtc_frames_skipped_total", "WebRTC frames not encoded because the screen was unchanged", &self.webrtc_frames_skipped);
+        counter(&mut out, "webrtc_bytes_sent_total", "Bytes sent over WebRTC data channels", &self.webrtc_bytes_sent);
+        histogram(&mut out, "webrtc_encode_seconds", "Time spent encoding one WebRTC frame", &self.webrtc_encode_seconds);
+        let _ = writeln!(out, "# HELP pocket_remote_webrtc_peer_connections WebRTC peer connections by state");
+        let _ = writeln!(out, "# TYPE pocket_remote_webrtc_peer_connections gauge");
+        for (state, count) in self.webrtc_state_counts() {
+            let _ = writeln!(out, "pocket_remote_webrtc_peer_connections{{state=\"{}\"}} {}", state, count);
+        }
+
+        gauge(&mut out, "ws_connections", "Open WebSocket connections", self.ws_connections.get());
+        gauge(&mut out, "authenticated_sessions", "Authenticated client sessions", self.authenticated_sessions.get());
+        gauge(&mut out, "encrypted_sessions", "Sessions using end-to-end encryption", self.encrypted_sessions.get());
+        gauge(&mut out, "screen_share_sessions", "Sessions currently receiving the screen stream", self.screen_share_sessions.get());
+        gauge(&mut out, "pty_sessions", "Running PTY sessions", self.pty_sessions.get());
+
+        out
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, c: &Counter) {
+    let _ = writeln!(out, "# HELP pocket_remote_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE pocket_remote_{} counter", name);
+    let _ = writeln!(out, "pocket_remote_{} {}", name, c.get());
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: i64) {
+    let _ = writeln!(out, "# HELP pocket_remote_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE pocket_remote_{} gauge", name);
+    let _ = writeln!(out, "pocket_remote_{} {}", name, value);
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, h: &Histogram) {
+    let _ = writeln!(out, "# HELP pocket_remote_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE pocket_remote_{} histogram", name);
+    // Prometheusのバケットは累積値
+    let mut cumulative = 0;
+    for (bound, bucket) in h.bounds.iter().zip(h.buckets.iter()) {
+        cumulative += bucket.load(Ordering::Relaxed);
+        let _ = writeln!(out, "pocket_remote_{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+    }
+    let _ = writeln!(out, "pocket_remote_{}_bucket{{le=\"+Inf\"}} {}", name, h.count());
+    let _ = writeln!(
+        out,
+        "pocket_remote_{}_sum {}",
+        name,
+        h.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+    );
+    let _ = writeln!(out, "pocket_remote_{}_count {}", name, h.count());
+}
+
+/// スループットゲージを1秒ごとに更新するサンプラー
+pub async fn run_throughput_sampler() {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    let mut last_bytes = METRICS.ws_bytes_sent.get();
+    let mut last_frames = METRICS.ws_frames_sent.get();
+    loop {
+        interval.tick().await;
+        let bytes = METRICS.ws_bytes_sent.get();
+        let frames = METRICS.ws_frames_sent.get();
+        METRICS.ws_bytes_per_second.set(bytes.saturating_sub(last_bytes) as i64);
+        METRICS.ws_frames_per_second.set(frames.saturating_sub(last_frames) as i64);
+        last_bytes = bytes;
+        last_frames = frames;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_prometheus() {
+        let metrics = Metrics::new();
+        metrics.frames_encoded.add(3);
+        metrics.encode_seconds.observe(0.004);
+        metrics.encode_seconds.observe(2.0);
+        metrics.webrtc_state_transition(None, Some("new"));
+        metrics.webrtc_state_transition(Some("new"), Some("connected"));
+        // closedに達せず破棄された接続も残らない
+        metrics.webrtc_state_transition(None, Some("new"));
+        metrics.webrtc_state_transition(Some("new"), None);
+
+        let text = metrics.render_prometheus();
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines.contains(&"# TYPE pocket_remote_frames_encoded_total counter"));
+        assert!(lines.contains(&"pocket_remote_frames_encoded_total 3"));
+        // バケットは累積、+Infは全件
+        assert!(lines.contains(&"# TYPE pocket_remote_encode_seconds histogram"));
+        assert!(lines.contains(&"pocket_remote_encode_seconds_bucket{le=\"0.0025\"} 0"));
+        assert!(lines.contains(&"pocket_remote_encode_seconds_bucket{le=\"0.005\"} 1"));
+        assert!(lines.contains(&"pocket_remote_encode_seconds_bucket{le=\"1\"} 1"));
+        assert!(lines.contains(&"pocket_remote_encode_seconds_bucket{le=\"+Inf\"} 2"));
+        assert!(lines.contains(&"pocket_remote_encode_seconds_sum 2.004"));
+        assert!(lines.contains(&"pocket_remote_encode_seconds_count 2"));
+        assert!(lines.contains(&"pocket_remote_webrtc_peer_connections{state=\"new\"} 0"));
+        assert!(lines.contains(&"pocket_remote_webrtc_peer_connections{state=\"connected\"} 1"));
+        // HELP/TYPE以外の行は「名前 値」
+        assert!(lines.iter().filter(|l| !l.starts_with('#')).all(|l| l.starts_with("pocket_remote_") && l.split(' ').count() == 2));
+    }
+}
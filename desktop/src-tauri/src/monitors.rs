@@ -0,0 +1,417 @@
+use image::{imageops, RgbaImage};
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use xcap::{Monitor, Window};
+
+/// モニター情報（座標・サイズは論理ピクセル、仮想デスクトップ上の位置）
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MonitorInfo {
+    pub id: u32,
+    pub name: String,
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    pub scale_factor: f32,
+    pub is_primary: bool,
+}
+
+/// キャプチャ対象の選択（セッションごと）
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum MonitorSelection {
+    /// プライマリモニター
+    #[default]
+    Primary,
+    /// IDで指定したモニター
+    Monitor { id: u32 },
+    /// 全モニターを1枚に結合
+    All,
+    /// IDで指定したウィンドウ（他のウィンドウに隠れていてもその内容だけを取得）
+    Window { id: u32 },
+}
+
+/// 仮想デスクトップ上の矩形（論理ピクセル）
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    /// 仮想デスクトップ座標 -> 選択範囲内の座標
+    pub fn to_local(self, x: i32, y: i32) -> (i32, i32) {
+        (x - self.x, y - self.y)
+    }
+
+    fn union(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.width as i32).max(other.x + other.width as i32);
+        let bottom = (self.y + self.height as i32).max(other.y + other.height as i32);
+        Rect { x, y, width: (right - x) as u32, height: (bottom - y) as u32 }
+    }
+}
+
+// xcapのモニター座標の単位（Windowsは物理ピクセル、それ以外は論理ピクセル）
+const XCAP_PHYSICAL: bool = cfg!(target_os = "windows");
+// 入力・カーソル位置の座標の単位（macOSはポイント、WindowsとX11は物理ピクセル）
+const OS_PHYSICAL: bool = !cfg!(target_os = "macos");
+
+/// モニターごとの論理座標とOSの座標（入力・カーソル位置）の対応
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct OsMapping {
+    /// 仮想デスクトップ上の論理座標
+    logical: Rect,
+    /// OS座標での原点
+    os_x: i32,
+    os_y: i32,
+    /// 論理ピクセル -> OS座標の倍率
+    os_scale: f32,
+}
+
+impl OsMapping {
+    /// xcapが返す範囲と倍率から対応を作る（1.25倍などの小数倍率も丸めずに保持する）
+    fn new(raw: Rect, scale: f32, xcap_physical: bool, os_physical: bool) -> Self {
+        let scale = if scale > 0.0 { scale } else { 1.0 };
+        let (logical, physical_x, physical_y) = if xcap_physical {
+            // 原点は物理座標のまま（倍率の異なるモニターが論理座標で重ならないように）
+            let logical = Rect {
+                x: raw.x,
+                y: raw.y,
+                width: (raw.width as f32 / scale).round() as u32,
+                height: (raw.height as f32 / scale).round() as u32,
+            };
+            (logical, raw.x, raw.y)
+        } else {
+            (raw, (raw.x as f32 * scale).round() as i32, (raw.y as f32 * scale).round() as i32)
+        };
+        if os_physical {
+            Self { logical, os_x: physical_x, os_y: physical_y, os_scale: scale }
+        } else {
+            Self { logical, os_x: logical.x, os_y: logical.y, os_scale: 1.0 }
+        }
+    }
+
+    fn contains_logical(&self, x: i32, y: i32) -> bool {
+        let r = self.logical;
+        x >= r.x && y >= r.y && x < r.x + r.width as i32 && y < r.y + r.height as i32
+    }
+
+    fn contains_os(&self, x: i32, y: i32) -> bool {
+        let (w, h) = (self.logical.width as f32 * self.os_scale, self.logical.height as f32 * self.os_scale);
+        x >= self.os_x && y >= self.os_y && ((x - self.os_x) as f32) < w && ((y - self.os_y) as f32) < h
+    }
+
+    fn logical_to_os(&self, x: i32, y: i32) -> (i32, i32) {
+        (
+            self.os_x + ((x - self.logical.x) as f32 * self.os_scale).round() as i32,
+            self.os_y + ((y - self.logical.y) as f32 * self.os_scale).round() as i32,
+        )
+    }
+
+    fn os_to_logical(&self, x: i32, y: i32) -> (i32, i32) {
+        (
+            self.logical.x + ((x - self.os_x) as f32 / self.os_scale).round() as i32,
+            self.logical.y + ((y - self.os_y) as f32 / self.os_scale).round() as i32,
+        )
+    }
+}
+
+// 入力のたびにモニター一覧を取得しないよう、一覧を取得した時に更新しておく
+static OS_MAPPINGS: Lazy<RwLock<Vec<OsMapping>>> = Lazy::new(|| {
+    let mappings = Monitor::all()
+        .map(|monitors| monitors.iter().filter_map(|m| monitor_info(m).ok().map(|(_, mapping)| mapping)).collect())
+        .unwrap_or_default();
+    RwLock::new(mappings)
+});
+
+// 点を含むモニター（どれにも含まれなければ先頭、モニターがなければNone）
+fn find_mapping(mappings: &[OsMapping], contains: impl Fn(&OsMapping) -> bool) -> Option<OsMapping> {
+    mappings.iter().find(|m| contains(m)).or(mappings.first()).copied()
+}
+
+/// 仮想デスクトップ上の論理座標 -> OSの座標（入力イベント用）
+pub fn to_os(x: i32, y: i32) -> (i32, i32) {
+    find_mapping(&OS_MAPPINGS.read(), |m| m.contains_logical(x, y)).map_or((x, y), |m| m.logical_to_os(x, y))
+}
+
+/// OSの座標（カーソル位置・ウィンドウ位置） -> 仮想デスクトップ上の論理座標
+pub fn from_os(x: i32, y: i32) -> (i32, i32) {
+    find_mapping(&OS_MAPPINGS.read(), |m| m.contains_os(x, y)).map_or((x, y), |m| m.os_to_logical(x, y))
+}
+
+/// OS座標の点での倍率（論理ピクセルあたりのOS座標）
+pub fn os_scale_at(x: i32, y: i32) -> f32 {
+    find_mapping(&OS_MAPPINGS.read(), |m| m.contains_os(x, y)).map_or(1.0, |m| m.os_scale)
+}
+
+/// OS座標の矩形 -> 論理座標の矩形
+fn from_os_rect(rect: Rect) -> Rect {
+    let (x, y) = from_os(rect.x, rect.y);
+    let scale = os_scale_at(rect.x, rect.y);
+    Rect {
+        x,
+        y,
+        width: (rect.width as f32 / scale).round() as u32,
+        height: (rect.height as f32 / scale).round() as u32,
+    }
+}
+
+fn monitor_info(monitor: &Monitor) -> Result<(MonitorInfo, OsMapping), String> {
+    let raw = Rect {
+        x: monitor.x().map_err(|e| format!("Failed to get x: {}", e))?,
+        y: monitor.y().map_err(|e| format!("Failed to get y: {}", e))?,
+        width: monitor.width().map_err(|e| format!("Failed to get width: {}", e))?,
+        height: monitor.height().map_err(|e| format!("Failed to get height: {}", e))?,
+    };
+    let scale_factor = monitor.scale_factor().unwrap_or(1.0);
+    let mapping = OsMapping::new(raw, scale_factor, XCAP_PHYSICAL, OS_PHYSICAL);
+    let info = MonitorInfo {
+        id: monitor.id().map_err(|e| format!("Failed to get monitor id: {}", e))?,
+        name: monitor.name().unwrap_or_default(),
+        x: mapping.logical.x,
+        y: mapping.logical.y,
+        width: mapping.logical.width,
+        height: mapping.logical.height,
+        scale_factor,
+        is_primary: monitor.is_primary().unwrap_or(false),
+    };
+    Ok((info, mapping))
+}
+
+// すべてのモニターの情報を取得し、座標の対応を更新する
+fn all_monitors() -> Result<Vec<(Monitor, MonitorInfo)>, String> {
+    let all = Monitor::all().map_err(|e| format!("Failed to get monitors: {}", e))?;
+    let mut monitors = Vec::with_capacity(all.len());
+    let mut mappings = Vec::with_capacity(all.len());
+    for monitor in all {
+        let (info, mapping) = monitor_info(&monitor)?;
+        monitors.push((monitor, info));
+        mappings.push(mapping);
+    }
+    *OS_MAPPINGS.write() = mappings;
+    Ok(monitors)
+}
+
+/// 接続されているモニター一覧
+pub fn list_monitors() -> Result<Vec<MonitorInfo>, String> {
+    Ok(all_monitors()?.into_iter().map(|(_, info)| info).collect())
+}
+
+/// ウィンドウ情報（座標・サイズは仮想デスクトップ上の論理ピクセル）
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WindowInfo {
+    pub id: u32,
+    pub title: String,
+    pub app_name: String,
+    pub pid: u32,
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    pub is_minimized: bool,
+    pub is_focused: bool,
+}
+
+// ウィンドウの範囲（xcapはOSの座標で返すので論理座標に変換する）
+fn window_rect(window: &Window) -> Result<Rect, String> {
+    Ok(from_os_rect(Rect {
+        x: window.x().map_err(|e| format!("Failed to get window x: {}", e))?,
+        y: window.y().map_err(|e| format!("Failed to get window y: {}", e))?,
+        width: window.width().map_err(|e| format!("Failed to get window width: {}", e))?,
+        height: window.height().map_err(|e| format!("Failed to get window height: {}", e))?,
+    }))
+}
+
+/// キャプチャできるウィンドウ一覧（前面から順、サイズのないものは除く）
+pub fn list_windows() -> Result<Vec<WindowInfo>, String> {
+    let windows = Window::all().map_err(|e| format!("Failed to get windows: {}", e))?;
+    Ok(windows
+        .iter()
+        .filter_map(|window| {
+            let rect = window_rect(window).ok().filter(|r| r.width > 0 && r.height > 0)?;
+            Some(WindowInfo {
+                id: window.id().ok()?,
+                title: window.title().unwrap_or_default(),
+                app_name: window.app_name().unwrap_or_default(),
+                pid: window.pid().unwrap_or(0),
+                x: rect.x,
+                y: rect.y,
+                width: rect.width,
+                height: rect.height,
+                is_minimized: window.is_minimized().unwrap_or(false),
+                is_focused: window.is_focused().unwrap_or(false),
+            })
+        })
+        .collect())
+}
+
+/// 選択に対応するモニター群（またはウィンドウ）をまとめてキャプチャする
+pub struct MonitorCapturer {
+    monitors: Vec<(Monitor, MonitorInfo)>,
+    window: Option<Window>,
+    bounds: Rect,
+    scale_factor: f32,
+}
+
+impl MonitorCapturer {
+    pub fn new(selection: &MonitorSelection) -> Result<Self, String> {
+        if let MonitorSelection::Window { id } = selection {
+            return Self::for_window(*id);
+        }
+        let monitors = all_monitors()?;
+
+        let monitors: Vec<(Monitor, MonitorInfo)> = match selection {
+            MonitorSelection::Primary => {
+                // プライマリが判定できない環境では先頭のモニター
+                let index = monitors.iter().position(|(_, info)| info.is_primary).unwrap_or(0);
+                monitors.into_iter().nth(index).into_iter().collect()
+            }
+            MonitorSelection::Monitor { id } => monitors
+                .into_iter()
+                .filter(|(_, info)| info.id == *id)
+                .collect(),
+            MonitorSelection::All => monitors,
+            MonitorSelection::Window { .. } => Vec::new(),
+        };
+        if monitors.is_empty() {
+            return Err(format!("No monitor found for {:?}", selection));
+        }
+
+        let rects: Vec<Rect> = monitors
+            .iter()
+            .map(|(_, m)| Rect { x: m.x, y: m.y, width: m.width, height: m.height })
+            .collect();
+        let bounds = rects[1..].iter().fold(rects[0], |acc, r| acc.union(r));
+        // 結合時は最も高いスケールに合わせる（Retinaの文字を潰さないため）
+        let scale_factor = monitors
+            .iter()
+            .map(|(_, m)| m.scale_factor)
+            .fold(0.0f32, f32::max)
+            .max(1.0);
+
+        Ok(Self { monitors, window: None, bounds, scale_factor })
+    }
+
+    fn for_window(id: u32) -> Result<Self, String> {
+        let window = Window::all()
+            .map_err(|e| format!("Failed to get windows: {}", e))?
+            .into_iter()
+            .find(|w| w.id().ok() == Some(id))
+            .ok_or_else(|| format!("No window found for id {}", id))?;
+        let bounds = window_rect(&window)?;
+        let scale_factor = window
+            .current_monitor()
+            .and_then(|m| m.scale_factor())
+            .unwrap_or(1.0)
+            .max(1.0);
+        Ok(Self { monitors: Vec::new(), window: Some(window), bounds, scale_factor })
+    }
+
+    /// キャプチャ範囲（仮想デスクトップ上の論理座標）
+    pub fn bounds(&self) -> Rect {
+        self.bounds
+    }
+
+    /// 論理ピクセル -> キャプチャ画像ピクセルの倍率
+    pub fn scale_factor(&self) -> f32 {
+        self.scale_factor
+    }
+
+    /// キャプチャ（複数モニターの場合は仮想デスクトップ上の配置どおりに結合）
+    /// ウィンドウは移動・リサイズされるので、キャプチャごとに範囲と倍率を更新する
+    pub fn capture(&mut self) -> Result<RgbaImage, String> {
+        if let Some(window) = &self.window {
+            if window.is_minimized().unwrap_or(false) {
+                return Err("Window is minimized".to_string());
+            }
+            let img = window.capture_image().map_err(|e| e.to_string())?;
+            self.bounds = window_rect(window)?;
+            self.scale_factor = img.width() as f32 / self.bounds.width.max(1) as f32;
+            return Ok(img);
+        }
+        if let [(monitor, _)] = self.monitors.as_slice() {
+            return monitor.capture_image().map_err(|e| e.to_string());
+        }
+
+        let scale = self.scale_factor;
+        let mut canvas = RgbaImage::new(
+            (self.bounds.width as f32 * scale) as u32,
+            (self.bounds.height as f32 * scale) as u32,
+        );
+        for (monitor, info) in &self.monitors {
+            let img = monitor.capture_image().map_err(|e| e.to_string())?;
+            let target_w = (info.width as f32 * scale) as u32;
+            let target_h = (info.height as f32 * scale) as u32;
+            let img = if img.width() != target_w || img.height() != target_h {
+                imageops::resize(&img, target_w, target_h, imageops::FilterType::Triangle)
+            } else {
+                img
+            };
+            let offset_x = ((info.x - self.bounds.x) as f32 * scale) as i64;
+            let offset_y = ((info.y - self.bounds.y) as f32 * scale) as i64;
+            imageops::replace(&mut canvas, &img, offset_x, offset_y);
+        }
+        Ok(canvas)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_os_mapping_fractional_scales() {
+        for scale in [1.0f32, 1.25, 1.5, 1.75, 2.0] {
+            // 論理1600x900のモニター
+            let (width, height) = ((1600.0 * scale) as u32, (900.0 * scale) as u32);
+
+            // Windows: xcapは物理ピクセル、入力も物理ピクセル（原点は物理座標のまま）
+            let windows = OsMapping::new(Rect { x: 1920, y: 0, width, height }, scale, true, true);
+            assert_eq!(windows.logical, Rect { x: 1920, y: 0, width: 1600, height: 900 }, "scale {}", scale);
+            // X11: xcapは論理ピクセル、入力は物理ピクセル
+            let x11 = OsMapping::new(Rect { x: 1920, y: 0, width: 1600, height: 900 }, scale, false, true);
+            assert_eq!((x11.os_x, x11.os_y), ((1920.0 * scale).round() as i32, 0), "scale {}", scale);
+            // macOS: どちらもポイント
+            let macos = OsMapping::new(Rect { x: 1920, y: 0, width: 1600, height: 900 }, scale, false, false);
+            assert_eq!(macos.logical_to_os(2320, 300), (2320, 300));
+
+            for m in [windows, x11] {
+                // 論理のオフセットに倍率を掛ける
+                let expected = (m.os_x + (400.0 * scale).round() as i32, (300.0 * scale).round() as i32);
+                assert_eq!(m.logical_to_os(1920 + 400, 300), expected, "scale {}", scale);
+                assert!(m.contains_os(m.os_x + width as i32 - 1, 0), "scale {}", scale);
+                assert!(!m.contains_os(m.os_x + width as i32 + 1, 0), "scale {}", scale);
+            }
+
+            // 論理 -> OS -> 論理で元に戻る
+            for m in [windows, x11, macos] {
+                for (x, y) in [(1920, 0), (2321, 333), (3519, 899)] {
+                    let (ox, oy) = m.logical_to_os(x, y);
+                    assert_eq!(m.os_to_logical(ox, oy), (x, y), "scale {}", scale);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_find_mapping_by_monitor() {
+        // 左: 倍率1.0、右: 倍率1.5（Windows、物理ピクセル）
+        let left = OsMapping::new(Rect { x: 0, y: 0, width: 1920, height: 1080 }, 1.0, true, true);
+        let right = OsMapping::new(Rect { x: 1920, y: 0, width: 2400, height: 1350 }, 1.5, true, true);
+        let mappings = [left, right];
+        let to_os = |x, y| find_mapping(&mappings, |m| m.contains_logical(x, y)).unwrap().logical_to_os(x, y);
+        let from_os = |x, y| find_mapping(&mappings, |m| m.contains_os(x, y)).unwrap().os_to_logical(x, y);
+
+        assert_eq!(to_os(100, 100), (100, 100));
+        // 右のモニターは論理1600x900、原点からのオフセットは1.5倍
+        assert_eq!(right.logical, Rect { x: 1920, y: 0, width: 1600, height: 900 });
+        assert_eq!(to_os(2020, 100), (2070, 150));
+        assert_eq!(from_os(2070, 150), (2020, 100));
+        assert_eq!(from_os(1919, 0), (1919, 0));
+    }
+}
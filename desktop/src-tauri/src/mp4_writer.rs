@@ -0,0 +1,332 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// タイムスケール（1秒あたりの単位数、H.264の慣例）
+pub const TIMESCALE: u32 = 90_000;
+
+// 1フラグメントの最大サンプル数（キーフレームが来なくても区切る）
+const MAX_FRAGMENT_SAMPLES: usize = 60;
+
+struct Sample {
+    data: Vec<u8>,
+    duration: u32,
+    keyframe: bool,
+}
+
+/// H.264のフラグメント化MP4書き出し（途中で止まってもそれまでの分は再生できる）
+/// サンプルはAVCC形式（4バイト長 + NAL、SPS/PPSは含めない）
+pub struct Mp4Writer<W: Write + Seek> {
+    out: W,
+    sequence: u32,
+    // 次のフラグメントの開始時刻（タイムスケール単位）
+    decode_time: u64,
+    samples: Vec<Sample>,
+    // mehd（全体の長さ）の位置（終了時に書き換える）
+    mehd_offset: u64,
+    bytes_written: u64,
+}
+
+// ボックスを書く（サイズは中身を書いた後に埋める）
+fn write_box(buf: &mut Vec<u8>, kind: &[u8; 4], body: impl FnOnce(&mut Vec<u8>)) {
+    let start = buf.len();
+    buf.extend_from_slice(&[0, 0, 0, 0]);
+    buf.extend_from_slice(kind);
+    body(buf);
+    let size = (buf.len() - start) as u32;
+    buf[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+// バージョン・フラグ付きのボックス
+fn write_full_box(buf: &mut Vec<u8>, kind: &[u8; 4], version: u8, flags: u32, body: impl FnOnce(&mut Vec<u8>)) {
+    write_box(buf, kind, |buf| {
+        buf.push(version);
+        buf.extend_from_slice(&flags.to_be_bytes()[1..]);
+        body(buf);
+    });
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+// 単位行列（mvhd / tkhd）
+fn put_matrix(buf: &mut Vec<u8>) {
+    for value in [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000u32] {
+        put_u32(buf, value);
+    }
+}
+
+impl<W: Write + Seek> Mp4Writer<W> {
+    /// ヘッダー（ftyp・moov）を書き出す
+    pub fn new(mut out: W, width: u32, height: u32, sps: &[u8], pps: &[u8]) -> io::Result<Self> {
+        if sps.len() < 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "SPS too short"));
+        }
+        let mut buf = Vec::new();
+        write_box(&mut buf, b"ftyp", |b| {
+            b.extend_from_slice(b"isom");
+            put_u32(b, 0x200);
+            for brand in [b"isom", b"iso5", b"iso6", b"avc1", b"mp41"] {
+                b.extend_from_slice(brand);
+            }
+        });
+
+        let mut mehd_offset = 0;
+        write_box(&mut buf, b"moov", |b| {
+            write_full_box(b, b"mvhd", 0, 0, |b| {
+                put_u32(b, 0); // 作成時刻
+                put_u32(b, 0); // 更新時刻
+                put_u32(b, TIMESCALE);
+                put_u32(b, 0); // 長さ（mehdで指定）
+                put_u32(b, 0x0001_0000); // 再生速度
+                put_u16(b, 0x0100); // 音量
+                b.extend_from_slice(&[0; 10]);
+                put_matrix(b);
+                b.extend_from_slice(&[0; 24]);
+                put_u32(b, 2); // 次のトラックID
+            });
+            write_box(b, b"trak", |b| {
+                write_full_box(b, b"tkhd", 0, 0x3, |b| {
+                    put_u32(b, 0);
+                    put_u32(b, 0);
+                    put_u32(b, 1); // トラックID
+                    put_u32(b, 0);
+                    put_u32(b, 0); // 長さ
+                    b.extend_from_slice(&[0; 8]);
+                    put_u16(b, 0); // レイヤー
+                    put_u16(b, 0); // 代替グループ
+                    put_u16(b, 0); // 音量（映像なので0）
+                    put_u16(b, 0);
+                    put_matrix(b);
+                    put_u32(b, width << 16);
+                    put_u32(b, height << 16);
+                });
+                write_box(b, b"mdia", |b| {
+                    write_full_box(b, b"mdhd", 0, 0, |b| {
+                        put_u32(b, 0);
+                        put_u32(b, 0);
+                        put_u32(b, TIMESCALE);
+                        put_u32(b, 0);
+                        put_u16(b, 0x55c4); // 言語 "und"
+                        put_u16(b, 0);
+                    });
+                    write_full_box(b, b"hdlr", 0, 0, |b| {
+                        put_u32(b, 0);
+                        b.extend_from_slice(b"vide");
+                        b.extend_from_slice(&[0; 12]);
+                        b.extend_from_slice(b"VideoHandler\0");
+                    });
+                    write_box(b, b"minf", |b| {
+                        write_full_box(b, b"vmhd", 0, 1, |b| b.extend_from_slice(&[0; 8]));
+                        write_box(b, b"dinf", |b| {
+                            write_full_box(b, b"dref", 0, 0, |b| {
+                                put_u32(b, 1);
+                                // 同じファイル内のデータ
+                                write_full_box(b, b"url ", 0, 1, |_| {});
+                            });
+                        });
+                        write_box(b, b"stbl", |b| {
+                            write_full_box(b, b"stsd", 0, 0, |b| {
+                                put_u32(b, 1);
+                                write_box(b, b"avc1", |b| {
+                                    b.extend_from_slice(&[0; 6]);
+                                    put_u16(b, 1); // データ参照インデックス
+                                    b.extend_from_slice(&[0; 16]);
+                                    put_u16(b, width as u16);
+                                    put_u16(b, height as u16);
+                                    put_u32(b, 0x0048_0000); // 72dpi
+                                    put_u32(b, 0x0048_0000);
+                                    put_u32(b, 0);
+                                    put_u16(b, 1); // フレーム数
+                                    b.extend_from_slice(&[0; 32]); // コンプレッサー名
+                                    put_u16(b, 0x0018); // 色深度
+                                    put_u16(b, 0xffff);
+                                    write_box(b, b"avcC", |b| {
+                                        b.push(1);
+                                        b.extend_from_slice(&sps[1..4]); // プロファイル・互換性・レベル
+                                        b.push(0xff); // NAL長は4バイト
+                                        b.push(0xe1); // SPS 1個
+                                        put_u16(b, sps.len() as u16);
+                                        b.extend_from_slice(sps);
+                                        b.push(1); // PPS 1個
+                                        put_u16(b, pps.len() as u16);
+                                        b.extend_from_slice(pps);
+                                    });
+                                });
+                            });
+                            // サンプル情報はフラグメント側（moof）に書く
+                            for kind in [b"stts", b"stsc", b"stco"] {
+                                write_full_box(b, kind, 0, 0, |b| put_u32(b, 0));
+                            }
+                            write_full_box(b, b"stsz", 0, 0, |b| {
+                                put_u32(b, 0);
+                                put_u32(b, 0);
+                            });
+                        });
+                    });
+                });
+            });
+            write_box(b, b"mvex", |b| {
+                write_full_box(b, b"mehd", 1, 0, |b| {
+                    mehd_offset = b.len() as u64;
+                    put_u64(b, 0);
+                });
+                write_full_box(b, b"trex", 0, 0, |b| {
+                    put_u32(b, 1); // トラックID
+                    put_u32(b, 1); // サンプル記述インデックス
+                    put_u32(b, 0);
+                    put_u32(b, 0);
+                    put_u32(b, 0);
+                });
+            });
+        });
+
+        out.write_all(&buf)?;
+        Ok(Self {
+            out,
+            sequence: 0,
+            decode_time: 0,
+            samples: Vec::new(),
+            mehd_offset,
+            bytes_written: buf.len() as u64,
+        })
+    }
+
+    /// サンプルを追加（キーフレームで新しいフラグメントを始める）
+    /// duration: 次のサンプルまでの時間（タイムスケール単位）
+    pub fn write_sample(&mut self, data: Vec<u8>, duration: u32, keyframe: bool) -> io::Result<()> {
+        if (keyframe && !self.samples.is_empty()) || self.samples.len() >= MAX_FRAGMENT_SAMPLES {
+            self.flush_fragment()?;
+        }
+        self.samples.push(Sample { data, duration, keyframe });
+        Ok(())
+    }
+
+    /// これまでに書いたバイト数（未書き出しのサンプルを含む）
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written + self.samples.iter().map(|s| s.data.len() as u64).sum::<u64>()
+    }
+
+    /// これまでの長さ（タイムスケール単位）
+    pub fn duration(&self) -> u64 {
+        self.decode_time + self.samples.iter().map(|s| s.duration as u64).sum::<u64>()
+    }
+
+    // 溜まったサンプルをmoof + mdatとして書き出す
+    fn flush_fragment(&mut self) -> io::Result<()> {
+        if self.samples.is_empty() {
+            return Ok(());
+        }
+        self.sequence += 1;
+
+        let mut moof = Vec::new();
+        let mut data_offset_pos = 0;
+        write_box(&mut moof, b"moof", |b| {
+            write_full_box(b, b"mfhd", 0, 0, |b| put_u32(b, self.sequence));
+            write_box(b, b"traf", |b| {
+                // default-base-is-moof: データ位置はmoofの先頭から
+                write_full_box(b, b"tfhd", 0, 0x02_0000, |b| put_u32(b, 1));
+                write_full_box(b, b"tfdt", 1, 0, |b| put_u64(b, self.decode_time));
+                // データ位置・長さ・サイズ・フラグをサンプルごとに持つ
+                write_full_box(b, b"trun", 0, 0x000701, |b| {
+                    put_u32(b, self.samples.len() as u32);
+                    data_offset_pos = b.len();
+                    put_u32(b, 0);
+                    for sample in &self.samples {
+                        put_u32(b, sample.duration);
+                        put_u32(b, sample.data.len() as u32);
+                        // キーフレーム以外は「他に依存・同期サンプルでない」
+                        put_u32(b, if sample.keyframe { 0x0200_0000 } else { 0x0101_0000 });
+                    }
+                });
+            });
+        });
+        let data_len: usize = self.samples.iter().map(|s| s.data.len()).sum();
+        let data_offset = (moof.len() + 8) as u32;
+        moof[data_offset_pos..data_offset_pos + 4].copy_from_slice(&data_offset.to_be_bytes());
+
+        self.out.write_all(&moof)?;
+        self.out.write_all(&((data_len + 8) as u32).to_be_bytes())?;
+        self.out.write_all(b"mdat")?;
+        for sample in self.samples.drain(..) {
+            self.decode_time += sample.duration as u64;
+            self.out.write_all(&sample.data)?;
+        }
+        self.bytes_written += (moof.len() + 8 + data_len) as u64;
+        Ok(())
+    }
+
+    /// 残りを書き出して全体の長さを記録（返り値: 長さ（タイムスケール単位））
+    pub fn finish(mut self) -> io::Result<u64> {
+        self.flush_fragment()?;
+        self.out.seek(SeekFrom::Start(self.mehd_offset))?;
+        self.out.write_all(&self.decode_time.to_be_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.decode_time)
+    }
+}
+
+/// 書き出したファイルの長さ（秒）をmehdから読む（記録中・中断したファイルは0）
+pub fn read_duration(mut file: impl Read) -> Option<f64> {
+    let mut head = vec![0u8; 4096];
+    let mut len = 0;
+    while len < head.len() {
+        match file.read(&mut head[len..]).ok()? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    let pos = head[..len].windows(4).position(|w| w == b"mehd")?;
+    let value = head.get(pos + 8..pos + 16)?;
+    let ticks = u64::from_be_bytes(value.try_into().ok()?);
+    Some(ticks as f64 / TIMESCALE as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // トップレベルのボックス種別を順に読む
+    fn top_level_boxes(data: &[u8]) -> Vec<String> {
+        let mut boxes = Vec::new();
+        let mut pos = 0;
+        while pos + 8 <= data.len() {
+            let size = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+            boxes.push(String::from_utf8_lossy(&data[pos + 4..pos + 8]).to_string());
+            assert!(size >= 8 && pos + size <= data.len(), "box size out of range");
+            pos += size;
+        }
+        assert_eq!(pos, data.len());
+        boxes
+    }
+
+    #[test]
+    fn test_fragmented_mp4_layout() {
+        let sps = [0x67, 0x42, 0xc0, 0x1f, 0xda];
+        let pps = [0x68, 0xce, 0x3c, 0x80];
+        let mut cursor = Cursor::new(Vec::new());
+        let mut writer = Mp4Writer::new(&mut cursor, 64, 48, &sps, &pps).unwrap();
+        let frame = TIMESCALE / 30;
+        writer.write_sample(vec![0, 0, 0, 2, 0x65, 0x88], frame, true).unwrap();
+        writer.write_sample(vec![0, 0, 0, 2, 0x41, 0x9a], frame, false).unwrap();
+        // 2つ目のキーフレームで最初のフラグメントが書き出される
+        writer.write_sample(vec![0, 0, 0, 2, 0x65, 0x88], frame * 2, true).unwrap();
+        assert_eq!(writer.duration(), (frame * 4) as u64);
+        assert_eq!(writer.finish().unwrap(), (frame * 4) as u64);
+
+        let out = cursor.into_inner();
+        assert_eq!(top_level_boxes(&out), ["ftyp", "moov", "moof", "mdat", "moof", "mdat"]);
+        // 終了時にmehdへ全体の長さが書き込まれる
+        let seconds = read_duration(Cursor::new(&out)).unwrap();
+        assert!((seconds - 4.0 / 30.0).abs() < 1e-9);
+    }
+}
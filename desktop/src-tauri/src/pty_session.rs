@@ -3,6 +3,7 @@ use std::io::{Read, Write};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use tokio::sync::mpsc;
+use crate::metrics::METRICS;
 
 pub struct PtySession {
     master: Arc<Mutex<Box<dyn MasterPty + Send>>>,
@@ -88,6 +89,7 @@ impl PtySession {
             master,
             output_buffer,
         };
+        METRICS.pty_sessions.inc();
 
         Ok(PtySessionHandle { session, output_rx })
     }
@@ -107,3 +109,9 @@ impl PtySession {
         buffer.join("")
     }
 }
+
+impl Drop for PtySession {
+    fn drop(&mut self) {
+        METRICS.pty_sessions.dec();
+    }
+}
@@ -0,0 +1,389 @@
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::capture::{self, CapturedFrame, FrameLayout, I420Buffer};
+use crate::h264_encoder::{nal_units, H264Encoder};
+use crate::monitors::MonitorSelection;
+use crate::mp4_writer::{self, Mp4Writer, TIMESCALE};
+
+// 録画の既定の上限
+const DEFAULT_MAX_DURATION_SECS: u64 = 60 * 60;
+const DEFAULT_MAX_BYTES: u64 = 2 * 1024 * 1024 * 1024;
+// 録画は回線状況に関係なく一定の品質で行う
+const RECORDING_BITRATE_BPS: u32 = 6_000_000;
+const RECORDING_FPS: f32 = 30.0;
+
+/// 録画の状態
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RecordingStatus {
+    pub recording: bool,
+    pub name: Option<String>,
+    pub duration_secs: f64,
+    pub size_bytes: u64,
+    /// 録画が止まった理由（上限到達・エラー）
+    pub stop_reason: Option<String>,
+}
+
+/// 保存済みの録画
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecordingInfo {
+    pub name: String,
+    pub size_bytes: u64,
+    /// 長さ（秒、録画中や中断したファイルは0）
+    pub duration_secs: f64,
+    /// 作成時刻（UNIX秒）
+    pub created: u64,
+}
+
+// 録画スレッドと共有する進捗
+#[derive(Default)]
+struct Progress {
+    duration_secs: f64,
+    size_bytes: u64,
+    stop_reason: Option<String>,
+}
+
+struct Active {
+    name: String,
+    stop: Arc<AtomicBool>,
+    progress: Arc<Mutex<Progress>>,
+    thread: JoinHandle<()>,
+}
+
+// 同時に録画できるのは1つだけ
+static ACTIVE: Lazy<Mutex<Option<Active>>> = Lazy::new(|| Mutex::new(None));
+
+/// 録画の保存先（ムービーフォルダ、なければアプリのデータディレクトリ）
+pub fn recordings_dir() -> PathBuf {
+    dirs::video_dir()
+        .map(|dir| dir.join("PocketRemote"))
+        .unwrap_or_else(|| crate::app_data_dir().join("recordings"))
+}
+
+/// 録画を開始（上限を省略した場合は1時間・2GB）
+pub fn start(selection: &MonitorSelection, max_duration_secs: Option<u64>, max_bytes: Option<u64>) -> Result<RecordingStatus, String> {
+    let mut active = ACTIVE.lock();
+    if active.as_ref().is_some_and(|a| !a.thread.is_finished()) {
+        return Err("Recording already in progress".to_string());
+    }
+
+    let dir = recordings_dir();
+    std::fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    let created = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let name = format!("recording-{}.mp4", created);
+    let path = dir.join(&name);
+    let file = File::create(&path).map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+
+    let limits = Limits {
+        max_duration: Duration::from_secs(max_duration_secs.unwrap_or(DEFAULT_MAX_DURATION_SECS)),
+        max_bytes: max_bytes.unwrap_or(DEFAULT_MAX_BYTES),
+    };
+    println!("[Recording] Started {} for {:?} (max {:?}, {} MB)",
+        path.display(), selection, limits.max_duration, limits.max_bytes / 1024 / 1024);
+    let stop = Arc::new(AtomicBool::new(false));
+    let progress = Arc::new(Mutex::new(Progress::default()));
+    let thread = {
+        let (selection, stop, progress) = (selection.clone(), stop.clone(), progress.clone());
+        std::thread::spawn(move || {
+            let capture = capture::subscribe(&selection);
+            let next_frame = |after| capture.next_frame(after, Duration::from_millis(500));
+            let result = record(next_frame, file, &limits, &stop, &progress);
+            if let Err(e) = &result {
+                eprintln!("[Recording] {}", e);
+            }
+            let mut progress = progress.lock();
+            if progress.stop_reason.is_none() {
+                progress.stop_reason = result.err();
+            }
+        })
+    };
+    *active = Some(Active { name, stop, progress, thread });
+    Ok(status_of(active.as_ref()))
+}
+
+/// 録画を止めてファイルを閉じる（書き出しが終わるまで待つ）
+pub fn stop() -> Result<RecordingStatus, String> {
+    let Some(active) = ACTIVE.lock().take() else {
+        return Err("Not recording".to_string());
+    };
+    active.stop.store(true, Ordering::SeqCst);
+    let Active { name, progress, thread, .. } = active;
+    thread.join().map_err(|_| "Recording thread panicked".to_string())?;
+
+    let progress = progress.lock();
+    println!("[Recording] Stopped {} ({:.1}s, {} KB)", name, progress.duration_secs, progress.size_bytes / 1024);
+    Ok(RecordingStatus {
+        recording: false,
+        name: Some(name),
+        duration_secs: progress.duration_secs,
+        size_bytes: progress.size_bytes,
+        stop_reason: progress.stop_reason.clone(),
+    })
+}
+
+/// 現在の録画状態（上限で止まった場合は recording: false と理由）
+pub fn status() -> RecordingStatus {
+    status_of(ACTIVE.lock().as_ref())
+}
+
+fn status_of(active: Option<&Active>) -> RecordingStatus {
+    let Some(active) = active else {
+        return RecordingStatus::default();
+    };
+    let progress = active.progress.lock();
+    RecordingStatus {
+        recording: !active.thread.is_finished(),
+        name: Some(active.name.clone()),
+        duration_secs: progress.duration_secs,
+        size_bytes: progress.size_bytes,
+        stop_reason: progress.stop_reason.clone(),
+    }
+}
+
+/// 保存済みの録画一覧（新しい順）
+pub fn list() -> Vec<RecordingInfo> {
+    let Ok(entries) = std::fs::read_dir(recordings_dir()) else {
+        return Vec::new();
+    };
+    let mut recordings: Vec<RecordingInfo> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !name.ends_with(".mp4") {
+                return None;
+            }
+            let metadata = entry.metadata().ok()?;
+            let created = metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let duration_secs = File::open(entry.path()).ok().and_then(mp4_writer::read_duration).unwrap_or(0.0);
+            Some(RecordingInfo { name, size_bytes: metadata.len(), duration_secs, created })
+        })
+        .collect();
+    recordings.sort_by(|a, b| b.created.cmp(&a.created).then_with(|| b.name.cmp(&a.name)));
+    recordings
+}
+
+/// 録画ファイルのパス（名前にパス区切りを含むものは拒否）
+pub fn path_of(name: &str) -> Result<PathBuf, String> {
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') || !name.ends_with(".mp4") {
+        return Err(format!("Invalid recording name: {}", name));
+    }
+    let path = recordings_dir().join(name);
+    if !path.is_file() {
+        return Err(format!("Recording not found: {}", name));
+    }
+    Ok(path)
+}
+
+struct Limits {
+    max_duration: Duration,
+    max_bytes: u64,
+}
+
+// 録画は領域指定に関係なくモニター全体を論理解像度で記録する（途中でサイズは変えない）
+fn recording_layout(frame: &CapturedFrame) -> FrameLayout {
+    let width = ((frame.width as f32 / frame.scale_factor) as u32 & !1).max(2);
+    let height = ((frame.height as f32 / frame.scale_factor) as u32 & !1).max(2);
+    FrameLayout {
+        crop_x: 0,
+        crop_y: 0,
+        crop_width: frame.width,
+        crop_height: frame.height,
+        logical_pixels: width * height,
+        width,
+        height,
+    }
+}
+
+fn ticks(duration: Duration) -> u32 {
+    ((duration.as_secs_f64() * TIMESCALE as f64) as u32).max(1)
+}
+
+// 録画スレッド: キャプチャサービスのフレームを専用のエンコーダーでMP4に書き出す
+// 画面に変化がない間はエンコードせず、直前のフレームの表示時間を延ばす
+// `next_frame` は指定したキャプチャ番号より新しいフレームを待つ（タイムアウトでNone）
+fn record(
+    mut next_frame: impl FnMut(u64) -> Option<Arc<CapturedFrame>>,
+    file: File,
+    limits: &Limits,
+    stop: &AtomicBool,
+    progress: &Mutex<Progress>,
+) -> Result<(), String> {
+    let mut file = Some(BufWriter::new(file));
+    let mut writer: Option<Mp4Writer<BufWriter<File>>> = None;
+    let mut encoder: Option<H264Encoder> = None;
+    let mut layout: Option<FrameLayout> = None;
+    let mut yuv = I420Buffer::default();
+    // 書き出し待ちのサンプル（次のフレームが来た時点で長さが決まる）
+    let mut pending: Option<(Vec<u8>, bool, Instant)> = None;
+    let mut seq = 0;
+    let mut content_id = None;
+    let started = Instant::now();
+
+    while !stop.load(Ordering::SeqCst) {
+        if started.elapsed() >= limits.max_duration {
+            progress.lock().stop_reason = Some("max_duration".to_string());
+            break;
+        }
+        if writer.as_ref().is_some_and(|w| w.bytes_written() >= limits.max_bytes) {
+            progress.lock().stop_reason = Some("max_size".to_string());
+            break;
+        }
+
+        let Some(frame) = next_frame(seq) else {
+            continue;
+        };
+        seq = frame.seq;
+        if content_id == Some(frame.content_id) {
+            continue;
+        }
+        content_id = Some(frame.content_id);
+        let now = Instant::now();
+
+        let frame_layout = recording_layout(&frame);
+        match layout {
+            None => layout = Some(frame_layout),
+            Some(l) if (l.width, l.height) != (frame_layout.width, frame_layout.height) => {
+                progress.lock().stop_reason = Some("resolution_changed".to_string());
+                break;
+            }
+            Some(_) => {}
+        }
+        let encoder = match encoder.as_mut() {
+            Some(encoder) => encoder,
+            None => encoder.insert(H264Encoder::with_rate(frame_layout.width, frame_layout.height, RECORDING_BITRATE_BPS, RECORDING_FPS)?),
+        };
+        capture::crop_scale_i420(&frame, &frame_layout, &mut yuv);
+        let annexb = encoder.encode_i420(&yuv)?;
+
+        // SPS/PPSはヘッダー（avcC）へ、それ以外はAVCC形式のサンプルにする
+        let (mut sps, mut pps, mut sample, mut keyframe) = (None, None, Vec::new(), false);
+        for nal in nal_units(&annexb) {
+            match nal[0] & 0x1F {
+                7 => sps = Some(nal),
+                8 => pps = Some(nal),
+                9 => {} // アクセスユニット区切り
+                nal_type => {
+                    keyframe |= nal_type == 5;
+                    sample.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+                    sample.extend_from_slice(nal);
+                }
+            }
+        }
+        if sample.is_empty() {
+            continue;
+        }
+
+        if writer.is_none() {
+            // 最初のキーフレームのSPS/PPSでヘッダーを書く
+            let (Some(sps), Some(pps), true) = (sps, pps, keyframe) else {
+                encoder.force_keyframe()?;
+                continue;
+            };
+            let out = file.take().ok_or("Recording file already closed")?;
+            writer = Some(
+                Mp4Writer::new(out, frame_layout.width, frame_layout.height, sps, pps)
+                    .map_err(|e| format!("Failed to write MP4 header: {}", e))?,
+            );
+        }
+        let Some(w) = writer.as_mut() else { continue };
+
+        if let Some((data, key, time)) = pending.take() {
+            w.write_sample(data, ticks(now - time), key).map_err(|e| format!("Failed to write sample: {}", e))?;
+        }
+        pending = Some((sample, keyframe, now));
+
+        let mut p = progress.lock();
+        p.duration_secs = w.duration() as f64 / TIMESCALE as f64;
+        p.size_bytes = w.bytes_written();
+    }
+
+    let Some(mut w) = writer else {
+        return Ok(());
+    };
+    if let Some((data, key, time)) = pending.take() {
+        // 最後のフレームは停止時刻まで表示する
+        let duration = ticks(time.elapsed().max(Duration::from_secs_f32(1.0 / RECORDING_FPS)));
+        w.write_sample(data, duration, key).map_err(|e| format!("Failed to write sample: {}", e))?;
+    }
+    let size = w.bytes_written();
+    let duration = w.finish().map_err(|e| format!("Failed to finish MP4: {}", e))?;
+    let mut p = progress.lock();
+    p.duration_secs = duration as f64 / TIMESCALE as f64;
+    p.size_bytes = size;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitors::Rect;
+
+    #[test]
+    fn test_path_of_rejects_unsafe_names() {
+        for name in ["../x.mp4", "a/b.mp4", "a\\b.mp4", ".hidden.mp4", "", "recording", "recording.mov", "x.mp4.txt"] {
+            let err = path_of(name).unwrap_err();
+            assert!(err.starts_with("Invalid recording name"), "{:?}: {}", name, err);
+        }
+        // 正しい名前でもファイルがなければ見つからない
+        let err = path_of("recording-0.mp4").unwrap_err();
+        assert!(err.starts_with("Recording not found"), "{}", err);
+    }
+
+    // 毎回内容が変わる64x64のフレームを返すキャプチャの代わり
+    fn changing_frames() -> impl FnMut(u64) -> Option<Arc<CapturedFrame>> {
+        move |after| {
+            std::thread::sleep(Duration::from_millis(5));
+            let seq = after + 1;
+            Some(Arc::new(CapturedFrame {
+                data: (0..64 * 64 * 4).map(|i| (i as u64 * 7 + seq * 13) as u8).collect(),
+                width: 64,
+                height: 64,
+                stride: 64 * 4,
+                bounds: Rect { x: 0, y: 0, width: 64, height: 64 },
+                scale_factor: 1.0,
+                seq,
+                content_id: seq,
+            }))
+        }
+    }
+
+    fn record_until_limit(test: &str, limits: Limits) -> (Progress, u64) {
+        let path = std::env::temp_dir().join(format!("pocket-remote-{}-{}.mp4", test, std::process::id()));
+        let stop = AtomicBool::new(false);
+        let progress = Mutex::new(Progress::default());
+        record(changing_frames(), File::create(&path).unwrap(), &limits, &stop, &progress).unwrap();
+        let size = std::fs::metadata(&path).unwrap().len();
+        std::fs::remove_file(&path).ok();
+        (progress.into_inner(), size)
+    }
+
+    #[test]
+    fn test_record_stops_at_max_duration() {
+        let limits = Limits { max_duration: Duration::from_millis(300), max_bytes: DEFAULT_MAX_BYTES };
+        let (progress, size) = record_until_limit("duration", limits);
+        assert_eq!(progress.stop_reason.as_deref(), Some("max_duration"));
+        assert!(progress.duration_secs > 0.0);
+        assert!(progress.size_bytes > 0 && progress.size_bytes <= size);
+    }
+
+    #[test]
+    fn test_record_stops_at_max_size() {
+        let limits = Limits { max_duration: Duration::from_secs(30), max_bytes: 4096 };
+        let (progress, size) = record_until_limit("size", limits);
+        assert_eq!(progress.stop_reason.as_deref(), Some("max_size"));
+        assert!(size >= 4096);
+    }
+}
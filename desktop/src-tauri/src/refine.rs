@@ -0,0 +1,197 @@
+use std::time::{Duration, Instant};
+use rayon::prelude::*;
+
+use crate::CaptureRegion;
+use crate::capture::{self, CapturedFrame, FrameLayout};
+use crate::codec::{self, Codec, TileFormat, TileRect};
+
+/// 静止画面の高画質化パケット
+/// [0x06, 形式(0=JPEG, 1=PNG, 2=WebP), 段階, refine_id(2),
+///  ビューポートx(2), y(2), 幅(2), 高さ(2)（領域内の論理座標）,
+///  画像幅(2), 画像高さ(2), タイルx(2), y(2), 幅(2), 高さ(2)] + 画像
+pub const PACKET_REFINE: u8 = 0x06;
+
+// 動きが止まってから最初の高画質化までの待ち時間
+const REFINE_DELAY: Duration = Duration::from_millis(300);
+// 段階ごとの間隔
+const LEVEL_INTERVAL: Duration = Duration::from_millis(250);
+// 高画質化する画像の最大ピクセル数（超える場合は縮小）
+const MAX_REFINE_PIXELS: usize = 4_000_000;
+
+/// 高画質化の段階
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RefineLevel {
+    Lossy(u8),
+    Lossless,
+}
+
+// 低い段階から順に送る
+const LEVELS: [RefineLevel; 3] = [RefineLevel::Lossy(85), RefineLevel::Lossy(95), RefineLevel::Lossless];
+
+/// 静止を検出して、表示中のビューポートを段階的に可逆まで高画質化する
+#[derive(Default)]
+pub struct Refiner {
+    static_since: Option<Instant>,
+    last_sent: Option<Instant>,
+    // 送信済みの段階数
+    level: usize,
+    refine_id: u16,
+}
+
+impl Refiner {
+    /// 動きがあった（通常のエンコードに戻る）
+    pub fn reset(&mut self) {
+        self.static_since = None;
+        self.last_sent = None;
+        self.level = 0;
+    }
+
+    /// 静止後に高画質化を送信済みか（定期キーフレームで上書きしないため）
+    pub fn refined(&self) -> bool {
+        self.level > 0
+    }
+
+    /// 次に送る段階（まだ送る時期でなければNone）
+    /// スクロール中（quality_mode = "low"）は高画質化しない
+    pub fn next_level(&mut self, region: Option<&CaptureRegion>) -> Option<(u8, RefineLevel)> {
+        if region.is_some_and(|r| r.quality_mode == "low") {
+            self.reset();
+            return None;
+        }
+        let now = Instant::now();
+        let static_since = *self.static_since.get_or_insert(now);
+        if now - static_since < REFINE_DELAY || self.level >= LEVELS.len() {
+            return None;
+        }
+        if self.last_sent.is_some_and(|t| now - t < LEVEL_INTERVAL) {
+            return None;
+        }
+        self.last_sent = Some(now);
+        self.level += 1;
+        Some((self.level as u8, LEVELS[self.level - 1]))
+    }
+
+    /// ビューポートをネイティブ解像度で切り抜いてタイルパケットにする
+    /// 可逆の形式は、PNGモードならPNG、それ以外はWebP
+    pub fn encode(
+        &mut self,
+        frame: &CapturedFrame,
+        region: Option<&CaptureRegion>,
+        (level_id, level): (u8, RefineLevel),
+        mode: Codec,
+    ) -> Result<Vec<Vec<u8>>, String> {
+        let (viewport, layout) = viewport_layout(frame, region);
+        if layout.crop_width == 0 || layout.crop_height == 0 {
+            return Ok(Vec::new());
+        }
+        let img = capture::crop_scale_rgba(frame, &layout);
+
+        let (format, format_id) = match level {
+            RefineLevel::Lossy(quality) => (TileFormat::Jpeg(quality), 0),
+            RefineLevel::Lossless if mode == Codec::Png => (TileFormat::Png, 1),
+            RefineLevel::Lossless => (TileFormat::Webp, 2),
+        };
+        self.refine_id = self.refine_id.wrapping_add(1);
+        let refine_id = self.refine_id;
+        let (width, height) = img.dimensions();
+        let header = |(x, y, w, h): TileRect| {
+            let mut packet = vec![PACKET_REFINE, format_id, level_id];
+            packet.extend_from_slice(&refine_id.to_be_bytes());
+            for value in viewport {
+                packet.extend_from_slice(&(value.clamp(0, u16::MAX as i32) as u16).to_be_bytes());
+            }
+            for value in [width, height, x, y, w, h] {
+                packet.extend_from_slice(&(value as u16).to_be_bytes());
+            }
+            packet
+        };
+
+        let packets: Vec<Vec<Vec<u8>>> = codec::tile_rects(width, height)
+            .par_iter()
+            .map(|&rect| {
+                let mut out = Vec::new();
+                codec::encode_tile(&img, rect, format, &header, &mut out).map(|_| out)
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(packets.into_iter().flatten().collect())
+    }
+}
+
+// ビューポート（領域内の論理座標）と、それをネイティブ解像度で切り抜くレイアウト
+fn viewport_layout(frame: &CapturedFrame, region: Option<&CaptureRegion>) -> ([i32; 4], FrameLayout) {
+    let scale = frame.scale_factor;
+    // 仮想デスクトップ上の論理座標
+    let (origin_x, origin_y, viewport) = match region {
+        Some(r) => (r.x, r.y, [r.viewport_x, r.viewport_y, r.viewport_width, r.viewport_height]),
+        None => (
+            frame.bounds.x,
+            frame.bounds.y,
+            [0, 0, (frame.width as f32 / scale) as i32, (frame.height as f32 / scale) as i32],
+        ),
+    };
+    let [vx, vy, vw, vh] = viewport;
+    let (crop_x, crop_y, crop_width, crop_height) =
+        capture::native_rect(frame.bounds, scale, (frame.width, frame.height), [origin_x + vx, origin_y + vy, vw, vh]);
+
+    // 大きすぎる場合だけ縮小（それ以外はネイティブ解像度のまま）
+    let pixels = crop_width * crop_height;
+    let shrink = if pixels > MAX_REFINE_PIXELS { (MAX_REFINE_PIXELS as f32 / pixels as f32).sqrt() } else { 1.0 };
+    let layout = FrameLayout {
+        crop_x,
+        crop_y,
+        crop_width,
+        crop_height,
+        logical_pixels: (vw.max(0) * vh.max(0)) as u32,
+        width: ((crop_width as f32 * shrink) as u32).max(1),
+        height: ((crop_height as f32 * shrink) as u32).max(1),
+    };
+    (viewport, layout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitors::Rect;
+
+    #[test]
+    fn test_refine_viewport_native_resolution() {
+        // 論理200x100、倍率2のフレーム
+        let frame = CapturedFrame {
+            data: vec![255; 400 * 200 * 4],
+            width: 400,
+            height: 200,
+            stride: 400 * 4,
+            bounds: Rect { x: 0, y: 0, width: 200, height: 100 },
+            scale_factor: 2.0,
+            seq: 1,
+            content_id: 1,
+        };
+        let region = CaptureRegion {
+            x: 20,
+            y: 10,
+            width: 100,
+            height: 80,
+            viewport_x: 10,
+            viewport_y: 0,
+            viewport_width: 50,
+            viewport_height: 40,
+            quality_mode: "high".to_string(),
+            roi: false,
+        };
+
+        let mut refiner = Refiner::default();
+        let packets = refiner.encode(&frame, Some(&region), (3, RefineLevel::Lossless), Codec::Jpeg).unwrap();
+        assert_eq!(packets.len(), 1);
+        let p = &packets[0];
+        assert_eq!(&p[..3], &[PACKET_REFINE, 2, 3]);
+        // ビューポート (10, 0, 50, 40)、画像はネイティブの100x80
+        let field = |i: usize| u16::from_be_bytes([p[5 + i * 2], p[6 + i * 2]]);
+        assert_eq!([field(0), field(1), field(2), field(3)], [10, 0, 50, 40]);
+        assert_eq!([field(4), field(5)], [100, 80]);
+
+        // スクロール中は高画質化しない
+        let low = CaptureRegion { quality_mode: "low".to_string(), ..region };
+        assert_eq!(refiner.next_level(Some(&low)), None);
+        assert!(!refiner.refined());
+    }
+}
@@ -0,0 +1,118 @@
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter};
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::tunnel::{self, TunnelStopped};
+use crate::AppState;
+
+// 再接続のバックオフ（1秒から倍々、最大60秒）
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(60);
+// 制御接続のキープアライブ間隔（プロキシのアイドル切断対策）
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// リレーからの制御メッセージ
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RelayNotice {
+    Incoming { session: String },
+}
+
+/// リレーのベースURLを正規化（http(s)もws(s)として扱う）
+pub fn normalize_base_url(url: &str) -> String {
+    tunnel::websocket_url(url.trim().trim_end_matches('/'))
+}
+
+/// スマホが接続するURL（QRコードに載せる）
+pub fn connect_url(base: &str, desktop_id: &str) -> String {
+    format!("{}/connect/{}", base, desktop_id)
+}
+
+/// リレークライアント: 制御接続を維持し、着信ごとに受け入れ用の接続を張る
+pub async fn run(base: String, desktop_id: String, state: Arc<AppState>, app_handle: AppHandle) {
+    let mut attempt: u32 = 0;
+
+    loop {
+        let reason = match serve_control(&base, &desktop_id, &state, &app_handle, &mut attempt).await {
+            Ok(()) => "relay closed the connection".to_string(),
+            Err(e) => e,
+        };
+
+        let delay = Duration::from_secs(1u64 << attempt.min(6)).min(RECONNECT_BACKOFF_MAX);
+        attempt = attempt.saturating_add(1);
+        eprintln!("[Relay] {}; reconnecting in {:?}", reason, delay);
+
+        *state.tunnel_info.write() = None;
+        app_handle
+            .emit(
+                "tunnel_stopped",
+                &TunnelStopped {
+                    provider: "relay".to_string(),
+                    reason,
+                    restarting: true,
+                    retry_in_secs: Some(delay.as_secs()),
+                },
+            )
+            .ok();
+
+        tokio::time::sleep(delay).await;
+    }
+}
+
+async fn serve_control(
+    base: &str,
+    desktop_id: &str,
+    state: &Arc<AppState>,
+    app_handle: &AppHandle,
+    attempt: &mut u32,
+) -> Result<(), String> {
+    let (ws, _) = connect_async(format!("{}/desktop/{}", base, desktop_id))
+        .await
+        .map_err(|e| format!("Failed to connect to relay: {}", e))?;
+    println!("[Relay] Registered at {}", base);
+    *attempt = 0;
+
+    crate::publish_tunnel_url(state, app_handle, "relay", connect_url(base, desktop_id), None);
+
+    let (mut write, mut read) = ws.split();
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = ping.tick() => {
+                write.send(Message::Ping(Vec::new())).await.map_err(|e| e.to_string())?;
+            }
+            msg = read.next() => {
+                let text = match msg {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(e.to_string()),
+                };
+                match serde_json::from_str::<RelayNotice>(&text) {
+                    Ok(RelayNotice::Incoming { session }) => {
+                        accept_session(base, desktop_id, session, state.clone(), app_handle.clone());
+                    }
+                    Err(e) => eprintln!("[Relay] Unknown control message: {}", e),
+                }
+            }
+        }
+    }
+}
+
+// 着信セッションを受け入れ、通常のWebSocketセッションとして処理する
+fn accept_session(base: &str, desktop_id: &str, session: String, state: Arc<AppState>, app_handle: AppHandle) {
+    let url = format!("{}/accept/{}/{}", base, desktop_id, session);
+    tokio::spawn(async move {
+        match connect_async(url).await {
+            Ok((ws, _)) => {
+                println!("[Relay] Accepted session {}", session);
+                crate::serve_connection(ws, format!("relay:{}", session), state, app_handle).await;
+            }
+            Err(e) => eprintln!("[Relay] Failed to accept session {}: {}", session, e),
+        }
+    });
+}
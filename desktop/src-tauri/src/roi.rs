@@ -0,0 +1,241 @@
+use std::time::{Duration, Instant};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use image::codecs::jpeg::JpegEncoder;
+use image::{ExtendedColorType, ImageEncoder};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::CaptureRegion;
+use crate::adaptive::StreamTargets;
+use crate::capture::{self, CapturedFrame, FrameLayout};
+use crate::client_display::ClientDisplay;
+use crate::codec::{self, TileFormat, TileRect};
+
+/// ビューポート優先エンコードの付随パケット（各フレームの前に送る）
+/// [0x07, 周辺あり, ビューポートx(2), y(2), 幅(2), 高さ(2), 領域幅(2), 高さ(2)（論理座標）,
+///  周辺画像幅(2), 高さ(2), タイルx(2), y(2), 幅(2), 高さ(2)] + 周辺のJPEG（なければタイルは0）
+pub const PACKET_ROI: u8 = 0x07;
+
+// ビューポートが領域のこの割合未満の時だけ使う（ほぼ全体なら通常のエンコード）
+const MAX_VIEWPORT_RATIO: f32 = 0.8;
+// 周辺画像の縮小率（通常の送信サイズに対して）
+const SURROUND_SCALE: f32 = 0.25;
+const SURROUND_QUALITY: u8 = 50;
+// 変化があっても周辺画像はこの間隔より頻繁には送らない
+const SURROUND_INTERVAL: Duration = Duration::from_millis(500);
+
+/// ビューポート（高解像度）と周辺（低解像度）の送信範囲
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RoiLayout {
+    /// メインのストリーム（ビューポートのみ）
+    pub viewport: FrameLayout,
+    /// 領域全体の縮小画像
+    pub surround: FrameLayout,
+    /// ビューポート (x, y, 幅, 高さ)（領域内の論理座標）
+    pub rect: [i32; 4],
+    /// 領域の論理サイズ
+    pub region_size: (i32, i32),
+}
+
+/// WebSocketでフレームの直前に送るビューポート情報（論理座標）
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RoiInfo {
+    pub viewport_x: i32,
+    pub viewport_y: i32,
+    pub viewport_width: i32,
+    pub viewport_height: i32,
+    pub region_width: i32,
+    pub region_height: i32,
+    /// 領域全体の縮小JPEG（Base64、送る時だけ）
+    pub surround: Option<String>,
+}
+
+impl RoiInfo {
+    pub fn new(roi: &RoiLayout, surround_jpeg: Option<&[u8]>) -> Self {
+        let [viewport_x, viewport_y, viewport_width, viewport_height] = roi.rect;
+        Self {
+            viewport_x,
+            viewport_y,
+            viewport_width,
+            viewport_height,
+            region_width: roi.region_size.0,
+            region_height: roi.region_size.1,
+            surround: surround_jpeg.map(|jpeg| STANDARD.encode(jpeg)),
+        }
+    }
+}
+
+/// クライアントがビューポート優先を有効にし、領域の一部を拡大表示している時のレイアウト
+/// ビューポートには領域全体に使うはずだったピクセル数を割り当てる（元の解像度は超えない）
+pub fn roi_layout(
+    frame: &CapturedFrame,
+    region: Option<&CaptureRegion>,
+    targets: &StreamTargets,
+    display: Option<&ClientDisplay>,
+) -> Option<RoiLayout> {
+    let r = region.filter(|r| r.roi)?;
+    let rect = [
+        r.viewport_x.clamp(0, r.width),
+        r.viewport_y.clamp(0, r.height),
+        r.viewport_width.min(r.width - r.viewport_x.clamp(0, r.width)),
+        r.viewport_height.min(r.height - r.viewport_y.clamp(0, r.height)),
+    ];
+    let [vx, vy, vw, vh] = rect;
+    if vw <= 0 || vh <= 0 || (vw * vh) as f32 >= (r.width * r.height) as f32 * MAX_VIEWPORT_RATIO {
+        return None;
+    }
+
+    let full = capture::layout(frame, Some(r), targets, display);
+    let viewport_region = CaptureRegion { x: r.x + vx, y: r.y + vy, width: vw, height: vh, ..r.clone() };
+    let mut viewport = capture::layout(frame, Some(&viewport_region), targets, display);
+    if viewport.crop_width == 0 || viewport.crop_height == 0 {
+        return None;
+    }
+    let budget = (full.width * full.height) as f32;
+    let native = (viewport.crop_width * viewport.crop_height) as f32;
+    let scale = (budget / native).sqrt().min(1.0);
+    // YUV420のため偶数に揃える
+    viewport.width = (((viewport.crop_width as f32 * scale) as u32) & !1).max(2);
+    viewport.height = (((viewport.crop_height as f32 * scale) as u32) & !1).max(2);
+
+    let surround = FrameLayout {
+        width: (((full.width as f32 * SURROUND_SCALE) as u32) & !1).max(2),
+        height: (((full.height as f32 * SURROUND_SCALE) as u32) & !1).max(2),
+        ..full
+    };
+    Some(RoiLayout { viewport, surround, rect, region_size: (r.width, r.height) })
+}
+
+/// 周辺画像の送信判定（キーフレーム時・レイアウト変更時は必ず、変化があれば間隔を空けて送る）
+pub struct SurroundEncoder {
+    content_id: Option<u64>,
+    layout: Option<RoiLayout>,
+    dirty: bool,
+    last_sent: Instant,
+}
+
+impl Default for SurroundEncoder {
+    fn default() -> Self {
+        Self {
+            content_id: None,
+            layout: None,
+            dirty: true,
+            last_sent: Instant::now(),
+        }
+    }
+}
+
+impl SurroundEncoder {
+    /// 今回のフレームで周辺画像を送るか
+    pub fn due(&mut self, frame: &CapturedFrame, roi: &RoiLayout, keyframe: bool) -> bool {
+        if self.content_id != Some(frame.content_id) {
+            self.content_id = Some(frame.content_id);
+            self.dirty = true;
+        }
+        let moved = self.layout != Some(*roi);
+        self.layout = Some(*roi);
+        if keyframe || moved || (self.dirty && self.last_sent.elapsed() >= SURROUND_INTERVAL) {
+            self.dirty = false;
+            self.last_sent = Instant::now();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// 周辺画像を1枚のJPEGにする（WebSocket用）
+    pub fn encode_jpeg(frame: &CapturedFrame, roi: &RoiLayout) -> Result<Vec<u8>, String> {
+        let img = capture::crop_scale_rgba(frame, &roi.surround);
+        let rgb: Vec<u8> = img.as_raw().chunks_exact(4).flat_map(|px| [px[0], px[1], px[2]]).collect();
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, SURROUND_QUALITY)
+            .write_image(&rgb, img.width(), img.height(), ExtendedColorType::Rgb8)
+            .map_err(|e| format!("Surround encode error: {}", e))?;
+        Ok(jpeg)
+    }
+
+    /// Data Channel用のパケット（周辺画像がなければヘッダーのみの1パケット）
+    pub fn packets(frame: &CapturedFrame, roi: &RoiLayout, with_surround: bool) -> Result<Vec<Vec<u8>>, String> {
+        let (surround_w, surround_h) = if with_surround { (roi.surround.width, roi.surround.height) } else { (0, 0) };
+        let header = |(x, y, w, h): TileRect| {
+            let mut packet = vec![PACKET_ROI, with_surround as u8];
+            let [vx, vy, vw, vh] = roi.rect;
+            for value in [vx, vy, vw, vh, roi.region_size.0, roi.region_size.1] {
+                packet.extend_from_slice(&(value.clamp(0, u16::MAX as i32) as u16).to_be_bytes());
+            }
+            for value in [surround_w, surround_h, x, y, w, h] {
+                packet.extend_from_slice(&(value as u16).to_be_bytes());
+            }
+            packet
+        };
+        if !with_surround {
+            return Ok(vec![header((0, 0, 0, 0))]);
+        }
+
+        let img = capture::crop_scale_rgba(frame, &roi.surround);
+        let packets: Vec<Vec<Vec<u8>>> = codec::tile_rects(img.width(), img.height())
+            .par_iter()
+            .map(|&rect| {
+                let mut out = Vec::new();
+                codec::encode_tile(&img, rect, TileFormat::Jpeg(SURROUND_QUALITY), &header, &mut out).map(|_| out)
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(packets.into_iter().flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adaptive::RateController;
+    use crate::monitors::Rect;
+
+    fn frame() -> CapturedFrame {
+        // 論理1000x800、倍率2
+        CapturedFrame {
+            data: vec![0; 2000 * 1600 * 4],
+            width: 2000,
+            height: 1600,
+            stride: 2000 * 4,
+            bounds: Rect { x: 0, y: 0, width: 1000, height: 800 },
+            scale_factor: 2.0,
+            seq: 1,
+            content_id: 1,
+        }
+    }
+
+    fn region(roi: bool, viewport: [i32; 4]) -> CaptureRegion {
+        CaptureRegion {
+            x: 100,
+            y: 0,
+            width: 800,
+            height: 800,
+            viewport_x: viewport[0],
+            viewport_y: viewport[1],
+            viewport_width: viewport[2],
+            viewport_height: viewport[3],
+            quality_mode: "high".to_string(),
+            roi,
+        }
+    }
+
+    #[test]
+    fn test_roi_layout() {
+        let frame = frame();
+        let targets = RateController::default().targets();
+
+        // 無効、またはビューポートが領域のほぼ全体なら通常のエンコード
+        assert_eq!(roi_layout(&frame, Some(&region(false, [0, 0, 200, 200])), &targets, None), None);
+        assert_eq!(roi_layout(&frame, Some(&region(true, [0, 0, 800, 760])), &targets, None), None);
+
+        let roi = roi_layout(&frame, Some(&region(true, [200, 100, 200, 200])), &targets, None).unwrap();
+        assert_eq!(roi.rect, [200, 100, 200, 200]);
+        // ビューポートはネイティブ座標で切り抜き、論理サイズより高解像度で送る
+        assert_eq!((roi.viewport.crop_x, roi.viewport.crop_y), (600, 200));
+        assert_eq!((roi.viewport.width, roi.viewport.height), (400, 400));
+        // 周辺は領域全体の縮小
+        let full = capture::layout(&frame, Some(&region(true, [0, 0, 800, 800])), &targets, None);
+        assert_eq!(roi.surround.crop_width, full.crop_width);
+        assert!(roi.surround.width < full.width);
+    }
+}
@@ -1,29 +1,239 @@
-use xcap::Monitor;
-use tokio::sync::broadcast;
-use image::{ImageBuffer, Rgba, DynamicImage, RgbaImage};
 use std::time::Duration;
-use serde::{Serialize, Deserialize};
 use parking_lot::RwLock;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
-use rayon::prelude::*;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use crate::CaptureRegion;
-use crate::h264_encoder::H264Encoder;
+use crate::client_display::{ClientDisplay, OutputResolution};
+use crate::capture::{self, I420Buffer};
+use crate::cursor::{self, CursorState};
+use crate::damage::{FrameAction, FrameGate};
+use crate::adaptive::{RateController, StreamStats, StreamTargets};
+use crate::h264_encoder::{H264Encoder, contains_idr};
+use crate::frame_bus::{Frame, FrameBus, FrameSubscriber, SubscriberStats};
+use bytes::Bytes;
+use crate::metrics::METRICS;
+use crate::monitors::{MonitorCapturer, MonitorSelection};
+use crate::roi::{self, RoiInfo, SurroundEncoder};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::Instant;
 
-// キーフレーム強制フラグ（新しいクライアントが接続した時に使用）
-static WS_FORCE_KEYFRAME: AtomicBool = AtomicBool::new(false);
+/// パイプラインの送信設定（同じ設定の接続だけがエンコーダーを共有する）
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct PipelineSettings {
+    targets: StreamTargets,
+    // クライアントが申告した表示環境（Noneは旧クライアントの規則）
+    display: Option<ClientDisplay>,
+    // カーソルをフレームに焼き込むか
+    cursor_burn_in: bool,
+}
+
+/// キャプチャパイプライン（購読者がいる間だけスレッドが動く）
+/// モニター・キャプチャ領域・送信設定がすべて同じ接続だけが、エンコーダーを共有する
+struct Pipeline {
+    selection: MonitorSelection,
+    capture_region: Arc<RwLock<Option<CaptureRegion>>>,
+    // エンコードスレッドが毎フレーム読む
+    settings: Arc<RwLock<PipelineSettings>>,
+    bus: Arc<FrameBus>,
+    // キーフレーム強制フラグ（新しいクライアントが接続した時に使用）
+    force_keyframe: Arc<AtomicBool>,
+}
+
+impl Pipeline {
+    fn matches(
+        &self,
+        selection: &MonitorSelection,
+        capture_region: &Arc<RwLock<Option<CaptureRegion>>>,
+        settings: &PipelineSettings,
+    ) -> bool {
+        self.selection == *selection
+            && Arc::ptr_eq(&self.capture_region, capture_region)
+            && *self.settings.read() == *settings
+    }
+}
+
+static PIPELINES: Lazy<Mutex<HashMap<u64, Pipeline>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_PIPELINE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// 選択したモニターの全パイプラインにキーフレームを要求（画面切り替え用）
+/// 他のモニターを見ている接続のGOPには影響しない
+pub fn request_keyframe(selection: &MonitorSelection) {
+    for pipeline in PIPELINES.lock().values().filter(|p| p.selection == *selection) {
+        pipeline.force_keyframe.store(true, Ordering::SeqCst);
+    }
+    println!("[Capture-H264] Keyframe requested for {:?}", selection);
+}
+
+/// 全パイプラインの購読者ごとの送信状況
+pub fn viewer_stats() -> Vec<SubscriberStats> {
+    PIPELINES.lock().values().flat_map(|p| p.bus.stats()).collect()
+}
+
+// 設定が一致するパイプラインを購読（なければ起動）。PIPELINESのロック中に呼ぶ
+fn subscribe_locked(
+    pipelines: &mut HashMap<u64, Pipeline>,
+    selection: &MonitorSelection,
+    peer: &str,
+    capture_region: &Arc<RwLock<Option<CaptureRegion>>>,
+    settings: PipelineSettings,
+) -> (u64, FrameSubscriber) {
+    if let Some((id, pipeline)) = pipelines
+        .iter()
+        .find(|(_, p)| p.matches(selection, capture_region, &settings))
+    {
+        return (*id, pipeline.bus.subscribe(peer));
+    }
 
-/// WebSocketモードでキーフレームを強制リクエスト
-pub fn request_ws_keyframe() {
-    WS_FORCE_KEYFRAME.store(true, Ordering::SeqCst);
-    println!("[xcap-H264] Keyframe requested for new client");
+    let id = NEXT_PIPELINE_ID.fetch_add(1, Ordering::Relaxed);
+    let force_keyframe = Arc::new(AtomicBool::new(true));
+    let bus = Arc::new(FrameBus::new(force_keyframe.clone()));
+    let subscriber = bus.subscribe(peer);
+    let settings = Arc::new(RwLock::new(settings));
+    pipelines.insert(id, Pipeline {
+        selection: selection.clone(),
+        capture_region: capture_region.clone(),
+        settings: settings.clone(),
+        bus: bus.clone(),
+        force_keyframe: force_keyframe.clone(),
+    });
+    println!("[Capture-H264] Starting encoder pipeline {} for {:?}", id, selection);
+    // テストではキャプチャを開かない（パイプラインの共有・分割だけを確かめる）
+    #[cfg(not(test))]
+    ScreenCapturer::start_capture(id, selection.clone(), bus, force_keyframe, settings, capture_region.clone());
+    (id, subscriber)
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct WindowInfo {
-    pub id: u32,
-    pub name: String,
-    pub owner_name: String,
+// 購読者が残っていなければパイプラインを登録から外す（エンコードスレッドは次のループで終了する）
+// `remaining` はまだ外れていない呼び出し元自身の購読の数
+fn remove_if_unused(pipelines: &mut HashMap<u64, Pipeline>, id: u64, remaining: usize) {
+    if pipelines.get(&id).is_some_and(|p| p.bus.subscriber_count() <= remaining) {
+        pipelines.remove(&id);
+        println!("[Capture-H264] Encoder pipeline {} stopped (no subscribers)", id);
+    }
+}
+
+// 購読者がいなくなったらエンコードスレッドを終える（購読はロック中に行うので競合しない）
+#[cfg_attr(test, allow(dead_code))]
+fn release_if_unused(id: u64, bus: &FrameBus) -> bool {
+    let mut pipelines = PIPELINES.lock();
+    if bus.subscriber_count() == 0 {
+        remove_if_unused(&mut pipelines, id, 0);
+        true
+    } else {
+        false
+    }
+}
+
+/// 接続ごとのH.264ストリーム（dropで購読解除）
+/// 送信目標は接続ごとに持ち、他の接続の受信統計の影響を受けない
+pub struct ScreenStream {
+    selection: MonitorSelection,
+    peer: String,
+    capture_region: Arc<RwLock<Option<CaptureRegion>>>,
+    // クライアントの受信統計で調整する送信目標
+    rate: RateController,
+    display: Option<ClientDisplay>,
+    cursor_burn_in: bool,
+    pipeline: u64,
+    subscriber: FrameSubscriber,
+}
+
+impl ScreenStream {
+    /// 選択したモニターのフレームを購読（同じ設定のパイプラインがなければ起動）
+    pub fn start(
+        selection: &MonitorSelection,
+        peer: &str,
+        capture_region: Arc<RwLock<Option<CaptureRegion>>>,
+        display: Option<ClientDisplay>,
+        cursor_burn_in: bool,
+    ) -> Self {
+        let rate = RateController::default();
+        let settings = PipelineSettings { targets: rate.targets(), display, cursor_burn_in };
+        let (pipeline, subscriber) = subscribe_locked(&mut PIPELINES.lock(), selection, peer, &capture_region, settings);
+        Self {
+            selection: selection.clone(),
+            peer: peer.to_string(),
+            capture_region,
+            rate,
+            display,
+            cursor_burn_in,
+            pipeline,
+            subscriber,
+        }
+    }
+
+    /// 次のフレームを待つ
+    pub async fn recv(&mut self) -> Frame {
+        self.subscriber.recv().await
+    }
+
+    /// この接続で捨てたフレーム数
+    pub fn dropped_frames(&self) -> u64 {
+        self.subscriber.dropped_frames()
+    }
+
+    /// このストリームのパイプラインにキーフレームを要求（デコーダーのエラー回復用）
+    pub fn request_keyframe(&self) {
+        if let Some(pipeline) = PIPELINES.lock().get(&self.pipeline) {
+            pipeline.force_keyframe.store(true, Ordering::SeqCst);
+            println!("[Capture-H264] Keyframe requested by {}", self.peer);
+        }
+    }
+
+    /// クライアントの受信統計をこの接続の送信目標に反映
+    pub fn report_stats(&mut self, stats: &StreamStats) -> StreamTargets {
+        let targets = self.rate.update(stats);
+        self.apply_settings();
+        targets
+    }
+
+    /// クライアントの表示環境を変更（送信解像度はこの接続だけ変わる）
+    pub fn set_display(&mut self, display: Option<ClientDisplay>) {
+        self.display = display;
+        self.apply_settings();
+    }
+
+    /// カーソルの焼き込みを設定（この接続の映像だけに合成する）
+    pub fn set_cursor_burn_in(&mut self, enabled: bool) {
+        self.cursor_burn_in = enabled;
+        self.apply_settings();
+    }
+
+    fn settings(&self) -> PipelineSettings {
+        PipelineSettings { targets: self.rate.targets(), display: self.display, cursor_burn_in: self.cursor_burn_in }
+    }
+
+    // 設定が変わったら、単独で使っているパイプラインはそのまま更新し、
+    // 共有中なら同じ設定のパイプラインに移る（他の接続の送信設定は変えない）
+    fn apply_settings(&mut self) {
+        let settings = self.settings();
+        let mut pipelines = PIPELINES.lock();
+        if let Some(pipeline) = pipelines.get(&self.pipeline) {
+            if *pipeline.settings.read() == settings {
+                return;
+            }
+            if pipeline.bus.subscriber_count() == 1 {
+                *pipeline.settings.write() = settings;
+                return;
+            }
+        }
+        let (pipeline, subscriber) =
+            subscribe_locked(&mut pipelines, &self.selection, &self.peer, &self.capture_region, settings);
+        println!("[Capture-H264] {} moved to encoder pipeline {}", self.peer, pipeline);
+        let previous = std::mem::replace(&mut self.pipeline, pipeline);
+        // 古いパイプラインの購読はここで外れる
+        self.subscriber = subscriber;
+        remove_if_unused(&mut pipelines, previous, 0);
+    }
+}
+
+impl Drop for ScreenStream {
+    fn drop(&mut self) {
+        // 最後の購読者なら、エンコードスレッドを待たずに登録から外す
+        remove_if_unused(&mut PIPELINES.lock(), self.pipeline, 1);
+    }
 }
 
 pub struct ScreenCapturer {
@@ -33,14 +243,13 @@ pub struct ScreenCapturer {
 }
 
 impl ScreenCapturer {
-    pub fn new() -> Result<Self, String> {
-        let monitors = Monitor::all().map_err(|e| format!("Failed to get monitors: {}", e))?;
-        let monitor = monitors.first().ok_or("No monitor found")?;
+    pub fn new(selection: &MonitorSelection) -> Result<Self, String> {
+        let capturer = MonitorCapturer::new(selection)?;
 
         // 論理解像度
-        let logical_width = monitor.width().map_err(|e| format!("Failed to get width: {}", e))?;
-        let logical_height = monitor.height().map_err(|e| format!("Failed to get height: {}", e))?;
-        let scale_factor = monitor.scale_factor().unwrap_or(1.0);
+        let logical_width = capturer.bounds().width;
+        let logical_height = capturer.bounds().height;
+        let scale_factor = capturer.scale_factor();
 
         // ネイティブ解像度（実際のキャプチャサイズ）
         let native_width = (logical_width as f32 * scale_factor) as usize;
@@ -67,193 +276,198 @@ impl ScreenCapturer {
         (logical_w, logical_h)
     }
 
-    /// 利用可能なウィンドウ一覧を取得（将来の拡張用）
-    pub fn list_windows() -> Vec<WindowInfo> {
-        vec![WindowInfo {
-            id: 0,
-            name: "全画面".to_string(),
-            owner_name: "Desktop".to_string(),
-        }]
-    }
-
-    pub fn start_capture(
-        width: usize,
-        height: usize,
-        tx: broadcast::Sender<Vec<u8>>,
+    // キャプチャサービスのフレームをH.264にエンコードしてフレームバスに流す
+    #[cfg_attr(test, allow(dead_code))]
+    fn start_capture(
+        id: u64,
+        selection: MonitorSelection,
+        bus: Arc<FrameBus>,
+        force_keyframe: Arc<AtomicBool>,
+        settings: Arc<RwLock<PipelineSettings>>,
         capture_region: Arc<RwLock<Option<CaptureRegion>>>,
-        ws_capture_running: Arc<std::sync::atomic::AtomicBool>,
     ) {
         std::thread::spawn(move || {
-            loop {
-                // WSキャプチャが有効になるまで待機
-                while !ws_capture_running.load(std::sync::atomic::Ordering::SeqCst) {
-                    std::thread::sleep(Duration::from_millis(100));
-                }
+            let capture = capture::subscribe(&selection);
+            let mut gate = FrameGate::default();
+            let mut seq: u64 = 0;
+            let mut frame_count: u64 = 0;
+            let mut h264_encoder: Option<H264Encoder> = None;
+            let mut yuv = I420Buffer::default();
+            let mut surround = SurroundEncoder::default();
 
-                let monitors = match Monitor::all() {
-                    Ok(m) => m,
-                    Err(e) => {
-                        eprintln!("Failed to get monitors: {}", e);
-                        std::thread::sleep(Duration::from_secs(1));
-                        continue;
-                    }
+            while !release_if_unused(id, &bus) {
+                let loop_start = Instant::now();
+                let PipelineSettings { targets, display, cursor_burn_in } = *settings.read();
+                let frame_interval = Duration::from_millis(1000 / targets.fps.max(1) as u64);
+
+                let Some(frame) = capture.next_frame(seq, Duration::from_millis(500)) else {
+                    continue;
                 };
+                seq = frame.seq;
 
-                let monitor = match monitors.first() {
-                    Some(m) => m,
-                    None => {
-                        eprintln!("No monitor found");
-                        std::thread::sleep(Duration::from_secs(1));
+                // 前回から変化がなければエンコードしない（キーフレーム要求と定期キーフレームは除く）
+                let region = capture_region.read().clone();
+                let cursor = if cursor_burn_in { cursor::current() } else { None };
+                match gate.check(frame.content_id, &region, cursor.as_ref().map(CursorState::key)) {
+                    FrameAction::Skip if !force_keyframe.load(Ordering::SeqCst) => {
+                        METRICS.frames_skipped.inc();
+                        std::thread::sleep(frame_interval);
                         continue;
                     }
+                    FrameAction::Keyframe => force_keyframe.store(true, Ordering::SeqCst),
+                    _ => {}
+                }
+
+                // カーソルはこのパイプラインでエンコードするフレームにだけ合成する
+                let frame = cursor::burned_in(frame, cursor.as_ref());
+
+                let encode_start = Instant::now();
+                // ビューポート優先ならビューポートだけをエンコードし、周辺は縮小JPEGで添える
+                let roi = roi::roi_layout(&frame, region.as_ref(), &targets, display.as_ref());
+                let layout = match roi {
+                    Some(ref roi) => roi.viewport,
+                    None => capture::layout(&frame, region.as_ref(), &targets, display.as_ref()),
                 };
+                let (new_width, new_height) = (layout.width, layout.height);
 
-                let scale_factor = monitor.scale_factor().unwrap_or(1.0);
-                println!("[xcap] Capture starting, scale factor: {}", scale_factor);
+                // エンコーダーがなければ作成（サイズ・レートの変更はエンコーダー側で対応）
+                if h264_encoder.is_none() {
+                    h264_encoder = match H264Encoder::with_rate(new_width, new_height, targets.bitrate_kbps * 1000, targets.fps as f32) {
+                        Ok(enc) => Some(enc),
+                        Err(e) => {
+                            eprintln!("[Capture-H264] Failed to create encoder: {}", e);
+                            std::thread::sleep(Duration::from_secs(1));
+                            continue;
+                        }
+                    };
+                }
+                let Some(encoder) = h264_encoder.as_mut() else { continue };
+                if let Err(e) = encoder.set_rate(targets.bitrate_kbps * 1000, targets.fps as f32) {
+                    eprintln!("[Capture-H264] {}", e);
+                }
 
-                let mut frame_count: u64 = 0;
-                let mut logged_info = false;
-                let mut h264_encoder: Option<H264Encoder> = None;
-                let mut last_encoder_size: (u32, u32) = (0, 0);
+                // 切り抜き・リサイズ・I420変換を1パスで行う
+                capture::crop_scale_i420(&frame, &layout, &mut yuv);
 
-                // 内側のキャプチャループ
-                while ws_capture_running.load(std::sync::atomic::Ordering::SeqCst) {
-                    match monitor.capture_image() {
-                        Ok(img) => {
-                            let cap_width = img.width() as usize;
-                            let cap_height = img.height() as usize;
+                // 新しいクライアント・エラー回復用にキーフレームを強制
+                if force_keyframe.swap(false, Ordering::SeqCst) {
+                    println!("[Capture-H264] Forcing keyframe");
+                    let _ = encoder.force_keyframe();
+                }
 
-                            if !logged_info {
-                                println!("[xcap] Captured: {}x{} (native resolution)", cap_width, cap_height);
-                                logged_info = true;
+                match encoder.encode_i420(&yuv) {
+                    Ok(h264_data) => {
+                        METRICS.encode_seconds.observe_duration(encode_start.elapsed());
+                        if !h264_data.is_empty() {
+                            METRICS.frames_encoded.inc();
+                            METRICS.encoded_frame_bytes.observe(h264_data.len() as f64);
+                            let keyframe = contains_idr(&h264_data);
+                            if keyframe {
+                                METRICS.keyframes_encoded.inc();
                             }
-
-                            // RgbaImageに変換
-                            let rgba_img: RgbaImage = img;
-                            let dynamic_img = DynamicImage::ImageRgba8(rgba_img);
-
-                            // キャプチャ領域をチェック（座標はスケール係数で変換）
-                            let region = capture_region.read().clone();
-
-                            // 論理座標でのウィンドウサイズを保持
-                            let (final_img, logical_w, logical_h) = if let Some(r) = region.clone() {
-                                // 領域指定あり: 座標をネイティブ解像度にスケール
-                                let crop_x = ((r.x as f32 * scale_factor) as u32).min(cap_width as u32);
-                                let crop_y = ((r.y as f32 * scale_factor) as u32).min(cap_height as u32);
-                                let crop_w = ((r.width as f32 * scale_factor) as u32).min(cap_width as u32 - crop_x);
-                                let crop_h = ((r.height as f32 * scale_factor) as u32).min(cap_height as u32 - crop_y);
-
-                                if crop_w > 0 && crop_h > 0 {
-                                    // 論理サイズを保持（モバイルとの整合性のため）
-                                    (dynamic_img.crop_imm(crop_x, crop_y, crop_w, crop_h),
-                                     r.width as f32, r.height as f32)
+                            let h264_size = h264_data.len();
+                            // キーフレームには必ず周辺画像を付ける（途中から受信するクライアント用）
+                            let roi = roi.map(|roi| {
+                                let jpeg = if surround.due(&frame, &roi, keyframe) {
+                                    SurroundEncoder::encode_jpeg(&frame, &roi)
+                                        .map_err(|e| eprintln!("[Capture-H264] {}", e))
+                                        .ok()
                                 } else {
-                                    let logical_w = cap_width as f32 / scale_factor;
-                                    let logical_h = cap_height as f32 / scale_factor;
-                                    (dynamic_img.clone(), logical_w, logical_h)
-                                }
-                            } else {
-                                let logical_w = cap_width as f32 / scale_factor;
-                                let logical_h = cap_height as f32 / scale_factor;
-                                (dynamic_img.clone(), logical_w, logical_h)
-                            };
-
-                            // モバイルと同じロジック: 論理ピクセル数で判定
-                            // 600,000ピクセル以上なら1/2サイズで送信
-                            let logical_pixel_count = (logical_w * logical_h) as u32;
-                            let (new_width, new_height) = if logical_pixel_count > 600000 {
-                                // 1/2サイズで送信（モバイルの期待に合わせる）
-                                let w = ((logical_w / 2.0) as u32 / 2) * 2;  // 2の倍数に
-                                let h = ((logical_h / 2.0) as u32 / 2) * 2;
-                                (w.max(2), h.max(2))
-                            } else {
-                                // 原寸で送信
-                                let w = (logical_w as u32 / 2) * 2;
-                                let h = (logical_h as u32 / 2) * 2;
-                                (w.max(2), h.max(2))
-                            };
-
-                            // エンコーダーサイズが変わったら再作成
-                            if h264_encoder.is_none() || last_encoder_size != (new_width, new_height) {
-                                h264_encoder = match H264Encoder::new(new_width, new_height) {
-                                    Ok(enc) => {
-                                        println!("[xcap-H264] Encoder created: {}x{}", new_width, new_height);
-                                        last_encoder_size = (new_width, new_height);
-                                        Some(enc)
-                                    }
-                                    Err(e) => {
-                                        eprintln!("[xcap-H264] Failed to create encoder: {}", e);
-                                        None
-                                    }
+                                    None
                                 };
-                            }
-
-                            let resized = final_img.resize_exact(
-                                new_width,
-                                new_height,
-                                image::imageops::FilterType::Triangle,
-                            );
-
-                            // RGBAからBGRAに変換
-                            let rgba_bytes = resized.to_rgba8().into_raw();
-                            let mut bgra_bytes = vec![0u8; rgba_bytes.len()];
-                            for (i, chunk) in rgba_bytes.chunks(4).enumerate() {
-                                bgra_bytes[i * 4] = chunk[2];     // B
-                                bgra_bytes[i * 4 + 1] = chunk[1]; // G
-                                bgra_bytes[i * 4 + 2] = chunk[0]; // R
-                                bgra_bytes[i * 4 + 3] = chunk[3]; // A
-                            }
-
-                            // H.264エンコード
-                            if let Some(ref mut encoder) = h264_encoder {
-                                // 新しいクライアント用にキーフレームを強制
-                                if WS_FORCE_KEYFRAME.swap(false, Ordering::SeqCst) {
-                                    println!("[xcap-H264] Forcing keyframe for new client");
-                                    let _ = encoder.force_keyframe();
-                                }
-
-                                match encoder.encode_bgra(&bgra_bytes, new_width, new_height) {
-                                    Ok(h264_data) => {
-                                        if !h264_data.is_empty() {
-                                            let receivers = tx.receiver_count();
-                                            if receivers > 0 {
-                                                let h264_size = h264_data.len();
-                                                match tx.send(h264_data) {
-                                                    Ok(_) => {
-                                                        frame_count += 1;
-                                                        if frame_count == 1 || frame_count % 100 == 0 {
-                                                            println!("[xcap-H264] Frame {} sent, {} receivers, {} KB, {}x{}",
-                                                                     frame_count, receivers, h264_size / 1024, new_width, new_height);
-                                                        }
-                                                    }
-                                                    Err(e) => {
-                                                        eprintln!("[xcap-H264] Failed to send frame: {}", e);
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                    Err(e) => {
-                                        if frame_count == 0 {
-                                            eprintln!("[xcap-H264] Encode error: {}", e);
-                                        }
-                                    }
-                                }
+                                Arc::new(RoiInfo::new(&roi, jpeg.as_deref()))
+                            });
+                            let output = OutputResolution::new(&layout, frame.scale_factor);
+                            bus.publish(Frame { data: Bytes::from(h264_data), keyframe, roi, output });
+                            frame_count += 1;
+                            if frame_count == 1 || frame_count % 100 == 0 {
+                                println!("[Capture-H264] Frame {} sent, {} receivers, {} KB, {}x{}",
+                                         frame_count, bus.subscriber_count(), h264_size / 1024, new_width, new_height);
                             }
                         }
-                        Err(e) => {
-                            eprintln!("[xcap] Capture error: {}", e);
-                            std::thread::sleep(Duration::from_millis(100));
+                    }
+                    Err(e) => {
+                        METRICS.encode_errors.inc();
+                        if frame_count == 0 {
+                            eprintln!("[Capture-H264] Encode error: {}", e);
                         }
                     }
-
-                    // 約30fps
-                    std::thread::sleep(Duration::from_millis(33));
                 }
 
-                println!("[xcap] Capture stopped, waiting for restart...");
-                std::thread::sleep(Duration::from_secs(1));
+                // 目標フレームレートに合わせる
+                if let Some(rest) = frame_interval.checked_sub(loop_start.elapsed()) {
+                    std::thread::sleep(rest);
+                }
             }
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // テストごとに別のモニターを使う（PIPELINESはプロセス全体で共有）
+    fn pipelines_for(selection: &MonitorSelection) -> Vec<u64> {
+        PIPELINES
+            .lock()
+            .iter()
+            .filter(|(_, p)| p.selection == *selection)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    #[test]
+    fn test_identical_settings_share_a_pipeline() {
+        let selection = MonitorSelection::Monitor { id: 9001 };
+        let region = Arc::new(RwLock::new(None));
+        let a = ScreenStream::start(&selection, "a", region.clone(), None, false);
+        let b = ScreenStream::start(&selection, "b", region.clone(), None, false);
+        assert_eq!(a.pipeline, b.pipeline);
+        assert_eq!(pipelines_for(&selection), vec![a.pipeline]);
+
+        // キャプチャ領域の共有元が違えば別のパイプライン
+        let c = ScreenStream::start(&selection, "c", Arc::new(RwLock::new(None)), None, false);
+        assert_ne!(c.pipeline, a.pipeline);
+    }
+
+    #[test]
+    fn test_changed_settings_move_only_that_connection() {
+        let selection = MonitorSelection::Monitor { id: 9002 };
+        let region = Arc::new(RwLock::new(None));
+        let a = ScreenStream::start(&selection, "a", region.clone(), None, false);
+        let mut b = ScreenStream::start(&selection, "b", region.clone(), None, false);
+        let shared = a.pipeline;
+
+        b.set_cursor_burn_in(true);
+        assert_ne!(b.pipeline, shared);
+        assert_eq!(a.pipeline, shared);
+        let settings = |id: u64| *PIPELINES.lock()[&id].settings.read();
+        assert!(!settings(shared).cursor_burn_in);
+        assert!(settings(b.pipeline).cursor_burn_in);
+
+        // 単独のパイプラインはその場で設定を変える
+        let alone = b.pipeline;
+        b.set_cursor_burn_in(false);
+        assert_eq!(b.pipeline, alone);
+        assert!(!settings(alone).cursor_burn_in);
+    }
+
+    #[test]
+    fn test_unused_pipeline_is_removed() {
+        let selection = MonitorSelection::Monitor { id: 9003 };
+        let region = Arc::new(RwLock::new(None));
+        let a = ScreenStream::start(&selection, "a", region.clone(), None, false);
+        let mut b = ScreenStream::start(&selection, "b", region.clone(), None, false);
+        let shared = a.pipeline;
+
+        // 最後の購読者が移ったら古いパイプラインは外れる
+        b.set_cursor_burn_in(true);
+        drop(a);
+        assert_eq!(pipelines_for(&selection), vec![b.pipeline]);
+        assert!(!PIPELINES.lock().contains_key(&shared));
+
+        drop(b);
+        assert!(pipelines_for(&selection).is_empty());
+    }
+}
@@ -0,0 +1,176 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::{imageops, ExtendedColorType, ImageEncoder, RgbaImage};
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::capture;
+use crate::monitors::{self, MonitorCapturer, MonitorSelection, Rect};
+use crate::CaptureRegion;
+
+/// スクリーンショットの対象（座標は仮想デスクトップ上の論理ピクセル）
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "target", rename_all = "snake_case")]
+pub enum ScreenshotTarget {
+    /// 全モニター
+    #[default]
+    FullScreen,
+    /// IDで指定したモニター
+    Monitor { id: u32 },
+    /// IDで指定したウィンドウ（他のウィンドウに隠れた部分も含む）
+    Window { id: u32 },
+    /// 指定した範囲
+    Region { x: i32, y: i32, width: u32, height: u32 },
+    /// 現在のキャプチャ領域（未設定なら全モニター）
+    CaptureRegion,
+}
+
+/// スクリーンショット（ネイティブ解像度の可逆PNG）
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Screenshot {
+    pub width: u32,
+    pub height: u32,
+    /// 撮影範囲（論理座標）
+    pub bounds: Rect,
+    /// 論理ピクセル -> 画像ピクセルの倍率
+    pub scale_factor: f32,
+    /// PNG（Base64）
+    pub image: String,
+    /// 保存先（保存した場合のみ）
+    pub path: Option<String>,
+}
+
+// 保存先フォルダ（未設定なら ピクチャ/PocketRemote）
+static SAVE_DIR: Lazy<RwLock<Option<PathBuf>>> = Lazy::new(|| RwLock::new(None));
+
+/// スクリーンショットの保存先
+pub fn save_dir() -> PathBuf {
+    SAVE_DIR.read().clone().unwrap_or_else(|| {
+        dirs::picture_dir()
+            .map(|dir| dir.join("PocketRemote"))
+            .unwrap_or_else(|| crate::app_data_dir().join("screenshots"))
+    })
+}
+
+/// 保存先を変更（Noneで既定に戻す）
+pub fn set_save_dir(dir: Option<PathBuf>) -> Result<(), String> {
+    if let Some(dir) = &dir {
+        std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    }
+    *SAVE_DIR.write() = dir;
+    Ok(())
+}
+
+/// スクリーンショットを撮る（縮小せずネイティブ解像度のまま、save なら保存先にも書き出す）
+pub fn take(target: &ScreenshotTarget, capture_region: Option<&CaptureRegion>, save: bool) -> Result<Screenshot, String> {
+    let region = match target {
+        ScreenshotTarget::FullScreen | ScreenshotTarget::Monitor { .. } | ScreenshotTarget::Window { .. } => None,
+        ScreenshotTarget::Region { x, y, width, height } => Some(Rect { x: *x, y: *y, width: *width, height: *height }),
+        ScreenshotTarget::CaptureRegion => capture_region.map(|r| Rect {
+            x: r.x,
+            y: r.y,
+            width: r.width.max(0) as u32,
+            height: r.height.max(0) as u32,
+        }),
+    };
+    if region.is_some_and(|r| r.width == 0 || r.height == 0) {
+        return Err("Screenshot region is empty".to_string());
+    }
+    let selection = match (target, region) {
+        (ScreenshotTarget::Monitor { id }, _) => MonitorSelection::Monitor { id: *id },
+        (ScreenshotTarget::Window { id }, _) => MonitorSelection::Window { id: *id },
+        (_, Some(region)) => monitor_containing(region),
+        (_, None) => MonitorSelection::All,
+    };
+
+    let mut capturer = MonitorCapturer::new(&selection)?;
+    let img = capturer.capture()?;
+    let (img, bounds) = match region {
+        Some(region) => crop(img, capturer.bounds(), capturer.scale_factor(), region)?,
+        None => (img, capturer.bounds()),
+    };
+
+    let mut png = Vec::new();
+    PngEncoder::new_with_quality(&mut png, CompressionType::Fast, FilterType::Adaptive)
+        .write_image(img.as_raw(), img.width(), img.height(), ExtendedColorType::Rgba8)
+        .map_err(|e| format!("PNG encode error: {}", e))?;
+
+    let path = if save { Some(write_file(&png)?) } else { None };
+    println!("[Screenshot] {:?}: {}x{} ({} KB){}", target, img.width(), img.height(), png.len() / 1024,
+        path.as_ref().map(|p| format!(" -> {}", p)).unwrap_or_default());
+    Ok(Screenshot {
+        width: img.width(),
+        height: img.height(),
+        bounds,
+        scale_factor: capturer.scale_factor(),
+        image: STANDARD.encode(&png),
+        path,
+    })
+}
+
+// 範囲が1台のモニターに収まるならそのモニター、またがる場合は全モニター
+fn monitor_containing(region: Rect) -> MonitorSelection {
+    let monitors = monitors::list_monitors().unwrap_or_default();
+    monitors
+        .iter()
+        .find(|m| {
+            region.x >= m.x
+                && region.y >= m.y
+                && region.x + region.width as i32 <= m.x + m.width as i32
+                && region.y + region.height as i32 <= m.y + m.height as i32
+        })
+        .map(|m| MonitorSelection::Monitor { id: m.id })
+        .unwrap_or(MonitorSelection::All)
+}
+
+// 論理座標の範囲をネイティブ解像度の画像から切り抜く（撮影範囲からはみ出す部分は除く）
+fn crop(mut img: RgbaImage, bounds: Rect, scale: f32, region: Rect) -> Result<(RgbaImage, Rect), String> {
+    let left = region.x.max(bounds.x);
+    let top = region.y.max(bounds.y);
+    let right = (region.x + region.width as i32).min(bounds.x + bounds.width as i32);
+    let bottom = (region.y + region.height as i32).min(bounds.y + bounds.height as i32);
+    if right <= left || bottom <= top {
+        return Err(format!("Screenshot region {:?} is outside the screen", region));
+    }
+
+    let dims = (img.width() as usize, img.height() as usize);
+    let (x, y, width, height) = capture::native_rect(bounds, scale, dims, [left, top, right - left, bottom - top]);
+    let cropped = imageops::crop(&mut img, x as u32, y as u32, width.max(1) as u32, height.max(1) as u32).to_image();
+    Ok((cropped, Rect { x: left, y: top, width: (right - left) as u32, height: (bottom - top) as u32 }))
+}
+
+fn write_file(png: &[u8]) -> Result<String, String> {
+    let dir = save_dir();
+    std::fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+    let path = dir.join(format!("screenshot-{}.png", millis));
+    std::fs::write(&path, png).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    Ok(path.to_string_lossy().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crop_native_resolution() {
+        // 論理200x100（原点が負の位置）、倍率2
+        let bounds = Rect { x: -200, y: 0, width: 200, height: 100 };
+        let img = RgbaImage::from_fn(400, 200, |x, y| image::Rgba([x as u8, y as u8, 0, 255]));
+
+        let (cropped, rect) = crop(img.clone(), bounds, 2.0, Rect { x: -150, y: 10, width: 50, height: 20 }).unwrap();
+        assert_eq!(cropped.dimensions(), (100, 40));
+        assert_eq!(cropped.get_pixel(0, 0).0, [100, 20, 0, 255]);
+        assert_eq!(rect, Rect { x: -150, y: 10, width: 50, height: 20 });
+
+        // はみ出す部分は除く
+        let (cropped, rect) = crop(img.clone(), bounds, 2.0, Rect { x: -20, y: 90, width: 100, height: 100 }).unwrap();
+        assert_eq!(cropped.dimensions(), (40, 20));
+        assert_eq!(rect, Rect { x: -20, y: 90, width: 20, height: 10 });
+
+        assert!(crop(img, bounds, 2.0, Rect { x: 10, y: 0, width: 10, height: 10 }).is_err());
+    }
+}
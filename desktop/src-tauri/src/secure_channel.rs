@@ -0,0 +1,364 @@
+//! WebSocket内のエンドツーエンド暗号化（Noise NK）
+//!
+//! QRコードにデスクトップの静的公開鍵を載せ、スマホはそれを使って
+//! 最初のメッセージでハンドシェイクを開始する。以降のメッセージはすべて
+//! 暗号化されたバイナリになるため、トンネルやリレーからは中身が見えない。
+//!
+//! ハンドシェイク: `[MAGIC, VERSION] + Noiseメッセージ`（双方向に1往復）
+//! トランスポート: WebSocketのバイナリ1通 = `[u16 BE 長さ][暗号文]` の繰り返し。
+//! 復号した平文を連結すると `[種別][ペイロード]`（0x00 = JSONテキスト, 0x01 = バイナリ）。
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use snow::{Builder, Keypair, TransportState};
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+
+const NOISE_PARAMS: &str = "Noise_NK_25519_ChaChaPoly_BLAKE2s";
+const MAGIC: u8 = 0x4e; // 'N'
+const VERSION: u8 = 0x01;
+// Noiseメッセージの最大長と認証タグ長
+const NOISE_MAX_MESSAGE: usize = 65535;
+const TAG_LEN: usize = 16;
+
+const KIND_TEXT: u8 = 0x00;
+const KIND_BINARY: u8 = 0x01;
+
+// 最初のメッセージを待つ時間
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// デスクトップの静的鍵（初回起動時に生成して保存）
+static STATIC_KEYPAIR: Lazy<Keypair> = Lazy::new(|| match load_or_create_keypair() {
+    Ok(keypair) => keypair,
+    Err(e) => {
+        // 保存できない場合もこのプロセス内では一時的な鍵で動作させる
+        eprintln!("[E2E] {}; using an ephemeral key", e);
+        new_keypair()
+    }
+});
+
+fn new_keypair() -> Keypair {
+    Builder::new(NOISE_PARAMS.parse().unwrap())
+        .generate_keypair()
+        .expect("failed to generate Noise keypair")
+}
+
+fn load_or_create_keypair() -> Result<Keypair, String> {
+    let path = crate::app_data_dir().join("noise_static.key");
+
+    // 保存形式: 秘密鍵32バイト + 公開鍵32バイト
+    if let Ok(bytes) = std::fs::read(&path) {
+        if bytes.len() == 64 {
+            return Ok(Keypair {
+                private: bytes[..32].to_vec(),
+                public: bytes[32..].to_vec(),
+            });
+        }
+        eprintln!("[E2E] Ignoring malformed key file {:?}", path);
+    }
+
+    let keypair = new_keypair();
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create directory: {}", e))?;
+    }
+    let mut bytes = keypair.private.clone();
+    bytes.extend_from_slice(&keypair.public);
+    std::fs::write(&path, &bytes).map_err(|e| format!("Failed to save key: {}", e))?;
+
+    // 秘密鍵なので所有者のみ読み書き可能にする
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))
+            .map_err(|e| format!("Failed to set permissions: {}", e))?;
+    }
+
+    println!("[E2E] Generated static key at {:?}", path);
+    Ok(keypair)
+}
+
+/// QRコードに載せる公開鍵（base64url）
+pub fn public_key() -> String {
+    URL_SAFE_NO_PAD.encode(&STATIC_KEYPAIR.public)
+}
+
+/// 接続文字列に公開鍵を付加（"<接続先>:<トークン>#<公開鍵>"）
+pub fn with_public_key(connection_string: &str) -> String {
+    format!("{}#{}", connection_string, public_key())
+}
+
+/// 平文セッションを拒否するか（暗号化済みのセッションは呼び出し側で除く）
+/// トンネル（cloudflared・ssh -R）はループバックから接続してくるため、
+/// トンネル起動中のループバック接続とリレー経由の接続はクライアントの申告に関係なく外部とみなす
+pub fn plaintext_rejected(peer: &str, tunnel_running: bool, claims_external: bool, require_encryption: bool) -> bool {
+    let via_tunnel = tunnel_running
+        && peer
+            .parse::<IpAddr>()
+            .map(|ip| ip.to_canonical().is_loopback())
+            .unwrap_or(false);
+    via_tunnel || peer.starts_with("relay:") || claims_external || require_encryption
+}
+
+fn encrypt(transport: &mut TransportState, kind: u8, payload: &[u8]) -> Result<Vec<u8>, snow::Error> {
+    let mut plaintext = Vec::with_capacity(payload.len() + 1);
+    plaintext.push(kind);
+    plaintext.extend_from_slice(payload);
+
+    let chunk_len = NOISE_MAX_MESSAGE - TAG_LEN;
+    let chunks = plaintext.len().div_ceil(chunk_len);
+    let mut out = Vec::with_capacity(plaintext.len() + chunks * (TAG_LEN + 2));
+    let mut buf = vec![0u8; NOISE_MAX_MESSAGE];
+    for chunk in plaintext.chunks(chunk_len) {
+        let n = transport.write_message(chunk, &mut buf)?;
+        out.extend_from_slice(&(n as u16).to_be_bytes());
+        out.extend_from_slice(&buf[..n]);
+    }
+    Ok(out)
+}
+
+fn decrypt(transport: &mut TransportState, data: &[u8]) -> Result<(u8, Vec<u8>), String> {
+    let mut plaintext = Vec::with_capacity(data.len());
+    let mut buf = vec![0u8; NOISE_MAX_MESSAGE];
+    let mut rest = data;
+    while !rest.is_empty() {
+        if rest.len() < 2 {
+            return Err("truncated chunk header".to_string());
+        }
+        let n = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+        let chunk = rest.get(2..2 + n).ok_or("truncated chunk")?;
+        let len = transport
+            .read_message(chunk, &mut buf)
+            .map_err(|e| format!("decrypt failed: {}", e))?;
+        plaintext.extend_from_slice(&buf[..len]);
+        rest = &rest[2 + n..];
+    }
+    if plaintext.is_empty() {
+        return Err("empty message".to_string());
+    }
+    let payload = plaintext.split_off(1);
+    Ok((plaintext[0], payload))
+}
+
+fn io_error(e: impl std::fmt::Display) -> WsError {
+    WsError::Io(io::Error::other(e.to_string()))
+}
+
+/// 送信側: 暗号化セッションではText/Binaryを暗号化してから送る
+pub struct SecureSink<S> {
+    inner: S,
+    transport: Option<Arc<Mutex<TransportState>>>,
+}
+
+impl<S> Sink<Message> for SecureSink<S>
+where
+    S: Sink<Message, Error = WsError> + Unpin,
+{
+    type Error = WsError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        let item = match (&self.transport, item) {
+            (Some(transport), Message::Text(text)) => {
+                Message::Binary(encrypt(&mut transport.lock(), KIND_TEXT, text.as_bytes()).map_err(io_error)?)
+            }
+            (Some(transport), Message::Binary(data)) => {
+                Message::Binary(encrypt(&mut transport.lock(), KIND_BINARY, &data).map_err(io_error)?)
+            }
+            (_, item) => item,
+        };
+        Pin::new(&mut self.inner).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// 受信側: 暗号化セッションではバイナリを復号し、平文のデータメッセージは拒否する
+pub struct SecureStream<R> {
+    inner: R,
+    transport: Option<Arc<Mutex<TransportState>>>,
+}
+
+impl<R> Stream for SecureStream<R>
+where
+    R: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    type Item = Result<Message, WsError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let msg = match Pin::new(&mut self.inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(msg))) => msg,
+            other => return other,
+        };
+        let Some(transport) = self.transport.as_ref() else {
+            return Poll::Ready(Some(Ok(msg)));
+        };
+
+        let result = match msg {
+            Message::Binary(data) => match decrypt(&mut transport.lock(), &data) {
+                Ok((KIND_TEXT, payload)) => String::from_utf8(payload)
+                    .map(Message::Text)
+                    .map_err(io_error),
+                Ok((KIND_BINARY, payload)) => Ok(Message::Binary(payload)),
+                Ok((kind, _)) => Err(io_error(format!("unknown message kind {}", kind))),
+                Err(e) => Err(io_error(e)),
+            },
+            Message::Text(_) => Err(io_error("plaintext message on an encrypted session")),
+            other => Ok(other),
+        };
+        Poll::Ready(Some(result))
+    }
+}
+
+/// 受信ストリーム（ハンドシェイク判定で読んだ最初のメッセージを先頭に戻す）
+pub type Incoming<R> = futures_util::stream::Chain<
+    futures_util::stream::Iter<std::vec::IntoIter<Result<Message, WsError>>>,
+    R,
+>;
+
+/// 最初のメッセージでハンドシェイクを判定し、送受信を包む
+/// （バイナリで始まれば暗号化セッション、それ以外は従来の平文セッション）
+pub async fn accept<W, R>(
+    mut write: W,
+    mut read: R,
+) -> Result<(SecureSink<W>, SecureStream<Incoming<R>>, bool), String>
+where
+    W: Sink<Message, Error = WsError> + Unpin,
+    R: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    let first = tokio::time::timeout(HANDSHAKE_TIMEOUT, read.next())
+        .await
+        .map_err(|_| "no message received".to_string())?
+        .ok_or("connection closed")?
+        .map_err(|e| e.to_string())?;
+
+    let mut pending = Vec::new();
+    let transport = match first {
+        Message::Binary(data) if data.len() > 2 && data[0] == MAGIC => {
+            if data[1] != VERSION {
+                return Err(format!("unsupported handshake version {}", data[1]));
+            }
+            let mut handshake = Builder::new(NOISE_PARAMS.parse().unwrap())
+                .local_private_key(&STATIC_KEYPAIR.private)
+                .build_responder()
+                .map_err(|e| e.to_string())?;
+
+            let mut buf = vec![0u8; NOISE_MAX_MESSAGE];
+            handshake
+                .read_message(&data[2..], &mut buf)
+                .map_err(|e| format!("handshake failed: {}", e))?;
+            let n = handshake.write_message(&[], &mut buf).map_err(|e| e.to_string())?;
+
+            let mut reply = vec![MAGIC, VERSION];
+            reply.extend_from_slice(&buf[..n]);
+            write.send(Message::Binary(reply)).await.map_err(|e| e.to_string())?;
+
+            let transport = handshake.into_transport_mode().map_err(|e| e.to_string())?;
+            Some(Arc::new(Mutex::new(transport)))
+        }
+        other => {
+            pending.push(Ok(other));
+            None
+        }
+    };
+
+    let encrypted = transport.is_some();
+    Ok((
+        SecureSink {
+            inner: write,
+            transport: transport.clone(),
+        },
+        SecureStream {
+            inner: futures_util::stream::iter(pending).chain(read),
+            transport,
+        },
+        encrypted,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plaintext_rejected() {
+        // (接続元, トンネル起動中, is_external申告, 暗号化必須設定) -> 拒否するか
+        let cases = [
+            ("192.168.1.20", false, false, false, false),
+            ("192.168.1.20", true, false, false, false),
+            ("192.168.1.20", false, false, true, true),
+            // トンネル経由はis_external=falseと申告しても拒否
+            ("127.0.0.1", true, false, false, true),
+            ("::1", true, false, false, true),
+            ("::ffff:127.0.0.1", true, false, false, true),
+            ("127.0.0.1", false, false, false, false),
+            ("relay:abc123", false, false, false, true),
+            ("192.168.1.20", false, true, false, true),
+        ];
+        for (peer, tunnel_running, claims_external, require, expected) in cases {
+            assert_eq!(
+                plaintext_rejected(peer, tunnel_running, claims_external, require),
+                expected,
+                "{} tunnel={} external={} require={}",
+                peer,
+                tunnel_running,
+                claims_external,
+                require
+            );
+        }
+    }
+
+    #[test]
+    fn test_handshake_and_chunked_roundtrip() {
+        let server_key = new_keypair();
+        let mut buf = vec![0u8; NOISE_MAX_MESSAGE];
+        let mut payload = vec![0u8; NOISE_MAX_MESSAGE];
+
+        let mut initiator = Builder::new(NOISE_PARAMS.parse().unwrap())
+            .remote_public_key(&server_key.public)
+            .build_initiator()
+            .unwrap();
+        let mut responder = Builder::new(NOISE_PARAMS.parse().unwrap())
+            .local_private_key(&server_key.private)
+            .build_responder()
+            .unwrap();
+
+        let n = initiator.write_message(&[], &mut buf).unwrap();
+        responder.read_message(&buf[..n], &mut payload).unwrap();
+        let n = responder.write_message(&[], &mut buf).unwrap();
+        initiator.read_message(&buf[..n], &mut payload).unwrap();
+
+        let mut client = initiator.into_transport_mode().unwrap();
+        let mut server = responder.into_transport_mode().unwrap();
+
+        // Noiseの1メッセージ上限を超えるフレームは分割される
+        let frame: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let sealed = encrypt(&mut server, KIND_BINARY, &frame).unwrap();
+        assert_eq!(decrypt(&mut client, &sealed).unwrap(), (KIND_BINARY, frame));
+
+        let sealed = encrypt(&mut client, KIND_TEXT, br#"{"type":"ping"}"#).unwrap();
+        let (kind, text) = decrypt(&mut server, &sealed).unwrap();
+        assert_eq!((kind, text.as_slice()), (KIND_TEXT, &br#"{"type":"ping"}"#[..]));
+
+        // 改ざんされたメッセージは復号できない
+        let mut sealed = encrypt(&mut server, KIND_TEXT, b"hello").unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(decrypt(&mut client, &sealed).is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::frame_bus::SubscriberStats;
+use crate::metrics::METRICS;
+use crate::AppState;
+
+/// ステータスサーバーのポート（localhostのみで待ち受け）
+pub const STATUS_PORT: u16 = 9877;
+
+/// ヘルスチェック用のサマリー
+#[derive(Serialize)]
+struct HealthSummary {
+    status: &'static str,
+    uptime_seconds: u64,
+    connected_device: Option<String>,
+    sessions: SessionSummary,
+    stream: StreamSummary,
+    webrtc: Vec<WebRTCStateCount>,
+    tunnel: TunnelSummary,
+}
+
+#[derive(Serialize)]
+struct SessionSummary {
+    ws_connections: i64,
+    authenticated: i64,
+    encrypted: i64,
+    screen_share: i64,
+    pty: i64,
+}
+
+#[derive(Serialize)]
+struct StreamSummary {
+    frames_captured: u64,
+    frames_encoded: u64,
+    ws_frames_sent: u64,
+    ws_frames_per_second: i64,
+    ws_bytes_per_second: i64,
+    broadcast_lagged_frames: u64,
+    mean_capture_ms: f64,
+    mean_encode_ms: f64,
+    // 画面共有中のクライアントごとのキュー長と取りこぼし数
+    viewers: Vec<SubscriberStats>,
+}
+
+#[derive(Serialize)]
+struct WebRTCStateCount {
+    state: &'static str,
+    count: i64,
+}
+
+#[derive(Serialize)]
+struct TunnelSummary {
+    running: bool,
+    url: Option<String>,
+    provider: Option<String>,
+}
+
+fn health_summary(state: &AppState) -> HealthSummary {
+    HealthSummary {
+        status: "ok",
+        uptime_seconds: METRICS.uptime().as_secs(),
+        connected_device: state.connected_device.read().clone(),
+        sessions: SessionSummary {
+            ws_connections: METRICS.ws_connections.get(),
+            authenticated: METRICS.authenticated_sessions.get(),
+            encrypted: METRICS.encrypted_sessions.get(),
+            screen_share: METRICS.screen_share_sessions.get(),
+            pty: METRICS.pty_sessions.get(),
+        },
+        stream: StreamSummary {
+            frames_captured: METRICS.frames_captured.get(),
+            frames_encoded: METRICS.frames_encoded.get(),
+            ws_frames_sent: METRICS.ws_frames_sent.get(),
+            ws_frames_per_second: METRICS.ws_frames_per_second.get(),
+            ws_bytes_per_second: METRICS.ws_bytes_per_second.get(),
+            broadcast_lagged_frames: METRICS.broadcast_lagged_frames.get(),
+            mean_capture_ms: METRICS.capture_seconds.mean() * 1000.0,
+            mean_encode_ms: METRICS.encode_seconds.mean() * 1000.0,
+            viewers: crate::screen_capture::viewer_stats(),
+        },
+        webrtc: METRICS
+            .webrtc_state_counts()
+            .into_iter()
+            .map(|(state, count)| WebRTCStateCount { state, count })
+            .collect(),
+        tunnel: TunnelSummary {
+            running: state.tunnel.read().is_some() || state.relay_task.read().is_some(),
+            url: state.tunnel_info.read().as_ref().map(|t| t.url.clone()),
+            provider: state.tunnel_info.read().as_ref().map(|t| t.provider.clone()),
+        },
+    }
+}
+
+/// ステータスHTTPサーバー起動（/metrics: Prometheus, /health: JSON）
+pub async fn start_status_server(state: Arc<AppState>) -> Result<(), String> {
+    let listener = TcpListener::bind(("127.0.0.1", STATUS_PORT))
+        .await
+        .map_err(|e| e.to_string())?;
+    println!("[Status] Listening on http://127.0.0.1:{} (/metrics, /health)", STATUS_PORT);
+
+    // スループットゲージの更新
+    tokio::spawn(crate::metrics::run_throughput_sampler());
+
+    loop {
+        let (stream, _) = listener.accept().await.map_err(|e| e.to_string())?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(stream, &state).await {
+                eprintln!("[Status] Request error: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_request(mut stream: TcpStream, state: &AppState) -> std::io::Result<()> {
+    // リクエストヘッダーを読み込む（ボディは使わない）
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") && buf.len() < 8192 {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&buf);
+    let mut parts = request.lines().next().unwrap_or("").split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("").split('?').next().unwrap_or("");
+
+    let (status, content_type, body) = match (method, path) {
+        ("GET", "/metrics") => (
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            METRICS.render_prometheus(),
+        ),
+        ("GET", "/health") => (
+            "200 OK",
+            "application/json",
+            serde_json::to_string(&health_summary(state)).unwrap_or_else(|_| "{}".to_string()),
+        ),
+        ("GET", _) => ("404 Not Found", "text/plain; charset=utf-8", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "text/plain; charset=utf-8", "method not allowed\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
@@ -0,0 +1,801 @@
+use parking_lot::Mutex;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// トンネルの実装（cloudflared、SSHなど）を差し替えるためのトレイト
+pub trait TunnelProvider: Send + Sync {
+    /// 表示用のプロバイダー名
+    fn name(&self) -> &'static str;
+
+    /// トンネルプロセスのコマンドを組み立てる
+    fn command(&self, local_port: u16) -> Result<Command, String>;
+
+    /// プロセスの出力1行から公開URLを抽出（まだ確立していなければNone）
+    fn extract_url(&self, line: &str) -> Option<String>;
+}
+
+/// トンネル設定（フロントエンドから渡される）
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TunnelConfig {
+    /// cloudflaredのクイックトンネル（*.trycloudflare.com、URLは毎回変わる）
+    #[default]
+    CloudflaredQuick,
+    /// cloudflaredの名前付きトンネル（固定ホスト名）
+    CloudflaredNamed {
+        tunnel: String,
+        hostname: String,
+        #[serde(default)]
+        credentials_file: Option<String>,
+    },
+    /// SSHリバーストンネル（ssh -R）
+    SshReverse {
+        /// 接続先（例: user@example.com）
+        destination: String,
+        /// リモート側で待ち受けるポート
+        remote_port: u16,
+        /// 外部から見たURL（例: https://remote.example.com）
+        public_url: String,
+        #[serde(default)]
+        ssh_port: Option<u16>,
+        #[serde(default)]
+        identity_file: Option<String>,
+    },
+    /// 任意のコマンド（{port}はローカルポートに置換、URLは正規表現で抽出）
+    Command {
+        command: String,
+        url_regex: String,
+    },
+}
+
+impl TunnelConfig {
+    /// 設定からプロバイダーを生成
+    pub fn provider(&self) -> Result<Box<dyn TunnelProvider>, String> {
+        Ok(match self.clone() {
+            TunnelConfig::CloudflaredQuick => Box::new(CloudflaredQuickTunnel),
+            TunnelConfig::CloudflaredNamed { tunnel, hostname, credentials_file } => {
+                Box::new(CloudflaredNamedTunnel { tunnel, hostname, credentials_file })
+            }
+            TunnelConfig::SshReverse { destination, remote_port, public_url, ssh_port, identity_file } => {
+                Box::new(SshReverseTunnel { destination, remote_port, public_url, ssh_port, identity_file })
+            }
+            TunnelConfig::Command { command, url_regex } => {
+                let url_regex = Regex::new(&url_regex)
+                    .map_err(|e| format!("Invalid URL regex: {}", e))?;
+                Box::new(CommandTunnel { command, url_regex })
+            }
+        })
+    }
+}
+
+/// 公開URLからWebSocket URLを生成（https -> wss, http -> ws）
+pub fn websocket_url(url: &str) -> String {
+    if let Some(rest) = url.strip_prefix("https://") {
+        format!("wss://{}", rest)
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        format!("ws://{}", rest)
+    } else {
+        url.to_string()
+    }
+}
+
+fn cloudflared_command() -> Result<Command, String> {
+    let path = crate::cloudflared::path().ok_or("cloudflared is not installed")?;
+    Ok(Command::new(path))
+}
+
+/// cloudflaredクイックトンネル
+pub struct CloudflaredQuickTunnel;
+
+impl TunnelProvider for CloudflaredQuickTunnel {
+    fn name(&self) -> &'static str {
+        "cloudflared_quick"
+    }
+
+    fn command(&self, local_port: u16) -> Result<Command, String> {
+        let mut cmd = cloudflared_command()?;
+        cmd.args(["tunnel", "--url", &format!("http://localhost:{}", local_port)]);
+        Ok(cmd)
+    }
+
+    fn extract_url(&self, line: &str) -> Option<String> {
+        // "https://xxxx.trycloudflare.com" のようなURLを探す
+        let patterns = [".trycloudflare.com", ".cloudflare.dev"];
+
+        for pattern in patterns {
+            if let Some(end_pos) = line.find(pattern) {
+                // https://の開始位置を探す
+                if let Some(start_pos) = line[..end_pos].rfind("https://") {
+                    let url_end = end_pos + pattern.len();
+                    return Some(line[start_pos..url_end].to_string());
+                }
+            }
+        }
+        None
+    }
+}
+
+/// cloudflared名前付きトンネル（DNSは事前にトンネルへルーティング済みであること）
+pub struct CloudflaredNamedTunnel {
+    tunnel: String,
+    hostname: String,
+    credentials_file: Option<String>,
+}
+
+impl TunnelProvider for CloudflaredNamedTunnel {
+    fn name(&self) -> &'static str {
+        "cloudflared_named"
+    }
+
+    fn command(&self, local_port: u16) -> Result<Command, String> {
+        let mut cmd = cloudflared_command()?;
+        cmd.args(["tunnel", "run", "--url", &format!("http://localhost:{}", local_port)]);
+        if let Some(ref file) = self.credentials_file {
+            cmd.args(["--credentials-file", file]);
+        }
+        cmd.arg(&self.tunnel);
+        Ok(cmd)
+    }
+
+    fn extract_url(&self, line: &str) -> Option<String> {
+        // URLは固定。エッジへの接続が登録されたら確立とみなす
+        if line.contains("Registered tunnel connection") {
+            Some(format!("https://{}", self.hostname.trim_start_matches("https://")))
+        } else {
+            None
+        }
+    }
+}
+
+/// SSHリバーストンネル
+pub struct SshReverseTunnel {
+    destination: String,
+    remote_port: u16,
+    public_url: String,
+    ssh_port: Option<u16>,
+    identity_file: Option<String>,
+}
+
+impl TunnelProvider for SshReverseTunnel {
+    fn name(&self) -> &'static str {
+        "ssh_reverse"
+    }
+
+    fn command(&self, local_port: u16) -> Result<Command, String> {
+        let mut cmd = Command::new("ssh");
+        cmd.args([
+            "-N",
+            "-v",
+            "-o", "ExitOnForwardFailure=yes",
+            "-o", "ServerAliveInterval=30",
+            "-o", "BatchMode=yes",
+            "-R", &format!("{}:localhost:{}", self.remote_port, local_port),
+        ]);
+        if let Some(port) = self.ssh_port {
+            cmd.args(["-p", &port.to_string()]);
+        }
+        if let Some(ref file) = self.identity_file {
+            cmd.args(["-i", file]);
+        }
+        cmd.arg(&self.destination);
+        Ok(cmd)
+    }
+
+    fn extract_url(&self, line: &str) -> Option<String> {
+        // ssh -v はリモートフォワードが受理されると "remote forward success" を出力する
+        if line.contains("remote forward success") {
+            Some(self.public_url.clone())
+        } else {
+            None
+        }
+    }
+}
+
+/// 任意コマンドによるトンネル
+pub struct CommandTunnel {
+    command: String,
+    url_regex: Regex,
+}
+
+impl TunnelProvider for CommandTunnel {
+    fn name(&self) -> &'static str {
+        "command"
+    }
+
+    fn command(&self, local_port: u16) -> Result<Command, String> {
+        let command_line = self.command.replace("{port}", &local_port.to_string());
+        #[cfg(unix)]
+        {
+            let mut cmd = Command::new("sh");
+            cmd.args(["-c", &command_line]);
+            Ok(cmd)
+        }
+        #[cfg(not(unix))]
+        {
+            let mut cmd = Command::new("cmd");
+            cmd.args(["/C", &command_line]);
+            Ok(cmd)
+        }
+    }
+
+    fn extract_url(&self, line: &str) -> Option<String> {
+        // キャプチャグループがあれば1番目、なければマッチ全体
+        let caps = self.url_regex.captures(line)?;
+        caps.get(1)
+            .or_else(|| caps.get(0))
+            .map(|m| m.as_str().to_string())
+    }
+}
+
+/// プロバイダーのプロセスを起動（stdout/stderrはパイプ、PIDはpid_fileに記録）
+pub fn spawn(provider: &dyn TunnelProvider, local_port: u16, pid_file: &Path) -> Result<Child, String> {
+    let mut cmd = provider.command(local_port)?;
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    // 独自のプロセスグループで起動（停止時に子孫プロセスごと終了させるため）
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+    }
+
+    let child = cmd
+        .spawn()
+        .map_err(|e| format!("Failed to start {} tunnel: {}", provider.name(), e))?;
+
+    write_pid_file(pid_file, child.id());
+    Ok(child)
+}
+
+/// tunnel_stopped イベントのペイロード
+#[derive(Clone, Debug, Serialize)]
+pub struct TunnelStopped {
+    pub provider: String,
+    pub reason: String,
+    pub restarting: bool,
+    pub retry_in_secs: Option<u64>,
+}
+
+/// URL確定時のコールバック（provider, url, 直前のURL）
+pub type UrlCallback = Box<dyn Fn(&str, String, Option<String>) + Send + Sync>;
+
+/// 監視スレッドからの通知先
+pub struct TunnelCallbacks {
+    pub on_url: UrlCallback,
+    /// プロセス終了時
+    pub on_stopped: Box<dyn Fn(TunnelStopped) + Send + Sync>,
+}
+
+// 再起動のバックオフ（1秒から倍々、最大60秒）
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(60);
+// この時間以上動いていればバックオフをリセット
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+fn restart_delay(attempt: u32) -> Duration {
+    Duration::from_secs(1u64 << attempt.min(6)).min(RESTART_BACKOFF_MAX)
+}
+
+/// トンネルプロセスを監視し、異常終了時はバックオフ付きで再起動する
+pub struct TunnelSupervisor {
+    provider_name: &'static str,
+    child: Arc<Mutex<Option<Child>>>,
+    stopping: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    pid_file: PathBuf,
+}
+
+impl TunnelSupervisor {
+    /// プロセスを起動して監視を開始（初回起動の失敗はそのまま返す）
+    /// 起動したプロセスはpid_file（通常はdefault_pid_file()）に記録する
+    pub fn start(
+        provider: Arc<dyn TunnelProvider>,
+        local_port: u16,
+        pid_file: PathBuf,
+        callbacks: TunnelCallbacks,
+    ) -> Result<Self, String> {
+        let first = spawn(provider.as_ref(), local_port, &pid_file)?;
+        println!("[Tunnel] Started {} (pid {})", provider.name(), first.id());
+
+        let provider_name = provider.name();
+        let child = Arc::new(Mutex::new(None));
+        let stopping = Arc::new(AtomicBool::new(false));
+
+        let child_clone = child.clone();
+        let stopping_clone = stopping.clone();
+        let pid_file_clone = pid_file.clone();
+        let thread = std::thread::spawn(move || {
+            let callbacks = Arc::new(callbacks);
+            supervise(provider, local_port, &pid_file_clone, first, callbacks, child_clone, stopping_clone);
+        });
+
+        Ok(Self {
+            provider_name,
+            child,
+            stopping,
+            thread: Some(thread),
+            pid_file,
+        })
+    }
+
+    pub fn provider_name(&self) -> &'static str {
+        self.provider_name
+    }
+
+    /// 監視を止めて、起動したプロセスだけを終了する
+    pub fn stop(&mut self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        let child = self.child.lock().take();
+        if let Some(mut child) = child {
+            terminate(&mut child);
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        remove_pid_file(&self.pid_file);
+        println!("[Tunnel] {} stopped", self.provider_name);
+    }
+}
+
+impl Drop for TunnelSupervisor {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            self.stop();
+        }
+    }
+}
+
+fn supervise(
+    provider: Arc<dyn TunnelProvider>,
+    local_port: u16,
+    pid_file: &Path,
+    first: Child,
+    callbacks: Arc<TunnelCallbacks>,
+    child_slot: Arc<Mutex<Option<Child>>>,
+    stopping: Arc<AtomicBool>,
+) {
+    let last_url: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+    let mut next = Some(first);
+    let mut attempt: u32 = 0;
+
+    loop {
+        // 起動済みプロセスの出力を読み、終了まで待つ
+        let reason = match next.take() {
+            Some(mut child) => {
+                let started = Instant::now();
+                attach_output_readers(&mut child, provider.clone(), callbacks.clone(), last_url.clone());
+                if !install_child(&child_slot, &stopping, child) {
+                    return;
+                }
+
+                let reason = match wait_for_exit(&child_slot, &stopping) {
+                    Some(reason) => reason,
+                    None => return, // stop() による終了
+                };
+                if started.elapsed() >= STABLE_RUN {
+                    attempt = 0;
+                }
+                reason
+            }
+            None => "failed to restart".to_string(),
+        };
+
+        if stopping.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let delay = restart_delay(attempt);
+        attempt = attempt.saturating_add(1);
+        eprintln!("[Tunnel] {} {}; restarting in {:?}", provider.name(), reason, delay);
+        (callbacks.on_stopped)(TunnelStopped {
+            provider: provider.name().to_string(),
+            reason,
+            restarting: true,
+            retry_in_secs: Some(delay.as_secs()),
+        });
+
+        // 停止要求に素早く反応できるよう細かく区切って待つ
+        let deadline = Instant::now() + delay;
+        while Instant::now() < deadline {
+            if stopping.load(Ordering::SeqCst) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(200));
+        }
+
+        match spawn(provider.as_ref(), local_port, pid_file) {
+            Ok(child) => {
+                println!("[Tunnel] Restarted {} (pid {})", provider.name(), child.id());
+                next = Some(child);
+            }
+            Err(e) => eprintln!("[Tunnel] {}", e),
+        }
+    }
+}
+
+/// 起動したプロセスを監視対象に登録（stop()と同じロックの中で停止要求を確認する）
+/// 停止要求後に起動したプロセスは、stop()が取り出せないのでここで終了させてfalseを返す
+fn install_child(child_slot: &Mutex<Option<Child>>, stopping: &AtomicBool, mut child: Child) -> bool {
+    let mut slot = child_slot.lock();
+    if stopping.load(Ordering::SeqCst) {
+        drop(slot);
+        terminate(&mut child);
+        return false;
+    }
+    *slot = Some(child);
+    true
+}
+
+/// プロセス終了を待つ（終了理由を返す。stop()で取り除かれた場合はNone）
+fn wait_for_exit(child_slot: &Mutex<Option<Child>>, stopping: &AtomicBool) -> Option<String> {
+    loop {
+        if stopping.load(Ordering::SeqCst) {
+            return None;
+        }
+        {
+            let mut guard = child_slot.lock();
+            let child = guard.as_mut()?;
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    guard.take();
+                    return Some(format!("exited ({})", status));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    guard.take();
+                    return Some(format!("wait failed ({})", e));
+                }
+            }
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+}
+
+/// stdout/stderrの両方からURLをパース（cloudflaredやsshはstderrに出力する）
+fn attach_output_readers(
+    child: &mut Child,
+    provider: Arc<dyn TunnelProvider>,
+    callbacks: Arc<TunnelCallbacks>,
+    last_url: Arc<Mutex<Option<String>>>,
+) {
+    let mut outputs: Vec<Box<dyn std::io::Read + Send>> = Vec::new();
+    if let Some(stderr) = child.stderr.take() {
+        outputs.push(Box::new(stderr));
+    }
+    if let Some(stdout) = child.stdout.take() {
+        outputs.push(Box::new(stdout));
+    }
+
+    for output in outputs {
+        let provider = provider.clone();
+        let callbacks = callbacks.clone();
+        let last_url = last_url.clone();
+
+        std::thread::spawn(move || {
+            use std::io::{BufRead, BufReader};
+            let reader = BufReader::new(output);
+
+            for line in reader.lines().map_while(Result::ok) {
+                println!("{}: {}", provider.name(), line);
+                if let Some(url) = provider.extract_url(&line) {
+                    let previous = last_url.lock().replace(url.clone());
+                    (callbacks.on_url)(provider.name(), url, previous);
+                }
+            }
+        });
+    }
+}
+
+/// 起動したプロセス（とそのプロセスグループ）を終了させる
+fn terminate(child: &mut Child) {
+    let pid = child.id();
+
+    // まず穏やかに終了を促す
+    #[cfg(unix)]
+    {
+        let _ = Command::new("kill")
+            .args(["-TERM", "--", &format!("-{}", pid)])
+            .status();
+        let deadline = Instant::now() + Duration::from_secs(3);
+        while Instant::now() < deadline {
+            if let Ok(Some(_)) = child.try_wait() {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        // /T: 子プロセスも含めて終了
+        let _ = Command::new("taskkill")
+            .args(["/T", "/F", "/PID", &pid.to_string()])
+            .status();
+    }
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// アプリが使うPIDファイルの場所
+pub fn default_pid_file() -> PathBuf {
+    crate::app_data_dir().join("tunnel.pid")
+}
+
+// 起動したプロセスを記録（クラッシュ後の再起動時に孤児プロセスを片付けるため）
+// PIDは再利用されるので、OSが報告するプロセス名と起動時刻も合わせて記録する
+fn write_pid_file(path: &Path, pid: u32) {
+    let Some((name, started)) = process_identity(pid) else {
+        return;
+    };
+    if let Some(dir) = path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    let _ = std::fs::write(path, format!("{}\n{}\n{}\n", pid, name, started));
+}
+
+fn remove_pid_file(path: &Path) {
+    let _ = std::fs::remove_file(path);
+}
+
+/// 前回の実行で残ったトンネルプロセスを終了（PID・プロセス名・起動時刻がすべて一致する場合のみ）
+/// 起動後にexecで名前が変わったプロセス（sh -c のコマンドなど）は、確認できないので終了しない
+pub fn cleanup_orphaned_tunnel(pid_file: &Path) {
+    let Ok(contents) = std::fs::read_to_string(pid_file) else {
+        return;
+    };
+    let mut lines = contents.lines();
+    let pid = lines.next().and_then(|l| l.trim().parse::<u32>().ok());
+    let name = lines.next().map(|l| l.trim().to_string()).unwrap_or_default();
+    let started = lines.next().map(|l| l.trim().to_string()).unwrap_or_default();
+
+    if let Some(pid) = pid {
+        let recorded = (name.clone(), started);
+        if !name.is_empty() && process_identity(pid).as_ref() == Some(&recorded) {
+            println!("[Tunnel] Terminating orphaned tunnel process {} ({})", pid, name);
+            #[cfg(unix)]
+            {
+                let _ = Command::new("kill").args(["-TERM", "--", &format!("-{}", pid)]).status();
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = Command::new("taskkill")
+                    .args(["/T", "/F", "/PID", &pid.to_string()])
+                    .status();
+            }
+        }
+    }
+    remove_pid_file(pid_file);
+}
+
+/// プロセス名と起動時刻（OSの表記のまま）を取得。プロセスがなければNone
+fn process_identity(pid: u32) -> Option<(String, String)> {
+    #[cfg(unix)]
+    let query = |field: &str| {
+        let output = Command::new("ps")
+            .args(["-p", &pid.to_string(), "-o", &format!("{}=", field)])
+            .output()
+            .ok()?;
+        let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        (output.status.success() && !value.is_empty()).then_some(value)
+    };
+    #[cfg(unix)]
+    return Some((query("comm")?, query("lstart")?));
+
+    #[cfg(not(unix))]
+    {
+        let output = Command::new("powershell")
+            .args([
+                "-NoProfile",
+                "-Command",
+                &format!(
+                    "$p = Get-Process -Id {} -ErrorAction Stop; $p.ProcessName; $p.StartTime.ToFileTimeUtc()",
+                    pid
+                ),
+            ])
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut lines = stdout.lines().map(str::trim).filter(|l| !l.is_empty());
+        Some((lines.next()?.to_string(), lines.next()?.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_url() {
+        let quick = CloudflaredQuickTunnel;
+        let named = CloudflaredNamedTunnel {
+            tunnel: "pocket".to_string(),
+            hostname: "remote.example.com".to_string(),
+            credentials_file: None,
+        };
+        let ssh = SshReverseTunnel {
+            destination: "user@example.com".to_string(),
+            remote_port: 8080,
+            public_url: "https://ssh.example.com".to_string(),
+            ssh_port: None,
+            identity_file: None,
+        };
+        // キャプチャグループなし（マッチ全体）とあり（1番目のグループ）
+        let whole = CommandTunnel {
+            command: String::new(),
+            url_regex: Regex::new(r"https://\S+\.ngrok\.app").unwrap(),
+        };
+        let group = CommandTunnel {
+            command: String::new(),
+            url_regex: Regex::new(r"url=(https://\S+)").unwrap(),
+        };
+
+        let cases: [(&dyn TunnelProvider, &str, Option<&str>); 10] = [
+            (
+                &quick,
+                "2024-12-20T10:00:00Z INF |  https://calm-river-1234.trycloudflare.com                     |",
+                Some("https://calm-river-1234.trycloudflare.com"),
+            ),
+            (&quick, "INF Visit it at https://abc.cloudflare.dev now", Some("https://abc.cloudflare.dev")),
+            (&quick, "INF Requesting new quick Tunnel on trycloudflare.com...", None),
+            (
+                &named,
+                "INF Registered tunnel connection connIndex=0 location=nrt01",
+                Some("https://remote.example.com"),
+            ),
+            (&named, "INF Starting tunnel tunnelID=pocket", None),
+            (
+                &ssh,
+                "debug1: remote forward success for: listen 8080, connect localhost:9876",
+                Some("https://ssh.example.com"),
+            ),
+            (&ssh, "debug1: Authentication succeeded (publickey).", None),
+            (&whole, "Forwarding https://pocket.ngrok.app -> http://localhost:9876", Some("https://pocket.ngrok.app")),
+            (&group, "t=now lvl=info msg=\"started\" url=https://tunnel.example/ws", Some("https://tunnel.example/ws")),
+            (&group, "t=now lvl=info msg=\"starting\"", None),
+        ];
+        for (provider, line, expected) in cases {
+            assert_eq!(provider.extract_url(line).as_deref(), expected, "{}: {}", provider.name(), line);
+        }
+    }
+
+    #[cfg(unix)]
+    fn command_tunnel(command: &str) -> Arc<dyn TunnelProvider> {
+        Arc::new(CommandTunnel {
+            command: command.to_string(),
+            url_regex: Regex::new(r"https://\S+").unwrap(),
+        })
+    }
+
+    // アプリ本体のPIDファイルには触れない
+    #[cfg(unix)]
+    fn temp_pid_file(test: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("pocket-remote-{}-{}.pid", test, std::process::id()))
+    }
+
+    #[cfg(unix)]
+    fn alive(pid: u32) -> bool {
+        Command::new("kill").args(["-0", &pid.to_string()]).status().is_ok_and(|s| s.success())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_supervisor_restarts_and_stops() {
+        // URLを出して終了するプロセスは再起動され、そのたびにURLが通知される
+        let urls = Arc::new(Mutex::new(Vec::new()));
+        let pids = std::env::temp_dir().join(format!("pocket-remote-tunnel-{}.pids", std::process::id()));
+        let _ = std::fs::remove_file(&pids);
+        let provider = command_tunnel(&format!(
+            "echo $$ >> {}; echo https://tunnel.example; sleep 1; exit 1",
+            pids.display()
+        ));
+        let recorded = urls.clone();
+        let pid_file = temp_pid_file("supervisor");
+        let mut supervisor = TunnelSupervisor::start(provider, 0, pid_file.clone(), TunnelCallbacks {
+            on_url: Box::new(move |_, url, _| recorded.lock().push(url)),
+            on_stopped: Box::new(|stopped| assert!(stopped.restarting)),
+        })
+        .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while urls.lock().len() < 2 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        supervisor.stop();
+        assert!(!pid_file.exists());
+        assert!(urls.lock().len() >= 2);
+        assert!(urls.lock().iter().all(|u| u == "https://tunnel.example"));
+
+        // 停止後に残っているプロセスはない
+        let started = std::fs::read_to_string(&pids).unwrap_or_default();
+        let _ = std::fs::remove_file(&pids);
+        for pid in started.lines().filter_map(|l| l.trim().parse::<u32>().ok()) {
+            assert!(!alive(pid), "tunnel process {} left running", pid);
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_child_started_after_stop_is_terminated() {
+        // stop()が監視対象を取り出した後に再起動したプロセスは登録せず終了させる
+        let slot = Mutex::new(None);
+        let stopping = AtomicBool::new(true);
+        let pid_file = temp_pid_file("install");
+        let child = spawn(command_tunnel("exec sleep 30").as_ref(), 0, &pid_file).unwrap();
+        let pid = child.id();
+        assert!(!install_child(&slot, &stopping, child));
+        assert!(slot.lock().is_none());
+        assert!(!alive(pid));
+
+        stopping.store(false, Ordering::SeqCst);
+        let child = spawn(command_tunnel("exec sleep 30").as_ref(), 0, &pid_file).unwrap();
+        assert!(install_child(&slot, &stopping, child));
+        let mut child = slot.lock().take().unwrap();
+        terminate(&mut child);
+        remove_pid_file(&pid_file);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_cleanup_orphaned_tunnel() {
+        // 記録したプロセスが生きていれば終了させ、PIDファイルを消す
+        // （sh -c はexecでプロセス名が変わるので、直接起動するプロバイダーで確認する）
+        struct SleepTunnel;
+        impl TunnelProvider for SleepTunnel {
+            fn name(&self) -> &'static str {
+                "sleep"
+            }
+            fn command(&self, _local_port: u16) -> Result<Command, String> {
+                let mut cmd = Command::new("sleep");
+                cmd.arg("30");
+                Ok(cmd)
+            }
+            fn extract_url(&self, _line: &str) -> Option<String> {
+                None
+            }
+        }
+        let pid_file = temp_pid_file("orphan");
+        let mut child = spawn(&SleepTunnel, 0, &pid_file).unwrap();
+        cleanup_orphaned_tunnel(&pid_file);
+        let deadline = Instant::now() + Duration::from_secs(3);
+        while child.try_wait().unwrap().is_none() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert!(child.try_wait().unwrap().is_some());
+        assert!(!pid_file.exists());
+
+        // 名前が一致しない（PIDが再利用された）場合は何もしない
+        let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+        let (_, started) = process_identity(child.id()).unwrap();
+        std::fs::write(&pid_file, format!("{}\nsh\n{}\n", child.id(), started)).unwrap();
+        cleanup_orphaned_tunnel(&pid_file);
+        assert!(child.try_wait().unwrap().is_none());
+        assert!(!pid_file.exists());
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_process_identity_is_exact() {
+        let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+        let (name, started) = process_identity(child.id()).unwrap();
+        assert_eq!(name, "sleep");
+        assert!(!started.is_empty());
+        // 部分一致（"sh" と "bash" のような）では一致とみなさない
+        assert_ne!(process_identity(child.id()), Some(("slee".to_string(), started)));
+
+        let _ = child.kill();
+        let _ = child.wait();
+        assert!(process_identity(child.id()).is_none());
+    }
+}
@@ -3,8 +3,7 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use tokio::sync::{mpsc, RwLock};
 use parking_lot::RwLock as ParkingRwLock;
 use parking_lot::Mutex as ParkingMutex;
-use rayon::prelude::*;
-use webrtc::api::media_engine::MediaEngine;
+use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_H264, MIME_TYPE_OPUS};
 use webrtc::api::APIBuilder;
 use webrtc::api::interceptor_registry::register_default_interceptors;
 use webrtc::data_channel::data_channel_message::DataChannelMessage;
@@ -15,59 +14,150 @@ use webrtc::peer_connection::configuration::RTCConfiguration;
 use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 use webrtc::peer_connection::RTCPeerConnection;
-use scrap::{Capturer, Display};
+use webrtc::media::Sample;
+use webrtc::rtcp::payload_feedbacks::full_intra_request::FullIntraRequest;
+use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use webrtc::rtcp::payload_feedbacks::receiver_estimated_maximum_bitrate::ReceiverEstimatedMaximumBitrate;
+use webrtc::rtcp::receiver_report::ReceiverReport;
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
+use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+use webrtc::track::track_local::TrackLocal;
 use std::time::{Duration, Instant};
-use std::io::{ErrorKind::WouldBlock, Write};
-use image::{ImageBuffer, Rgba, DynamicImage};
+use std::io::Write;
 use bytes::Bytes;
 use crate::CaptureRegion;
-use crate::h264_encoder::H264Encoder;
-use once_cell::sync::Lazy;
-
-/// エンコーディングモード
-#[derive(Clone, Copy, PartialEq)]
-pub enum EncodingMode {
-    Jpeg,
-    H264,
+use crate::audio::AudioStream;
+use crate::capture;
+use crate::client_display::ClientDisplay;
+use crate::cursor::{self, CursorState};
+use crate::codec::{self, Codec, Encoder, H264FrameEncoder};
+use crate::damage::{FrameAction, FrameGate};
+use crate::adaptive::{RateController, StreamStats, StreamTargets};
+use crate::metrics::METRICS;
+use crate::monitors::MonitorSelection;
+use crate::refine::Refiner;
+use crate::roi::{self, SurroundEncoder};
+
+// 受信レポートの損失率がこれを超えたら回線の詰まりとみなす（fraction_lostは1/256単位、約5%）
+const CONGESTION_FRACTION_LOST: u8 = 13;
+
+/// 接続ごとのエンコード設定（コーデック・キーフレーム要求・送信目標・表示環境・カーソルの焼き込み）
+/// 他のクライアントのモード変更やキーフレーム要求の影響を受けないよう、WebSocket接続ごとに持つ
+pub struct StreamEncoding {
+    // Data Channel経路のコーデック
+    mode: ParkingRwLock<Codec>,
+    // 次のフレームをキーフレームにするフラグ
+    force_keyframe: AtomicBool,
+    // 送信目標
+    rate: ParkingMutex<RateController>,
+    // クライアントが申告した表示環境（Noneは旧クライアントの規則）
+    display: ParkingRwLock<Option<ClientDisplay>>,
+    // カーソルをフレームに焼き込むか
+    cursor_burn_in: AtomicBool,
 }
 
-/// グローバルH.264エンコーダー（スレッドセーフ）
-static H264_ENCODER: Lazy<ParkingMutex<Option<H264Encoder>>> = Lazy::new(|| {
-    ParkingMutex::new(None)
-});
+impl Default for StreamEncoding {
+    fn default() -> Self {
+        Self {
+            mode: ParkingRwLock::new(Codec::H264),
+            force_keyframe: AtomicBool::new(false),
+            rate: ParkingMutex::new(RateController::default()),
+            display: ParkingRwLock::new(None),
+            cursor_burn_in: AtomicBool::new(false),
+        }
+    }
+}
+
+impl StreamEncoding {
+    /// エンコーディングモードを設定
+    pub fn set_mode(&self, mode: Codec) {
+        *self.mode.write() = mode;
+        println!("[WebRTC] Encoding mode set to: {}", mode.name());
+    }
+
+    /// 現在のエンコーディングモード
+    pub fn mode(&self) -> Codec {
+        *self.mode.read()
+    }
+
+    /// 次のフレームをキーフレームにする
+    pub fn request_keyframe(&self) {
+        self.force_keyframe.store(true, Ordering::SeqCst);
+        println!("[H264] Keyframe requested");
+    }
 
-/// 現在のエンコーディングモード
-static ENCODING_MODE: Lazy<ParkingRwLock<EncodingMode>> = Lazy::new(|| {
-    ParkingRwLock::new(EncodingMode::H264) // H.264のみ使用
-});
+    /// クライアントの受信統計を送信目標に反映
+    pub fn report_stats(&self, stats: &StreamStats) -> StreamTargets {
+        self.rate.lock().update(stats)
+    }
 
-/// Data Channel開通時にキーフレームを強制するフラグ
-static FORCE_KEYFRAME: AtomicBool = AtomicBool::new(false);
+    /// クライアントの表示環境を設定
+    pub fn set_display(&self, display: Option<ClientDisplay>) {
+        *self.display.write() = display;
+    }
 
-/// キーフレームを強制するフラグをセット
-pub fn request_keyframe() {
-    FORCE_KEYFRAME.store(true, Ordering::SeqCst);
-    println!("[H264] Keyframe requested");
+    /// カーソルの焼き込みを設定（この接続の映像だけに合成する）
+    pub fn set_cursor_burn_in(&self, enabled: bool) {
+        self.cursor_burn_in.store(enabled, Ordering::SeqCst);
+        println!("[Cursor] Burn-in {}", if enabled { "enabled" } else { "disabled" });
+    }
+
+    fn targets(&self) -> StreamTargets {
+        self.rate.lock().targets()
+    }
+
+    fn display(&self) -> Option<ClientDisplay> {
+        *self.display.read()
+    }
+
+    fn cursor_burn_in(&self) -> bool {
+        self.cursor_burn_in.load(Ordering::SeqCst)
+    }
+
+    fn keyframe_pending(&self) -> bool {
+        self.force_keyframe.load(Ordering::SeqCst)
+    }
+
+    fn take_keyframe(&self) -> bool {
+        self.force_keyframe.swap(false, Ordering::SeqCst)
+    }
 }
 
-/// WebRTC Data Channelを使った低遅延画面共有
+/// WebRTCを使った低遅延画面共有（H.264ビデオトラック、またはData Channel）
 pub struct WebRTCScreenShare {
     peer_connection: Arc<RTCPeerConnection>,
     data_channel: Arc<RwLock<Option<Arc<RTCDataChannel>>>>,
+    // RTPのH.264ビデオトラック（クライアントが要求した場合のみ）
+    video_track: Option<Arc<TrackLocalStaticSample>>,
+    // アンサーでビデオトラックが受け入れられたか（拒否されたらData Channelで送る）
+    video_negotiated: Arc<AtomicBool>,
+    // Opusの音声トラック（クライアントが要求した場合のみ）とミュートフラグ
+    audio_track: Option<(Arc<TrackLocalStaticSample>, Arc<AtomicBool>)>,
+    audio_negotiated: Arc<AtomicBool>,
+    audio: ParkingMutex<Option<AudioStream>>,
     capture_running: Arc<RwLock<bool>>,
     capture_region: Arc<ParkingRwLock<Option<CaptureRegion>>>,
+    monitor: MonitorSelection,
+    encoding: Arc<StreamEncoding>,
+    // メトリクスに計上中の接続状態
+    peer_state: Arc<ParkingMutex<Option<String>>>,
 }
 
 impl WebRTCScreenShare {
     pub async fn new(
         ice_candidates_tx: mpsc::Sender<String>,
         capture_region: Arc<ParkingRwLock<Option<CaptureRegion>>>,
+        monitor: MonitorSelection,
+        encoding: Arc<StreamEncoding>,
+        video_track: bool,
+        audio_muted: Option<Arc<AtomicBool>>,
     ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
         // メディアエンジン設定
         let mut media_engine = MediaEngine::default();
         media_engine.register_default_codecs()?;
 
-        // インターセプター設定
+        // インターセプター設定（NACK再送・RTCPレポート）
         let mut registry = Registry::new();
         registry = register_default_interceptors(registry, &mut media_engine)?;
 
@@ -96,7 +186,55 @@ impl WebRTCScreenShare {
         let peer_connection = Arc::new(api.new_peer_connection(config).await?);
         let data_channel_holder: Arc<RwLock<Option<Arc<RTCDataChannel>>>> = Arc::new(RwLock::new(None));
 
+        // H.264ビデオトラック（RTPへの分割とNACK再送はwebrtcクレートに任せる）
+        let video_track = if video_track {
+            let track = Arc::new(TrackLocalStaticSample::new(
+                RTCRtpCodecCapability {
+                    mime_type: MIME_TYPE_H264.to_owned(),
+                    clock_rate: 90000,
+                    sdp_fmtp_line: "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f".to_owned(),
+                    ..Default::default()
+                },
+                "screen".to_owned(),
+                "pocket-remote".to_owned(),
+            ));
+            let rtp_sender = peer_connection
+                .add_track(Arc::clone(&track) as Arc<dyn TrackLocal + Send + Sync>)
+                .await?;
+            tokio::spawn(read_rtcp(rtp_sender, Arc::clone(&encoding)));
+            println!("[WebRTC] H.264 video track added");
+            Some(track)
+        } else {
+            None
+        };
+
+        // システム音声のOpusトラック（映像と同じストリームIDにして受信側で同期させる）
+        let audio_track = match audio_muted {
+            Some(muted) => {
+                let track = Arc::new(TrackLocalStaticSample::new(
+                    RTCRtpCodecCapability {
+                        mime_type: MIME_TYPE_OPUS.to_owned(),
+                        clock_rate: 48000,
+                        channels: 2,
+                        sdp_fmtp_line: "minptime=10;useinbandfec=1".to_owned(),
+                        ..Default::default()
+                    },
+                    "audio".to_owned(),
+                    "pocket-remote".to_owned(),
+                ));
+                let rtp_sender = peer_connection
+                    .add_track(Arc::clone(&track) as Arc<dyn TrackLocal + Send + Sync>)
+                    .await?;
+                // インターセプターのレポート処理のためRTCPを読み捨てる
+                tokio::spawn(async move { while rtp_sender.read_rtcp().await.is_ok() {} });
+                println!("[WebRTC] Opus audio track added");
+                Some((track, muted))
+            }
+            None => None,
+        };
+
         // Data Channel作成（順序なし、信頼性なし = UDP的動作）
+        // ビデオトラックがアンサーで拒否された場合のフォールバックとして常に作成する
         let dc_config = webrtc::data_channel::data_channel_init::RTCDataChannelInit {
             ordered: Some(false),        // 順序保証なし
             max_retransmits: Some(0),    // 再送なし
@@ -114,12 +252,12 @@ impl WebRTCScreenShare {
         }
 
         // Data Channel開通イベント
-        let dc_holder_clone = Arc::clone(&data_channel_holder);
+        let open_encoding = Arc::clone(&encoding);
         data_channel.on_open(Box::new(move || {
             println!("[WebRTC] Data channel opened!");
             let _ = std::io::stdout().flush();
             // キーフレームを強制送信（H.264デコーダー初期化のため）
-            request_keyframe();
+            open_encoding.request_keyframe();
             Box::pin(async {})
         }));
 
@@ -145,20 +283,44 @@ impl WebRTCScreenShare {
             Box::pin(async {})
         }));
 
-        // 接続状態変更イベント
+        // 接続状態変更イベント（メトリクス用に直前の状態を保持、解放後はNone）
+        METRICS.webrtc_state_transition(None, Some("new"));
+        let peer_state = Arc::new(ParkingMutex::new(Some("new".to_string())));
+        let last_state = Arc::clone(&peer_state);
         peer_connection.on_peer_connection_state_change(Box::new(move |state| {
             println!("[WebRTC] Peer connection state: {:?}", state);
+            let new_state = state.to_string();
+            let mut last = last_state.lock();
+            if let Some(prev) = last.as_deref() {
+                METRICS.webrtc_state_transition(Some(prev), Some(&new_state));
+                *last = Some(new_state);
+            }
             Box::pin(async {})
         }));
 
         Ok(Self {
             peer_connection,
             data_channel: data_channel_holder,
+            video_track,
+            video_negotiated: Arc::new(AtomicBool::new(false)),
+            audio_track,
+            audio_negotiated: Arc::new(AtomicBool::new(false)),
+            audio: ParkingMutex::new(None),
             capture_running: Arc::new(RwLock::new(false)),
             capture_region,
+            monitor,
+            encoding,
+            peer_state,
         })
     }
 
+    // このピア接続をメトリクスの状態別ゲージから外す（closedに達せず破棄された場合も残さない）
+    fn release_state(&self) {
+        if let Some(last) = self.peer_state.lock().take() {
+            METRICS.webrtc_state_transition(Some(&last), None);
+        }
+    }
+
     /// オファー作成
     pub async fn create_offer(&self) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
         let offer = self.peer_connection.create_offer(None).await?;
@@ -172,6 +334,24 @@ impl WebRTCScreenShare {
         let answer = RTCSessionDescription::answer(sdp.to_owned())?;
         self.peer_connection.set_remote_description(answer).await?;
         println!("[WebRTC] Set answer");
+
+        // ビデオのm行がポート0（拒否）ならData Channelにフォールバック
+        if self.video_track.is_some() {
+            let accepted = media_accepted(sdp, "video");
+            self.video_negotiated.store(accepted, Ordering::SeqCst);
+            if accepted {
+                println!("[WebRTC] Video track accepted by client");
+            } else {
+                println!("[WebRTC] Video track rejected by client, falling back to data channel");
+            }
+        }
+        if self.audio_track.is_some() {
+            let accepted = media_accepted(sdp, "audio");
+            self.audio_negotiated.store(accepted, Ordering::SeqCst);
+            if !accepted {
+                println!("[WebRTC] Audio track rejected by client");
+            }
+        }
         Ok(())
     }
 
@@ -196,11 +376,23 @@ impl WebRTCScreenShare {
         }
 
         let data_channel = Arc::clone(&self.data_channel);
+        let video_track = self.video_track.clone().filter(|_| self.video_negotiated.load(Ordering::SeqCst));
+        if video_track.is_some() {
+            // 受信側のデコーダー初期化のため最初はキーフレームから送る
+            self.encoding.request_keyframe();
+        }
         let capture_running = Arc::clone(&self.capture_running);
         let capture_region = Arc::clone(&self.capture_region);
+        let monitor = self.monitor.clone();
+        let encoding = Arc::clone(&self.encoding);
+
+        // 音声は映像と別スレッドで20msごとに送る
+        if let Some((track, muted)) = self.audio_track.clone().filter(|_| self.audio_negotiated.load(Ordering::SeqCst)) {
+            *self.audio.lock() = Some(AudioStream::start(track, muted));
+        }
 
         tokio::spawn(async move {
-            capture_loop(data_channel, capture_running, capture_region).await;
+            capture_loop(data_channel, video_track, capture_running, capture_region, monitor, encoding).await;
         });
     }
 
@@ -215,17 +407,56 @@ impl WebRTCScreenShare {
             let mut running = self.capture_running.write().await;
             *running = false;
         }
+        if let Some(audio) = self.audio.lock().take() {
+            audio.stop();
+        }
         self.peer_connection.close().await?;
+        self.release_state();
         println!("[WebRTC] Connection closed");
         Ok(())
     }
 }
 
-/// 画面キャプチャループ
+impl Drop for WebRTCScreenShare {
+    fn drop(&mut self) {
+        self.release_state();
+    }
+}
+
+/// RTCPを読み続ける（インターセプターのNACK・レポート処理に必要）
+/// PLI/FIRでキーフレームを送り、帯域推定（REMB）と受信レポートの損失率で送信目標を調整する
+async fn read_rtcp(rtp_sender: Arc<RTCRtpSender>, encoding: Arc<StreamEncoding>) {
+    while let Ok((packets, _)) = rtp_sender.read_rtcp().await {
+        for packet in packets {
+            let packet = packet.as_any();
+            if packet.is::<PictureLossIndication>() || packet.is::<FullIntraRequest>() {
+                encoding.request_keyframe();
+            } else if let Some(remb) = packet.downcast_ref::<ReceiverEstimatedMaximumBitrate>() {
+                encoding.rate.lock().apply_estimate((remb.bitrate / 1000.0) as u32);
+            } else if let Some(rr) = packet.downcast_ref::<ReceiverReport>() {
+                if rr.reports.iter().any(|r| r.fraction_lost > CONGESTION_FRACTION_LOST) {
+                    encoding.report_stats(&StreamStats {
+                        received_kbps: 0.0,
+                        received_fps: 0.0,
+                        decode_ms: 0.0,
+                        dropped_frames: 1,
+                        transport: "webrtc-rtcp".to_string(),
+                    });
+                }
+            }
+        }
+    }
+    println!("[WebRTC] RTCP reader ended");
+}
+
+/// 画面キャプチャループ（キャプチャサービスのフレームをエンコードしてビデオトラックまたはData Channelへ送る）
 async fn capture_loop(
     data_channel: Arc<RwLock<Option<Arc<RTCDataChannel>>>>,
+    video_track: Option<Arc<TrackLocalStaticSample>>,
     capture_running: Arc<RwLock<bool>>,
     capture_region: Arc<ParkingRwLock<Option<CaptureRegion>>>,
+    monitor: MonitorSelection,
+    encoding: Arc<StreamEncoding>,
 ) {
     // Data Channelの参照を事前に取得（キャッシュ）
     let cached_dc = {
@@ -237,65 +468,34 @@ async fn capture_loop(
     let result = tokio::task::spawn_blocking(move || {
         let rt = tokio::runtime::Handle::current();
 
-        // 前のCapturerが解放されるまで待機
-        // macOSのDisplay Streamコールバックが完全に終了するまで待機
-        // scrapライブラリのquartzバックエンドに競合状態があるため長めに待つ
-        println!("[WebRTC] Waiting for system to release display resources (3s)...");
-        std::thread::sleep(Duration::from_secs(3));
-
-        // Capturerの作成をリトライ（最大10回、1秒間隔）
-        let mut capturer = None;
-        for attempt in 1..=10 {
-            let display = match Display::primary() {
-                Ok(d) => {
-                    println!("[WebRTC] Got primary display (attempt {})", attempt);
-                    d
-                }
-                Err(e) => {
-                    eprintln!("[WebRTC] Failed to get display (attempt {}): {:?}", attempt, e);
-                    std::thread::sleep(Duration::from_millis(1000));
-                    continue;
-                }
-            };
+        // WebSocket経路と同じキャプチャを共有する
+        let capture = capture::subscribe(&monitor);
+        println!("[WebRTC] Subscribed to capture service for {:?}", monitor);
 
-            match Capturer::new(display) {
-                Ok(c) => {
-                    println!("[WebRTC] Capturer created successfully (attempt {})", attempt);
-                    capturer = Some(c);
-                    break;
-                }
-                Err(e) => {
-                    eprintln!("[WebRTC] Failed to create capturer (attempt {}): {:?}", attempt, e);
-                    std::thread::sleep(Duration::from_millis(1000));
-                }
-            }
-        }
-
-        let mut capturer = match capturer {
-            Some(c) => c,
-            None => {
-                eprintln!("[WebRTC] Failed to create capturer after 5 attempts");
-                return;
-            }
-        };
-
-        let width = capturer.width();
-        let height = capturer.height();
-
-        println!("[WebRTC] Starting capture: {}x{}", width, height);
-
-        let target_fps = 30;
-        let frame_duration = Duration::from_millis(1000 / target_fps);
+        let mut seq: u64 = 0;
         let mut frame_count: u64 = 0;
         let mut last_send_time = Instant::now();
-        let mut would_block_count: u32 = 0;
+        // ビデオトラックのサンプル間隔（RTPタイムスタンプの進み幅）
+        let mut last_sample_time = Instant::now();
+        // 静止画面ではエンコードを省略する
+        let mut gate = FrameGate::default();
+        // 静止したら表示中のビューポートを段階的に高画質化する
+        let mut refiner = Refiner::default();
+        // ビューポート優先時の周辺画像
+        let mut surround = SurroundEncoder::default();
+        // 送信しなかったフレームも含めたループ回数（静止画面でも停止を検知するため）
+        let mut iteration: u64 = 0;
 
         // Data Channelをローカル変数として保持
         let dc = cached_dc;
 
+        // エンコーダーはこのループが持つ（モードが変わったら作り直す）
+        let mut encoder: Box<dyn Encoder> = codec::create_encoder(encoding.mode());
+        let mut track_encoder = H264FrameEncoder::default();
+
         loop {
-            // 実行フラグチェック（30フレームごと、または最初のフレーム）
-            if frame_count % 30 == 0 {
+            // 実行フラグチェック（30回ごと、または最初のループ）
+            if iteration % 30 == 0 {
                 let running = rt.block_on(async {
                     *capture_running.read().await
                 });
@@ -303,83 +503,179 @@ async fn capture_loop(
                     break;
                 }
             }
+            iteration += 1;
 
             let start = Instant::now();
+            let targets = encoding.targets();
+            let frame_duration = Duration::from_millis(1000 / targets.fps.max(1) as u64);
 
-            match capturer.frame() {
-                Ok(frame) => {
-                    let capture_time = start.elapsed();
+            let Some(frame) = capture.next_frame(seq, Duration::from_millis(500)) else {
+                continue;
+            };
+            seq = frame.seq;
+
+            // 前回から変化がなければエンコードしない（定期的にキーフレームだけ送る）
+            let region = capture_region.read().clone();
+            // 高画質化済みの静止画面は、定期キーフレームで低画質に戻さない（要求されたキーフレームは送る）
+            let forced = encoding.keyframe_pending();
+            let cursor = if encoding.cursor_burn_in() { cursor::current() } else { None };
+            let send = match gate.check(frame.content_id, &region, cursor.as_ref().map(CursorState::key)) {
+                FrameAction::Skip => forced,
+                FrameAction::Keyframe if refiner.refined() => forced,
+                FrameAction::Keyframe => {
+                    encoding.force_keyframe.store(true, Ordering::SeqCst);
+                    true
+                }
+                FrameAction::Encode => true,
+            };
+            if !send {
+                METRICS.webrtc_frames_skipped.inc();
+                if let Some(dc) = open_channel(&dc) {
+                    if let Some(level) = refiner.next_level(region.as_ref()) {
+                        let frame = cursor::burned_in(frame, cursor.as_ref());
+                        match refiner.encode(&frame, region.as_ref(), level, encoding.mode()) {
+                            Ok(packets) => {
+                                let total_size = send_packets(&rt, dc, packets);
+                                METRICS.webrtc_bytes_sent.add(total_size as u64);
+                                println!("[WebRTC] Refined viewport: level {:?} ({} KB)", level.1, total_size / 1024);
+                            }
+                            Err(e) => eprintln!("[WebRTC] Refine error: {}", e),
+                        }
+                    }
+                }
+                std::thread::sleep(frame_duration);
+                continue;
+            }
+            // 動きがあったら高速な非可逆エンコードに戻る
+            refiner.reset();
+            // カーソルはこの接続でエンコードするフレームにだけ合成する
+            let frame = cursor::burned_in(frame, cursor.as_ref());
+
+            let codec = encoding.mode();
+            if encoder.codec() != codec {
+                println!("[WebRTC] Switching encoder: {} -> {}", encoder.codec().name(), codec.name());
+                encoder = codec::create_encoder(codec);
+            }
+            // 新しいクライアント・エラー回復・定期送信のキーフレーム
+            let keyframe = encoding.take_keyframe();
+            if keyframe {
+                encoder.force_keyframe();
+                track_encoder.force_keyframe();
+            }
 
-                    // キャプチャ領域を取得
-                    let region = capture_region.read().clone();
+            // 領域情報をログ出力（最初の5フレームのみ）
+            if frame_count < 5 {
+                if let Some(ref r) = region {
+                    println!("[WebRTC] Region: {}x{} at ({}, {})", r.width, r.height, r.x, r.y);
+                } else {
+                    println!("[WebRTC] Region: None (full screen)");
+                }
+            }
 
-                    // 領域情報をログ出力（最初の5フレームのみ）
-                    if frame_count < 5 {
-                        if let Some(ref r) = region {
-                            println!("[WebRTC] Region: {}x{} at ({}, {})", r.width, r.height, r.x, r.y);
-                        } else {
-                            println!("[WebRTC] Region: None (full screen)");
+            // フレームをエンコード（コーデックに応じて複数パケット）
+            let encode_start = Instant::now();
+            let display = encoding.display();
+            let roi = roi::roi_layout(&frame, region.as_ref(), &targets, display.as_ref());
+            let layout = match roi {
+                Some(ref roi) => roi.viewport,
+                None => capture::layout(&frame, region.as_ref(), &targets, display.as_ref()),
+            };
+            // ビューポート優先: ストリームはビューポートだけなので、先に範囲（と周辺画像）を送る
+            if let (Some(roi), Some(dc)) = (roi, open_channel(&dc)) {
+                let with_surround = surround.due(&frame, &roi, keyframe);
+                match SurroundEncoder::packets(&frame, &roi, with_surround) {
+                    Ok(packets) => METRICS.webrtc_bytes_sent.add(send_packets(&rt, dc, packets) as u64),
+                    Err(e) => eprintln!("[WebRTC] {}", e),
+                }
+            }
+            if let Some(ref track) = video_track {
+                // ビデオトラック: RTPパケット化・再送はwebrtcクレートに任せる
+                let h264_data = match track_encoder.encode_bitstream(&frame, &layout, &targets) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        if should_log_frame(frame_count) {
+                            eprintln!("[H264] Encode error: {}", e);
+                        }
+                        continue;
+                    }
+                };
+                let encode_time = encode_start.elapsed();
+                METRICS.webrtc_encode_seconds.observe_duration(encode_time);
+                if !h264_data.is_empty() {
+                    let size = h264_data.len();
+                    let sample = Sample {
+                        data: Bytes::from(h264_data),
+                        duration: last_sample_time.elapsed(),
+                        ..Default::default()
+                    };
+                    last_sample_time = Instant::now();
+                    if let Err(e) = rt.block_on(track.write_sample(&sample)) {
+                        if should_log_frame(frame_count) {
+                            eprintln!("[WebRTC] Track write error: {} (size: {} KB)", e, size / 1024);
                         }
                     }
 
-                    // フレームをエンコード（JPEG or H.264、複数パケット対応）
-                    let encode_start = Instant::now();
-                    if let Some(packets) = encode_frame_auto(&frame, width, height, region, frame_count) {
-                        let encode_time = encode_start.elapsed();
-                        if let Some(ref dc) = dc {
-                            // Data Channelが開いているか確認
-                            let dc_state = dc.ready_state();
-                            if dc_state == webrtc::data_channel::data_channel_state::RTCDataChannelState::Open {
-                                let total_size: usize = packets.iter().map(|p| p.len()).sum();
-                                let packet_count = packets.len();
-
-                                // 各パケットを送信
-                                rt.block_on(async {
-                                    for packet in packets {
-                                        let data = Bytes::from(packet);
-                                        if let Err(e) = dc.send(&data).await {
-                                            if frame_count % 30 == 0 {
-                                                eprintln!("[WebRTC] Send error: {} (size: {} KB)", e, data.len() / 1024);
-                                            }
-                                            break;
-                                        }
-                                    }
-                                });
-
-                                frame_count += 1;
-                                // 最初の10フレームと、その後は100フレームごとにログ
-                                if frame_count <= 10 || frame_count % 100 == 0 {
-                                    let elapsed = last_send_time.elapsed();
-                                    let fps = if frame_count > 1 { (frame_count as f64) / elapsed.as_secs_f64() } else { 0.0 };
-                                    let mode_str = if get_encoding_mode() == EncodingMode::H264 { "H264" } else { "JPEG" };
-                                    println!("[WebRTC] Frame {} sent ({} KB, {} packets, {}), {:.1} fps, capture={:?}, encode={:?}",
-                                        frame_count, total_size / 1024, packet_count, mode_str, fps, capture_time, encode_time);
-                                    if frame_count == 100 {
-                                        last_send_time = Instant::now();
+                    METRICS.webrtc_frames_sent.inc();
+                    METRICS.webrtc_bytes_sent.add(size as u64);
+                    frame_count += 1;
+                    if should_log_frame(frame_count) {
+                        println!("[WebRTC] Frame {} sent ({} KB, video track), {}x{}, encode={:?}",
+                            frame_count, size / 1024, layout.width, layout.height, encode_time);
+                    }
+                }
+            } else {
+                let packets = match encoder.encode(&frame, &layout, &targets) {
+                    Ok(packets) => packets,
+                    Err(e) => {
+                        if should_log_frame(frame_count) {
+                            eprintln!("[WebRTC] Encode error ({}): {}", codec.name(), e);
+                        }
+                        continue;
+                    }
+                };
+                let encode_time = encode_start.elapsed();
+                METRICS.webrtc_encode_seconds.observe_duration(encode_time);
+                if packets.is_empty() {
+                    // 送るものなし（変化したタイルがない、サイズ超過でスキップなど）
+                } else if let Some(ref dc) = dc {
+                    // Data Channelが開いているか確認
+                    let dc_state = dc.ready_state();
+                    if dc_state == webrtc::data_channel::data_channel_state::RTCDataChannelState::Open {
+                        let total_size: usize = packets.iter().map(|p| p.len()).sum();
+                        let packet_count = packets.len();
+
+                        // 各パケットを送信
+                        rt.block_on(async {
+                            for packet in packets {
+                                let data = Bytes::from(packet);
+                                if let Err(e) = dc.send(&data).await {
+                                    if frame_count % 30 == 0 {
+                                        eprintln!("[WebRTC] Send error: {} (size: {} KB)", e, data.len() / 1024);
                                     }
+                                    break;
                                 }
-                            } else if frame_count == 0 {
-                                // 最初のフレームでData Channelが開いていない場合のみログ
-                                println!("[WebRTC] Data channel not open yet: {:?}", dc_state);
                             }
-                        } else if frame_count == 0 {
-                            println!("[WebRTC] Data channel not available");
+                        });
+
+                        METRICS.webrtc_frames_sent.inc();
+                        METRICS.webrtc_bytes_sent.add(total_size as u64);
+                        frame_count += 1;
+                        // 最初の10フレームと、その後は100フレームごとにログ
+                        if frame_count <= 10 || frame_count % 100 == 0 {
+                            let elapsed = last_send_time.elapsed();
+                            let fps = if frame_count > 1 { (frame_count as f64) / elapsed.as_secs_f64() } else { 0.0 };
+                            println!("[WebRTC] Frame {} sent ({} KB, {} packets, {}), {:.1} fps, {}x{}, encode={:?}",
+                                frame_count, total_size / 1024, packet_count, codec.name(), fps, layout.width, layout.height, encode_time);
+                            if frame_count == 100 {
+                                last_send_time = Instant::now();
+                            }
                         }
+                    } else if frame_count == 0 {
+                        // 最初のフレームでData Channelが開いていない場合のみログ
+                        println!("[WebRTC] Data channel not open yet: {:?}", dc_state);
                     }
-                }
-                Err(ref e) if e.kind() == WouldBlock => {
-                    // フレーム準備中 - 短いスリープで待機
-                    would_block_count += 1;
-                    if would_block_count == 100 || would_block_count % 1000 == 0 {
-                        println!("[WebRTC] WouldBlock count: {}", would_block_count);
-                    }
-                    // WouldBlockの場合は短いスリープで次のフレームを待つ
-                    std::thread::sleep(Duration::from_millis(5));
-                    continue;
-                }
-                Err(e) => {
-                    eprintln!("[WebRTC] Capture error: {}", e);
-                    break;
+                } else if frame_count == 0 {
+                    println!("[WebRTC] Data channel not available");
                 }
             }
 
@@ -398,418 +694,66 @@ async fn capture_loop(
     }
 }
 
-/// フレームエンコード（JPEG、ビューポート・画質モード対応）
-fn encode_frame(bgra: &[u8], width: usize, height: usize, region: Option<CaptureRegion>, frame_count: u64) -> Option<Vec<u8>> {
-    let should_log = frame_count < 5;
-    let encode_start = std::time::Instant::now();
-    let bytes_per_pixel = 4;
-
-    // macOS IOSurfaceは128バイトアライメントを使用
-    let row_bytes = width * bytes_per_pixel;
-    let alignment = 128;
-    let actual_stride = ((row_bytes + alignment - 1) / alignment) * alignment;
-
-    let expected_len = actual_stride * height;
-    if bgra.len() < expected_len {
-        eprintln!("[WebRTC] encode_frame: buffer too small: {} < {}", bgra.len(), expected_len);
-        return None;
-    }
-
-    // Retinaディスプレイ対応: スケールファクターを検出
-    // キャプチャはネイティブ解像度、CaptureRegionは論理座標（AppleScriptから）
-    let display_scale = if let Some(r) = &region {
-        if r.height > 0 {
-            // キャプチャ高さと領域高さの比率からスケールを推定
-            let ratio = height as f64 / (r.height as f64 + r.y as f64);
-            if ratio > 1.5 { 2usize } else { 1usize }
-        } else { 1usize }
-    } else { 1usize };
-
-    if should_log {
-        println!("[WebRTC] Display scale: {}x, capture: {}x{}, region: {:?}",
-            display_scale, width, height, region);
-    }
-
-    // ウィンドウ領域をネイティブ座標でクロップ
-    let (crop_x, crop_y, crop_w, crop_h, logical_w, logical_h) = match &region {
-        Some(r) => {
-            // 論理座標をネイティブ座標に変換してクロップ
-            let x = ((r.x as usize) * display_scale).min(width.saturating_sub(1));
-            let y = ((r.y as usize) * display_scale).min(height.saturating_sub(1));
-            let w = ((r.width as usize) * display_scale).min(width.saturating_sub(x));
-            let h = ((r.height as usize) * display_scale).min(height.saturating_sub(y));
-
-            (x, y, w, h, r.width as usize, r.height as usize)
-        }
-        None => (0, 0, width, height, width / display_scale, height / display_scale),
-    };
-
-    // 常に高画質モード（シンプル化）
-    let is_low_quality = false;
-
-    // サイズが有効か確認
-    if crop_w == 0 || crop_h == 0 {
-        eprintln!("[WebRTC] encode_frame: invalid crop size: {}x{}", crop_w, crop_h);
-        return None;
-    }
-
-    // BGRAからRGBAに変換（切り抜き領域のみ、rayon並列化版）
-    let rgba_size = crop_w * crop_h * 4;
-    let mut rgba_data = vec![0u8; rgba_size];
-    let row_width = crop_w * 4;
-
-    // 行単位で並列処理
-    rgba_data
-        .par_chunks_mut(row_width)
-        .enumerate()
-        .for_each(|(row_idx, dst_row)| {
-            let y = crop_y + row_idx;
-            let row_start = y * actual_stride + crop_x * bytes_per_pixel;
-            let row_end = row_start + crop_w * bytes_per_pixel;
-
-            if row_end <= bgra.len() {
-                let src_row = &bgra[row_start..row_end];
-                for (dst_chunk, src_chunk) in dst_row.chunks_exact_mut(4).zip(src_row.chunks_exact(4)) {
-                    dst_chunk[0] = src_chunk[2]; // R (from B)
-                    dst_chunk[1] = src_chunk[1]; // G
-                    dst_chunk[2] = src_chunk[0]; // B (from R)
-                    dst_chunk[3] = 255;          // A
-                }
-            }
-        });
-
-    if rgba_data.len() != crop_w * crop_h * 4 {
-        return None;
-    }
-
-    let convert_time = encode_start.elapsed();
-
-    let img: ImageBuffer<Rgba<u8>, _> = ImageBuffer::from_raw(
-        crop_w as u32,
-        crop_h as u32,
-        rgba_data,
-    )?;
-
-    let dynamic_img = DynamicImage::ImageRgba8(img);
-
-    // モバイルと同じロジック: 論理ピクセル数で判定（H264と統一）
-    // 600,000ピクセル以上なら1/2サイズで送信
-    let logical_pixel_count = (logical_w * logical_h) as u32;
-
-    // デバッグ: 常に最初の5フレームでサイズ情報を出力
-    if should_log {
-        println!("[WebRTC/JPEG] DEBUG: crop={}x{}, logical={}x{}, display_scale={}, region={:?}",
-            crop_w, crop_h, logical_w, logical_h, display_scale, region);
-    }
-
-    let (new_width, new_height, start_quality) = if logical_pixel_count <= 300000 {
-        // 小さいウィンドウ → 原寸、高品質
-        let w = (logical_w as u32 / 2) * 2;  // 偶数に
-        let h = (logical_h as u32 / 2) * 2;
-        if should_log {
-            println!("[WebRTC/JPEG] Small window: logical={}x{} ({}px) → original scale → {}x{}",
-                logical_w, logical_h, logical_pixel_count, w, h);
-        }
-        (w.max(2), h.max(2), 75u8)
-    } else if logical_pixel_count <= 600000 {
-        // 中程度のウィンドウ → 原寸、中品質
-        let w = (logical_w as u32 / 2) * 2;
-        let h = (logical_h as u32 / 2) * 2;
-        if should_log {
-            println!("[WebRTC/JPEG] Medium window: logical={}x{} ({}px) → original scale → {}x{}",
-                logical_w, logical_h, logical_pixel_count, w, h);
-        }
-        (w.max(2), h.max(2), 65u8)
-    } else {
-        // 大きいウィンドウ → 1/2サイズ
-        let w = ((logical_w / 2) as u32 / 2) * 2;  // 偶数に
-        let h = ((logical_h / 2) as u32 / 2) * 2;
-        if should_log {
-            println!("[WebRTC/JPEG] Large window: logical={}x{} ({}px) → 1/2 scale → {}x{}",
-                logical_w, logical_h, logical_pixel_count, w, h);
-        }
-        (w.max(2), h.max(2), 60u8)
-    };
-    let resize_start = std::time::Instant::now();
-    let final_img = dynamic_img.resize_exact(
-        new_width.max(1),
-        new_height.max(1),
-        image::imageops::FilterType::Nearest,  // 高速リサイズ
-    );
-    let resize_time = resize_start.elapsed();
-
-    let jpeg_start = std::time::Instant::now();
-    // 動的品質調整: 63KB以下になるまで品質を下げる（WebRTC上限64KB）
-    let max_size = 63 * 1024;
-    let mut quality = start_quality;
-    let original_quality = quality;
-
-    loop {
-        let mut jpeg_data = Vec::new();
-        let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg_data, quality);
-
-        if final_img.write_with_encoder(encoder).is_ok() {
-            if jpeg_data.len() <= max_size {
-                let jpeg_time = jpeg_start.elapsed();
-                if should_log {
-                    println!("[WebRTC] Timing: convert={:?}, resize={:?}, jpeg={:?}, total={:?}",
-                        convert_time, resize_time, jpeg_time, encode_start.elapsed());
-                    if quality < original_quality {
-                        println!("[WebRTC] Quality adjusted: {}% → {}%, size: {} KB", original_quality, quality, jpeg_data.len() / 1024);
-                    }
-                }
-                return Some(jpeg_data);
-            }
-            // 品質が最低でもサイズオーバーの場合はフレームをスキップ
-            if quality <= 10 {
-                eprintln!("[WebRTC] Frame too large even at {}% quality ({} KB), skipping", quality, jpeg_data.len() / 1024);
-                return None;
-            }
-            quality = quality.saturating_sub(5); // 5%刻みで細かく調整
-        } else {
-            return None;
-        }
-    }
+// アンサーでメディア（video / audio）のm行が受け入れられたか（ポート0は拒否）
+fn media_accepted(sdp: &str, kind: &str) -> bool {
+    let prefix = format!("m={} ", kind);
+    sdp.lines().any(|l| l.starts_with(&prefix) && !l.starts_with(&format!("{}0 ", prefix)))
 }
 
-/// エンコーディングモードを設定
-pub fn set_encoding_mode(mode: EncodingMode) {
-    let mut current_mode = ENCODING_MODE.write();
-    *current_mode = mode;
-    println!("[WebRTC] Encoding mode set to: {:?}",
-        match mode {
-            EncodingMode::Jpeg => "JPEG",
-            EncodingMode::H264 => "H.264",
-        });
+// 開いているData Channel（まだ開いていなければNone）
+fn open_channel(dc: &Option<Arc<RTCDataChannel>>) -> Option<&Arc<RTCDataChannel>> {
+    dc.as_ref()
+        .filter(|dc| dc.ready_state() == webrtc::data_channel::data_channel_state::RTCDataChannelState::Open)
 }
 
-/// 現在のエンコーディングモードを取得
-pub fn get_encoding_mode() -> EncodingMode {
-    *ENCODING_MODE.read()
-}
-
-/// H.264でフレームをエンコード（BGRAデータを直接受け取る）
-/// Data Channelの64KB制限に対応するため、フラグメントに分割して返す
-fn encode_frame_h264(bgra_data: &[u8], width: u32, height: u32, frame_count: u64) -> Option<Vec<Vec<u8>>> {
-    let should_log = frame_count < 10 || frame_count % 100 == 0;
-
-    // H.264エンコーダーを取得または作成
-    let mut encoder_guard = H264_ENCODER.lock();
-    if encoder_guard.is_none() {
-        match H264Encoder::new(width, height) {
-            Ok(encoder) => {
-                println!("[H264] Encoder initialized: {}x{}", width, height);
-                *encoder_guard = Some(encoder);
-            }
-            Err(e) => {
-                eprintln!("[H264] Failed to create encoder: {}", e);
-                return None;
-            }
-        }
-    }
-
-    let encoder = encoder_guard.as_mut()?;
-
-    // キーフレーム強制フラグをチェック
-    if FORCE_KEYFRAME.swap(false, Ordering::SeqCst) {
-        println!("[H264] Forcing keyframe (Data Channel opened)");
-        let _ = encoder.force_keyframe();
-    }
-
-    // H.264エンコード（BGRAを直接渡す）
-    let encode_start = Instant::now();
-    let h264_data = match encoder.encode_bgra(bgra_data, width, height) {
-        Ok(data) => data,
-        Err(e) => {
-            if should_log {
-                eprintln!("[H264] Encode error: {}", e);
+// 補助パケット（高画質化・ビューポート情報）を順に送る（返り値: 送信したバイト数）
+fn send_packets(rt: &tokio::runtime::Handle, dc: &RTCDataChannel, packets: Vec<Vec<u8>>) -> usize {
+    rt.block_on(async {
+        let mut sent = 0;
+        for packet in packets {
+            let size = packet.len();
+            if let Err(e) = dc.send(&Bytes::from(packet)).await {
+                eprintln!("[WebRTC] Send error: {} (size: {} KB)", e, size / 1024);
+                break;
             }
-            return None;
+            sent += size;
         }
-    };
-
-    if should_log {
-        println!("[H264] Encoded frame {}: {} bytes in {:?}",
-            frame_count, h264_data.len(), encode_start.elapsed());
-    }
-
-    // 空のフレーム（Pフレームでスキップされた場合など）
-    if h264_data.is_empty() {
-        return Some(vec![]);
-    }
-
-    // WebRTC Data Channelの制限（flutter_webrtcは16KB制限の可能性）
-    let max_packet_size = 15 * 1024; // 15KB（WebRTC安全圏）
-    if h264_data.len() <= max_packet_size {
-        // ヘッダー: [0x01] = H.264 single packet
-        let mut packet = Vec::with_capacity(h264_data.len() + 1);
-        packet.push(0x01); // H.264 single packet marker
-        packet.extend_from_slice(&h264_data);
-        return Some(vec![packet]);
-    }
-
-    // 大きいフレームはフラグメントに分割
-    // ヘッダー: [0x02, fragment_index, total_fragments, frame_id(2bytes)]
-    let frame_id = (frame_count & 0xFFFF) as u16;
-    let total_fragments = ((h264_data.len() + max_packet_size - 1) / max_packet_size) as u8;
-    let mut fragments = Vec::new();
-
-    for (i, chunk) in h264_data.chunks(max_packet_size).enumerate() {
-        let mut packet = Vec::with_capacity(chunk.len() + 5);
-        packet.push(0x02); // H.264 fragment marker
-        packet.push(i as u8); // fragment index
-        packet.push(total_fragments); // total fragments
-        packet.extend_from_slice(&frame_id.to_be_bytes()); // frame ID
-        packet.extend_from_slice(chunk);
-        fragments.push(packet);
-    }
-
-    if should_log && fragments.len() > 1 {
-        println!("[H264] Frame fragmented into {} packets", fragments.len());
-    }
-
-    Some(fragments)
+        sent
+    })
 }
 
-/// 統合エンコード関数（モードに応じてJPEGまたはH.264を使用）
-pub fn encode_frame_auto(
-    bgra: &[u8],
-    width: usize,
-    height: usize,
-    region: Option<CaptureRegion>,
-    frame_count: u64
-) -> Option<Vec<Vec<u8>>> {
-    let mode = get_encoding_mode();
-
-    match mode {
-        EncodingMode::Jpeg => {
-            // JPEG: 1パケットで返す
-            encode_frame(bgra, width, height, region, frame_count)
-                .map(|data| {
-                    // ヘッダー: [0x00] = JPEG packet
-                    let mut packet = Vec::with_capacity(data.len() + 1);
-                    packet.push(0x00); // JPEG marker
-                    packet.extend_from_slice(&data);
-                    vec![packet]
-                })
-        }
-        EncodingMode::H264 => {
-            let should_log = frame_count < 5;
-
-            // Retinaディスプレイ対応: スケールファクターを検出
-            // CaptureRegionは論理座標（AppleScriptから）、キャプチャはネイティブ解像度
-            let display_scale = if let Some(r) = &region {
-                if r.height > 0 {
-                    // キャプチャ高さと領域高さ+Yオフセットの比率からスケールを推定
-                    let ratio = height as f64 / (r.height as f64 + r.y as f64);
-                    if ratio > 1.5 { 2usize } else { 1usize }
-                } else { 1usize }
-            } else { 1usize };
-
-            if should_log {
-                println!("[H264] Display scale: {}x, capture: {}x{}, region: {:?}",
-                    display_scale, width, height, region);
-            }
-
-            // クロップしてBGRAデータを抽出（論理座標をネイティブ座標に変換）
-            let (crop_x, crop_y, crop_w, crop_h, logical_w, logical_h) = match &region {
-                Some(r) => {
-                    // 論理座標をネイティブ座標に変換
-                    let x = ((r.x as usize) * display_scale).min(width.saturating_sub(1));
-                    let y = ((r.y as usize) * display_scale).min(height.saturating_sub(1));
-                    let w = ((r.width as usize) * display_scale).min(width.saturating_sub(x));
-                    let h = ((r.height as usize) * display_scale).min(height.saturating_sub(y));
-                    // 論理サイズ（モバイルが期待するサイズ）
-                    (x, y, w, h, r.width as f32, r.height as f32)
-                }
-                None => {
-                    let logical_w = width as f32 / display_scale as f32;
-                    let logical_h = height as f32 / display_scale as f32;
-                    (0, 0, width, height, logical_w, logical_h)
-                }
-            };
-
-            if crop_w == 0 || crop_h == 0 {
-                return None;
-            }
-
-            // macOS IOSurfaceは128バイトアライメントを使用
-            let bytes_per_pixel = 4;
-            let row_bytes = width * bytes_per_pixel;
-            let alignment = 128;
-            let actual_stride = ((row_bytes + alignment - 1) / alignment) * alignment;
-
-            // BGRAからRGBAに変換しながらクロップ
-            let mut rgba_data = Vec::with_capacity(crop_w * crop_h * 4);
-            for y_pos in crop_y..(crop_y + crop_h) {
-                let row_start = y_pos * actual_stride + crop_x * bytes_per_pixel;
-                for x_pos in 0..crop_w {
-                    let idx = row_start + x_pos * 4;
-                    if idx + 3 < bgra.len() {
-                        rgba_data.push(bgra[idx + 2]); // R
-                        rgba_data.push(bgra[idx + 1]); // G
-                        rgba_data.push(bgra[idx]);     // B
-                        rgba_data.push(bgra[idx + 3]); // A
-                    }
-                }
-            }
-
-            // 画像を作成してリサイズ
-            let img: ImageBuffer<Rgba<u8>, _> = ImageBuffer::from_raw(
-                crop_w as u32,
-                crop_h as u32,
-                rgba_data,
-            )?;
-
-            let dynamic_img = DynamicImage::ImageRgba8(img);
-
-            // モバイルと同じロジック: 論理ピクセル数で判定
-            // 600,000ピクセル以上なら1/2サイズで送信（モバイルの期待に合わせる）
-            let logical_pixel_count = (logical_w * logical_h) as u32;
-            let (new_width, new_height) = if logical_pixel_count > 600000 {
-                // 1/2サイズで送信
-                let w = ((logical_w / 2.0) as u32 / 2) * 2;  // 2の倍数に（YUV420要件）
-                let h = ((logical_h / 2.0) as u32 / 2) * 2;
-                (w.max(2), h.max(2))
-            } else {
-                // 原寸で送信
-                let w = (logical_w as u32 / 2) * 2;
-                let h = (logical_h as u32 / 2) * 2;
-                (w.max(2), h.max(2))
-            };
-
-            if should_log {
-                if logical_pixel_count > 600000 {
-                    println!("[H264] Large window: logical={}x{} ({}px) → 1/2 scale → {}x{}",
-                        logical_w as i32, logical_h as i32, logical_pixel_count, new_width, new_height);
-                } else {
-                    println!("[H264] Small window: logical={}x{} ({}px) → original scale → {}x{}",
-                        logical_w as i32, logical_h as i32, logical_pixel_count, new_width, new_height);
-                }
-            }
-
-            let final_img = dynamic_img.resize_exact(
-                new_width.max(2),
-                new_height.max(2),
-                image::imageops::FilterType::Triangle,
-            );
-
-            // RGBAからBGRAに戻す（H.264エンコーダー用）
-            let rgba_bytes = final_img.to_rgba8();
-            let mut bgra_resized = Vec::with_capacity((new_width * new_height * 4) as usize);
-            for pixel in rgba_bytes.pixels() {
-                bgra_resized.push(pixel[2]); // B
-                bgra_resized.push(pixel[1]); // G
-                bgra_resized.push(pixel[0]); // R
-                bgra_resized.push(pixel[3]); // A
-            }
-
-            if should_log {
-                println!("[H264] Sending frame: crop={}x{}, final={}x{}",
-                    crop_w, crop_h, new_width, new_height);
-            }
+// 最初の10フレームと、その後は100フレームごとにログを出す
+fn should_log_frame(frame_count: u64) -> bool {
+    frame_count < 10 || frame_count % 100 == 0
+}
 
-            encode_frame_h264(&bgra_resized, new_width, new_height, frame_count)
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_encoding_is_per_session() {
+        let a = StreamEncoding::default();
+        let b = StreamEncoding::default();
+        let initial = b.targets();
+
+        // 片方のセッションの欠落・REMB・キーフレーム要求はもう片方に影響しない
+        let lossy = StreamStats {
+            received_kbps: 2500.0,
+            received_fps: 30.0,
+            decode_ms: 5.0,
+            dropped_frames: 3,
+            transport: "webrtc".to_string(),
+        };
+        assert!(a.report_stats(&lossy).bitrate_kbps < initial.bitrate_kbps);
+        a.rate.lock().apply_estimate(500);
+        a.request_keyframe();
+        a.set_cursor_burn_in(true);
+
+        assert_eq!(b.targets(), initial);
+        assert!(!b.keyframe_pending());
+        assert!(!b.cursor_burn_in());
+        assert!(a.take_keyframe());
+        assert!(!a.keyframe_pending());
     }
 }
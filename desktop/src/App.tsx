@@ -17,11 +17,30 @@ interface TunnelInfo {
   qr_code: string;
 }
 
+interface TunnelUrlChanged extends TunnelInfo {
+  previous_url: string;
+}
+
+interface TunnelStopped {
+  provider: string;
+  reason: string;
+  restarting: boolean;
+  retry_in_secs: number | null;
+}
+
 interface CloudflaredStatus {
   installed: boolean;
   is_system: boolean;
   is_local: boolean;
   path: string | null;
+  version: string | null;
+  pinned_version: string;
+}
+
+interface InstallProgress {
+  stage: "download" | "verify" | "extract" | "done";
+  percent: number | null;
+  message: string;
 }
 
 interface ConnectionRequest {
@@ -68,6 +87,7 @@ function App() {
   const [cloudflaredStatus, setCloudflaredStatus] = useState<CloudflaredStatus | null>(null);
   const [tunnelInfo, setTunnelInfo] = useState<TunnelInfo | null>(null);
   const [tunnelStarting, setTunnelStarting] = useState(false);
+  const [tunnelRestarting, setTunnelRestarting] = useState(false);
   const [showExternalQR, setShowExternalQR] = useState(false);
   const [installing, setInstalling] = useState(false);
   const [installProgress, setInstallProgress] = useState<string | null>(null);
@@ -139,13 +159,33 @@ function App() {
       console.log("Tunnel started:", event.payload);
       setTunnelInfo(event.payload);
       setTunnelStarting(false);
+      setTunnelRestarting(false);
       setShowExternalQR(true);
     });
 
+    // Listen for a new URL after the tunnel was restarted (the old QR code no longer works)
+    const unlistenTunnelUrlChanged = listen<TunnelUrlChanged>("tunnel_url_changed", (event) => {
+      console.log("Tunnel URL changed:", event.payload.previous_url, "->", event.payload.url);
+      setTunnelInfo(event.payload);
+      setTunnelStarting(false);
+      setTunnelRestarting(false);
+    });
+
+    // Listen for tunnel stopped (clear the stale QR code until a new URL arrives)
+    const unlistenTunnelStopped = listen<TunnelStopped>("tunnel_stopped", (event) => {
+      console.log("Tunnel stopped:", event.payload);
+      setTunnelInfo(null);
+      setTunnelStarting(event.payload.restarting);
+      setTunnelRestarting(event.payload.restarting);
+      if (!event.payload.restarting) {
+        setShowExternalQR(false);
+      }
+    });
+
     // Listen for install progress
-    const unlistenProgress = listen<string>("cloudflared_install_progress", (event) => {
-      console.log("Install progress:", event.payload);
-      setInstallProgress(event.payload);
+    const unlistenProgress = listen<InstallProgress>("cloudflared_install_progress", (event) => {
+      const { message, percent } = event.payload;
+      setInstallProgress(percent !== null ? `${message} ${percent}%` : message);
     });
 
     // Listen for connection requests
@@ -163,6 +203,8 @@ function App() {
 
     return () => {
       unlistenTunnel.then(fn => fn());
+      unlistenTunnelUrlChanged.then(fn => fn());
+      unlistenTunnelStopped.then(fn => fn());
       unlistenProgress.then(fn => fn());
       unlistenConnectionRequest.then(fn => fn());
     };
@@ -197,6 +239,7 @@ function App() {
             console.log("Tunnel info received via polling:", info);
             setTunnelInfo(info);
             setTunnelStarting(false);
+            setTunnelRestarting(false);
             setShowExternalQR(true);
           }
         }
@@ -480,7 +523,7 @@ function App() {
               ) : tunnelStarting ? (
                 <div className="tunnel-loading">
                   <div className="spinner"></div>
-                  <p>{t.startingTunnel}</p>
+                  <p>{tunnelRestarting ? t.restartingTunnel : t.startingTunnel}</p>
                 </div>
               ) : (
                 <div className="tunnel-setup">
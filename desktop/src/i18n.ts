@@ -241,6 +241,13 @@ export function getTranslations(lang: AppLanguage) {
       ko: '터널 시작 중...',
       de: 'Tunnel wird gestartet...',
     }, lang),
+    restartingTunnel: t({
+      ja: 'トンネルを再接続中...',
+      en: 'Tunnel disconnected. Reconnecting...',
+      zh: '隧道已断开，正在重新连接...',
+      ko: '터널 연결이 끊겼습니다. 다시 연결 중...',
+      de: 'Tunnel getrennt. Verbindung wird wiederhergestellt...',
+    }, lang),
     cloudflaredNotInstalled: t({
       ja: 'cloudflaredがインストールされていません',
       en: 'cloudflared is not installed',
@@ -4,6 +4,7 @@ class ConnectionInfo {
   final String token;
   final bool isExternal; // 外部接続（Cloudflare Tunnel）かどうか
   final String? externalUrl; // 外部接続時のURL
+  final String? desktopPublicKey; // E2E暗号化用のデスクトップ公開鍵（base64url）
 
   ConnectionInfo({
     required this.ip,
@@ -11,28 +12,37 @@ class ConnectionInfo {
     required this.token,
     this.isExternal = false,
     this.externalUrl,
+    this.desktopPublicKey,
   });
 
-  factory ConnectionInfo.fromQrData(String data) {
-    // 外部接続（wss://で始まる場合）
-    if (data.startsWith('wss://')) {
+  factory ConnectionInfo.fromQrData(String qrData) {
+    // 末尾の "#<公開鍵>" を分離
+    final hashIndex = qrData.indexOf('#');
+    final data = hashIndex == -1 ? qrData : qrData.substring(0, hashIndex);
+    final publicKey = hashIndex == -1 ? null : qrData.substring(hashIndex + 1);
+
+    // 外部接続（wss://で始まる場合。ws://はローカルでのリレー動作確認用）
+    if (data.startsWith('wss://') || data.startsWith('ws://')) {
       // 形式: wss://xxxx.trycloudflare.com:token
+      //       wss://relay.example.com/connect/<desktop-id>:token
+      final secure = data.startsWith('wss://');
       final colonIndex = data.lastIndexOf(':');
-      if (colonIndex == -1 || colonIndex <= 6) {
+      if (colonIndex == -1 || colonIndex <= (secure ? 6 : 5)) {
         throw FormatException('Invalid external connection format');
       }
       final url = data.substring(0, colonIndex);
       final token = data.substring(colonIndex + 1);
 
       // URLからホスト名を抽出
-      final host = url.replaceFirst('wss://', '');
+      final host = url.replaceFirst(secure ? 'wss://' : 'ws://', '');
 
       return ConnectionInfo(
         ip: host,
-        port: 443, // wssはデフォルトで443
+        port: secure ? 443 : 80, // wssはデフォルトで443
         token: token,
         isExternal: true,
         externalUrl: url,
+        desktopPublicKey: publicKey,
       );
     }
 
@@ -50,6 +60,7 @@ class ConnectionInfo {
       ip: parts[0],
       port: port,
       token: parts.sublist(2).join(':'),
+      desktopPublicKey: publicKey,
     );
   }
 
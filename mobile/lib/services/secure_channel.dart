@@ -0,0 +1,318 @@
+import 'dart:async';
+import 'dart:convert';
+import 'dart:math';
+import 'dart:typed_data';
+
+import 'package:cryptography/cryptography.dart';
+import 'package:web_socket_channel/web_socket_channel.dart';
+
+// WebSocket内のエンドツーエンド暗号化（Noise NK）
+// デスクトップの secure_channel.rs と対になるクライアント（イニシエーター）側
+//
+// ハンドシェイク: `[MAGIC, VERSION] + Noiseメッセージ`（双方向に1往復）
+// トランスポート: バイナリ1通 = `[u16 BE 長さ][暗号文]` の繰り返し
+// 復号した平文を連結すると `[種別][ペイロード]`（0x00 = JSONテキスト, 0x01 = バイナリ）
+
+const _protocolName = 'Noise_NK_25519_ChaChaPoly_BLAKE2s';
+const _magic = 0x4e; // 'N'
+const _version = 0x01;
+// Noiseメッセージの最大長・認証タグ長・ハッシュ長・公開鍵長
+const _maxMessage = 65535;
+const _tagLength = 16;
+const _hashLength = 32;
+const _keyLength = 32;
+
+const _kindText = 0x00;
+const _kindBinary = 0x01;
+
+// デスクトップからの応答を待つ時間
+const _handshakeTimeout = Duration(seconds: 30);
+
+final _blake2s = Blake2s();
+final _hmac = Hmac(Blake2s());
+final _aead = Chacha20.poly1305Aead();
+final _x25519 = X25519();
+
+Future<List<int>> _hash(List<int> data) async => (await _blake2s.hash(data)).bytes;
+
+Future<List<int>> _hmacHash(List<int> key, List<int> data) async =>
+    (await _hmac.calculateMac(data, secretKey: SecretKey(key))).bytes;
+
+// NoiseのHKDF（出力2つ）
+Future<(List<int>, List<int>)> _hkdf(List<int> chainingKey, List<int> input) async {
+  final tempKey = await _hmacHash(chainingKey, input);
+  final output1 = await _hmacHash(tempKey, [0x01]);
+  final output2 = await _hmacHash(tempKey, [...output1, 0x02]);
+  return (output1, output2);
+}
+
+Future<List<int>> _dh(SimpleKeyPair keyPair, List<int> remotePublicKey) async {
+  final shared = await _x25519.sharedSecretKey(
+    keyPair: keyPair,
+    remotePublicKey: SimplePublicKey(remotePublicKey, type: KeyPairType.x25519),
+  );
+  return shared.extractBytes();
+}
+
+// 1方向の暗号化状態（ノンスはメッセージごとに1ずつ増やす）
+class _CipherState {
+  final SecretKey _key;
+  int _nonce = 0;
+
+  _CipherState(List<int> key) : _key = SecretKey(key);
+
+  // ChaChaPolyのノンス: 4バイトの0 + 64ビットのカウンター（リトルエンディアン）
+  List<int> _nextNonce() {
+    final nonce = Uint8List(12);
+    ByteData.sublistView(nonce).setUint64(4, _nonce++, Endian.little);
+    return nonce;
+  }
+
+  Future<List<int>> encrypt(List<int> plaintext, {List<int> ad = const []}) async {
+    final box = await _aead.encrypt(plaintext, secretKey: _key, nonce: _nextNonce(), aad: ad);
+    return [...box.cipherText, ...box.mac.bytes];
+  }
+
+  Future<List<int>> decrypt(List<int> ciphertext, {List<int> ad = const []}) async {
+    if (ciphertext.length < _tagLength) {
+      throw const FormatException('Truncated message');
+    }
+    final split = ciphertext.length - _tagLength;
+    final box = SecretBox(
+      ciphertext.sublist(0, split),
+      nonce: _nextNonce(),
+      mac: Mac(ciphertext.sublist(split)),
+    );
+    return _aead.decrypt(box, secretKey: _key, aad: ad);
+  }
+}
+
+// ハンドシェイク中の状態（チェイニングキー・ハンドシェイクハッシュ）
+class _SymmetricState {
+  List<int> _h;
+  List<int> _ck;
+  _CipherState? _cipher;
+
+  _SymmetricState._(this._h) : _ck = _h;
+
+  static Future<_SymmetricState> initialize() async {
+    final name = utf8.encode(_protocolName);
+    // プロトコル名がハッシュ長を超える場合はハッシュする
+    final h = name.length <= _hashLength
+        ? [...name, ...List.filled(_hashLength - name.length, 0)]
+        : await _hash(name);
+    return _SymmetricState._(h);
+  }
+
+  Future<void> mixHash(List<int> data) async {
+    _h = await _hash([..._h, ...data]);
+  }
+
+  Future<void> mixKey(List<int> input) async {
+    final (ck, key) = await _hkdf(_ck, input);
+    _ck = ck;
+    _cipher = _CipherState(key);
+  }
+
+  Future<List<int>> encryptAndHash(List<int> plaintext) async {
+    final cipher = _cipher;
+    final ciphertext = cipher == null ? plaintext : await cipher.encrypt(plaintext, ad: _h);
+    await mixHash(ciphertext);
+    return ciphertext;
+  }
+
+  Future<List<int>> decryptAndHash(List<int> ciphertext) async {
+    final cipher = _cipher;
+    final plaintext = cipher == null ? ciphertext : await cipher.decrypt(ciphertext, ad: _h);
+    await mixHash(ciphertext);
+    return plaintext;
+  }
+
+  // 送信用・受信用の鍵に分ける（イニシエーターは1つ目で送信）
+  Future<(_CipherState, _CipherState)> split() async {
+    final (send, receive) = await _hkdf(_ck, const []);
+    return (_CipherState(send), _CipherState(receive));
+  }
+}
+
+/// 暗号化したWebSocketセッション
+/// 送受信はそれぞれ順番に処理する（ノンスの順序とメッセージの順序を一致させるため）
+class SecureChannel {
+  final WebSocketChannel _channel;
+  final _CipherState _send;
+  final _CipherState _receive;
+  final StreamController<dynamic> _incoming;
+  final StreamSubscription _subscription;
+  Future<void> _sendQueue = Future.value();
+  Future<void> _receiveQueue = Future.value();
+
+  SecureChannel._(this._channel, this._send, this._receive, this._incoming, this._subscription);
+
+  /// 復号済みのメッセージ（JSONはString、画面フレームはUint8List）
+  Stream<dynamic> get stream => _incoming.stream;
+
+  /// QRコードの公開鍵（base64url）でハンドシェイクを行う
+  /// チャンネルのストリームはこのクラスが購読するので、以降は [stream] を使う
+  static Future<SecureChannel> connect(WebSocketChannel channel, String desktopPublicKey) async {
+    final remoteStatic = base64Url.decode(base64Url.normalize(desktopPublicKey));
+    if (remoteStatic.length != _keyLength) {
+      throw const FormatException('Invalid desktop public key');
+    }
+
+    // NK: デスクトップの静的公開鍵は事前に知っている（プロローグは空）
+    final state = await _SymmetricState.initialize();
+    await state.mixHash(const []);
+    await state.mixHash(remoteStatic);
+
+    // -> e, es
+    final ephemeral = await _x25519.newKeyPair();
+    final ephemeralPublic = (await ephemeral.extractPublicKey()).bytes;
+    await state.mixHash(ephemeralPublic);
+    await state.mixKey(await _dh(ephemeral, remoteStatic));
+    final request = await state.encryptAndHash(const []);
+
+    // 最初のメッセージは応答として受け取り、以降は復号して流す
+    final reply = Completer<List<int>>();
+    final incoming = StreamController<dynamic>();
+    SecureChannel? secure;
+    final subscription = channel.stream.listen(
+      (message) {
+        if (secure != null) {
+          secure!._onMessage(message);
+        } else if (reply.isCompleted) {
+          // 応答の処理中に届いたメッセージ（デスクトップは応答を待たずに送らない）
+          incoming.addError(const FormatException('Unexpected message during handshake'));
+        } else if (message is List<int>) {
+          reply.complete(message);
+        } else {
+          reply.completeError(const FormatException('Desktop did not accept the encrypted session'));
+        }
+      },
+      onError: (Object e) {
+        if (!reply.isCompleted) {
+          reply.completeError(e);
+        } else {
+          incoming.addError(e);
+        }
+      },
+      onDone: () {
+        if (!reply.isCompleted) {
+          reply.completeError(const FormatException('Connection closed during handshake'));
+        } else {
+          // 復号待ちのメッセージを流してから閉じる
+          (secure?._receiveQueue ?? Future.value()).whenComplete(incoming.close);
+        }
+      },
+    );
+
+    try {
+      channel.sink.add(Uint8List.fromList([_magic, _version, ...ephemeralPublic, ...request]));
+
+      // <- e, ee
+      final data = await reply.future.timeout(_handshakeTimeout);
+      if (data.length < 2 + _keyLength + _tagLength || data[0] != _magic || data[1] != _version) {
+        throw const FormatException('Invalid handshake reply');
+      }
+      final remoteEphemeral = data.sublist(2, 2 + _keyLength);
+      await state.mixHash(remoteEphemeral);
+      await state.mixKey(await _dh(ephemeral, remoteEphemeral));
+      await state.decryptAndHash(data.sublist(2 + _keyLength));
+
+      final (send, receive) = await state.split();
+      secure = SecureChannel._(channel, send, receive, incoming, subscription);
+      print('[E2E] Encrypted session established');
+      return secure!;
+    } catch (e) {
+      await subscription.cancel();
+      await incoming.close();
+      rethrow;
+    }
+  }
+
+  /// JSON文字列またはバイナリを暗号化して送信
+  void add(dynamic message) {
+    final (int kind, List<int> payload) = message is String
+        ? (_kindText, utf8.encode(message))
+        : (_kindBinary, message as List<int>);
+    _sendQueue = _sendQueue.then((_) async {
+      _channel.sink.add(await _seal(kind, payload));
+    }).catchError((Object e) {
+      print('[E2E] Encrypt failed: $e');
+    });
+  }
+
+  Future<void> close() async {
+    await _subscription.cancel();
+    await _channel.sink.close();
+    if (!_incoming.isClosed) {
+      await _incoming.close();
+    }
+  }
+
+  void _onMessage(dynamic message) {
+    _receiveQueue = _receiveQueue.then((_) async {
+      if (message is! List<int>) {
+        throw const FormatException('Plaintext message on an encrypted session');
+      }
+      final opened = await _open(message);
+      if (!_incoming.isClosed) {
+        _incoming.add(opened);
+      }
+    }).catchError((Object e) {
+      // 改ざん・欠落したメッセージ以降は復号できないので切断する
+      print('[E2E] Decrypt failed: $e');
+      if (!_incoming.isClosed) {
+        _incoming.addError(e);
+      }
+      _channel.sink.close();
+    });
+  }
+
+  // Noiseの1メッセージ上限を超えるデータは分割して暗号化する
+  Future<Uint8List> _seal(int kind, List<int> payload) async {
+    final plaintext = Uint8List(payload.length + 1)
+      ..[0] = kind
+      ..setRange(1, payload.length + 1, payload);
+    const chunkLength = _maxMessage - _tagLength;
+    final out = BytesBuilder(copy: false);
+    for (var offset = 0; offset < plaintext.length; offset += chunkLength) {
+      final end = min(offset + chunkLength, plaintext.length);
+      final sealed = await _send.encrypt(Uint8List.sublistView(plaintext, offset, end));
+      out
+        ..addByte(sealed.length >> 8)
+        ..addByte(sealed.length & 0xff)
+        ..add(sealed);
+    }
+    return out.takeBytes();
+  }
+
+  Future<dynamic> _open(List<int> data) async {
+    final plaintext = BytesBuilder(copy: false);
+    var offset = 0;
+    while (offset < data.length) {
+      if (data.length - offset < 2) {
+        throw const FormatException('Truncated chunk header');
+      }
+      final length = (data[offset] << 8) | data[offset + 1];
+      final end = offset + 2 + length;
+      if (end > data.length) {
+        throw const FormatException('Truncated chunk');
+      }
+      plaintext.add(await _receive.decrypt(data.sublist(offset + 2, end)));
+      offset = end;
+    }
+    final bytes = plaintext.takeBytes();
+    if (bytes.isEmpty) {
+      throw const FormatException('Empty message');
+    }
+    final payload = Uint8List.sublistView(bytes, 1);
+    switch (bytes[0]) {
+      case _kindText:
+        return utf8.decode(payload);
+      case _kindBinary:
+        return payload;
+      default:
+        throw FormatException('Unknown message kind ${bytes[0]}');
+    }
+  }
+}
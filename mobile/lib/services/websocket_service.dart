@@ -5,6 +5,7 @@ import 'package:flutter_riverpod/flutter_riverpod.dart';
 import 'package:web_socket_channel/web_socket_channel.dart';
 import '../models/command.dart';
 import '../models/connection_info.dart';
+import 'secure_channel.dart';
 import 'webrtc_service.dart';
 import 'h264_decoder_service.dart';
 
@@ -292,6 +293,8 @@ class WebSocketState {
 
 class WebSocketService extends StateNotifier<WebSocketState> {
   WebSocketChannel? _channel;
+  // E2E暗号化セッション（QRコードに公開鍵がある場合）
+  SecureChannel? _secure;
   StreamSubscription? _subscription;
   ConnectionInfo? _connectionInfo;
   WebRTCService? _webrtcService;
@@ -347,6 +350,7 @@ class WebSocketService extends StateNotifier<WebSocketState> {
     _h264Decoder = null;
     _channel?.sink.close();
     _channel = null;
+    _secure = null;
     _ptyOutputController.close();
     _terminalContentController.close();
     super.dispose();
@@ -362,6 +366,7 @@ class WebSocketService extends StateNotifier<WebSocketState> {
       _subscription?.cancel();
       _channel?.sink.close();
       _channel = null;
+      _secure = null;
       _subscription = null;
     }
 
@@ -387,7 +392,24 @@ class WebSocketService extends StateNotifier<WebSocketState> {
         return;
       }
 
-      _subscription = _channel!.stream.listen(
+      // 公開鍵があればハンドシェイクしてから認証する（トンネルやリレーから中身を見えなくする）
+      final desktopPublicKey = info.desktopPublicKey;
+      if (desktopPublicKey != null) {
+        try {
+          _secure = await SecureChannel.connect(_channel!, desktopPublicKey);
+        } catch (e) {
+          print('[WebSocket] E2E handshake failed: $e');
+          _safeSetState((s) => s.copyWith(
+            connectionState: WsConnectionState.error,
+            errorMessage: '暗号化接続に失敗しました: $e',
+          ));
+          _channel?.sink.close();
+          _channel = null;
+          return;
+        }
+      }
+
+      _subscription = (_secure?.stream ?? _channel!.stream).listen(
         _onMessage,
         onError: _onError,
         onDone: _onDone,
@@ -418,6 +440,7 @@ class WebSocketService extends StateNotifier<WebSocketState> {
     _subscription?.cancel();
     _channel?.sink.close();
     _channel = null;
+    _secure = null;
     // H264デコーダーをリセット
     _h264Decoder?.dispose();
     _h264Decoder = null;
@@ -780,7 +803,9 @@ class WebSocketService extends StateNotifier<WebSocketState> {
   }
 
   void _send(Map<String, dynamic> data) {
-    if (_channel != null) {
+    if (_secure != null) {
+      _secure!.add(jsonEncode(data));
+    } else if (_channel != null) {
       _channel!.sink.add(jsonEncode(data));
     }
   }
@@ -1141,6 +1166,7 @@ class WebSocketService extends StateNotifier<WebSocketState> {
     // 接続情報をクリア
     _connectionInfo = null;
     _channel = null;
+    _secure = null;
   }
 }
 
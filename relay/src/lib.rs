@@ -0,0 +1,304 @@
+//! Pocket Remote リレーサーバー
+//!
+//! デスクトップは `/desktop/{id}` に制御用WebSocketを張ったままにしておき、
+//! スマホは `/connect/{id}` に接続する。リレーはデスクトップに
+//! `{"type":"incoming","session":"..."}` を送り、デスクトップが
+//! `/accept/{id}/{session}` に接続した時点で2本のWebSocketをつなぐ。
+//! 中継するのはメッセージの中身を解釈しないバイト列のみ。
+
+use futures_util::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// デスクトップが受け入れ用の接続を張るまでの待ち時間
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(15);
+
+type Ws = WebSocketStream<TcpStream>;
+
+#[derive(Default)]
+struct Relay {
+    // デスクトップID -> 制御チャンネル
+    desktops: Mutex<HashMap<String, mpsc::UnboundedSender<String>>>,
+    // セッションID -> スマホ側の待ち受け（接続先デスクトップIDと組で持つ）
+    pending: Mutex<HashMap<String, Pending>>,
+}
+
+struct Pending {
+    desktop: String,
+    tx: oneshot::Sender<Ws>,
+}
+
+impl Relay {
+    // セッションがこのデスクトップ宛てに待機中か（他のデスクトップには受け入れさせない）
+    fn owns(&self, id: &str, session: &str) -> bool {
+        self.pending.lock().unwrap().get(session).is_some_and(|p| p.desktop == id)
+    }
+}
+
+enum Route {
+    Desktop(String),
+    Connect(String),
+    Accept(String, String),
+}
+
+// デスクトップIDは推測されにくいランダム値を想定（英数字とハイフンのみ）
+fn valid_id(id: &str) -> bool {
+    (16..=128).contains(&id.len()) && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+fn parse_route(path: &str) -> Option<Route> {
+    let path = path.split('?').next().unwrap_or("");
+    let parts: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let route = match parts.as_slice() {
+        ["desktop", id] => Route::Desktop(id.to_string()),
+        ["connect", id] => Route::Connect(id.to_string()),
+        ["accept", id, session] if valid_id(session) => Route::Accept(id.to_string(), session.to_string()),
+        _ => return None,
+    };
+    let id = match &route {
+        Route::Desktop(id) | Route::Connect(id) | Route::Accept(id, _) => id,
+    };
+    valid_id(id).then_some(route)
+}
+
+fn error_response(status: StatusCode) -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some(status.canonical_reason().unwrap_or("").to_string()));
+    *response.status_mut() = status;
+    response
+}
+
+/// リレーサーバーを起動（listenerは呼び出し側でbind）
+pub async fn run(listener: TcpListener) -> std::io::Result<()> {
+    let relay = Arc::new(Relay::default());
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let relay = relay.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(relay, stream).await {
+                eprintln!("[Relay] {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+// ハンドシェイクのコールバックの型はtungsteniteが決めている
+#[allow(clippy::result_large_err)]
+async fn handle(relay: Arc<Relay>, stream: TcpStream) -> Result<(), String> {
+    let mut route = None;
+    let ws = tokio_tungstenite::accept_hdr_async(stream, |req: &Request, resp: Response| {
+        // 登録状況はハンドシェイク時点で確認し、HTTPステータスで返す
+        let parsed = parse_route(req.uri().path()).ok_or_else(|| error_response(StatusCode::NOT_FOUND))?;
+        match &parsed {
+            Route::Desktop(id) if relay.desktops.lock().unwrap().contains_key(id) => {
+                return Err(error_response(StatusCode::CONFLICT));
+            }
+            Route::Connect(id) if !relay.desktops.lock().unwrap().contains_key(id) => {
+                return Err(error_response(StatusCode::NOT_FOUND));
+            }
+            Route::Accept(id, session) if !relay.owns(id, session) => {
+                return Err(error_response(StatusCode::NOT_FOUND));
+            }
+            _ => {}
+        }
+        route = Some(parsed);
+        Ok(resp)
+    })
+    .await
+    .map_err(|e| format!("handshake failed: {}", e))?;
+
+    match route {
+        Some(Route::Desktop(id)) => serve_desktop(relay, id, ws).await,
+        Some(Route::Connect(id)) => serve_phone(relay, id, ws).await,
+        Some(Route::Accept(id, session)) => {
+            let waiter = {
+                let mut pending = relay.pending.lock().unwrap();
+                match pending.get(&session) {
+                    Some(p) if p.desktop == id => pending.remove(&session),
+                    _ => None,
+                }
+            };
+            match waiter {
+                Some(p) => p.tx.send(ws).map_err(|_| "phone already gone".to_string()),
+                None => Err(format!("session {} is not pending for {}", session, id)),
+            }
+        }
+        None => Ok(()),
+    }
+}
+
+// 制御チャンネル: 着信をデスクトップに通知する
+async fn serve_desktop(relay: Arc<Relay>, id: String, ws: Ws) -> Result<(), String> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    {
+        let mut desktops = relay.desktops.lock().unwrap();
+        if desktops.contains_key(&id) {
+            return Err("desktop id already registered".to_string());
+        }
+        desktops.insert(id.clone(), tx);
+    }
+    println!("[Relay] Desktop registered: {}", id);
+
+    let (mut write, mut read) = ws.split();
+    loop {
+        tokio::select! {
+            notice = rx.recv() => match notice {
+                Some(text) => {
+                    if write.send(Message::Text(text)).await.is_err() {
+                        break;
+                    }
+                }
+                None => break,
+            },
+            msg = read.next() => match msg {
+                // Pingへの応答はtungsteniteが自動で行う
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+
+    relay.desktops.lock().unwrap().remove(&id);
+    println!("[Relay] Desktop unregistered: {}", id);
+    Ok(())
+}
+
+// スマホ側: デスクトップの受け入れを待ってから双方向に中継する
+async fn serve_phone(relay: Arc<Relay>, id: String, phone: Ws) -> Result<(), String> {
+    let session = uuid::Uuid::new_v4().to_string();
+    let (tx, rx) = oneshot::channel();
+    relay.pending.lock().unwrap().insert(session.clone(), Pending { desktop: id.clone(), tx });
+
+    let notice = format!(r#"{{"type":"incoming","session":"{}"}}"#, session);
+    let notified = relay
+        .desktops
+        .lock()
+        .unwrap()
+        .get(&id)
+        .map(|desktop| desktop.send(notice).is_ok())
+        .unwrap_or(false);
+
+    let desktop = if notified {
+        tokio::time::timeout(ACCEPT_TIMEOUT, rx).await.ok().and_then(|r| r.ok())
+    } else {
+        None
+    };
+    relay.pending.lock().unwrap().remove(&session);
+
+    let Some(desktop) = desktop else {
+        let mut phone = phone;
+        phone.close(None).await.ok();
+        return Err(format!("desktop {} did not accept session {}", id, session));
+    };
+
+    println!("[Relay] Session {} opened for {}", session, id);
+    pipe(phone, desktop).await;
+    println!("[Relay] Session {} closed", session);
+    Ok(())
+}
+
+// どちらかが切断するまでメッセージをそのまま転送
+async fn pipe(a: Ws, b: Ws) {
+    let (mut a_write, mut a_read) = a.split();
+    let (mut b_write, mut b_read) = b.split();
+
+    let a_to_b = async {
+        while let Some(Ok(msg)) = a_read.next().await {
+            if forward(&mut b_write, msg).await.is_err() {
+                break;
+            }
+        }
+        b_write.close().await.ok();
+    };
+    let b_to_a = async {
+        while let Some(Ok(msg)) = b_read.next().await {
+            if forward(&mut a_write, msg).await.is_err() {
+                break;
+            }
+        }
+        a_write.close().await.ok();
+    };
+
+    tokio::select! {
+        _ = a_to_b => {}
+        _ = b_to_a => {}
+    }
+}
+
+async fn forward<S>(sink: &mut S, msg: Message) -> Result<(), ()>
+where
+    S: futures_util::Sink<Message> + Unpin,
+{
+    match msg {
+        Message::Text(_) | Message::Binary(_) => sink.send(msg).await.map_err(|_| ()),
+        Message::Close(_) => Err(()),
+        // Ping/Pongは各区間で処理される
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_tungstenite::connect_async;
+
+    #[tokio::test]
+    async fn test_relay_forwards_both_directions() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(run(listener));
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let (mut control, _) = connect_async(format!("{}/desktop/{}", base, id)).await.unwrap();
+
+        // 未登録のIDには接続できない
+        assert!(connect_async(format!("{}/connect/{}", base, uuid::Uuid::new_v4())).await.is_err());
+
+        let phone = tokio::spawn(connect_async(format!("{}/connect/{}", base, id)));
+
+        let notice = match control.next().await.unwrap().unwrap() {
+            Message::Text(text) => text,
+            other => panic!("unexpected control message: {:?}", other),
+        };
+        let session = notice.split('"').nth(7).unwrap().to_string();
+
+        let (mut desktop, _) = connect_async(format!("{}/accept/{}/{}", base, id, session)).await.unwrap();
+        let (mut phone, _) = phone.await.unwrap().unwrap();
+
+        phone.send(Message::Text("hello".into())).await.unwrap();
+        assert_eq!(desktop.next().await.unwrap().unwrap(), Message::Text("hello".into()));
+
+        desktop.send(Message::Binary(vec![1, 2, 3])).await.unwrap();
+        assert_eq!(phone.next().await.unwrap().unwrap(), Message::Binary(vec![1, 2, 3]));
+    }
+
+    #[tokio::test]
+    async fn test_accept_rejects_other_desktop() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(run(listener));
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let other = uuid::Uuid::new_v4().to_string();
+        let (mut control, _) = connect_async(format!("{}/desktop/{}", base, id)).await.unwrap();
+        let (_other_control, _) = connect_async(format!("{}/desktop/{}", base, other)).await.unwrap();
+        let phone = tokio::spawn(connect_async(format!("{}/connect/{}", base, id)));
+
+        let notice = match control.next().await.unwrap().unwrap() {
+            Message::Text(text) => text,
+            other => panic!("unexpected control message: {:?}", other),
+        };
+        let session = notice.split('"').nth(7).unwrap().to_string();
+
+        // 別のデスクトップは他人宛てのセッションを受け入れられない（待機中のまま残る）
+        assert!(connect_async(format!("{}/accept/{}/{}", base, other, session)).await.is_err());
+        let (_desktop, _) = connect_async(format!("{}/accept/{}/{}", base, id, session)).await.unwrap();
+        assert!(phone.await.unwrap().is_ok());
+    }
+}
@@ -0,0 +1,27 @@
+use tokio::net::TcpListener;
+
+/// 使い方: pocket-remote-relay [--bind 0.0.0.0:9880]
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let mut bind = std::env::var("RELAY_BIND").unwrap_or_else(|_| "0.0.0.0:9880".to_string());
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bind" => {
+                if let Some(addr) = args.next() {
+                    bind = addr;
+                }
+            }
+            "-h" | "--help" => {
+                println!("Usage: pocket-remote-relay [--bind ADDR:PORT]");
+                return Ok(());
+            }
+            other => eprintln!("Unknown argument: {}", other),
+        }
+    }
+
+    let listener = TcpListener::bind(&bind).await?;
+    println!("[Relay] Listening on ws://{}", listener.local_addr()?);
+    pocket_remote_relay::run(listener).await
+}